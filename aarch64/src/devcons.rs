@@ -1,11 +1,18 @@
 // Racy to start.
 
 use crate::param::KZERO;
-use crate::uartmini::MiniUart;
+use crate::trap;
+#[cfg(feature = "miniuart_tx_irq")]
+use crate::uartmini;
+use crate::uartmini::{MiniUart, MiniUartIrqHandler, AUX_IRQ};
+use crate::uartpl011::Pl011Uart;
 use core::cell::SyncUnsafeCell;
 use core::mem::MaybeUninit;
-use port::devcons::Console;
+#[cfg(feature = "miniuart_tx_irq")]
+use core::sync::atomic::{AtomicBool, Ordering};
+use port::devcons::{self, Console};
 use port::fdt::DeviceTree;
+use port::mcslock::{Lock, LockNode};
 
 // The aarch64 devcons implementation is focussed on Raspberry Pi 3, 4 for now.
 
@@ -30,19 +37,188 @@ use port::fdt::DeviceTree;
 // TODO
 // - Detect board type and set MMIO base address accordingly
 //     https://wiki.osdev.org/Detecting_Raspberry_Pi_Board
-// - Break out mailbox, gpio code
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UartKind {
+    PL011,
+    MiniUART,
+}
+
+static UART_KIND: Lock<Option<UartKind>> = Lock::new("uartkind", None);
+
+/// Set once the MiniUART's interrupt handler has been registered
+#[cfg(feature = "miniuart_tx_irq")]
+static MINIUART_IRQ_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Work out which UART to use for the console from the devicetree.  We follow
+/// the stdout-path property of /chosen (which may be an alias), and look at the
+/// compatible property of the node it refers to.  Defaults to the MiniUART.
+fn detect_uart_kind(dt: &DeviceTree) -> UartKind {
+    let stdout_path = dt
+        .find_by_path("/chosen")
+        .and_then(|chosen| {
+            dt.property(&chosen, "stdout-path").or_else(|| dt.property(&chosen, "serial"))
+        })
+        .and_then(|prop| dt.property_value_as_str(&prop));
+    uart_kind_for_stdout_path(dt, stdout_path)
+}
+
+/// The kind of UART at stdout_path, the value of a stdout-path property
+fn uart_kind_for_stdout_path(dt: &DeviceTree, stdout_path: Option<&str>) -> UartKind {
+    // Strip any options, e.g. "serial0:115200n8"
+    let path = stdout_path.and_then(|path| path.split(':').next());
+
+    let path = match path {
+        Some(path) if !path.starts_with('/') => dt
+            .find_by_path("/aliases")
+            .and_then(|aliases| dt.property(&aliases, path))
+            .and_then(|prop| dt.property_value_as_str(&prop)),
+        path => path,
+    };
+
+    let compatible = path
+        .and_then(|path| dt.find_by_path(path))
+        .and_then(|node| dt.property(&node, "compatible"))
+        .and_then(|prop| dt.property_value_as_str(&prop));
+    match compatible {
+        Some("arm,pl011") => UartKind::PL011,
+        _ => UartKind::MiniUART,
+    }
+}
+
+/// Return the clock-frequency property of the first node compatible with comp
+fn uart_clock_hz(dt: &DeviceTree, comp: &str) -> Option<u32> {
+    dt.find_compatible(comp)
+        .next()
+        .and_then(|node| dt.property(&node, "clock-frequency"))
+        .and_then(|prop| dt.property_value_as_u32(&prop))
+}
+
+/// Return the kind of UART used by the console
+#[allow(dead_code)]
+pub fn uart_kind() -> UartKind {
+    let node = LockNode::new();
+    let uart_kind = UART_KIND.lock(&node);
+    uart_kind.unwrap_or(UartKind::MiniUART)
+}
 
 pub fn init(dt: &DeviceTree) {
-    Console::new(|| {
-        let uart = MiniUart::new(dt, KZERO);
-        uart.init();
-
-        static UART: SyncUnsafeCell<MaybeUninit<MiniUart>> =
-            SyncUnsafeCell::new(MaybeUninit::uninit());
-        unsafe {
-            let cons = &mut *UART.get();
-            cons.write(uart);
-            cons.assume_init_mut()
+    let kind = detect_uart_kind(dt);
+    {
+        let node = LockNode::new();
+        *UART_KIND.lock(&node) = Some(kind);
+    }
+
+    match kind {
+        UartKind::PL011 => Console::new(|| {
+            let uart = Pl011Uart::new(dt);
+            uart.init_pins();
+            pl011_uart(uart, dt)
+        }),
+        UartKind::MiniUART => Console::new(|| {
+            let mut uart = MiniUart::new(dt, KZERO);
+            uart.init();
+            if let Some(uart_clock_hz) = uart_clock_hz(dt, "brcm,bcm2835-aux-uart") {
+                uart.set_baud(115200, uart_clock_hz);
+            }
+
+            // Received bytes are buffered by the interrupt handler.  The
+            // interrupt won't be delivered until there's an interrupt
+            // controller, and until then we fall back to polling.
+            static IRQ_HANDLER: SyncUnsafeCell<MaybeUninit<MiniUartIrqHandler>> =
+                SyncUnsafeCell::new(MaybeUninit::uninit());
+            let irq_handler = unsafe {
+                let irq_handler = &mut *IRQ_HANDLER.get();
+                irq_handler.write(uart.irq_handler());
+                irq_handler.assume_init_ref()
+            };
+            if trap::register_irq_handler(AUX_IRQ, irq_handler).is_ok() {
+                uart.enable_rx_interrupt();
+                #[cfg(feature = "miniuart_tx_irq")]
+                MINIUART_IRQ_REGISTERED.store(true, Ordering::Release);
+            }
+
+            static UART: SyncUnsafeCell<MaybeUninit<MiniUart>> =
+                SyncUnsafeCell::new(MaybeUninit::uninit());
+            unsafe {
+                let cons = &mut *UART.get();
+                cons.write(uart);
+                cons.assume_init_mut()
+            }
+        }),
+    };
+
+    // When the console is the MiniUART, write to the PL011 too, if there is
+    // one and a pin is muxed to its transmit line, so there's output from
+    // whichever is wired up.  Not the other way round, as initialising the
+    // MiniUART takes GPIO pins 14 and 15 from the PL011.  The sink leaves
+    // the pins as they are.
+    if kind == UartKind::MiniUART && dt.find_compatible("arm,pl011").next().is_some() {
+        let uart = Pl011Uart::new(dt);
+        if uart.owns_tx_pin() {
+            let _ = devcons::add_sink(pl011_uart(uart, dt));
         }
-    });
+    }
+}
+
+/// Switch the console to interrupt driven transmit, if it's the MiniUART and
+/// its interrupt will be delivered.  Call once the interrupt controller is
+/// set up.
+#[cfg(feature = "miniuart_tx_irq")]
+pub fn enable_tx_interrupt() {
+    if uart_kind() == UartKind::MiniUART
+        && MINIUART_IRQ_REGISTERED.load(Ordering::Acquire)
+        && trap::has_irq_controller()
+    {
+        uartmini::enable_tx_interrupt();
+    }
+}
+
+/// Initialise the PL011, returning it in a static
+fn pl011_uart(mut uart: Pl011Uart, dt: &DeviceTree) -> &'static mut Pl011Uart {
+    // init sets the baud rate from the clock rate the firmware reports.  The
+    // Raspberry Pi devicetrees give the UART clocks rather than a
+    // clock-frequency, so this only overrides it on other boards.
+    uart.init();
+    if let Some(uart_clock_hz) = uart_clock_hz(dt, "arm,pl011") {
+        uart.set_baud(115200, uart_clock_hz);
+    }
+
+    static UART: SyncUnsafeCell<MaybeUninit<Pl011Uart>> =
+        SyncUnsafeCell::new(MaybeUninit::uninit());
+    unsafe {
+        let cons = &mut *UART.get();
+        cons.write(uart);
+        cons.assume_init_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_uart_kind_defaults_to_miniuart() {
+        // test1.dtb has no stdout-path in /chosen
+        let dtb = include_bytes!("../../port/lib/test/fdt/test1.dtb");
+        let dt = DeviceTree::new(dtb).unwrap();
+        assert_eq!(detect_uart_kind(&dt), UartKind::MiniUART);
+    }
+
+    #[test]
+    fn uart_kind_from_stdout_path() {
+        // In test1.dtb, serial0 and uart1 are the MiniUART, and serial1 and
+        // uart0 the PL011
+        let dtb = include_bytes!("../../port/lib/test/fdt/test1.dtb");
+        let dt = DeviceTree::new(dtb).unwrap();
+        let kind = |path| uart_kind_for_stdout_path(&dt, Some(path));
+        assert_eq!(kind("serial0"), UartKind::MiniUART);
+        assert_eq!(kind("serial1"), UartKind::PL011);
+        assert_eq!(kind("serial1:115200n8"), UartKind::PL011);
+        assert_eq!(kind("/soc/serial@7e201000"), UartKind::PL011);
+        assert_eq!(kind("/soc/serial@7e215040:115200n8"), UartKind::MiniUART);
+        // Anything that isn't a PL011 falls back to the MiniUART
+        assert_eq!(kind("nosuchalias"), UartKind::MiniUART);
+        assert_eq!(kind("/soc/mmc@7e202000"), UartKind::MiniUART);
+    }
 }
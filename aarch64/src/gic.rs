@@ -0,0 +1,140 @@
+//! GICv2 interrupt controller
+//!
+//! Just enough of the distributor and CPU interface to route interrupts to
+//! the boot core.  The Raspberry Pi 4 has a GIC-400, while the Raspberry Pi 3
+//! has no GIC at all, in which case init does nothing.
+//!
+//! https://developer.arm.com/documentation/ihi0048/b
+
+use crate::io::{read_reg, write_reg};
+use crate::trap::{self, IrqController};
+use crate::vm::{self, PageSize};
+use core::cell::SyncUnsafeCell;
+use core::mem::MaybeUninit;
+use port::fdt::DeviceTree;
+use port::mem::VirtRange;
+use port::println;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+
+// CPU interface registers
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00c;
+const GICC_EOIR: usize = 0x010;
+
+/// Interrupt IDs of 1020 and above are special, with 1023 meaning there was
+/// no pending interrupt.
+const SPURIOUS_IRQ: u32 = 1020;
+
+/// Shared peripheral interrupts start at 32.  Below that are per-core.
+const FIRST_SPI: u32 = 32;
+
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+pub struct Gic {
+    dist: VirtRange,
+    cpu: VirtRange,
+}
+
+impl Gic {
+    /// Find the GIC in the devicetree and map its registers
+    fn from_dt(dt: &DeviceTree) -> Option<Gic> {
+        let node = dt
+            .find_compatible("arm,gic-400")
+            .next()
+            .or_else(|| dt.find_compatible("arm,cortex-a15-gic").next())?;
+        let regs = vm::map_device_node("gic", dt, node, PageSize::Page4K).ok()?;
+        let mut regs = regs.iter().map(|r| VirtRange(r.0.clone()));
+        let dist = regs.next()?;
+        let cpu = regs.next()?;
+        Some(Gic { dist, cpu })
+    }
+
+    /// Number of interrupt lines supported by the distributor
+    pub fn num_irqs(&self) -> u32 {
+        ((read_reg(&self.dist, GICD_TYPER) & 0x1f) + 1) * 32
+    }
+
+    /// Enable forwarding of interrupts from the distributor and the CPU
+    /// interface, with all interrupts initially disabled.
+    fn init(&self) {
+        write_reg(&self.dist, GICD_CTLR, 0);
+        for i in 0..self.num_irqs() / 32 {
+            write_reg(&self.dist, GICD_ICENABLER + i as usize * 4, 0xffff_ffff);
+        }
+        write_reg(&self.dist, GICD_CTLR, 1);
+
+        // Allow all priorities through
+        write_reg(&self.cpu, GICC_PMR, 0xff);
+        write_reg(&self.cpu, GICC_CTLR, 1);
+    }
+
+    /// Set the byte for irq in a register array with one byte per interrupt
+    fn write_byte(&self, base: usize, irq: u32, val: u8) {
+        let offset = base + (irq as usize & !3);
+        let shift = (irq % 4) * 8;
+        let old = read_reg(&self.dist, offset) & !(0xff << shift);
+        write_reg(&self.dist, offset, old | ((val as u32) << shift));
+    }
+
+    /// Enable the interrupt, routing it to the boot core
+    pub fn enable_irq(&self, irq: u32) {
+        self.write_byte(GICD_IPRIORITYR, irq, DEFAULT_PRIORITY);
+        if irq >= FIRST_SPI {
+            self.write_byte(GICD_ITARGETSR, irq, 1);
+        }
+        write_reg(&self.dist, GICD_ISENABLER + (irq as usize / 32) * 4, 1 << (irq % 32));
+    }
+
+    /// Acknowledge the highest priority pending interrupt, returning its ID
+    pub fn ack(&self) -> u32 {
+        read_reg(&self.cpu, GICC_IAR)
+    }
+
+    /// Signal the end of handling for an interrupt returned by ack
+    pub fn eoi(&self, irq: u32) {
+        write_reg(&self.cpu, GICC_EOIR, irq);
+    }
+}
+
+impl IrqController for Gic {
+    fn acknowledge(&self) -> Option<u32> {
+        let irq = self.ack() & 0x3ff;
+        (irq < SPURIOUS_IRQ).then_some(irq)
+    }
+
+    fn end_of_interrupt(&self, irq_num: u32) {
+        self.eoi(irq_num);
+    }
+
+    fn enable_irq(&self, irq_num: u32) {
+        Gic::enable_irq(self, irq_num);
+    }
+}
+
+/// Find and initialise the GIC, if there is one, and make it the interrupt
+/// controller used by trap.  Must be called after switching to the kernel
+/// page table.
+pub fn init(dt: &DeviceTree) {
+    let Some(gic) = Gic::from_dt(dt) else {
+        println!("No GIC found");
+        return;
+    };
+    gic.init();
+    println!("GIC: {} irqs", gic.num_irqs());
+
+    static GIC: SyncUnsafeCell<MaybeUninit<Gic>> = SyncUnsafeCell::new(MaybeUninit::uninit());
+    let gic = unsafe {
+        let maybe_gic = &mut *GIC.get();
+        maybe_gic.write(gic);
+        maybe_gic.assume_init_ref()
+    };
+    trap::set_irq_controller(gic);
+}
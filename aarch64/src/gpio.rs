@@ -0,0 +1,136 @@
+use crate::io::{delay, read_reg, write_reg};
+use crate::registers::{GPFSEL0, GPPUD, GPPUDCLK0};
+use port::fdt::DeviceTree;
+use port::mem::VirtRange;
+
+/// Number of GPIO pins on the BCM2835
+pub const NUM_PINS: u8 = 54;
+
+/// The function of a pin, with the values used in the GPFSELn registers.
+/// The alternate functions aren't numbered in order.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioFunction {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioPull {
+    Off = 0,
+    Down,
+    Up,
+}
+
+/// GPIO pin control for the Raspberry Pi (BCM2835 and compatible)
+/// https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf
+pub struct Gpio {
+    pub range: VirtRange,
+}
+
+impl Gpio {
+    pub fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> Gpio {
+        // Bcm2835 and bcm2711 are essentially the same for our needs here.
+        // If fdt.rs supported aliases well, we could try to just look up 'gpio'.
+        Gpio {
+            range: VirtRange::from(
+                &dt.find_compatible("brcm,bcm2835-gpio")
+                    .next()
+                    .or_else(|| dt.find_compatible("brcm,bcm2711-gpio").next())
+                    .and_then(|gpio| dt.property_translated_reg_iter(gpio).next())
+                    .and_then(|reg| reg.regblock())
+                    .unwrap()
+                    .with_offset(mmio_virt_offset as u64),
+            ),
+        }
+    }
+
+    /// Select the function of pin
+    pub fn set_function(&self, pin: u8, func: GpioFunction) {
+        assert!(pin < NUM_PINS, "bad gpio pin {pin}");
+        // Each GPFSELn register has 3 bits for each of 10 pins
+        let reg = GPFSEL0 + (pin as usize / 10) * 4;
+        let shift = (pin as usize % 10) * 3;
+        let mut gpfsel = read_reg(&self.range, reg);
+        gpfsel &= !(0b111 << shift);
+        gpfsel |= (func as u32) << shift;
+        write_reg(&self.range, reg, gpfsel);
+    }
+
+    /// The function pin is selected for
+    pub fn function(&self, pin: u8) -> GpioFunction {
+        assert!(pin < NUM_PINS, "bad gpio pin {pin}");
+        let reg = GPFSEL0 + (pin as usize / 10) * 4;
+        let shift = (pin as usize % 10) * 3;
+        match (read_reg(&self.range, reg) >> shift) & 0b111 {
+            0b000 => GpioFunction::Input,
+            0b001 => GpioFunction::Output,
+            0b100 => GpioFunction::Alt0,
+            0b101 => GpioFunction::Alt1,
+            0b110 => GpioFunction::Alt2,
+            0b111 => GpioFunction::Alt3,
+            0b011 => GpioFunction::Alt4,
+            _ => GpioFunction::Alt5,
+        }
+    }
+
+    /// Set the pull up/down state of pin
+    pub fn set_pull(&self, pin: u8, pull: GpioPull) {
+        assert!(pin < NUM_PINS, "bad gpio pin {pin}");
+        // The GPIO pull up/down bits are spread across consecutive registers GPPUDCLK0 to GPPUDCLK1
+        // GPPUDCLK0: pins  0-31
+        // GPPUDCLK1: pins 32-53
+        let gppudclk_reg = GPPUDCLK0 + (pin as usize / 32) * 4;
+        let pud_bit = 1 << (pin % 32);
+
+        // You can't read the GPPUD registers, so to set the state we first set the PUD value we want...
+        write_reg(&self.range, GPPUD, pull as u32);
+        // ...wait 150 cycles for it to set
+        delay(150);
+        // ...clock it into the pin
+        write_reg(&self.range, gppudclk_reg, pud_bit);
+        // ...wait 150 cycles for it to set
+        delay(150);
+        // ...clear up
+        write_reg(&self.range, GPPUD, 0);
+        write_reg(&self.range, gppudclk_reg, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_function() {
+        let mut regs = [0xffff_ffffu32; 0xb4 / 4];
+        let gpio = Gpio { range: VirtRange::with_len(regs.as_mut_ptr() as usize, 0xb4) };
+        gpio.set_function(14, GpioFunction::Alt5);
+        gpio.set_function(15, GpioFunction::Input);
+        gpio.set_function(53, GpioFunction::Alt0);
+        let regs = unsafe { core::ptr::read_volatile(&regs) };
+        // Pins 14 and 15 are bits 12-17 of GPFSEL1, and 53 bits 9-11 of GPFSEL5
+        assert_eq!(regs[1], !(0b111_111 << 12) | (0b000_010 << 12));
+        assert_eq!(regs[5], !(0b111 << 9) | (0b100 << 9));
+        assert_eq!(regs[0], 0xffff_ffff);
+    }
+
+    #[test]
+    fn function() {
+        let mut regs = [0u32; 0xb4 / 4];
+        let gpio = Gpio { range: VirtRange::with_len(regs.as_mut_ptr() as usize, 0xb4) };
+        assert_eq!(gpio.function(14), GpioFunction::Input);
+        for func in [GpioFunction::Alt0, GpioFunction::Alt4, GpioFunction::Alt5] {
+            gpio.set_function(14, func);
+            assert_eq!(gpio.function(14), func);
+            assert_eq!(gpio.function(15), GpioFunction::Input);
+        }
+    }
+}
@@ -1,13 +1,8 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
 use core::ptr::{read_volatile, write_volatile};
 use port::mem::VirtRange;
 
-#[allow(dead_code)]
-pub enum GpioPull {
-    Off = 0,
-    Down,
-    Up,
-}
-
 /// Delay for count cycles
 #[allow(dead_code)]
 pub fn delay(count: u32) {
@@ -41,3 +36,85 @@ pub fn read_reg(range: &VirtRange, offset: usize) -> u32 {
     let src = range.offset_addr(offset).expect("offset outside bounds");
     unsafe { read_volatile(src as *const u32) }
 }
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// The types a Reg can hold: unsigned integers, for which whatever the
+/// device returns is a valid value.
+pub trait RegValue: Copy + private::Sealed {}
+impl RegValue for u8 {}
+impl RegValue for u16 {}
+impl RegValue for u32 {}
+impl RegValue for u64 {}
+
+/// A register of type T at a fixed offset in a device's registers.  These
+/// can be declared as constants, and read and written given the range the
+/// device's registers are mapped at.  Unlike read_reg and write_reg, the
+/// whole register must be within the range, and it must be aligned for T.
+pub struct Reg<T: RegValue> {
+    offset: usize,
+    _type: PhantomData<T>,
+}
+
+impl<T: RegValue> Reg<T> {
+    /// Panics if offset isn't aligned for T, which for a constant register
+    /// is a compile time error.
+    pub const fn new(offset: usize) -> Reg<T> {
+        assert!(offset % align_of::<T>() == 0, "misaligned register offset");
+        Reg { offset, _type: PhantomData }
+    }
+
+    /// Address of the register in range.  Panics if any of it is outside
+    /// the range, or if it's misaligned because the range is.
+    fn addr(&self, range: &VirtRange) -> usize {
+        let addr = range.offset_addr(self.offset).expect("offset outside bounds");
+        range.offset_addr(self.offset + size_of::<T>() - 1).expect("register outside bounds");
+        assert!(addr % align_of::<T>() == 0, "misaligned register at {addr:#x}");
+        addr
+    }
+
+    pub fn read(&self, range: &VirtRange) -> T {
+        unsafe { read_volatile(self.addr(range) as *const T) }
+    }
+
+    pub fn write(&self, range: &VirtRange, val: T) {
+        unsafe { write_volatile(self.addr(range) as *mut T, val) }
+    }
+
+    /// Replace the value of the register with f applied to it
+    #[allow(dead_code)]
+    pub fn modify(&self, range: &VirtRange, f: impl FnOnce(T) -> T) {
+        let addr = self.addr(range);
+        unsafe { write_volatile(addr as *mut T, f(read_volatile(addr as *const T))) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REG0: Reg<u32> = Reg::new(0x0);
+    const REG1: Reg<u32> = Reg::new(0x4);
+    const BYTE: Reg<u8> = Reg::new(0x9);
+
+    #[test]
+    fn reg_read_write_modify() {
+        let mut regs = [0u32; 4];
+        let range = VirtRange::with_len(regs.as_mut_ptr() as usize, 16);
+        REG1.write(&range, 0x1234_5678);
+        assert_eq!(REG1.read(&range), 0x1234_5678);
+        REG1.modify(&range, |v| v | 0x8000_0000);
+        assert_eq!(REG1.read(&range), 0x9234_5678);
+        assert_eq!(REG0.read(&range), 0);
+        BYTE.write(&range, 0xab);
+        assert_eq!(BYTE.read(&range), 0xab);
+        let regs = unsafe { read_volatile(&regs) };
+        assert_eq!(regs, [0, 0x9234_5678, u32::from_ne_bytes([0, 0xab, 0, 0]), 0]);
+    }
+}
@@ -0,0 +1,45 @@
+//! Kernel tests run under QEMU with `cargo xtask qemu-test`.  See
+//! `port::ktest` for the exit protocol.
+
+extern crate alloc;
+
+use crate::kmem::from_ptr_to_physaddr;
+use crate::pagealloc;
+use crate::semihosting::Semihost;
+use crate::vm;
+use alloc::vec::Vec;
+use port::ktest::{run_tests, ExitCode, KernelTest};
+use port::semihosting::Semihosting;
+
+const TESTS: &[KernelTest] = &[
+    KernelTest { name: "heap_alloc", run: heap_alloc },
+    KernelTest { name: "page_alloc", run: page_alloc },
+    KernelTest { name: "clone_user", run: vm::ktest::clone_user },
+];
+
+/// Run the kernel tests and report the result to QEMU.  Never returns, but
+/// isn't `-> !` so the rest of main9 doesn't warn as unreachable.
+pub fn run() {
+    exit(run_tests(TESTS))
+}
+
+/// Exit QEMU through semihosting, with the code as its exit status.  QEMU
+/// must be run with `-semihosting`.
+pub fn exit(code: ExitCode) -> ! {
+    Semihost::exit(code as u32)
+}
+
+fn heap_alloc() {
+    let v: Vec<u64> = (0..1000).collect();
+    assert_eq!(v.iter().sum::<u64>(), 499500);
+}
+
+fn page_alloc() {
+    let (used_before, _) = pagealloc::usage_bytes();
+    let page = pagealloc::allocate().expect("page allocation failed");
+    page.clear();
+    let pa = from_ptr_to_physaddr(page);
+    assert!(pagealloc::usage_bytes().0 > used_before);
+    assert_eq!(pagealloc::decref(pa), Ok(0));
+    assert_eq!(pagealloc::usage_bytes().0, used_before);
+}
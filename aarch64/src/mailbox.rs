@@ -18,7 +18,12 @@ static MAILBOX: Lock<Option<&'static mut Mailbox>> = Lock::new("mailbox", None);
 /// Mailbox init.  Mainly initialises a lock to ensure only one mailbox request
 /// can be made at a time.  We have no heap at this point, so creating a mailbox
 /// that can be initialised based off the devicetree is rather convoluted.
+/// If the devicetree has no mailbox, requests can't be made, which callers
+/// that can cope without one check for with `available`.
 pub fn init(dt: &DeviceTree) {
+    let Some(new_mailbox) = Mailbox::new(dt, KZERO) else {
+        return;
+    };
     let node = LockNode::new();
     let mut mailbox = MAILBOX.lock(&node);
     *mailbox = Some({
@@ -26,12 +31,19 @@ pub fn init(dt: &DeviceTree) {
             SyncUnsafeCell::new(MaybeUninit::uninit());
         unsafe {
             let maybe_mailbox = &mut *MAYBE_MAILBOX.get();
-            maybe_mailbox.write(Mailbox::new(dt, KZERO));
+            maybe_mailbox.write(new_mailbox);
             maybe_mailbox.assume_init_mut()
         }
     });
 }
 
+/// Whether there's a mailbox to make requests through
+pub fn available() -> bool {
+    let node = LockNode::new();
+    let mailbox = MAILBOX.lock(&node);
+    mailbox.is_some()
+}
+
 /// https://developer.arm.com/documentation/ddi0306/b/CHDGHAIG
 /// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
 struct Mailbox {
@@ -39,17 +51,13 @@ struct Mailbox {
 }
 
 impl Mailbox {
-    fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> Mailbox {
-        Mailbox {
-            mbox_range: VirtRange::from(
-                &dt.find_compatible("brcm,bcm2835-mbox")
-                    .next()
-                    .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                    .and_then(|reg| reg.regblock())
-                    .unwrap()
-                    .with_offset(mmio_virt_offset as u64),
-            ),
-        }
+    fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> Option<Mailbox> {
+        let reg = dt
+            .find_compatible("brcm,bcm2835-mbox")
+            .next()
+            .and_then(|mbox| dt.property_translated_reg_iter(mbox).next())
+            .and_then(|reg| reg.regblock())?;
+        Some(Mailbox { mbox_range: VirtRange::from(&reg.with_offset(mmio_virt_offset as u64)) })
     }
 
     fn request<T, U>(&self, req: &mut Message<T, U>)
@@ -101,8 +109,8 @@ struct Response<T> {
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
-struct Tag<T> {
-    tag_id0: TagId,
+struct Tag<T, Id = TagId> {
+    tag_id0: Id,
     tag_buffer_size0: u32,
     tag_code0: u32,
     body: T,
@@ -116,15 +124,16 @@ union Message<T: Copy, U: Copy> {
     response: Response<U>,
 }
 
-type MessageWithTags<T, U> = Message<Tag<T>, Tag<U>>;
+type MessageWithTags<T, U, Id> = Message<Tag<T, Id>, Tag<U, Id>>;
 
-fn request<T, U>(code: u32, tags: &Tag<T>) -> U
+fn request<T, U, Id>(code: u32, tags: &Tag<T, Id>) -> U
 where
     T: Copy,
     U: Copy,
+    Id: Copy,
 {
-    let size = size_of::<Message<T, U>>() as u32;
-    let req = Request::<Tag<T>> { size, code, tags: *tags };
+    let size = size_of::<MessageWithTags<T, U, Id>>() as u32;
+    let req = Request::<Tag<T, Id>> { size, code, tags: *tags };
     let mut msg = MessageWithTags { request: req };
     let node = LockNode::new();
     let mut mailbox = MAILBOX.lock(&node);
@@ -133,6 +142,127 @@ where
     res.tags.body
 }
 
+/// The largest value buffer mailbox_property supports, in bytes
+const MAX_PROPERTY_BUFFER_SIZE: usize = 256;
+
+/// The value buffer for mailbox_property, with room after it for the end
+/// tag.  Only the first buffer_size bytes are part of the tag, so the zeroes
+/// after them are the end tag, wherever buffer_size puts it.
+#[repr(C, align(4))]
+#[derive(Clone, Copy)]
+struct PropertyBuffer([u8; MAX_PROPERTY_BUFFER_SIZE + 4]);
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Types mailbox_property can send to and read back from the firmware as
+/// bytes: integers and arrays of them.  They have no padding, and any bytes
+/// are a valid value.  The trait is sealed, so no other types can claim to be
+/// plain data.
+pub trait PlainData: sealed::Sealed + Copy {}
+
+macro_rules! plain_data {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl PlainData for $t {}
+        )*
+    };
+}
+plain_data!(u8, u16, u32, u64);
+impl<T: PlainData, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: PlainData, const N: usize> PlainData for [T; N] {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxError {
+    /// There's no mailbox in the devicetree
+    Unavailable,
+    /// The firmware didn't process the request, and returned this code
+    RequestFailed(u32),
+    /// The firmware didn't handle the tag, such as if it doesn't know it
+    TagNotHandled,
+    /// The firmware's response was shorter than expected, with this length
+    ResponseTooShort(u32),
+}
+
+/// Check the codes the firmware returned for a request with a single tag,
+/// which should have a response of at least response_size bytes.
+fn check_response(code: u32, tag_code: u32, response_size: usize) -> Result<(), MailboxError> {
+    if code != RESPONSE_SUCCESS {
+        return Err(MailboxError::RequestFailed(code));
+    }
+    if tag_code & TAG_RESPONSE == 0 {
+        return Err(MailboxError::TagNotHandled);
+    }
+    let len = tag_code & !TAG_RESPONSE;
+    if (len as usize) < response_size {
+        return Err(MailboxError::ResponseTooShort(len));
+    }
+    Ok(())
+}
+
+/// Make a request for a single property tag, for tags that don't have their
+/// own function in this module.  buffer_size is the size in bytes of the
+/// tag's value buffer, which must be what the firmware expects for the tag,
+/// a multiple of 4, and at least the size of both T and U.  Fails if the
+/// firmware doesn't handle the tag or returns fewer bytes than a U.
+pub fn mailbox_property<T, U>(tag_id: u32, buffer_size: u32, body: T) -> Result<U, MailboxError>
+where
+    T: PlainData,
+    U: PlainData,
+{
+    let len = buffer_size as usize;
+    assert!(len % 4 == 0, "mailbox buffer size {len} not a multiple of 4");
+    assert!(len <= MAX_PROPERTY_BUFFER_SIZE, "mailbox buffer size {len} too big");
+    assert!(
+        size_of::<T>() <= len && size_of::<U>() <= len,
+        "mailbox buffer size {len} smaller than request or response"
+    );
+    if !available() {
+        return Err(MailboxError::Unavailable);
+    }
+
+    let mut buffer = PropertyBuffer([0; MAX_PROPERTY_BUFFER_SIZE + 4]);
+    // Safe as T is plain data, and the buffer has room for it
+    unsafe { core::ptr::write_unaligned(buffer.0.as_mut_ptr() as *mut T, body) };
+    let tags = Tag::<PropertyBuffer, u32> {
+        tag_id0: tag_id,
+        tag_buffer_size0: buffer_size,
+        tag_code0: 0,
+        body: buffer,
+        end_tag: 0,
+    };
+    let (code, res) = request_tags(0, &tags);
+    check_response(code, res.tag_code0, size_of::<U>())?;
+    // Safe as any bytes are a valid U, and the buffer has room for one
+    Ok(unsafe { core::ptr::read_unaligned(res.body.0.as_ptr() as *const U) })
+}
+
+/// Make a request consisting of multiple tags.  `tags` must be a repr(C)
+/// struct of tag headers and bodies, terminated by an end tag.  Returns the
+/// response code along with the tags, which have been updated in place by
+/// the firmware.
+fn request_tags<T>(code: u32, tags: &T) -> (u32, T)
+where
+    T: Copy,
+{
+    let size = size_of::<Message<T, T>>() as u32;
+    let req = Request::<T> { size, code, tags: *tags };
+    let mut msg = Message::<T, T> { request: req };
+    let node = LockNode::new();
+    let mut mailbox = MAILBOX.lock(&node);
+    mailbox.as_deref_mut().unwrap().request(&mut msg);
+    let res = unsafe { msg.response };
+    (res.code, res.tags)
+}
+
+/// Set in the message response code on success
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+
+/// Set in the tag response code when the firmware has handled the tag
+const TAG_RESPONSE: u32 = 0x8000_0000;
+
 // https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface#tags-arm-to-vc
 #[repr(u32)]
 #[derive(Debug, Clone, Copy)]
@@ -144,9 +274,21 @@ enum TagId {
     GetBoardSerial = 0x0001_0004,
     GetArmMemory = 0x0001_0005,
     GetVcMemory = 0x0001_0006,
+    GetPowerState = 0x0002_0001,
+    SetPowerState = 0x0002_8001,
+    GetTemperature = 0x0003_0006,
+    AllocateFramebuffer = 0x0004_0001,
+    GetPitch = 0x0004_0008,
+    SetPhysicalSize = 0x0004_8003,
+    SetVirtualSize = 0x0004_8004,
+    SetDepth = 0x0004_8005,
+    GetClockRate = 0x0003_0002,
     SetClockRate = 0x0003_8002,
 }
 
+/// The clock id of the PL011 UART's reference clock
+pub const CLOCK_ID_UART: u32 = 2;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct SetClockRateRequest {
@@ -174,6 +316,177 @@ pub fn set_clock_rate(clock_id: u32, rate_hz: u32, skip_setting_turbo: u32) {
     let _: SetClockRateResponse = request(0, &tags);
 }
 
+/// Return the rate of the clock in Hz, or None if the firmware doesn't know
+/// it or there's no mailbox.  Clock ids are listed at
+/// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface#clocks
+pub fn get_clock_rate(clock_id: u32) -> Option<u32> {
+    let res = mailbox_property::<[u32; 1], [u32; 2]>(TagId::GetClockRate as u32, 8, [clock_id]);
+    res.ok().map(|[_, rate_hz]| rate_hz).filter(|&rate_hz| rate_hz != 0)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PowerStateRequest {
+    device_id: u32,
+    state: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PowerStateResponse {
+    device_id: u32,
+    state: u32,
+}
+
+const POWER_STATE_ON: u32 = 1 << 0;
+const POWER_STATE_WAIT: u32 = 1 << 1;
+const POWER_STATE_NO_DEVICE: u32 = 1 << 1;
+
+impl PowerStateResponse {
+    fn is_on(&self) -> bool {
+        (self.state & POWER_STATE_NO_DEVICE) == 0 && (self.state & POWER_STATE_ON) != 0
+    }
+}
+
+/// Return true if the device is powered on.  Device ids are listed at
+/// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface#get-power-state
+#[allow(dead_code)]
+pub fn get_power_state(device_id: u32) -> bool {
+    let tags = Tag::<PowerStateRequest> {
+        tag_id0: TagId::GetPowerState,
+        tag_buffer_size0: 8,
+        tag_code0: 0,
+        body: PowerStateRequest { device_id, state: 0 },
+        end_tag: 0,
+    };
+    let res: PowerStateResponse = request(0, &tags);
+    res.is_on()
+}
+
+/// Power the device on or off, optionally waiting for the power to become
+/// stable.  Returns true if the device is now powered on.
+#[allow(dead_code)]
+pub fn set_power_state(device_id: u32, on: bool, wait: bool) -> bool {
+    let mut state = 0;
+    if on {
+        state |= POWER_STATE_ON;
+    }
+    if wait {
+        state |= POWER_STATE_WAIT;
+    }
+    let tags = Tag::<PowerStateRequest> {
+        tag_id0: TagId::SetPowerState,
+        tag_buffer_size0: 8,
+        tag_code0: 0,
+        body: PowerStateRequest { device_id, state },
+        end_tag: 0,
+    };
+    let res: PowerStateResponse = request(0, &tags);
+    res.is_on()
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetTemperatureRequest {
+    temp_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetTemperatureResponse {
+    temp_id: u32,
+    value: u32,
+}
+
+/// Return the temperature of the given sensor in thousandths of a degree C.
+/// Sensor 0 is the SoC.
+pub fn get_temperature(temp_id: u32) -> u32 {
+    let tags = Tag::<GetTemperatureRequest> {
+        tag_id0: TagId::GetTemperature,
+        tag_buffer_size0: 8,
+        tag_code0: 0,
+        body: GetTemperatureRequest { temp_id },
+        end_tag: 0,
+    };
+    let res: GetTemperatureResponse = request(0, &tags);
+    res.value
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct TagHeader {
+    tag_id: TagId,
+    tag_buffer_size: u32,
+    tag_code: u32,
+}
+
+impl TagHeader {
+    const fn new(tag_id: TagId, tag_buffer_size: u32) -> Self {
+        Self { tag_id, tag_buffer_size, tag_code: 0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FramebufferTags {
+    physical_size_tag: TagHeader,
+    physical_size: [u32; 2], // width, height
+    virtual_size_tag: TagHeader,
+    virtual_size: [u32; 2], // width, height
+    depth_tag: TagHeader,
+    depth: u32,
+    allocate_tag: TagHeader,
+    allocate: [u32; 2], // alignment on request; base, size on response
+    pitch_tag: TagHeader,
+    pitch: u32,
+    end_tag: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct Framebuffer {
+    pub base: PhysAddr,
+    pub size: u32,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+/// Ask the firmware to allocate a framebuffer of the given dimensions and
+/// depth (in bits per pixel).  The firmware may not honour the requested
+/// values, so the returned Framebuffer contains what was actually allocated.
+#[allow(dead_code)]
+pub fn alloc_framebuffer(width: u32, height: u32, depth: u32) -> Option<Framebuffer> {
+    let tags = FramebufferTags {
+        physical_size_tag: TagHeader::new(TagId::SetPhysicalSize, 8),
+        physical_size: [width, height],
+        virtual_size_tag: TagHeader::new(TagId::SetVirtualSize, 8),
+        virtual_size: [width, height],
+        depth_tag: TagHeader::new(TagId::SetDepth, 4),
+        depth,
+        allocate_tag: TagHeader::new(TagId::AllocateFramebuffer, 8),
+        allocate: [16, 0],
+        pitch_tag: TagHeader::new(TagId::GetPitch, 4),
+        pitch: 0,
+        end_tag: 0,
+    };
+    let (code, res) = request_tags(0, &tags);
+    if code != RESPONSE_SUCCESS || (res.allocate_tag.tag_code & TAG_RESPONSE) == 0 {
+        return None;
+    }
+
+    let [base, size] = res.allocate;
+    if base == 0 || size == 0 {
+        return None;
+    }
+
+    // The firmware returns a VideoCore bus address
+    let base = PhysAddr::new((base & 0x3fff_ffff) as u64);
+    let [width, height] = res.physical_size;
+    Some(Framebuffer { base, size, pitch: res.pitch, width, height, depth: res.depth })
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct EmptyRequest {}
@@ -194,36 +507,32 @@ pub struct MemoryInfo {
     pub end: u32,
 }
 
-pub fn get_arm_memory() -> PhysRange {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetArmMemory,
-        tag_buffer_size0: 12,
-        tag_code0: 0,
-        body: EmptyRequest {},
-        end_tag: 0,
-    };
-    let res: MemoryResponse = request(0, &tags);
-    let start = res.base_addr;
-    let size = res.size;
-    let end = start + size;
+/// The memory the ARM cores can use, or None if the firmware doesn't answer
+pub fn get_arm_memory() -> Option<PhysRange> {
+    get_memory(TagId::GetArmMemory)
+}
 
-    PhysRange::new(PhysAddr::new(start as u64), PhysAddr::new(end as u64))
+/// The memory set aside for the GPU, or None if the firmware doesn't answer
+pub fn get_vc_memory() -> Option<PhysRange> {
+    get_memory(TagId::GetVcMemory)
 }
 
-pub fn get_vc_memory() -> PhysRange {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetVcMemory,
-        tag_buffer_size0: 12,
+fn get_memory(tag_id: TagId) -> Option<PhysRange> {
+    if !available() {
+        return None;
+    }
+    let tags = Tag::<MemoryResponse> {
+        tag_id0: tag_id,
+        tag_buffer_size0: size_of::<MemoryResponse>() as u32,
         tag_code0: 0,
-        body: EmptyRequest {},
+        body: MemoryResponse { base_addr: 0, size: 0 },
         end_tag: 0,
     };
-    let res: MemoryResponse = request(0, &tags);
-    let start = res.base_addr;
-    let size = res.size;
-    let end = start + size;
-
-    PhysRange::new(PhysAddr::new(start as u64), PhysAddr::new(end as u64))
+    let (code, res) = request_tags(0, &tags);
+    if code != RESPONSE_SUCCESS || (res.tag_code0 & TAG_RESPONSE) == 0 || res.body.size == 0 {
+        return None;
+    }
+    Some(PhysRange::with_len(res.body.base_addr as u64, res.body.size as usize))
 }
 
 pub fn get_firmware_revision() -> u32 {
@@ -234,7 +543,7 @@ pub fn get_firmware_revision() -> u32 {
         body: EmptyRequest {},
         end_tag: 0,
     };
-    request::<_, u32>(0, &tags)
+    request::<_, u32, _>(0, &tags)
 }
 
 pub fn get_board_model() -> u32 {
@@ -245,7 +554,7 @@ pub fn get_board_model() -> u32 {
         body: EmptyRequest {},
         end_tag: 0,
     };
-    request::<_, u32>(0, &tags)
+    request::<_, u32, _>(0, &tags)
 }
 
 pub fn get_board_revision() -> u32 {
@@ -256,7 +565,7 @@ pub fn get_board_revision() -> u32 {
         body: EmptyRequest {},
         end_tag: 0,
     };
-    request::<_, u32>(0, &tags)
+    request::<_, u32, _>(0, &tags)
 }
 
 #[repr(C)]
@@ -278,7 +587,7 @@ pub fn get_board_macaddr() -> MacAddress {
         body: EmptyRequest {},
         end_tag: 0,
     };
-    request::<_, MacAddress>(0, &tags)
+    request::<_, MacAddress, _>(0, &tags)
 }
 
 pub fn get_board_serial() -> u64 {
@@ -294,3 +603,24 @@ pub fn get_board_serial() -> u64 {
     let res: [u32; 2] = request(0, &tags);
     ((res[0] as u64) << 32) | res[1] as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_codes() {
+        let handled = TAG_RESPONSE | 8;
+        assert_eq!(check_response(RESPONSE_SUCCESS, handled, 8), Ok(()));
+        assert_eq!(check_response(RESPONSE_SUCCESS, handled, 4), Ok(()));
+        assert_eq!(
+            check_response(0x8000_0001, handled, 8),
+            Err(MailboxError::RequestFailed(0x8000_0001))
+        );
+        assert_eq!(check_response(RESPONSE_SUCCESS, 0, 8), Err(MailboxError::TagNotHandled));
+        assert_eq!(
+            check_response(RESPONSE_SUCCESS, TAG_RESPONSE | 4, 8),
+            Err(MailboxError::ResponseTooShort(4))
+        );
+    }
+}
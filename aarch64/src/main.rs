@@ -8,16 +8,25 @@
 #![forbid(unsafe_op_in_unsafe_fn)]
 
 mod devcons;
+mod gic;
+mod gpio;
 mod io;
 mod kmem;
+#[cfg(feature = "qemu_test")]
+mod ktest;
 mod mailbox;
 mod pagealloc;
 mod param;
 mod registers;
+mod rng;
+#[cfg(feature = "qemu_test")]
+mod semihosting;
+mod timer;
 mod trap;
 mod uartmini;
 mod uartpl011;
 mod vm;
+mod watchdog;
 
 use crate::kmem::from_virt_to_physaddr;
 use crate::vm::kernel_root;
@@ -50,12 +59,36 @@ fn print_binary_sections() {
     }
 }
 
-fn print_physical_memory_info() {
+fn print_physical_memory_info(available_mem: &PhysRange) {
     println!("Physical memory map:");
-    let arm_mem = mailbox::get_arm_memory();
-    println!("  Memory:\t{arm_mem} ({:#x})", arm_mem.size());
-    let vc_mem = mailbox::get_vc_memory();
-    println!("  Video:\t{vc_mem} ({:#x})", vc_mem.size());
+    match mailbox::get_arm_memory() {
+        Some(arm_mem) => println!("  Memory:\t{arm_mem} ({:#x})", arm_mem.size()),
+        None => println!("  Memory:\tunknown"),
+    }
+    match mailbox::get_vc_memory() {
+        Some(vc_mem) => println!("  Video:\t{vc_mem} ({:#x})", vc_mem.size()),
+        None => println!("  Video:\tunknown"),
+    }
+    println!("  Available:\t{available_mem} ({:#x})", available_mem.size());
+}
+
+/// The RAM the kernel can use.  On a Raspberry Pi the firmware decides how
+/// memory is split with the GPU, so ask it through the mailbox, falling back
+/// to the first non-empty devicetree memory node if it doesn't answer.
+/// Either way the GPU's memory is left out.
+fn available_memory(dt: &DeviceTree) -> PhysRange {
+    let mem = mailbox::get_arm_memory()
+        .or_else(|| dt.memory_ranges().next())
+        .expect("no memory found via the mailbox or devicetree");
+
+    // The GPU's memory is normally above the ARM's, so trim anything from
+    // where it starts
+    match mailbox::get_vc_memory() {
+        Some(vc_mem) if mem.overlaps(&vc_mem) && vc_mem.start() > mem.start() => {
+            PhysRange::new(mem.start(), vc_mem.start())
+        }
+        _ => mem,
+    }
 }
 
 fn print_memory_info() {
@@ -63,6 +96,12 @@ fn print_memory_info() {
     let (used, total) = pagealloc::usage_bytes();
     println!("  Used:\t\t{used:#016x}");
     println!("  Total:\t{total:#016x}");
+
+    let heap = port::allocator::heap_stats();
+    println!("Heap usage:");
+    println!("  Allocs:\t{} ({} freed)", heap.total_allocs, heap.total_frees);
+    println!("  Current:\t{:#016x}", heap.current_alloc_bytes);
+    println!("  Peak:\t\t{:#016x}", heap.peak_alloc_bytes);
 }
 
 // https://github.com/raspberrypi/documentation/blob/develop/documentation/asciidoc/computers/raspberry-pi/revision-codes.adoc
@@ -71,6 +110,7 @@ fn print_pi_name(board_revision: u32) {
         0xa21041 => "Raspberry Pi 2B",
         0xa02082 => "Raspberry Pi 3B",
         0xb03115 => "Raspberry Pi 4B",
+        0xb04170 | 0xc04170 | 0xd04170 => "Raspberry Pi 5B",
         0xa220a0 => "Raspberry Compute Module 3",
         _ => "Unrecognised",
     };
@@ -90,12 +130,19 @@ fn print_board_info() {
     println!("  MAC Address:\t{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}");
     let fw_revision = mailbox::get_firmware_revision();
     println!("  Firmware Rev:\t{fw_revision:#010x}");
+    let temp = mailbox::get_temperature(0);
+    println!("  SoC Temp:\t{}.{:03}C", temp / 1000, temp % 1000);
 }
 
 /// dtb_va is the virtual address of the DTB structure.  The physical address is
 /// assumed to be dtb_va-KZERO.
 #[no_mangle]
 pub extern "C" fn main9(dtb_va: usize) {
+    // l.S should have dropped us down to EL1.  If not, the EL1 registers we
+    // configure won't be the ones in effect.  We can't say so until the
+    // console is up, so just note the EL for now.
+    let el = registers::current_el();
+
     trap::init();
 
     // Parse the DTB before we set up memory so we can correctly map it
@@ -105,26 +152,42 @@ pub extern "C" fn main9(dtb_va: usize) {
     mailbox::init(&dt);
     devcons::init(&dt);
 
+    if el != 1 {
+        panic!("main9 expected to run at EL1, but running at EL{el}");
+    }
+
     println!();
     println!("r9 from the Internet");
     println!("DTB found at: {:#x}", dtb_va);
     println!("midr_el1: {:?}", registers::MidrEl1::read());
+    println!("sctlr_el1: {:?}", registers::SctlrEl1::read());
 
+    let available_mem = available_memory(&dt);
     print_binary_sections();
-    print_physical_memory_info();
+    print_physical_memory_info(&available_mem);
     print_board_info();
 
+    rng::init(&dt);
+    match rng::get_random() {
+        Some(random) => println!("Random number:\t{random:#010x}"),
+        None => println!("Random number:\thardware RNG unavailable"),
+    }
+
     // Map address space accurately using rust VM code to manage page tables
     unsafe {
         let dtb_range = PhysRange::with_len(from_virt_to_physaddr(dtb_va).addr(), dt.size());
-        vm::init(&mut *ptr::addr_of_mut!(KPGTBL), dtb_range, mailbox::get_arm_memory());
+        vm::init(&mut *ptr::addr_of_mut!(KPGTBL), dtb_range, available_mem);
         vm::switch(&*ptr::addr_of!(KPGTBL));
     }
 
     // From this point we can use the global allocator
+    pagealloc::enable_zero_on_free();
 
     print_memory_info();
 
+    #[cfg(feature = "qemu_test")]
+    ktest::run();
+
     if let Ok(page) = pagealloc::allocate() {
         println!("page addr: {:#016x}", page.data().as_ptr() as *const _ as u64);
 
@@ -133,7 +196,25 @@ pub extern "C" fn main9(dtb_va: usize) {
         // kpgtable.map_phys_range(range, *flags, *page_size).expect("dynamic mapping failed");
     }
 
-    kernel_root().print_recursive_tables();
+    // Only dump the page tables when debugging, as there are a lot of them
+    if port::log::enabled(port::log::Level::Debug) {
+        kernel_root().print_recursive_tables();
+    }
+
+    // Now device registers can be mapped, set up interrupts
+    gic::init(&dt);
+    if timer::init(100) {
+        // Wait for a few ticks, to show the timer is running
+        while timer::ticks() < 10 {
+            #[cfg(not(test))]
+            unsafe {
+                core::arch::asm!("wfi")
+            };
+        }
+        println!("{} timer ticks", timer::ticks());
+    }
+    #[cfg(feature = "miniuart_tx_irq")]
+    devcons::enable_tx_interrupt();
 
     println!("looping now");
 
@@ -7,21 +7,36 @@
 ///    setting up the initial page tables.
 /// 2. `free_unused_ranges` to mark available ranges as the inverse of the
 ///    physical memory map within the bounds of the available memory.
+///
+/// Allocated pages are reference counted so they can be shared.  `allocate`
+/// returns a page with a count of 1, and the page is freed once `decref`
+/// drops the count to zero.
 use crate::kmem;
 use crate::kmem::physaddr_as_ptr_mut;
 use crate::vm::Page4K;
-use port::bitmapalloc::BitmapPageAlloc;
+use core::cell::SyncUnsafeCell;
 use port::bitmapalloc::BitmapPageAllocError;
-use port::mem::PhysRange;
+use port::bitmapalloc::RefCountedPageAlloc;
+use port::mem::{PhysAddr, PhysRange};
 use port::{
     mcslock::{Lock, LockNode},
     mem::PAGE_SIZE_4K,
 };
 
+/// Number of pages covered by the allocator's bitmaps, each of which needs a
+/// reference count.
+const NUM_PAGES: usize = 32 * PAGE_SIZE_4K * 8;
+
+/// The reference counts, in their own static so they're in .bss.  Only
+/// PAGE_ALLOC refers to them.
+static PAGE_REFS: SyncUnsafeCell<[u16; NUM_PAGES]> = SyncUnsafeCell::new([0; NUM_PAGES]);
+
 /// Set up bitmap page allocator assuming everything is allocated.
-static PAGE_ALLOC: Lock<BitmapPageAlloc<32, PAGE_SIZE_4K>> = Lock::new(
+static PAGE_ALLOC: Lock<RefCountedPageAlloc<'static, 32, PAGE_SIZE_4K>> = Lock::new(
     "page_alloc",
-    const { BitmapPageAlloc::<32, PAGE_SIZE_4K>::new_all_allocated(PAGE_SIZE_4K) },
+    RefCountedPageAlloc::<32, PAGE_SIZE_4K>::new_all_allocated(PAGE_SIZE_4K, unsafe {
+        &mut *PAGE_REFS.get()
+    }),
 );
 
 /// The bitmap allocator has all pages marked as allocated initially.  We'll
@@ -52,6 +67,15 @@ pub fn free_unused_ranges<'a>(
     page_alloc.free_unused_ranges(available_mem, used_ranges)
 }
 
+/// Clear pages as they're freed, so their contents can't leak into the next
+/// user.  Only pages freed after this is called are cleared.
+pub fn enable_zero_on_free() {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.set_zero_on_free(Some(|pa| unsafe { (*physaddr_as_ptr_mut::<Page4K>(pa)).clear() }));
+}
+
 /// Try to allocate a page
 pub fn allocate() -> Result<&'static mut Page4K, BitmapPageAllocError> {
     let node = LockNode::new();
@@ -64,6 +88,25 @@ pub fn allocate() -> Result<&'static mut Page4K, BitmapPageAllocError> {
     }
 }
 
+/// Add a reference to an allocated page, returning the new count.
+#[allow(dead_code)]
+pub fn incref(pa: PhysAddr) -> Result<usize, BitmapPageAllocError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.incref(pa)
+}
+
+/// Drop a reference to an allocated page, returning the new count.  The page
+/// is freed when the count reaches zero.
+#[allow(dead_code)]
+pub fn decref(pa: PhysAddr) -> Result<usize, BitmapPageAllocError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.decref(pa)
+}
+
 /// Return a tuple of (bytes used, total bytes available) based on the page allocator.
 pub fn usage_bytes() -> (usize, usize) {
     let node = LockNode::new();
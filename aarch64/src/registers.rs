@@ -1,26 +1,26 @@
 #![allow(non_upper_case_globals)]
 
-use aarch64_cpu::registers::{Readable, Writeable};
-use aarch64_cpu::{asm, registers::MIDR_EL1};
+use crate::io::Reg;
+use aarch64_cpu::registers::{Readable, MIDR_EL1};
 use bitstruct::bitstruct;
 use core::fmt;
 use num_enum::TryFromPrimitive;
 use port::mem::{PhysRange, PAGE_SIZE_2M};
 
 // GPIO registers
-pub const GPFSEL1: usize = 0x04; // GPIO function select register 1
+pub const GPFSEL0: usize = 0x00; // GPIO function select register 0
 pub const GPPUD: usize = 0x94; // GPIO pin pull up/down enable
 pub const GPPUDCLK0: usize = 0x98; // GPIO pin pull up/down enable clock 0
 
 // UART 0 (PL011) registers
-pub const UART0_DR: usize = 0x00; // Data register
-pub const UART0_FR: usize = 0x18; // Flag register
-pub const UART0_IBRD: usize = 0x24; // Integer baud rate divisor
-pub const UART0_FBRD: usize = 0x28; // Fractional baud rate divisor
-pub const UART0_LCRH: usize = 0x2c; // Line control register
-pub const UART0_CR: usize = 0x30; // Control register
-pub const UART0_IMSC: usize = 0x38; // Interrupt mask set clear register
-pub const UART0_ICR: usize = 0x44; // Interrupt clear register
+pub const UART0_DR: Reg<u32> = Reg::new(0x00); // Data register
+pub const UART0_FR: Reg<u32> = Reg::new(0x18); // Flag register
+pub const UART0_IBRD: Reg<u32> = Reg::new(0x24); // Integer baud rate divisor
+pub const UART0_FBRD: Reg<u32> = Reg::new(0x28); // Fractional baud rate divisor
+pub const UART0_LCRH: Reg<u32> = Reg::new(0x2c); // Line control register
+pub const UART0_CR: Reg<u32> = Reg::new(0x30); // Control register
+pub const UART0_IMSC: Reg<u32> = Reg::new(0x38); // Interrupt mask set clear register
+pub const UART0_ICR: Reg<u32> = Reg::new(0x44); // Interrupt clear register
 
 // AUX registers, offset from aux_reg
 pub const AUX_ENABLE: usize = 0x04; // AUX enable register (Mini Uart, SPIs)
@@ -80,6 +80,7 @@ pub enum PartNum {
     RaspberryPi2 = 0xc07,
     RaspberryPi3 = 0xd03,
     RaspberryPi4 = 0xd08,
+    RaspberryPi5 = 0xd0b,
 }
 
 impl PartNum {
@@ -90,6 +91,7 @@ impl PartNum {
             Self::RaspberryPi1 => Some(PhysRange::with_len(0x20000000, len)),
             Self::RaspberryPi2 | Self::RaspberryPi3 => Some(PhysRange::with_len(0x3f000000, len)),
             Self::RaspberryPi4 => Some(PhysRange::with_len(0xfe000000, len)),
+            Self::RaspberryPi5 => Some(PhysRange::with_len(0x1f00000000, len)),
             Self::Unknown => None,
         }
     }
@@ -99,13 +101,99 @@ pub fn rpi_mmio() -> Option<PhysRange> {
     MidrEl1::read().partnum_enum().ok().and_then(|p| p.mmio())
 }
 
+/// Return the current exception level (0..3)
+pub fn current_el() -> u8 {
+    #[cfg(not(test))]
+    {
+        let value: u64;
+        unsafe {
+            core::arch::asm!("mrs {value}, CurrentEL", value = out(reg) value);
+        }
+        ((value >> 2) & 0b11) as u8
+    }
+    #[cfg(test)]
+    1
+}
+
+bitstruct! {
+    /// System Control Register (EL1).  Controls the MMU, caches and alignment
+    /// checking for EL1 and EL0.  The early boot code in l.S enables M, C and I.
+    #[derive(Copy, Clone)]
+    pub struct SctlrEl1(pub u64) {
+        m: bool = 0;      // MMU enable
+        a: bool = 1;      // Alignment fault checking enable
+        c: bool = 2;      // Data cacheability
+        sa: bool = 3;     // SP alignment check enable (EL1)
+        sa0: bool = 4;    // SP alignment check enable (EL0)
+        i: bool = 12;     // Instruction cacheability
+        dze: bool = 14;   // Allow DC ZVA at EL0
+        uct: bool = 15;   // Allow access to CTR_EL0 at EL0
+        ntwi: bool = 16;  // Don't trap WFI at EL0
+        ntwe: bool = 18;  // Don't trap WFE at EL0
+        wxn: bool = 19;   // Write permission implies execute-never
+        e0e: bool = 24;   // Endianness of data accesses at EL0
+        ee: bool = 25;    // Endianness of data accesses at EL1
+        uci: bool = 26;   // Allow cache maintenance instructions at EL0
+    }
+}
+
+impl SctlrEl1 {
+    pub fn read() -> Self {
+        #[cfg(not(test))]
+        {
+            let value: u64;
+            unsafe {
+                core::arch::asm!("mrs {value}, sctlr_el1", value = out(reg) value);
+            }
+            Self(value)
+        }
+        #[cfg(test)]
+        Self(0)
+    }
+
+    /// Write the register, followed by an isb so the change is visible to
+    /// subsequent instructions.
+    ///
+    /// # Safety
+    /// Changing the MMU, cache or alignment settings can break any code that
+    /// relies on the current configuration.
+    #[allow(dead_code, unused_variables)]
+    pub unsafe fn write(v: SctlrEl1) {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("msr sctlr_el1, {value}", "isb", value = in(reg) v.0);
+        }
+    }
+}
+
+impl fmt::Debug for SctlrEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SctlrEl1")
+            .field("m", &self.m())
+            .field("a", &self.a())
+            .field("c", &self.c())
+            .field("sa", &self.sa())
+            .field("sa0", &self.sa0())
+            .field("i", &self.i())
+            .field("dze", &self.dze())
+            .field("uct", &self.uct())
+            .field("ntwi", &self.ntwi())
+            .field("ntwe", &self.ntwe())
+            .field("wxn", &self.wxn())
+            .field("e0e", &self.e0e())
+            .field("ee", &self.ee())
+            .field("uci", &self.uci())
+            .finish()
+    }
+}
+
 bitstruct! {
     #[derive(Copy, Clone)]
     pub struct EsrEl1(pub u64) {
-        iss: u32 = 0..25;
-        il: bool = 25;
-        ec: u8 = 26..32;
-        iss2: u8 = 32..37;
+        pub iss: u32 = 0..25;
+        pub il: bool = 25;
+        pub ec: u8 = 26..32;
+        pub iss2: u8 = 32..37;
     }
 }
 
@@ -128,6 +216,67 @@ impl fmt::Debug for EsrEl1 {
     }
 }
 
+bitstruct! {
+    /// Saved Program Status Register (EL1).  Holds the PSTATE at the time an
+    /// exception was taken to EL1.
+    #[derive(Copy, Clone)]
+    pub struct SpsrEl1(pub u64) {
+        pub m: u8 = 0..4;     // Exception level and selected SP
+        pub nrw: bool = 4;    // Execution state (0 = AArch64)
+        pub f: bool = 6;      // FIQ mask
+        pub i: bool = 7;      // IRQ mask
+        pub a: bool = 8;      // SError mask
+        pub d: bool = 9;      // Debug mask
+        pub il: bool = 20;    // Illegal execution state
+        pub ss: bool = 21;    // Software step
+        pub nzcv: u8 = 28..32;
+    }
+}
+
+impl SpsrEl1 {
+    #[allow(dead_code)]
+    pub fn read() -> Self {
+        #[cfg(not(test))]
+        {
+            let value: u64;
+            unsafe {
+                core::arch::asm!("mrs {value}, spsr_el1", value = out(reg) value);
+            }
+            Self(value)
+        }
+        #[cfg(test)]
+        Self(0)
+    }
+
+    /// Exception level the exception was taken from
+    pub fn el(&self) -> u8 {
+        self.m() >> 2
+    }
+
+    /// True if the dedicated SP for the exception level was selected (ELxh),
+    /// false if SP_EL0 was used (ELxt)
+    pub fn sp_elx(&self) -> bool {
+        self.m() & 1 != 0
+    }
+}
+
+impl fmt::Debug for SpsrEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpsrEl1")
+            .field("el", &self.el())
+            .field("sp_elx", &self.sp_elx())
+            .field("nrw", &self.nrw())
+            .field("f", &self.f())
+            .field("i", &self.i())
+            .field("a", &self.a())
+            .field("d", &self.d())
+            .field("il", &self.il())
+            .field("ss", &self.ss())
+            .field("nzcv", &format_args!("{:#06b}", self.nzcv()))
+            .finish()
+    }
+}
+
 /// Exception class maps to ESR_EL1 EC bits[31:26]. We skip aarch32 exceptions.
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
@@ -138,6 +287,9 @@ pub enum ExceptionClass {
     Ls64 = 10,
     BranchTargetException = 13,
     IllegalExecutionState = 14,
+    Svc64 = 21,
+    Hvc64 = 22,
+    Smc64 = 23,
     MsrMrsSystem = 24,
     Sve = 25,
     Tstart = 27,
@@ -187,6 +339,54 @@ impl EsrEl1IssInstructionAbort {
     }
 }
 
+bitstruct! {
+    #[derive(Copy, Clone)]
+    pub struct EsrEl1IssDataAbort(pub u32) {
+        pub dfsc: u8 = 0..6;
+        pub wnr: bool = 6;      // Write not Read
+        pub s1ptw: bool = 7;
+        pub cm: bool = 8;       // Cache maintenance
+        pub ea: bool = 9;
+        pub fnv: bool = 10;     // FAR not valid
+        pub set: u8 = 11..13;
+        pub isv: bool = 24;     // Instruction syndrome valid
+    }
+}
+
+#[allow(dead_code)]
+impl EsrEl1IssDataAbort {
+    pub fn from_esr_el1(r: EsrEl1) -> Option<EsrEl1IssDataAbort> {
+        r.exception_class_enum()
+            .ok()
+            .filter(|ec| {
+                *ec == ExceptionClass::DataAbortSameEl || *ec == ExceptionClass::DataAbortLowerEl
+            })
+            .map(|_| EsrEl1IssDataAbort(r.iss()))
+    }
+
+    /// The data fault status codes share their encoding with the instruction
+    /// fault status codes, with the exception of a few data-only codes such as
+    /// alignment faults, which are returned as the error.
+    pub fn data_fault(&self) -> Result<InstructionFaultStatusCode, u8> {
+        InstructionFaultStatusCode::try_from(self.dfsc()).map_err(|e| e.number)
+    }
+}
+
+impl fmt::Debug for EsrEl1IssDataAbort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EsrEl1IssDataAbort")
+            .field("dfsc", &format_args!("{:?}", self.data_fault()))
+            .field("wnr", &self.wnr())
+            .field("s1ptw", &self.s1ptw())
+            .field("cm", &self.cm())
+            .field("ea", &self.ea())
+            .field("fnv", &self.fnv())
+            .field("set", &self.set())
+            .field("isv", &self.isv())
+            .finish()
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum InstructionFaultStatusCode {
@@ -233,6 +433,7 @@ pub enum InstructionFaultStatusCode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use port::mem::PhysAddr;
 
     // This test is useful for making sense of early-stage exceptions.  Qemu
     // will report an exception of the form below.  Copy the ESR value into
@@ -254,4 +455,63 @@ mod tests {
             InstructionFaultStatusCode::TranslationFaultLevel0
         );
     }
+
+    #[test]
+    fn test_parse_esr_el1_data_abort() {
+        // Write to an unmapped address
+        let r = EsrEl1(0x96000045);
+        assert_eq!(r.exception_class_enum().unwrap(), ExceptionClass::DataAbortSameEl);
+        assert!(EsrEl1IssInstructionAbort::from_esr_el1(r).is_none());
+        let iss = EsrEl1IssDataAbort::from_esr_el1(r).unwrap();
+        assert_eq!(iss.data_fault().unwrap(), InstructionFaultStatusCode::TranslationFaultLevel1);
+        assert!(iss.wnr());
+        assert!(!iss.fnv());
+
+        // Alignment fault on read
+        let iss = EsrEl1IssDataAbort::from_esr_el1(EsrEl1(0x96000021)).unwrap();
+        assert_eq!(iss.data_fault(), Err(0x21));
+        assert!(!iss.wnr());
+    }
+
+    #[test]
+    fn test_parse_midr_el1() {
+        // Cortex-A76 on the Raspberry Pi 5
+        let r = MidrEl1(0x414fd0b1);
+        assert_eq!(r.implementer(), 0x41);
+        assert_eq!(r.partnum_enum(), Ok(PartNum::RaspberryPi5));
+        assert_eq!(r.revision(), 1);
+        assert_eq!(
+            r.partnum_enum().ok().and_then(|p| p.mmio()).map(|r| r.start()),
+            Some(PhysAddr::new(0x1f00000000))
+        );
+    }
+
+    #[test]
+    fn test_parse_spsr_el1() {
+        // PSTATE 0x3c5 from the qemu output above: EL1h with DAIF all masked
+        let r = SpsrEl1(0x3c5);
+        assert_eq!(r.el(), 1);
+        assert!(r.sp_elx());
+        assert!(!r.nrw());
+        assert!(r.d() && r.a() && r.i() && r.f());
+        assert!(!r.il());
+        assert_eq!(r.nzcv(), 0);
+
+        let r = SpsrEl1(0x6000_0000);
+        assert_eq!(r.el(), 0);
+        assert_eq!(r.nzcv(), 0b0110);
+    }
+
+    #[test]
+    fn test_parse_sctlr_el1() {
+        // Value written by l.S when enabling the MMU
+        let r = SctlrEl1(0x1005);
+        assert!(r.m());
+        assert!(!r.a());
+        assert!(r.c());
+        assert!(r.i());
+        assert!(!r.wxn());
+        assert!(!r.uct());
+        assert_eq!(SctlrEl1(0).with_m(true).with_c(true).with_i(true).0, 0x1005);
+    }
 }
@@ -0,0 +1,95 @@
+//! The BCM2835 hardware random number generator.
+//! https://github.com/raspberrypi/linux/blob/rpi-6.6.y/drivers/char/hw_random/bcm2835-rng.c
+
+use crate::io::{read_reg, write_or_reg, write_reg};
+use crate::kmem::physaddr_as_virt;
+use port::fdt::DeviceTree;
+use port::mcslock::{Lock, LockNode};
+use port::mem::{PhysRange, VirtRange};
+
+const RNG_CTRL: usize = 0x00;
+const RNG_STATUS: usize = 0x04;
+const RNG_DATA: usize = 0x08;
+const RNG_INT_MASK: usize = 0x10;
+
+/// Enable the generator (RNG_CTRL)
+const RNG_RBGEN: u32 = 0x1;
+/// Mask the interrupt (RNG_INT_MASK)
+const RNG_INT_OFF: u32 = 0x1;
+/// The number of initial values to discard, as the generator warms up
+const RNG_WARMUP_COUNT: u32 = 0x40000;
+/// The number of words available to read is in the top byte of RNG_STATUS
+const RNG_STATUS_AVAILABLE_SHIFT: u32 = 24;
+/// How many times to poll RNG_STATUS for a word before giving up.  This is
+/// generous enough to cover the warm up.
+const RNG_MAX_POLLS: u32 = 0x100_0000;
+
+static RNG: Lock<Option<Rng>> = Lock::new("rng", None);
+
+/// Find the RNG in the devicetree and start it.  If there isn't one, or it
+/// never produces anything, get_random will return None.
+pub fn init(dt: &DeviceTree) {
+    let Some(range) = dt
+        .find_compatible("brcm,bcm2835-rng")
+        .next()
+        .and_then(|rng| dt.property_translated_reg_iter(rng).next())
+        .and_then(|reg| reg.regblock())
+        .map(|reg| PhysRange::from(&reg))
+    else {
+        return;
+    };
+
+    let mut rng = Rng::from_physrange(range);
+    if !rng.init() {
+        return;
+    }
+    let node = LockNode::new();
+    *RNG.lock(&node) = Some(rng);
+}
+
+/// Return a random number from the hardware RNG, or None if there isn't one
+/// or it timed out
+pub fn get_random() -> Option<u32> {
+    let node = LockNode::new();
+    let rng = RNG.lock(&node);
+    rng.as_ref().and_then(|rng| rng.read_u32())
+}
+
+pub struct Rng {
+    range: VirtRange,
+}
+
+impl Rng {
+    /// The registers are in the peripherals' range, which is mapped at KZERO
+    /// from boot, like the mailbox's, so only the address needs converting.
+    pub fn from_physrange(range: PhysRange) -> Rng {
+        Rng { range: VirtRange::with_len(physaddr_as_virt(range.start()), range.size()) }
+    }
+
+    /// Enable the generator and wait for it to warm up.  Returns false if
+    /// it didn't produce a word in time.
+    pub fn init(&mut self) -> bool {
+        write_reg(&self.range, RNG_STATUS, RNG_WARMUP_COUNT);
+        write_or_reg(&self.range, RNG_INT_MASK, RNG_INT_OFF);
+        write_or_reg(&self.range, RNG_CTRL, RNG_RBGEN);
+        self.wait_available()
+    }
+
+    /// Wait for a word to be available, returning false if there still isn't
+    /// one after RNG_MAX_POLLS attempts
+    fn wait_available(&self) -> bool {
+        for _ in 0..RNG_MAX_POLLS {
+            if read_reg(&self.range, RNG_STATUS) >> RNG_STATUS_AVAILABLE_SHIFT != 0 {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+
+    /// Return the next random number, waiting for one if necessary, or None
+    /// if none became available
+    pub fn read_u32(&self) -> Option<u32> {
+        self.wait_available().then(|| read_reg(&self.range, RNG_DATA))
+    }
+}
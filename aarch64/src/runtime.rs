@@ -2,6 +2,7 @@
 
 extern crate alloc;
 
+use crate::gpio::Gpio;
 use crate::kmem::physaddr_as_virt;
 use crate::registers::rpi_mmio;
 use crate::uartmini::MiniUart;
@@ -12,7 +13,6 @@ use port::devcons::PanicConsole;
 use port::mem::VirtRange;
 
 // TODO
-//  - Add qemu integration test
 //  - Use Console via println!() macro once available
 //  - Add support for raspi4
 #[panic_handler]
@@ -23,16 +23,26 @@ pub fn panic(info: &PanicInfo) -> ! {
     let aux_range = VirtRange::with_len(mmio + 0x215000, 0x8);
     let miniuart_range = VirtRange::with_len(mmio + 0x215040, 0x40);
 
-    let uart = MiniUart { gpio_range, aux_range, miniuart_range };
+    let uart = MiniUart { gpio: Gpio { range: gpio_range }, aux_range, miniuart_range };
     //uart.init();
+    #[cfg(feature = "miniuart_tx_irq")]
+    crate::uartmini::disable_tx_interrupt();
 
-    PanicConsole::new(uart).write_fmt(format_args!("{}\n", info)).unwrap();
+    let mut console = PanicConsole::new(uart);
+    console.write_fmt(format_args!("{}\n", info)).unwrap();
 
     // TODO Once the Console is available, we should use this
     // println!("{}", info);
 
-    #[allow(clippy::empty_loop)]
-    loop {}
+    // Let the message finish sending before exiting or resetting cuts it off
+    console.flush();
+
+    // A panic fails the kernel tests
+    #[cfg(feature = "qemu_test")]
+    crate::ktest::exit(port::ktest::ExitCode::Failure);
+
+    #[cfg(not(feature = "qemu_test"))]
+    crate::watchdog::reboot();
 }
 
 #[alloc_error_handler]
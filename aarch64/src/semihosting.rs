@@ -0,0 +1,27 @@
+//! Arm semihosting calls, for port::semihosting.  The call is a `hlt`.
+
+use port::semihosting::Semihosting;
+
+pub struct Semihost;
+
+impl Semihosting for Semihost {
+    /// Make a semihosting call, returning the result from x0
+    #[cfg(target_arch = "aarch64")]
+    fn call(op: usize, param: *const u8) -> usize {
+        let result: usize;
+        unsafe {
+            core::arch::asm!(
+                "hlt #0xf000",
+                inout("x0") op => result,
+                in("x1") param,
+                options(nostack)
+            );
+        }
+        result
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fn call(_op: usize, _param: *const u8) -> usize {
+        0
+    }
+}
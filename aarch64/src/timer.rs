@@ -0,0 +1,144 @@
+//! ARM generic timer
+//!
+//! Uses the EL1 physical timer to generate a periodic tick.  The timer fires
+//! on a per-core interrupt (PPI), so this needs an interrupt controller to be
+//! registered with trap before it can do anything useful.
+
+use crate::trap::{self, IrqHandler, LocalInterrupts};
+use core::sync::atomic::{AtomicU64, Ordering};
+use port::mcslock::{IrqLock, LockNode};
+use port::println;
+
+/// Non-secure EL1 physical timer PPI
+pub const TIMER_IRQ: u32 = 30;
+
+// CNTP_CTL_EL0 bits
+const CTL_ENABLE: u64 = 1 << 0;
+#[allow(dead_code)]
+const CTL_IMASK: u64 = 1 << 1;
+
+/// Number of timer ticks taken since the timer was started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interval in counter units
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the timer interrupt on each tick, with the tick count
+static TICK_CALLBACK: IrqLock<Option<fn(u64)>, LocalInterrupts> =
+    IrqLock::new("tick_callback", None);
+
+struct TimerIrqHandler;
+
+impl IrqHandler for TimerIrqHandler {
+    fn handle_irq(&self, _irq_num: u32) {
+        tick();
+    }
+}
+
+static TIMER_IRQ_HANDLER: TimerIrqHandler = TimerIrqHandler;
+
+/// Frequency of the system counter in Hz
+fn cntfrq() -> u64 {
+    #[cfg(not(test))]
+    {
+        let freq: u64;
+        unsafe { core::arch::asm!("mrs {freq}, cntfrq_el0", freq = out(reg) freq) };
+        freq
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Set the number of counter ticks until the timer fires
+#[allow(unused_variables)]
+fn set_tval(tval: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("msr cntp_tval_el0, {tval}", "isb", tval = in(reg) tval);
+    }
+}
+
+#[allow(unused_variables)]
+fn set_ctl(ctl: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("msr cntp_ctl_el0, {ctl}", "isb", ctl = in(reg) ctl);
+    }
+}
+
+/// Number of counter ticks between timer interrupts at the given rate
+fn interval(freq: u64, hz: u32) -> u64 {
+    (freq / hz.max(1) as u64).max(1)
+}
+
+/// Start the timer firing hz times per second, returning whether it was
+/// started.  Does nothing if there's no interrupt controller yet.
+pub fn init(hz: u32) -> bool {
+    if !trap::has_irq_controller() {
+        println!("timer: no interrupt controller, not starting");
+        return false;
+    }
+    if let Err(err) = trap::register_irq_handler(TIMER_IRQ, &TIMER_IRQ_HANDLER) {
+        println!("timer: couldn't register irq handler: {err:?}");
+        return false;
+    }
+
+    let interval = interval(cntfrq(), hz);
+    TICK_INTERVAL.store(interval, Ordering::Relaxed);
+    set_tval(interval);
+    set_ctl(CTL_ENABLE);
+    true
+}
+
+/// Set the function called on each tick, replacing any previous one.  It's
+/// called from the timer interrupt, with the number of ticks so far, so it
+/// mustn't take locks that are held with interrupts enabled, such as the
+/// console's.
+#[allow(dead_code)]
+pub fn set_tick_callback(callback: fn(u64)) {
+    let node = LockNode::new();
+    *TICK_CALLBACK.lock(&node) = Some(callback);
+}
+
+/// Called on each timer interrupt.  Counts the tick, re-arms the timer and
+/// calls the tick callback.
+fn tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    set_tval(TICK_INTERVAL.load(Ordering::Relaxed));
+
+    let node = LockNode::new();
+    let callback = *TICK_CALLBACK.lock(&node);
+    if let Some(callback) = callback {
+        callback(ticks);
+    }
+}
+
+/// Number of timer ticks since init
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_interval() {
+        assert_eq!(interval(62_500_000, 100), 625_000);
+        assert_eq!(interval(54_000_000, 1000), 54_000);
+        assert_eq!(interval(10, 100), 1);
+        assert_eq!(interval(100, 0), 100);
+    }
+
+    #[test]
+    fn tick_calls_callback() {
+        static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+        set_tick_callback(|ticks| LAST_TICK.store(ticks, Ordering::Relaxed));
+
+        tick();
+        assert_eq!(LAST_TICK.load(Ordering::Relaxed), ticks());
+        tick();
+        assert_eq!(LAST_TICK.load(Ordering::Relaxed), ticks());
+        assert!(ticks() >= 2);
+    }
+}
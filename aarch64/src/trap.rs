@@ -1,5 +1,9 @@
-use crate::registers::EsrEl1;
+use crate::registers::{
+    EsrEl1, EsrEl1IssDataAbort, EsrEl1IssInstructionAbort, ExceptionClass, SpsrEl1,
+};
+use port::mcslock::{Interrupts, IrqLock, LockNode};
 use port::println;
+use port::syscall;
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("trap.S"));
@@ -56,6 +60,7 @@ pub struct TrapFrame {
     elr_el1: u64,
     far_el1: u64,
     interrupt_type: u64,
+    spsr_el1: SpsrEl1,
 }
 
 #[no_mangle]
@@ -63,11 +68,277 @@ pub extern "C" fn trap_unsafe(frame: *mut TrapFrame) {
     unsafe { trap(&mut *frame) }
 }
 
+#[no_mangle]
+pub extern "C" fn irq_unsafe(_frame: *mut TrapFrame) {
+    irq_handler();
+}
+
 fn trap(frame: &mut TrapFrame) {
+    // Synchronous exceptions are the first of each group of 4 vectors
+    let is_sync = frame.interrupt_type % 4 == 0;
+    if is_sync && frame.esr_el1.exception_class_enum() == Ok(ExceptionClass::Svc64) {
+        handle_syscall(frame);
+        return;
+    }
+
     // Just print out the frame and loop for now
     // TODO Make it a little prettier and more space efficient
+    let spsr = frame.spsr_el1;
+    println!(
+        "Exception type {} from EL{} (DAIF: {}{}{}{})",
+        frame.interrupt_type,
+        spsr.el(),
+        if spsr.d() { 'D' } else { '-' },
+        if spsr.a() { 'A' } else { '-' },
+        if spsr.i() { 'I' } else { '-' },
+        if spsr.f() { 'F' } else { '-' },
+    );
+    if is_sync {
+        describe_exception(frame.esr_el1, frame.far_el1, frame.elr_el1);
+    }
     println!("{:#x?}", frame);
     loop {
         core::hint::spin_loop();
     }
 }
+
+/// Dispatch the syscall in x8 with arguments x0-x5, returning the result in
+/// x0.  ELR_EL1 already points at the instruction after the svc.
+fn handle_syscall(frame: &mut TrapFrame) {
+    let args = [frame.x0, frame.x1, frame.x2, frame.x3, frame.x4, frame.x5].map(|x| x as usize);
+    frame.x0 = syscall::dispatch(frame.x8 as usize, &args) as u64;
+}
+
+/// Print a human readable description of a synchronous exception
+fn describe_exception(esr: EsrEl1, far: u64, elr: u64) {
+    match esr.exception_class_enum() {
+        Ok(ec @ (ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl)) => {
+            let iss = EsrEl1IssDataAbort(esr.iss());
+            println!(
+                "{:?} {} {:#018x} at elr {:#018x}: {:?}",
+                ec,
+                if iss.wnr() { "writing" } else { "reading" },
+                far,
+                elr,
+                iss.data_fault()
+            );
+            if iss.fnv() {
+                println!("  (far is not valid)");
+            }
+        }
+        Ok(
+            ec @ (ExceptionClass::InstructionAbortLowerEl | ExceptionClass::InstructionAbortSameEl),
+        ) => {
+            let iss = EsrEl1IssInstructionAbort(esr.iss());
+            println!("{:?} at elr {:#018x}: {:?}", ec, elr, iss.instruction_fault());
+        }
+        Ok(ExceptionClass::Svc64) => {
+            println!("SVC #{:#x} at elr {:#018x}", esr.iss() & 0xffff, elr);
+        }
+        Ok(ec @ (ExceptionClass::PcAlignmentFault | ExceptionClass::SpAlignmentFault)) => {
+            println!("{:?} at elr {:#018x} (far: {:#018x})", ec, elr, far);
+        }
+        Ok(ec) => {
+            println!("{:?} at elr {:#018x} (iss: {:#010x})", ec, elr, esr.iss());
+        }
+        Err(ec) => {
+            println!("Unknown exception class {:#04x} at elr {:#018x}", ec, elr);
+        }
+    }
+}
+
+/// Maximum number of interrupts supported.  GICv2 supports up to 1020.
+pub const MAX_IRQS: usize = 1024;
+
+/// Implemented by drivers that want to be called when an interrupt fires.
+pub trait IrqHandler: Sync {
+    fn handle_irq(&self, irq_num: u32);
+}
+
+/// Implemented by the interrupt controller driver, so that irq_handler can
+/// find out which interrupt fired and signal when it's been handled.
+pub trait IrqController: Sync {
+    /// Acknowledge the highest priority pending interrupt and return its
+    /// number, or None if the interrupt was spurious.
+    fn acknowledge(&self) -> Option<u32>;
+
+    /// Signal that the interrupt has been handled.
+    fn end_of_interrupt(&self, irq_num: u32);
+
+    /// Allow the interrupt to be delivered.
+    fn enable_irq(&self, irq_num: u32);
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum IrqError {
+    IrqOutOfRange(u32),
+    HandlerAlreadyRegistered(u32),
+}
+
+// Also taken by the IRQ handler
+static IRQ_CONTROLLER: IrqLock<Option<&'static dyn IrqController>, LocalInterrupts> =
+    IrqLock::new("irqctl", None);
+static IRQ_HANDLERS: IrqLock<[Option<&'static dyn IrqHandler>; MAX_IRQS], LocalInterrupts> =
+    IrqLock::new("irqhandlers", [None; MAX_IRQS]);
+
+/// Mask IRQs on this core, returning the previous DAIF value.  Needed when
+/// taking a lock that an interrupt handler also takes.
+pub fn disable_irqs() -> u64 {
+    #[cfg(not(test))]
+    {
+        let daif: u64;
+        unsafe {
+            core::arch::asm!("mrs {daif}, DAIF", "msr DAIFSet, #2", daif = out(reg) daif);
+        }
+        daif
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Restore the DAIF value returned by disable_irqs
+#[allow(unused_variables)]
+pub fn restore_irqs(daif: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("msr DAIF, {daif}", daif = in(reg) daif);
+    }
+}
+
+/// Masks IRQs on this core, for IrqLock
+pub struct LocalInterrupts;
+
+impl Interrupts for LocalInterrupts {
+    /// The previous DAIF value
+    type State = u64;
+
+    fn disable() -> u64 {
+        disable_irqs()
+    }
+
+    fn restore(daif: u64) {
+        restore_irqs(daif);
+    }
+}
+
+/// Set the interrupt controller used to acknowledge interrupts.  Any
+/// interrupts that already have handlers are enabled.
+pub fn set_irq_controller(controller: &'static dyn IrqController) {
+    {
+        let node = LockNode::new();
+        *IRQ_CONTROLLER.lock(&node) = Some(controller);
+    }
+    {
+        let node = LockNode::new();
+        let handlers = IRQ_HANDLERS.lock(&node);
+        for (irq_num, _) in handlers.iter().enumerate().filter(|(_, h)| h.is_some()) {
+            controller.enable_irq(irq_num as u32);
+        }
+    }
+}
+
+/// Returns true if an interrupt controller has been set.
+pub fn has_irq_controller() -> bool {
+    let node = LockNode::new();
+    let controller = IRQ_CONTROLLER.lock(&node);
+    controller.is_some()
+}
+
+/// Register a handler for the given interrupt, and enable it if there's an
+/// interrupt controller.  Only a single handler may be registered per
+/// interrupt.
+pub fn register_irq_handler(
+    irq_num: u32,
+    handler: &'static dyn IrqHandler,
+) -> Result<(), IrqError> {
+    if irq_num as usize >= MAX_IRQS {
+        return Err(IrqError::IrqOutOfRange(irq_num));
+    }
+
+    let result = {
+        let node = LockNode::new();
+        let mut handlers = IRQ_HANDLERS.lock(&node);
+        let slot = &mut handlers[irq_num as usize];
+        if slot.is_some() {
+            Err(IrqError::HandlerAlreadyRegistered(irq_num))
+        } else {
+            *slot = Some(handler);
+            Ok(())
+        }
+    };
+    if result.is_ok() {
+        let controller = {
+            let node = LockNode::new();
+            let controller = IRQ_CONTROLLER.lock(&node);
+            *controller
+        };
+        if let Some(controller) = controller {
+            controller.enable_irq(irq_num);
+        }
+    }
+    result
+}
+
+/// Call the handler registered for irq_num.  Returns false if there's no
+/// handler.
+fn dispatch_irq(irq_num: u32) -> bool {
+    let handler = {
+        let node = LockNode::new();
+        let handlers = IRQ_HANDLERS.lock(&node);
+        handlers.get(irq_num as usize).copied().flatten()
+    };
+    handler.map(|h| h.handle_irq(irq_num)).is_some()
+}
+
+/// Handle an IRQ exception: ask the interrupt controller which interrupt
+/// fired, dispatch to the registered handler, then send EOI.
+fn irq_handler() {
+    let controller = {
+        let node = LockNode::new();
+        let controller = IRQ_CONTROLLER.lock(&node);
+        *controller
+    };
+    let Some(controller) = controller else {
+        println!("IRQ taken, but no interrupt controller registered");
+        return;
+    };
+
+    if let Some(irq_num) = controller.acknowledge() {
+        if !dispatch_irq(irq_num) {
+            println!("Unhandled IRQ {irq_num}");
+        }
+        controller.end_of_interrupt(irq_num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    struct CountingHandler(AtomicU32);
+
+    impl IrqHandler for CountingHandler {
+        fn handle_irq(&self, irq_num: u32) {
+            self.0.fetch_add(irq_num, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn register_and_dispatch_irq() {
+        static HANDLER: CountingHandler = CountingHandler(AtomicU32::new(0));
+        assert!(!dispatch_irq(30));
+        register_irq_handler(30, &HANDLER).unwrap();
+        assert!(matches!(
+            register_irq_handler(30, &HANDLER),
+            Err(IrqError::HandlerAlreadyRegistered(30))
+        ));
+        assert!(matches!(
+            register_irq_handler(MAX_IRQS as u32, &HANDLER),
+            Err(IrqError::IrqOutOfRange(_))
+        ));
+        assert!(dispatch_irq(30));
+        assert_eq!(HANDLER.0.load(Ordering::Relaxed), 30);
+    }
+}
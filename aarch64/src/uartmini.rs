@@ -1,18 +1,92 @@
+#[cfg(feature = "miniuart_tx_irq")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use port::devcons::Uart;
 use port::fdt::DeviceTree;
+use port::maths::mini_uart_baud;
+use port::mcslock::{Lock, LockNode};
 use port::mem::VirtRange;
+use port::ringbuf::RingBuf;
 
-use crate::io::{delay, read_reg, write_or_reg, write_reg};
+use crate::gpio::{Gpio, GpioFunction, GpioPull};
+use crate::io::{read_reg, write_or_reg, write_reg};
 use crate::registers::{
     AUX_ENABLE, AUX_MU_BAUD, AUX_MU_CNTL, AUX_MU_IER, AUX_MU_IIR, AUX_MU_IO, AUX_MU_LCR,
-    AUX_MU_LSR, AUX_MU_MCR, GPFSEL1, GPPUD, GPPUDCLK0,
+    AUX_MU_LSR, AUX_MU_MCR,
 };
+use crate::trap::{disable_irqs, restore_irqs, IrqHandler};
+
+/// The mini UART shares the AUX interrupt with SPI1 and SPI2.  This is
+/// VideoCore IRQ 29, which is bit 29 of the first pending register of the
+/// legacy interrupt controller (Raspberry Pi 3).  On the GIC-400 (Raspberry Pi
+/// 4) VideoCore interrupts start at SPI 64, so it's SPI 93, or interrupt ID 125.
+pub const AUX_IRQ: u32 = 125;
+
+/// Assumed system clock frequency if we don't know better
+const DEFAULT_CLOCK_HZ: u32 = 250_000_000;
+
+const RX_BUFFER_SIZE: usize = 256;
+#[cfg(feature = "miniuart_tx_irq")]
+const TX_BUFFER_SIZE: usize = 1024;
+
+/// Bytes received by the interrupt handler, waiting to be read
+static RX_BUFFER: Lock<RingBuf<u8, RX_BUFFER_SIZE>> = Lock::new("miniuart_rx", RingBuf::new());
+
+/// Number of received bytes dropped because RX_BUFFER was full
+static RX_OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "miniuart_tx_irq")]
+static TX_BUFFER: Lock<RingBuf<u8, TX_BUFFER_SIZE>> = Lock::new("miniuart_tx", RingBuf::new());
+
+/// Set once the interrupt handler is able to drain TX_BUFFER.  Until then we
+/// transmit by polling.
+#[cfg(feature = "miniuart_tx_irq")]
+static TX_IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Run f with the buffer locked and IRQs masked, so the interrupt handler
+/// can't deadlock trying to take the lock.
+fn with_buffer<const N: usize, F, R>(buffer: &Lock<RingBuf<u8, N>>, f: F) -> R
+where
+    F: FnOnce(&mut RingBuf<u8, N>) -> R,
+{
+    let daif = disable_irqs();
+    let result = {
+        let node = LockNode::new();
+        let mut buffer = buffer.lock(&node);
+        f(&mut buffer)
+    };
+    restore_irqs(daif);
+    result
+}
+
+/// Number of received bytes dropped because the receive buffer was full
+#[allow(dead_code)]
+pub fn rx_overruns() -> usize {
+    RX_OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Switch from polling to interrupt driven transmit.  This should only be
+/// called once the handler returned by MiniUart::irq_handler has been
+/// registered and the interrupt controller will deliver AUX_IRQ, otherwise
+/// bytes will sit in the transmit buffer until it overflows.
+#[cfg(feature = "miniuart_tx_irq")]
+pub fn enable_tx_interrupt() {
+    TX_IRQ_ENABLED.store(true, Ordering::Release);
+}
+
+/// Go back to transmitting by polling, e.g. when panicking.  Anything still
+/// in the transmit buffer is left there.
+#[cfg(feature = "miniuart_tx_irq")]
+#[allow(dead_code)]
+pub fn disable_tx_interrupt() {
+    TX_IRQ_ENABLED.store(false, Ordering::Release);
+}
 
 /// MiniUart is assigned to UART1 on the Raspberry Pi.  It is easier to use with
 /// real hardware, as it requires no additional configuration.  Conversely, it's
 /// harded to use with QEMU, as it can't be used with the `nographic` switch.
 pub struct MiniUart {
-    pub gpio_range: VirtRange,
+    pub gpio: Gpio,
     pub aux_range: VirtRange,
     pub miniuart_range: VirtRange,
 }
@@ -20,17 +94,7 @@ pub struct MiniUart {
 #[allow(dead_code)]
 impl MiniUart {
     pub fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> MiniUart {
-        // Bcm2835 and bcm2711 are essentially the same for our needs here.
-        // If fdt.rs supported aliases well, we could try to just look up 'gpio'.
-        let gpio_range = VirtRange::from(
-            &dt.find_compatible("brcm,bcm2835-gpio")
-                .next()
-                .or_else(|| dt.find_compatible("brcm,bcm2711-gpio").next())
-                .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                .and_then(|reg| reg.regblock())
-                .unwrap()
-                .with_offset(mmio_virt_offset as u64),
-        );
+        let gpio = Gpio::new(dt, mmio_virt_offset);
 
         // Find a compatible aux
         let aux_range = VirtRange::from(
@@ -52,23 +116,21 @@ impl MiniUart {
                 .with_offset(mmio_virt_offset as u64),
         );
 
-        MiniUart { gpio_range, aux_range, miniuart_range }
+        MiniUart { gpio, aux_range, miniuart_range }
+    }
+
+    /// Set the baud rate, given the frequency of the system (VPU core) clock
+    pub fn set_baud(&mut self, baud: u32, system_clock_hz: u32) {
+        write_reg(&self.miniuart_range, AUX_MU_BAUD, mini_uart_baud(system_clock_hz, baud));
     }
 
     pub fn init(&self) {
-        // Set GPIO pins 14 and 15 to be used for UART1.  This is done by
-        // setting the appropriate flags in GPFSEL1 to ALT5, which is
-        // represented by the 0b010
-        let mut gpfsel1 = read_reg(&self.gpio_range, GPFSEL1);
-        gpfsel1 &= !((7 << 12) | (7 << 15));
-        gpfsel1 |= (2 << 12) | (2 << 15);
-        write_reg(&self.gpio_range, GPFSEL1, gpfsel1);
-
-        write_reg(&self.gpio_range, GPPUD, 0);
-        delay(150);
-        write_reg(&self.gpio_range, GPPUDCLK0, (1 << 14) | (1 << 15));
-        delay(150);
-        write_reg(&self.gpio_range, GPPUDCLK0, 0);
+        // Set GPIO pins 14 and 15 to be used for UART1, which is their ALT5
+        // function, with no pull up/down
+        for pin in [14, 15] {
+            self.gpio.set_function(pin, GpioFunction::Alt5);
+            self.gpio.set_pull(pin, GpioPull::Off);
+        }
 
         // Enable mini uart - required to write to its registers
         write_or_reg(&self.aux_range, AUX_ENABLE, 1);
@@ -81,22 +143,31 @@ impl MiniUart {
         // Clear receive/transmit FIFOs
         write_reg(&self.miniuart_range, AUX_MU_IIR, 0xc6);
 
-        // We want 115200 baud.  This is calculated as:
-        //   system_clock_freq / (8 * (baudrate_reg + 1))
-        // For now we're making assumptions about the clock frequency
+        // We want 115200 baud.  For now we're making assumptions about the
+        // clock frequency, which may be corrected by calling set_baud.
         // TODO Get the clock freq via the mailbox, and update if it changes.
-        // let arm_clock_rate = 500000000.0;
-        // let baud_rate_reg = arm_clock_rate / (8.0 * 115200.0) + 1.0;
-        //write_reg(self.miniuart_reg, AUX_MU_BAUD, baud_rate_reg as u32);
-        write_reg(&self.miniuart_range, AUX_MU_BAUD, 270);
+        write_reg(&self.miniuart_range, AUX_MU_BAUD, mini_uart_baud(DEFAULT_CLOCK_HZ, 115200));
 
         // Finally enable transmit
         write_reg(&self.miniuart_range, AUX_MU_CNTL, 3);
     }
-}
 
-impl Uart for MiniUart {
-    fn putb(&self, b: u8) {
+    /// Enable the receive interrupt.  Received bytes are stored in the receive
+    /// buffer by the handler returned by irq_handler, which should be
+    /// registered for AUX_IRQ.
+    pub fn enable_rx_interrupt(&self) {
+        write_or_reg(&self.miniuart_range, AUX_MU_IER, 1);
+    }
+
+    /// Create an interrupt handler that reads received bytes into the receive
+    /// buffer, and (if enabled) drains the transmit buffer.
+    pub fn irq_handler(&self) -> MiniUartIrqHandler {
+        let start = self.miniuart_range.start();
+        let len = self.miniuart_range.end() - start;
+        MiniUartIrqHandler { miniuart_range: VirtRange::with_len(start, len) }
+    }
+
+    fn putb_polled(&self, b: u8) {
         // Wait for UART to become ready to transmit
         while read_reg(&self.miniuart_range, AUX_MU_LSR) & (1 << 5) == 0 {
             core::hint::spin_loop();
@@ -104,3 +175,88 @@ impl Uart for MiniUart {
         write_reg(&self.miniuart_range, AUX_MU_IO, b as u32);
     }
 }
+
+/// Handles the mini UART interrupt, moving received bytes from the FIFO into
+/// the receive buffer, and bytes from the transmit buffer into the FIFO.
+pub struct MiniUartIrqHandler {
+    miniuart_range: VirtRange,
+}
+
+impl IrqHandler for MiniUartIrqHandler {
+    fn handle_irq(&self, _irq_num: u32) {
+        // Reading AUX_MU_IO clears the interrupt once the FIFO is empty
+        while read_reg(&self.miniuart_range, AUX_MU_LSR) & 1 != 0 {
+            let b = read_reg(&self.miniuart_range, AUX_MU_IO) as u8;
+            let node = LockNode::new();
+            if !RX_BUFFER.lock(&node).push(b) {
+                RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // Fill the FIFO while it has space.  Once the buffer is empty, disable
+        // the transmit interrupt, otherwise it'll keep firing.
+        #[cfg(feature = "miniuart_tx_irq")]
+        {
+            let node = LockNode::new();
+            let mut tx_buffer = TX_BUFFER.lock(&node);
+            while read_reg(&self.miniuart_range, AUX_MU_LSR) & (1 << 5) != 0 {
+                match tx_buffer.pop() {
+                    Some(b) => write_reg(&self.miniuart_range, AUX_MU_IO, b as u32),
+                    None => {
+                        let ier = read_reg(&self.miniuart_range, AUX_MU_IER);
+                        write_reg(&self.miniuart_range, AUX_MU_IER, ier & !(1 << 1));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Uart for MiniUart {
+    fn putb(&self, b: u8) {
+        #[cfg(feature = "miniuart_tx_irq")]
+        if TX_IRQ_ENABLED.load(Ordering::Acquire) {
+            // Queue the byte and make sure the transmit interrupt is enabled,
+            // with the buffer locked so the handler can't disable it under us.
+            let queued = with_buffer(&TX_BUFFER, |tx_buffer| {
+                let queued = tx_buffer.push(b);
+                if queued {
+                    write_or_reg(&self.miniuart_range, AUX_MU_IER, 1 << 1);
+                }
+                queued
+            });
+            if queued {
+                return;
+            }
+
+            // The buffer is full, so drain it by polling to keep bytes in order
+            while let Some(queued_b) = with_buffer(&TX_BUFFER, |tx_buffer| tx_buffer.pop()) {
+                self.putb_polled(queued_b);
+            }
+        }
+
+        self.putb_polled(b);
+    }
+
+    /// Wait for the transmitter to go idle, with the FIFO empty and the last
+    /// byte shifted out.  Bytes queued for the transmit interrupt aren't
+    /// waited for.
+    fn flush(&self) {
+        while read_reg(&self.miniuart_range, AUX_MU_LSR) & (1 << 6) == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Return a byte from the receive buffer, falling back to polling if the
+    /// buffer is empty (e.g. if interrupts aren't enabled).
+    fn try_getb(&self) -> Option<u8> {
+        with_buffer(&RX_BUFFER, |rx_buffer| rx_buffer.pop()).or_else(|| {
+            if read_reg(&self.miniuart_range, AUX_MU_LSR) & 1 != 0 {
+                Some(read_reg(&self.miniuart_range, AUX_MU_IO) as u8)
+            } else {
+                None
+            }
+        })
+    }
+}
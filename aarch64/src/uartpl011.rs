@@ -1,16 +1,16 @@
-use crate::io::{delay, read_reg, write_reg, GpioPull};
+use crate::gpio::{Gpio, GpioFunction, GpioPull};
 use crate::mailbox;
 use crate::registers::{
-    GPPUD, GPPUDCLK0, UART0_CR, UART0_DR, UART0_FBRD, UART0_FR, UART0_IBRD, UART0_ICR, UART0_IMSC,
-    UART0_LCRH,
+    UART0_CR, UART0_DR, UART0_FBRD, UART0_FR, UART0_IBRD, UART0_ICR, UART0_IMSC, UART0_LCRH,
 };
 use port::devcons::Uart;
 use port::fdt::DeviceTree;
+use port::maths::baud_divisors;
 use port::mem::VirtRange;
 
 #[allow(dead_code)]
 pub struct Pl011Uart {
-    gpio_range: VirtRange,
+    gpio: Gpio,
     pl011_range: VirtRange,
 }
 
@@ -21,13 +21,7 @@ pub struct Pl011Uart {
 impl Pl011Uart {
     pub fn new(dt: &DeviceTree) -> Pl011Uart {
         // TODO use aliases?
-        let gpio_range = VirtRange::from(
-            &dt.find_compatible("brcm,bcm2835-gpio")
-                .next()
-                .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                .and_then(|reg| reg.regblock())
-                .unwrap(),
-        );
+        let gpio = Gpio::new(dt, 0);
 
         // Find a compatible pl011 uart
         let pl011_range = VirtRange::from(
@@ -38,70 +32,117 @@ impl Pl011Uart {
                 .unwrap(),
         );
 
-        Pl011Uart { gpio_range, pl011_range }
+        Pl011Uart { gpio, pl011_range }
+    }
+
+    /// Whether a pin is muxed to the PL011's transmit line, so its output
+    /// goes somewhere.  Its TXD0 can be on GPIO 14 (ALT0), 32 (ALT3) or 36
+    /// (ALT2).
+    pub fn owns_tx_pin(&self) -> bool {
+        [(14, GpioFunction::Alt0), (32, GpioFunction::Alt3), (36, GpioFunction::Alt2)]
+            .into_iter()
+            .any(|(pin, func)| self.gpio.function(pin) == func)
+    }
+
+    /// Set GPIO pins 14 and 15 (tx/rx) to be used for UART0, which is their
+    /// ALT0 function, with no pull up/down.  This takes them from the
+    /// MiniUART, so is separate from init.
+    pub fn init_pins(&self) {
+        for pin in [14, 15] {
+            self.gpio.set_function(pin, GpioFunction::Alt0);
+            self.gpio.set_pull(pin, GpioPull::Off);
+        }
     }
 
     pub fn init(&self) {
         // Disable UART0
-        write_reg(&self.pl011_range, UART0_CR, 0);
-
-        // Turn pull up/down off for pins 14/15 (tx/rx)
-        self.gpiosetpull(14, GpioPull::Off);
-        self.gpiosetpull(15, GpioPull::Off);
+        UART0_CR.write(&self.pl011_range, 0);
 
         // Clear interrupts
-        write_reg(&self.pl011_range, UART0_ICR, 0x7ff);
+        UART0_ICR.write(&self.pl011_range, 0x7ff);
 
-        // Set the uart clock rate to 3MHz
+        // Ask for a 3MHz uart clock, but go by the rate the firmware reports,
+        // as it may not have set the one asked for
         let uart_clock_rate_hz = 3_000_000;
-        mailbox::set_clock_rate(2, uart_clock_rate_hz, 0);
+        mailbox::set_clock_rate(mailbox::CLOCK_ID_UART, uart_clock_rate_hz, 0);
+        let uart_clock_rate_hz =
+            mailbox::get_clock_rate(mailbox::CLOCK_ID_UART).unwrap_or(uart_clock_rate_hz);
 
         // Set the baud rate via the integer and fractional baud rate regs
-        let baud_rate = 115200;
-        let baud_rate_divisor = (uart_clock_rate_hz as f32) / ((16 * baud_rate) as f32);
-        let int_brd = baud_rate_divisor as u32;
-        let frac_brd = (((baud_rate_divisor - (int_brd as f32)) * 64.0) + 0.5) as u32;
-        write_reg(&self.pl011_range, UART0_IBRD, int_brd);
-        write_reg(&self.pl011_range, UART0_FBRD, frac_brd);
+        let (int_brd, frac_brd) = baud_divisors(uart_clock_rate_hz, 115200);
+        UART0_IBRD.write(&self.pl011_range, int_brd as u32);
+        UART0_FBRD.write(&self.pl011_range, frac_brd as u32);
 
         // Enable FIFOs (tx and rx), 8 bit
-        write_reg(&self.pl011_range, UART0_LCRH, 0x70);
+        UART0_LCRH.write(&self.pl011_range, 0x70);
 
         // Mask all interrupts
-        write_reg(&self.pl011_range, UART0_IMSC, 0x7f2);
+        UART0_IMSC.write(&self.pl011_range, 0x7f2);
 
-        // Enable UART0, receive only
-        write_reg(&self.pl011_range, UART0_CR, 0x81);
+        // Enable UART0, transmit and receive
+        UART0_CR.write(&self.pl011_range, 0x301);
     }
 
-    fn gpiosetpull(&self, pin: u32, pull: GpioPull) {
-        // The GPIO pull up/down bits are spread across consecutive registers GPPUDCLK0 to GPPUDCLK1
-        // GPPUDCLK0: pins  0-31
-        // GPPUDCLK1: pins 32-53
-        let reg_offset = pin as usize / 32;
-        // Number of bits to shift pull, in order to affect the required pin (just 1 bit)
-        let pud_bit = 1 << (pin % 32);
-        // Which GPPUDCLK register to use
-        let gppudclk_reg = GPPUDCLK0 + reg_offset * 4;
-
-        // You can't read the GPPUD registers, so to set the state we first set the PUD value we want...
-        write_reg(&self.pl011_range, GPPUD, pull as u32);
-        // ...wait 150 cycles for it to set
-        delay(150);
-        // ...set the appropriate PUD bit
-        write_reg(&self.pl011_range, gppudclk_reg, pud_bit);
-        // ...wait 150 cycles for it to set
-        delay(150);
-        // ...clear up
-        write_reg(&self.pl011_range, GPPUD, 0);
-        write_reg(&self.pl011_range, gppudclk_reg, 0);
+    /// Set the baud rate, given the frequency of the UART reference clock.
+    /// The UART is disabled while the divisors are updated, and the line
+    /// control register is rewritten, as required for the new divisors to be
+    /// latched.
+    pub fn set_baud(&mut self, baud: u32, uart_clock_hz: u32) {
+        let cr = UART0_CR.read(&self.pl011_range);
+        UART0_CR.write(&self.pl011_range, 0);
+
+        let (int_brd, frac_brd) = baud_divisors(uart_clock_hz, baud);
+        UART0_IBRD.write(&self.pl011_range, int_brd as u32);
+        UART0_FBRD.write(&self.pl011_range, frac_brd as u32);
+        let lcrh = UART0_LCRH.read(&self.pl011_range);
+        UART0_LCRH.write(&self.pl011_range, lcrh);
+
+        UART0_CR.write(&self.pl011_range, cr);
+    }
+
+    /// Return the next received byte, or None if the receive FIFO is empty.
+    /// Bytes received with a framing, parity, break or overrun error are
+    /// discarded.
+    pub fn try_getc(&self) -> Option<u8> {
+        // Receive FIFO empty
+        if UART0_FR.read(&self.pl011_range) & (1 << 4) != 0 {
+            return None;
+        }
+        let data = UART0_DR.read(&self.pl011_range);
+        // Error bits (FE, PE, BE, OE) are in bits 8-11
+        if data & 0xf00 != 0 {
+            return None;
+        }
+        Some(data as u8)
+    }
+
+    /// Block until a byte is received
+    pub fn getc(&self) -> u8 {
+        loop {
+            if let Some(b) = self.try_getc() {
+                return b;
+            }
+            core::hint::spin_loop();
+        }
     }
 }
 
 impl Uart for Pl011Uart {
     fn putb(&self, b: u8) {
         // Wait for UART to become ready to transmit.
-        while read_reg(&self.pl011_range, UART0_FR) & (1 << 5) != 0 {}
-        write_reg(&self.pl011_range, UART0_DR, b as u32);
+        while UART0_FR.read(&self.pl011_range) & (1 << 5) != 0 {}
+        UART0_DR.write(&self.pl011_range, b as u32);
+    }
+
+    fn try_getb(&self) -> Option<u8> {
+        self.try_getc()
+    }
+
+    /// Wait until the UART isn't busy sending, which includes the FIFO
+    /// being empty
+    fn flush(&self) {
+        while UART0_FR.read(&self.pl011_range) & (1 << 3) != 0 {
+            core::hint::spin_loop();
+        }
     }
 }
@@ -10,15 +10,17 @@ use crate::{
 };
 use bitstruct::bitstruct;
 use core::fmt;
-use core::ptr::write_volatile;
+use core::ptr::{self, write_volatile};
 use num_enum::{FromPrimitive, IntoPrimitive};
 use port::{
     bitmapalloc::BitmapPageAllocError,
-    mem::{PhysAddr, PhysRange, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K},
+    collections::FixedVec,
+    fdt::{DeviceTree, Node},
+    mem::{PhysAddr, PhysRange, VirtRange, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K},
 };
 
 #[cfg(not(test))]
-use port::println;
+use port::{print, println};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -203,6 +205,68 @@ impl fmt::Debug for Entry {
     }
 }
 
+/// Translation granule size.  This determines the size of each page table,
+/// the number of bits used to index into each table, and the smallest page
+/// size.  With 48-bit virtual addresses, the virtual address is split as
+/// follows:
+///  4KiB:  [47-39] L0, [38-30] L1, [29-21] L2, [20-12] L3
+///  16KiB: [47] L0, [46-36] L1, [35-25] L2, [24-14] L3
+///  64KiB: [47-42] L1, [41-29] L2, [28-16] L3
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granule {
+    Size4K,
+    Size16K,
+    Size64K,
+}
+
+/// The granule used by the kernel page tables.  This must match the TG1
+/// setting of TCR_EL1 in l.S.
+pub const GRANULE: Granule = Granule::Size4K;
+
+/// The root table entry that points back at the root table itself, for
+/// recursive addressing of the tables
+const RECURSIVE_INDEX: usize = GRANULE.root_entries() - 1;
+
+/// Mask for the bits of a virtual address that are used in translation
+const VA_MASK: usize = 0x0000_ffff_ffff_ffff;
+
+impl Granule {
+    /// Number of bits in the page offset
+    pub const fn page_shift(&self) -> usize {
+        match self {
+            Granule::Size4K => 12,
+            Granule::Size16K => 14,
+            Granule::Size64K => 16,
+        }
+    }
+
+    /// Number of bits used to index into a full table
+    pub const fn index_bits(&self) -> usize {
+        // Each entry is 8 bytes
+        self.page_shift() - 3
+    }
+
+    /// The first level used in translation.  64KiB granules don't use Level0
+    /// with 48-bit addresses.
+    pub const fn first_level(&self) -> Level {
+        match self {
+            Granule::Size4K | Granule::Size16K => Level::Level0,
+            Granule::Size64K => Level::Level1,
+        }
+    }
+
+    /// Number of entries in the root table
+    pub const fn root_entries(&self) -> usize {
+        1 << (48 - self.level_shift(self.first_level()))
+    }
+
+    /// Lowest bit of the virtual address used to index into the table at level
+    pub const fn level_shift(&self, level: Level) -> usize {
+        self.page_shift() + (3 - level.depth()) * self.index_bits()
+    }
+}
+
 /// Levels start at the lowest number (most significant) and increase from
 /// there.  Four levels would support (for example) 4kiB granules with 4KiB
 /// pages using Level0 - Level3, while three would support 2MiB pages with the
@@ -216,7 +280,8 @@ pub enum Level {
 }
 
 impl Level {
-    /// Returns the next level to translate
+    /// Returns the next level to translate.  Every granule uses the levels
+    /// down to Level3; the larger granules just start later.
     pub fn next(&self) -> Option<Level> {
         match self {
             Level::Level0 => Some(Level::Level1),
@@ -226,7 +291,7 @@ impl Level {
         }
     }
 
-    pub fn depth(&self) -> usize {
+    pub const fn depth(&self) -> usize {
         match self {
             Level::Level0 => 0,
             Level::Level1 => 1,
@@ -234,46 +299,63 @@ impl Level {
             Level::Level3 => 3,
         }
     }
-}
 
-pub fn va_index(va: usize, level: Level) -> usize {
-    match level {
-        Level::Level0 => (va >> 39) & 0x1ff,
-        Level::Level1 => (va >> 30) & 0x1ff,
-        Level::Level2 => (va >> 21) & 0x1ff,
-        Level::Level3 => (va >> 12) & 0x1ff,
+    const fn from_depth(depth: usize) -> Level {
+        match depth {
+            0 => Level::Level0,
+            1 => Level::Level1,
+            2 => Level::Level2,
+            _ => Level::Level3,
+        }
     }
 }
 
+pub fn va_index(va: usize, level: Level, granule: Granule) -> usize {
+    let mask = (1 << granule.index_bits()) - 1;
+    ((va & VA_MASK) >> granule.level_shift(level)) & mask
+}
+
 /// Returns a tuple of page table indices for the given virtual address
 #[cfg(test)]
-fn va_indices(va: usize) -> (usize, usize, usize, usize) {
+fn va_indices(va: usize, granule: Granule) -> (usize, usize, usize, usize) {
     (
-        va_index(va, Level::Level0),
-        va_index(va, Level::Level1),
-        va_index(va, Level::Level2),
-        va_index(va, Level::Level3),
+        va_index(va, Level::Level0, granule),
+        va_index(va, Level::Level1, granule),
+        va_index(va, Level::Level2, granule),
+        va_index(va, Level::Level3, granule),
     )
 }
 
 /// Return the virtual address for the page table at level `level` for the
-/// given virtual address, assuming the use of recursive page tables.
-fn recursive_table_addr(va: usize, level: Level) -> usize {
-    let indices_mask = 0x0000_ffff_ffff_f000;
-    let indices = va & indices_mask;
-    let shift = match level {
-        Level::Level0 => 36,
-        Level::Level1 => 27,
-        Level::Level2 => 18,
-        Level::Level3 => 9,
-    };
-    let recursive_indices = match level {
-        Level::Level0 => (511 << 39) | (511 << 30) | (511 << 21) | (511 << 12),
-        Level::Level1 => (511 << 39) | (511 << 30) | (511 << 21),
-        Level::Level2 => (511 << 39) | (511 << 30),
-        Level::Level3 => 511 << 39,
-    };
-    0xffff_0000_0000_0000 | recursive_indices | ((indices >> shift) & indices_mask)
+/// given virtual address, assuming the use of recursive page tables.  The
+/// recursive entry is the last entry in the root table.
+fn recursive_table_addr(va: usize, level: Level, granule: Granule) -> usize {
+    let first = granule.first_level().depth();
+    let num_levels = 4 - first;
+    // Number of times we need to pass through the recursive entry
+    let num_recursive = num_levels - (level.depth() - first);
+    let recursive_index = granule.root_entries() - 1;
+
+    let mut addr = 0;
+    for i in 0..num_levels {
+        let slot = Level::from_depth(first + i);
+        let index = if i < num_recursive {
+            recursive_index
+        } else {
+            va_index(va, Level::from_depth(first + i - num_recursive), granule)
+        };
+        addr |= index << granule.level_shift(slot);
+    }
+    0xffff_0000_0000_0000 | addr
+}
+
+/// Return the recursive address of the child table at index i of the table
+/// at table_va, which is itself a recursive address.  Each level of the walk
+/// takes one less pass through the recursive entry, so the indices move up a
+/// level to make room for i.
+fn recursive_child_table_addr(table_va: usize, i: usize, granule: Granule) -> usize {
+    let shifted = (table_va << granule.index_bits()) & VA_MASK;
+    0xffff_0000_0000_0000 | shifted | (i << granule.page_shift())
 }
 
 #[derive(Debug)]
@@ -282,6 +364,19 @@ pub enum PageTableError {
     AllocationFailed(BitmapPageAllocError),
     EntryIsNotTable,
     PhysRangeIsZero,
+    /// Copying block (2MiB or 1GiB) mappings isn't supported
+    BlockMappingUnsupported,
+    /// map_virtpage was asked to map physical address 0, which is never a
+    /// page it should be given
+    PhysAddrIsZero,
+    /// map_virtpage was given a physical address that isn't 4KiB aligned
+    PhysAddrMisaligned,
+    /// A device reg block has no size, so can't be mapped
+    RegBlockHasNoSize,
+    /// A device has more than MAX_DEVICE_REGS reg blocks
+    TooManyRegBlocks,
+    /// A device's unmapped pages make up more than MAX_DEVICE_RUNS runs
+    TooManyDeviceRuns,
 }
 
 impl From<BitmapPageAllocError> for PageTableError {
@@ -300,14 +395,14 @@ impl Table {
     /// the level.  (It uses the level to extract the index from the correct
     /// part of the virtual address).
     pub fn entry_mut(&mut self, level: Level, va: usize) -> Result<&mut Entry, PageTableError> {
-        let idx = va_index(va, level);
+        let idx = va_index(va, level, GRANULE);
         Ok(&mut self.entries[idx])
     }
 
     /// Return the next table in the walk.  If it doesn't exist, create it.
     fn next_mut(&mut self, level: Level, va: usize) -> Result<&mut Table, PageTableError> {
         // Try to get a valid page table entry.  If it doesn't exist, create it.
-        let index = va_index(va, level);
+        let index = va_index(va, level, GRANULE);
         let mut entry = self.entries[index];
         if !entry.valid() {
             // Create a new page table and write the entry into the parent table
@@ -325,15 +420,88 @@ impl Table {
         }
 
         // Return the address of the next table as a recursive address
-        let recursive_page_addr = recursive_table_addr(va, level.next().unwrap());
+        let recursive_page_addr = recursive_table_addr(va, level.next().unwrap(), GRANULE);
         Ok(unsafe { &mut *(recursive_page_addr as *mut Table) })
     }
 
+    /// Return the next table in the walk, or None if there isn't one
+    fn next(&mut self, level: Level, va: usize) -> Option<&mut Table> {
+        let entry = self.entries[va_index(va, level, GRANULE)];
+        if !entry.valid() || !entry.table(level) {
+            return None;
+        }
+        let recursive_page_addr = recursive_table_addr(va, level.next()?, GRANULE);
+        Some(unsafe { &mut *(recursive_page_addr as *mut Table) })
+    }
+
     fn alloc_pagetable() -> Result<&'static mut Table, PageTableError> {
         let page = pagealloc::allocate()?;
         page.clear();
         Ok(unsafe { &mut *(page as *mut Page4K as *mut Table) })
     }
+
+    /// Is the entry at index i of a table at level the root's recursive
+    /// entry, which points at the root itself rather than a child table?
+    fn is_recursive_entry(level: Level, i: usize) -> bool {
+        level == GRANULE.first_level() && i == RECURSIVE_INDEX
+    }
+
+    /// The table pointed to by entry, found by its physical address rather
+    /// than recursively, so it needn't be in the current translation.
+    fn child(entry: Entry) -> &'static mut Table {
+        unsafe { &mut *(entry.virt_page_addr() as *mut Table) }
+    }
+
+    /// Copy the user mappings in src, a table at level, into self, which is
+    /// empty.  Each entry is written before anything under it is copied, so
+    /// a partial copy can be freed with free_user_entries.  The root's
+    /// recursive entry isn't copied.
+    fn copy_user_entries(&mut self, src: &Table, level: Level) -> Result<(), PageTableError> {
+        for (i, &entry) in src.entries.iter().enumerate() {
+            if !entry.valid() || Self::is_recursive_entry(level, i) {
+                continue;
+            }
+            if entry.table(level) {
+                let table = Self::alloc_pagetable()?;
+                unsafe {
+                    write_volatile(
+                        &mut self.entries[i],
+                        entry.with_phys_addr(from_ptr_to_physaddr(table)),
+                    );
+                }
+                table.copy_user_entries(Self::child(entry), level.next().unwrap())?;
+            } else if level == Level::Level3 {
+                let page = pagealloc::allocate()?;
+                *page = unsafe { *physaddr_as_ptr_mut::<Page4K>(entry.phys_page_addr()) };
+                unsafe {
+                    write_volatile(
+                        &mut self.entries[i],
+                        entry.with_phys_addr(from_ptr_to_physaddr(page)),
+                    );
+                }
+            } else {
+                return Err(PageTableError::BlockMappingUnsupported);
+            }
+        }
+        Ok(())
+    }
+
+    /// Free the pages and tables under this table at level, but not the
+    /// table itself.  The root's recursive entry is left alone.
+    fn free_user_entries(&mut self, level: Level) {
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            if !entry.valid() || Self::is_recursive_entry(level, i) {
+                continue;
+            }
+            if entry.table(level) {
+                Self::child(*entry).free_user_entries(level.next().unwrap());
+            }
+            if entry.table(level) || level == Level::Level3 {
+                let _ = pagealloc::decref(entry.phys_page_addr());
+            }
+            *entry = Entry::empty();
+        }
+    }
 }
 
 pub type PageTable = Table;
@@ -352,21 +520,23 @@ impl PageTable {
         va: usize,
         page_size: PageSize,
     ) -> Result<(), PageTableError> {
-        // We change the last entry of the root page table to the address of
-        // self for the duration of this method.  This allows us to work with
-        // this hierarchy of pagetables even if it's not the current translation
+        // Unless self is the current translation table, we change the last
+        // entry of the root page table to the address of self for the
+        // duration of this method.  This allows us to work with this
+        // hierarchy of pagetables even if it's not the current translation
         // table.  We *must* return it to its original state on exit.
-        // TODO Only do this if self != kernel_root()
-        let old_recursive_entry = kernel_root().entries[511];
-        let temp_recursive_entry = Entry::rw_kernel_data()
-            .with_phys_addr(from_ptr_to_physaddr(self))
-            .with_page_or_table(true);
-
-        unsafe {
-            write_volatile(&mut kernel_root().entries[511], temp_recursive_entry);
-            // TODO Need to invalidate the single cache entry
-            invalidate_all_tlb_entries();
-        };
+        let swap_recursive_entry = !ptr::eq(self, kernel_root());
+        let old_recursive_entry = kernel_root().entries[RECURSIVE_INDEX];
+        if swap_recursive_entry {
+            let temp_recursive_entry = Entry::rw_kernel_data()
+                .with_phys_addr(from_ptr_to_physaddr(self))
+                .with_page_or_table(true);
+            unsafe {
+                write_volatile(&mut kernel_root().entries[RECURSIVE_INDEX], temp_recursive_entry);
+                // The whole recursive region has changed, so flush everything
+                invalidate_all_tlb_entries();
+            }
+        }
 
         let dest_entry = match page_size {
             PageSize::Page4K => self
@@ -387,15 +557,94 @@ impl PageTable {
         let entry =
             if page_size == PageSize::Page4K { entry.with_page_or_table(true) } else { entry };
 
-        unsafe {
-            write_volatile(dest_entry?, entry);
+        let result = dest_entry.map(|dest_entry| unsafe {
+            write_volatile(dest_entry, entry);
+            // Only the mapping for va has changed
+            invalidate_tlb_va(va);
+        });
+        if swap_recursive_entry {
             // Return the recursive entry to its original state
-            write_volatile(&mut kernel_root().entries[511], old_recursive_entry);
-            // TODO Need to invalidate the single cache entry (+ optionally the recursive entry)
-            invalidate_all_tlb_entries();
+            unsafe {
+                write_volatile(&mut kernel_root().entries[RECURSIVE_INDEX], old_recursive_entry);
+                invalidate_all_tlb_entries();
+            }
         }
 
-        Ok(())
+        result
+    }
+
+    /// Is va mapped, by a page or block of any size?  Like unmap, this walks
+    /// the tables recursively, so must be called on the kernel root.
+    fn is_mapped(&mut self, va: usize) -> bool {
+        let mut table = self;
+        let mut level = GRANULE.first_level();
+        loop {
+            let entry = table.entries[va_index(va, level, GRANULE)];
+            if !entry.valid() {
+                return false;
+            }
+            if !entry.table(level) {
+                return true;
+            }
+            table = table.next(level, va).unwrap();
+            level = level.next().unwrap();
+        }
+    }
+
+    /// Remove the mapping of the page_size page at va, if there is one.  The
+    /// tables are walked recursively, so this must be the kernel root.
+    fn unmap(&mut self, va: usize, page_size: PageSize) {
+        let leaf_level = match page_size {
+            PageSize::Page4K => Level::Level3,
+            PageSize::Page2M => Level::Level2,
+            PageSize::Page1G => Level::Level1,
+        };
+        let mut table = self;
+        let mut level = GRANULE.first_level();
+        while level != leaf_level {
+            let Some(next) = table.next(level, va) else {
+                return;
+            };
+            table = next;
+            level = level.next().unwrap();
+        }
+
+        // Leave any table where a block or page was expected
+        let entry = &mut table.entries[va_index(va, level, GRANULE)];
+        if entry.valid() && !entry.table(level) {
+            unsafe {
+                write_volatile(entry, Entry::empty());
+                invalidate_tlb_va(va);
+            }
+        }
+    }
+
+    /// Make a copy of this user (ttbr0) address space, with new tables and
+    /// a copy of every mapped page.  The kernel's mappings are in the ttbr1
+    /// tables, which every address space shares, so there's nothing to copy
+    /// for them.  Only 4KiB pages can be copied, not blocks.
+    ///
+    /// TODO Share the pages copy-on-write, rather than copying them all up
+    /// front.
+    #[allow(dead_code)]
+    pub fn clone_user(&self) -> Result<&'static mut PageTable, PageTableError> {
+        let root = Table::alloc_pagetable()?;
+        if let Err(err) = root.copy_user_entries(self, GRANULE.first_level()) {
+            free_user(root);
+            return Err(err);
+        }
+        // If self has a recursive entry, the clone's must point at itself
+        let recursive_entry = self.entries[RECURSIVE_INDEX];
+        if recursive_entry.valid() {
+            let root_pa = from_ptr_to_physaddr(root);
+            unsafe {
+                write_volatile(
+                    &mut root.entries[RECURSIVE_INDEX],
+                    recursive_entry.with_phys_addr(root_pa),
+                );
+            }
+        }
+        Ok(root)
     }
 
     /// Map the physical range using the requested page size.
@@ -425,7 +674,32 @@ impl PageTable {
     /// Recursively write out all the tables and all its children
     pub fn print_recursive_tables(&self) {
         println!("Root va:{:p}", self);
-        self.print_table_at_level(Level::Level0, 0xffff_ffff_ffff_f000);
+        let first = GRANULE.first_level();
+        self.print_table_at_level(first, recursive_table_addr(0, first, GRANULE));
+    }
+
+    /// Write out the entry at each level of the walk for va, stopping at the
+    /// leaf or the first invalid entry.  Like print_recursive_tables, this
+    /// uses recursive addresses, so it shows the current kernel (ttbr1)
+    /// translation.
+    #[allow(dead_code)]
+    pub fn print_va_walk(&self, va: usize) {
+        println!("Walk va:{:#018x}", va);
+        let mut level = Some(GRANULE.first_level());
+        while let Some(l) = level {
+            let table_va = recursive_table_addr(va, l, GRANULE);
+            let table = unsafe { &*(table_va as *const PageTable) };
+            let i = va_index(va, l, GRANULE);
+            let pte = table.entries[i];
+            let indent = 2 + l.depth() * 2;
+            if !pte.valid() {
+                println!("{:indent$}{:?} [{:03}] invalid (pte:{:#016x})", "", l, i, pte.0);
+                return;
+            }
+            print!("{:indent$}{:?} ", "", l);
+            print_pte(0, i, l, pte);
+            level = if pte.table(l) { l.next() } else { None };
+        }
     }
 
     /// Recursively write out the table and all its children
@@ -437,9 +711,9 @@ impl PageTable {
                 print_pte(indent, i, level, pte);
 
                 // Recurse into child table (unless it's the recursive index)
-                if i != 511 && pte.table(level) {
+                if !Table::is_recursive_entry(level, i) && pte.table(level) {
                     let next_nevel = level.next().unwrap();
-                    let child_va = (table_va << 9) | (i << 12);
+                    let child_va = recursive_child_table_addr(table_va, i, GRANULE);
                     let child_table = unsafe { &*(child_va as *const PageTable) };
                     child_table.print_table_at_level(next_nevel, child_va);
                 }
@@ -484,7 +758,7 @@ pub unsafe fn init(kpage_table: &mut PageTable, dtb_range: PhysRange, available_
         let entry = Entry::rw_kernel_data()
             .with_phys_addr(from_ptr_to_physaddr(kpage_table))
             .with_page_or_table(true);
-        write_volatile(&mut kpage_table.entries[511], entry);
+        write_volatile(&mut kpage_table.entries[RECURSIVE_INDEX], entry);
     }
 
     // TODO leave the first page unmapped to catch null pointer dereferences in unsafe code
@@ -494,14 +768,18 @@ pub unsafe fn init(kpage_table: &mut PageTable, dtb_range: PhysRange, available_
         let bss_range = bss_range();
         let mmio_range = rpi_mmio().expect("mmio base detect failed");
 
-        let mut map = [
-            ("DTB", dtb_range, Entry::ro_kernel_data(), PageSize::Page4K),
-            ("Kernel Text", text_range, Entry::ro_kernel_text(), PageSize::Page4K),
-            ("Kernel Data", data_range, Entry::rw_kernel_data(), PageSize::Page4K),
-            ("Kernel BSS", bss_range, Entry::rw_kernel_data(), PageSize::Page4K),
-            ("MMIO", mmio_range, Entry::ro_kernel_device(), PageSize::Page2M),
-        ];
-        map.sort_by_key(|a| a.1.start());
+        let mut map = FixedVec::<(&str, PhysRange, Entry, PageSize), 16>::new();
+        let mut add = |name, range, flags, page_size| {
+            if map.push((name, range, flags, page_size)).is_err() {
+                panic!("Too many kernel mappings, can't add {name}");
+            }
+        };
+        add("DTB", dtb_range, Entry::ro_kernel_data(), PageSize::Page4K);
+        add("Kernel Text", text_range, Entry::ro_kernel_text(), PageSize::Page4K);
+        add("Kernel Data", data_range, Entry::rw_kernel_data(), PageSize::Page4K);
+        add("Kernel BSS", bss_range, Entry::rw_kernel_data(), PageSize::Page4K);
+        add("MMIO", mmio_range, Entry::ro_kernel_device(), PageSize::Page2M);
+        map.as_mut_slice().sort_by_key(|a| a.1.start());
         map
     };
 
@@ -516,12 +794,22 @@ pub unsafe fn init(kpage_table: &mut PageTable, dtb_range: PhysRange, available_
         );
     }
 
-    if let Err(err) = pagealloc::free_unused_ranges(&available_mem, custom_map.map(|m| m.1).iter())
+    if let Err(err) = pagealloc::free_unused_ranges(&available_mem, custom_map.iter().map(|m| &m.1))
     {
         panic!("Couldn't mark unused pages as free: err: {:?}", err);
     }
 }
 
+/// Free a user address space, such as one from `PageTable::clone_user`: its
+/// pages, its tables and the root table itself.  It mustn't be in use.  Its
+/// recursive entry, if it has one, only refers to the root, so isn't
+/// followed.
+#[allow(dead_code)]
+pub fn free_user(root: &'static mut PageTable) {
+    root.free_user_entries(GRANULE.first_level());
+    let _ = pagealloc::decref(from_ptr_to_physaddr(root));
+}
+
 /// Return the root kernel page table physical address
 fn ttbr1_el1() -> u64 {
     #[cfg(not(test))]
@@ -567,43 +855,336 @@ pub unsafe fn invalidate_all_tlb_entries() {
     }
 }
 
+/// Invalidate any TLB entries for the page containing va, across the inner
+/// shareable domain.
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_va(va: usize) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "tlbi vae1is, {page}", // invalidate TLB entries for page
+            "dsb ish",             // ensure write has completed
+            "isb",                 // synchronize context
+            page = in(reg) tlbi_va_operand(va));
+    }
+}
+
+/// The operand for a TLBI by VA: bits 55:12 of va in bits 43:0.  The upper
+/// bits hold the ASID, so a kernel va's high bits mustn't spill into them.
+fn tlbi_va_operand(va: usize) -> usize {
+    (va >> 12) & ((1 << 44) - 1)
+}
+
 /// Return the root kernel page table
 pub fn kernel_root() -> &'static mut PageTable {
     unsafe { &mut *physaddr_as_ptr_mut::<PageTable>(PhysAddr::new(ttbr1_el1())) }
 }
 
+/// Map a device's registers into the kernel address space as device memory,
+/// returning the virtual range they can be accessed through.  Must only be
+/// called after switching to the kernel page table.
+#[allow(dead_code)]
+pub fn map_device_register(range: &PhysRange) -> Result<VirtRange, PageTableError> {
+    let mut mapped = false;
+    for pa in range.step_by_rounded(PAGE_SIZE_4K) {
+        map_virtpage(kernel_root(), "device", Entry::ro_kernel_device(), pa, physaddr_as_virt(pa))?;
+        mapped = true;
+    }
+    if !mapped {
+        return Err(PageTableError::PhysRangeIsZero);
+    }
+    Ok(VirtRange::with_len(physaddr_as_virt(range.start()), range.size()))
+}
+
+/// Map the existing 4KiB physical page at pa to va, such as a device's
+/// registers or a frame that's already been reserved, rather than allocating
+/// a new page.  Any existing mapping for va is replaced.  debug_name is only
+/// used for logging.
+pub fn map_virtpage(
+    page_table: &mut PageTable,
+    debug_name: &str,
+    entry: Entry,
+    pa: PhysAddr,
+    va: usize,
+) -> Result<(), PageTableError> {
+    if pa.addr() == 0 {
+        return Err(PageTableError::PhysAddrIsZero);
+    }
+    if !pa.is_aligned(PAGE_SIZE_4K as u64) {
+        return Err(PageTableError::PhysAddrMisaligned);
+    }
+    port::debug!("map_virtpage {debug_name}: {va:#018x} -> {:#018x}", pa.addr());
+    page_table.map_to(entry.with_phys_addr(pa), va, PageSize::Page4K)
+}
+
+/// Most reg blocks map_device_node will map for a device
+pub const MAX_DEVICE_REGS: usize = 8;
+
+/// Most runs of contiguous pages map_device_node can map and track.  Pages
+/// that are already mapped are skipped, which can split a reg block's pages
+/// into more than one run.
+const MAX_DEVICE_RUNS: usize = 2 * MAX_DEVICE_REGS;
+
+/// Map all of a devicetree node's reg blocks into the kernel address space as
+/// device memory, using page_size pages, returning their virtual ranges in
+/// order.  Pages that are already mapped, such as those in the boot-time MMIO
+/// block, are left as they are.  If any block can't be mapped, the pages this
+/// call mapped are unmapped again.  name is only used for logging.  Must only
+/// be called after switching to the kernel page table.
+pub fn map_device_node(
+    name: &str,
+    dt: &DeviceTree,
+    node: Node,
+    page_size: PageSize,
+) -> Result<FixedVec<VirtRange, MAX_DEVICE_REGS>, PageTableError> {
+    let mut ranges = FixedVec::<PhysRange, MAX_DEVICE_REGS>::new();
+    let mut new_runs = FixedVec::<PhysRange, MAX_DEVICE_RUNS>::new();
+    if let Err(err) = map_device_regs(name, dt, node, page_size, &mut ranges, &mut new_runs) {
+        for run in new_runs.iter() {
+            for pa in run.step_by_rounded(page_size.size()) {
+                kernel_root().unmap(physaddr_as_virt(pa), page_size);
+            }
+        }
+        return Err(err);
+    }
+
+    let mut vranges = FixedVec::new();
+    for range in ranges.iter() {
+        let _ = vranges.push(VirtRange::with_len(physaddr_as_virt(range.start()), range.size()));
+    }
+    Ok(vranges)
+}
+
+/// Map the node's reg blocks for map_device_node, adding each block to ranges
+/// and each page this maps to new_runs.
+fn map_device_regs(
+    name: &str,
+    dt: &DeviceTree,
+    node: Node,
+    page_size: PageSize,
+    ranges: &mut FixedVec<PhysRange, MAX_DEVICE_REGS>,
+    new_runs: &mut FixedVec<PhysRange, MAX_DEVICE_RUNS>,
+) -> Result<(), PageTableError> {
+    for reg in dt.property_translated_reg_iter(node).filter_map(|reg| reg.regblock()) {
+        let len = reg.len.ok_or(PageTableError::RegBlockHasNoSize)?;
+        let range = PhysRange::with_len(reg.addr, len as usize);
+        port::debug!("map_device_node {name}: {range}");
+        let pages = range.step_by_rounded(page_size.size());
+        ranges.push(range).map_err(|_| PageTableError::TooManyRegBlocks)?;
+
+        for pa in pages {
+            let va = physaddr_as_virt(pa);
+            if kernel_root().is_mapped(va) {
+                continue;
+            }
+            // Track the page before mapping it, so a partly made mapping is
+            // undone too
+            let end = PhysAddr::new(pa.addr() + page_size.size() as u64);
+            match new_runs.as_mut_slice().last_mut() {
+                Some(run) if run.end() == pa => *run = PhysRange::new(run.start(), end),
+                _ => new_runs
+                    .push(PhysRange::new(pa, end))
+                    .map_err(|_| PageTableError::TooManyDeviceRuns)?,
+            }
+            kernel_root().map_to(Entry::ro_kernel_device().with_phys_addr(pa), va, page_size)?;
+        }
+    }
+    Ok(())
+}
+
+/// Kernel tests that need the page table internals
+#[cfg(feature = "qemu_test")]
+pub mod ktest {
+    use super::*;
+
+    /// The leaf entry for va, walking the tables by physical address
+    fn leaf(root: &Table, va: usize) -> Entry {
+        let mut table = root;
+        let mut level = GRANULE.first_level();
+        loop {
+            let entry = table.entries[va_index(va, level, GRANULE)];
+            assert!(entry.valid());
+            match level.next() {
+                Some(next) => {
+                    table = Table::child(entry);
+                    level = next;
+                }
+                None => return entry,
+            }
+        }
+    }
+
+    /// Build a user address space with a single page, and check a clone of
+    /// it has its own tables and copy of the page.
+    pub fn clone_user() {
+        let (used_before, _) = pagealloc::usage_bytes();
+        let va = 0x40_1000;
+        let root = Table::alloc_pagetable().unwrap();
+        let mut table = &mut *root;
+        let mut level = GRANULE.first_level();
+        while let Some(next) = level.next() {
+            let child = Table::alloc_pagetable().unwrap();
+            table.entries[va_index(va, level, GRANULE)] = Entry::rw_kernel_data()
+                .with_phys_addr(from_ptr_to_physaddr(child))
+                .with_page_or_table(true);
+            table = child;
+            level = next;
+        }
+        let page = pagealloc::allocate().unwrap();
+        page.clear();
+        page.data()[..5].copy_from_slice(b"hello");
+        table.entries[va_index(va, level, GRANULE)] = Entry::rw_kernel_data()
+            .with_phys_addr(from_ptr_to_physaddr(page))
+            .with_page_or_table(true);
+
+        // Give the original a recursive entry, which mustn't be followed
+        let root_pa = from_ptr_to_physaddr(root);
+        root.entries[RECURSIVE_INDEX] =
+            Entry::rw_kernel_data().with_phys_addr(root_pa).with_page_or_table(true);
+
+        let clone = root.clone_user().unwrap();
+        let clone_recursive_pa = clone.entries[RECURSIVE_INDEX].phys_page_addr();
+        assert_eq!(clone_recursive_pa, from_ptr_to_physaddr(clone));
+        let original_pa = leaf(root, va).phys_page_addr();
+        let cloned_pa = leaf(clone, va).phys_page_addr();
+        assert_ne!(cloned_pa, original_pa);
+        let cloned_page = unsafe { &mut *physaddr_as_ptr_mut::<Page4K>(cloned_pa) };
+        assert_eq!(&cloned_page.data()[..5], b"hello");
+        assert!(cloned_page.data()[5..].iter().all(|&b| b == 0));
+
+        free_user(clone);
+        free_user(root);
+        assert_eq!(pagealloc::usage_bytes().0, used_before);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn can_break_down_va() {
-        assert_eq!(va_indices(0xffff8000049fd000), (256, 0, 36, 509));
+        assert_eq!(va_indices(0xffff8000049fd000, Granule::Size4K), (256, 0, 36, 509));
     }
 
     #[test]
     fn test_to_use_for_debugging_vaddrs() {
-        assert_eq!(va_indices(0xffff8000049fd000), (256, 0, 36, 509));
+        assert_eq!(va_indices(0xffff8000049fd000, Granule::Size4K), (256, 0, 36, 509));
     }
 
     #[test]
     fn test_recursive_table_addr() {
-        assert_eq!(va_indices(0xffff800008000000), (256, 0, 64, 0));
+        assert_eq!(va_indices(0xffff800008000000, Granule::Size4K), (256, 0, 64, 0));
         assert_eq!(
-            va_indices(recursive_table_addr(0xffff800008000000, Level::Level0)),
+            va_indices(
+                recursive_table_addr(0xffff800008000000, Level::Level0, Granule::Size4K),
+                Granule::Size4K
+            ),
             (511, 511, 511, 511)
         );
         assert_eq!(
-            va_indices(recursive_table_addr(0xffff800008000000, Level::Level1)),
+            va_indices(
+                recursive_table_addr(0xffff800008000000, Level::Level1, Granule::Size4K),
+                Granule::Size4K
+            ),
             (511, 511, 511, 256)
         );
         assert_eq!(
-            va_indices(recursive_table_addr(0xffff800008000000, Level::Level2)),
+            va_indices(
+                recursive_table_addr(0xffff800008000000, Level::Level2, Granule::Size4K),
+                Granule::Size4K
+            ),
             (511, 511, 256, 0)
         );
         assert_eq!(
-            va_indices(recursive_table_addr(0xffff800008000000, Level::Level3)),
+            va_indices(
+                recursive_table_addr(0xffff800008000000, Level::Level3, Granule::Size4K),
+                Granule::Size4K
+            ),
             (511, 256, 0, 64)
         );
     }
+
+    #[test]
+    fn can_break_down_va_16k() {
+        let g = Granule::Size16K;
+        assert_eq!(g.root_entries(), 2);
+        // Each index is 11 bits: [47], [46-36], [35-25], [24-14]
+        let va = 0xffff_8000_0000_0000 | (5 << 36) | (7 << 25) | (9 << 14) | 0x3fff;
+        assert_eq!(va_indices(va, g), (1, 5, 7, 9));
+        assert_eq!(va_indices(0xffff_7fff_ffff_ffff, g), (0, 0x7ff, 0x7ff, 0x7ff));
+        assert_eq!(va_indices(0x0000_0000_0000_3fff, g), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_recursive_table_addr_16k() {
+        let g = Granule::Size16K;
+        let va = 0xffff_8000_0000_0000 | (5 << 36) | (7 << 25) | (9 << 14);
+        assert_eq!(va_indices(recursive_table_addr(va, Level::Level0, g), g), (1, 1, 1, 1));
+        assert_eq!(va_indices(recursive_table_addr(va, Level::Level1, g), g), (1, 1, 1, 1));
+        assert_eq!(va_indices(recursive_table_addr(va, Level::Level2, g), g), (1, 1, 1, 5));
+        assert_eq!(va_indices(recursive_table_addr(va, Level::Level3, g), g), (1, 1, 5, 7));
+    }
+
+    #[test]
+    fn can_break_down_va_64k() {
+        let g = Granule::Size64K;
+        assert_eq!(g.root_entries(), 64);
+        // [47-42], [41-29], [28-16]
+        let va = 0xffff_8000_0000_0000 | (3 << 42) | (5 << 29) | (7 << 16) | 0xffff;
+        assert_eq!(va_indices(va, g), (0, 35, 5, 7));
+        assert_eq!(va_indices(recursive_table_addr(va, Level::Level3, g), g), (0, 63, 35, 5));
+    }
+
+    #[test]
+    fn level_next() {
+        assert_eq!(Level::Level0.next(), Some(Level::Level1));
+        assert_eq!(Level::Level2.next(), Some(Level::Level3));
+        assert_eq!(Level::Level3.next(), None);
+    }
+
+    #[test]
+    fn map_virtpage_rejects_bad_phys_addrs() {
+        // The checks come before any table is touched, so this needn't be
+        // the kernel root
+        let mut table = PageTable::empty();
+        let entry = Entry::ro_kernel_device();
+        let va = 0xffff_8000_0010_0000;
+        assert!(matches!(
+            map_virtpage(&mut table, "zero", entry, PhysAddr::new(0), va),
+            Err(PageTableError::PhysAddrIsZero)
+        ));
+        assert!(matches!(
+            map_virtpage(&mut table, "misaligned", entry, PhysAddr::new(0x1234), va),
+            Err(PageTableError::PhysAddrMisaligned)
+        ));
+        assert!(table.entries.iter().all(|entry| !entry.valid()));
+    }
+
+    #[test]
+    fn tlbi_va_operand_drops_high_bits() {
+        assert_eq!(tlbi_va_operand(0xffff8000049fd000), 0xff8000049fd);
+        assert_eq!(tlbi_va_operand(0x0000_ffff_ffff_f000), 0xf_ffff_ffff);
+    }
+
+    #[test]
+    fn test_recursive_child_table_addr() {
+        assert_eq!(
+            recursive_child_table_addr(0xffff_ffff_ffff_f000, 256, Granule::Size4K),
+            recursive_table_addr(0xffff800008000000, Level::Level1, Granule::Size4K)
+        );
+
+        // Walking down from the root gives the same addresses as working out
+        // each level's table directly, for every granule
+        let va = 0xffff_8000_0000_0000 | (5 << 36) | (7 << 29) | (9 << 25) | (3 << 16);
+        for g in [Granule::Size4K, Granule::Size16K, Granule::Size64K] {
+            let mut level = g.first_level();
+            let mut table_va = recursive_table_addr(va, level, g);
+            while let Some(next) = level.next() {
+                table_va = recursive_child_table_addr(table_va, va_index(va, level, g), g);
+                assert_eq!(table_va, recursive_table_addr(va, next, g), "{g:?} {next:?}");
+                level = next;
+            }
+        }
+    }
 }
@@ -0,0 +1,75 @@
+//! The BCM2835 power management (PM) watchdog, which the Raspberry Pi uses to
+//! reset the SoC.  Once started, the watchdog counts down and resets the
+//! board when it reaches zero.
+//! https://github.com/raspberrypi/linux/blob/rpi-6.6.y/drivers/watchdog/bcm2835_wdt.c
+
+use crate::io::{read_reg, write_reg};
+use crate::kmem::physaddr_as_virt;
+use crate::registers::rpi_mmio;
+use port::mem::VirtRange;
+
+/// Offset of the PM block from the peripherals base
+const PM_OFFSET: usize = 0x10_0000;
+
+const PM_RSTC: usize = 0x1c; // Reset control register
+const PM_WDOG: usize = 0x24; // Watchdog timer register
+
+/// Every write to a PM register must include the password
+const PM_PASSWORD: u32 = 0x5a00_0000;
+const PM_RSTC_WRCFG_CLR: u32 = 0xffff_ffcf;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x0000_0020;
+
+/// The watchdog counts down in ticks of 1/65536 seconds, up to about 16s
+const PM_WDOG_TIME_SET: u32 = 0x000f_ffff;
+const TICKS_PER_SEC: u64 = 1 << 16;
+
+fn pm_range() -> VirtRange {
+    let mmio = physaddr_as_virt(rpi_mmio().expect("mmio base detect failed").start());
+    VirtRange::with_len(mmio + PM_OFFSET, PM_WDOG + 4)
+}
+
+/// Convert ms to watchdog ticks, clamped to the longest timeout it supports
+fn ms_to_ticks(ms: u32) -> u32 {
+    let ticks = ms as u64 * TICKS_PER_SEC / 1000;
+    ticks.min(PM_WDOG_TIME_SET as u64) as u32
+}
+
+/// Start the watchdog so it resets the board after ticks
+fn start(ticks: u32) {
+    let pm = pm_range();
+    write_reg(&pm, PM_WDOG, PM_PASSWORD | (ticks & PM_WDOG_TIME_SET));
+    let rstc = read_reg(&pm, PM_RSTC) & PM_RSTC_WRCFG_CLR;
+    write_reg(&pm, PM_RSTC, PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+}
+
+/// Reset the board after ms milliseconds (up to about 16s).  This returns
+/// straight away, so the caller can carry on until the reset.
+#[allow(dead_code)]
+pub fn reboot_timeout_ms(ms: u32) {
+    start(ms_to_ticks(ms));
+}
+
+/// Reset the board now.  Kernel tests exit QEMU instead.
+#[allow(dead_code)]
+pub fn reboot() -> ! {
+    // Short enough to be immediate
+    start(10);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_ticks() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(1000), 65536);
+        assert_eq!(ms_to_ticks(250), 16384);
+        assert_eq!(ms_to_ticks(15_000), 983_040);
+        assert_eq!(ms_to_ticks(20_000), PM_WDOG_TIME_SET);
+        assert_eq!(ms_to_ticks(u32::MAX), PM_WDOG_TIME_SET);
+    }
+}
@@ -7,6 +7,7 @@
 
 #![allow(clippy::too_long_first_doc_paragraph)]
 
+use crate::maths::log2_floor;
 use alloc::alloc::{AllocError, Allocator, Layout};
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicUsize, Ordering};
@@ -101,6 +102,26 @@ impl BumpAlloc {
         let block = unsafe { Block::new_from_raw_parts(ptr, size) };
         Some((prefix, block))
     }
+
+    /// Returns the number of bytes of the arena allocated so
+    /// far, including any padding for alignment.
+    pub fn used_bytes(&self) -> usize {
+        self.cursor.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes of the arena still
+    /// available for allocation.
+    pub fn remaining_bytes(&self) -> usize {
+        self.arena.len() - self.used_bytes()
+    }
+
+    /// Frees everything allocated from the arena at once, by
+    /// moving the cursor back to the start.  Blocks allocated
+    /// before the reset must no longer be used, as they will be
+    /// handed out again.
+    pub fn reset(&self) {
+        self.cursor.store(0, Ordering::Relaxed);
+    }
 }
 
 /// BumpAlloc<T> implements the allocator interface, and is
@@ -108,7 +129,7 @@ impl BumpAlloc {
 /// unimplemented and will panic.
 unsafe impl Allocator for BumpAlloc {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let (_, block) = self.try_alloc(layout.size(), layout.align()).ok_or(AllocError)?;
+        let (_, block) = self.try_alloc(layout.align(), layout.size()).ok_or(AllocError)?;
         Ok(NonNull::slice_from_raw_parts(block.ptr, block.len()))
     }
 
@@ -179,6 +200,58 @@ impl Header {
     }
 }
 
+/// A snapshot of the allocation statistics of a QuickFit.
+/// Byte counts are of the blocks handed out, which may be
+/// larger than requested.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuickFitStats {
+    pub total_allocs: usize,
+    pub total_frees: usize,
+    pub current_alloc_bytes: usize,
+    pub peak_alloc_bytes: usize,
+}
+
+/// Running allocation statistics.  Allocations the allocator
+/// makes for itself, e.g. for misc block headers, and frees
+/// of tail prefixes into the quick lists aren't counted.
+struct Counters {
+    total_allocs: AtomicUsize,
+    total_frees: AtomicUsize,
+    current_alloc_bytes: AtomicUsize,
+    peak_alloc_bytes: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            total_allocs: AtomicUsize::new(0),
+            total_frees: AtomicUsize::new(0),
+            current_alloc_bytes: AtomicUsize::new(0),
+            peak_alloc_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn record_alloc(&self, size: usize) {
+        self.total_allocs.fetch_add(1, Ordering::Relaxed);
+        let current = self.current_alloc_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_alloc_bytes.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn record_free(&self, size: usize) {
+        self.total_frees.fetch_add(1, Ordering::Relaxed);
+        self.current_alloc_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> QuickFitStats {
+        QuickFitStats {
+            total_allocs: self.total_allocs.load(Ordering::Relaxed),
+            total_frees: self.total_frees.load(Ordering::Relaxed),
+            current_alloc_bytes: self.current_alloc_bytes.load(Ordering::Relaxed),
+            peak_alloc_bytes: self.peak_alloc_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// The QuickFit allocator itself.  The allocator takes
 /// ownership of a bump allocator for the tail, and contains a
 /// set of lists for the quick blocks, as well as a misc list
@@ -192,6 +265,7 @@ pub struct QuickFit {
     qlists: [Option<NonNull<Header>>; NUM_QLISTS],
     misc: Option<NonNull<Header>>,
     allocated_misc: [Option<NonNull<Header>>; NUM_HASH_BUCKETS],
+    counters: Counters,
 }
 
 impl QuickFit {
@@ -200,18 +274,43 @@ impl QuickFit {
         let qlists = [None; NUM_QLISTS];
         let misc = None;
         let allocated_misc = [None; NUM_HASH_BUCKETS];
-        QuickFit { tail, qlists, misc, allocated_misc }
+        let counters = Counters::new();
+        QuickFit { tail, qlists, misc, allocated_misc, counters }
+    }
+
+    /// Returns a snapshot of the allocation statistics.
+    pub fn stats(&self) -> QuickFitStats {
+        self.counters.snapshot()
     }
 
     /// Allocates a block of memory of the requested size and
     /// alignment.  Returns a pointer to such a block, or nil if
     /// the block cannot be allocated.
     pub fn malloc(&mut self, layout: Layout) -> *mut u8 {
+        let p = self.alloc(layout);
+        if !p.is_null() {
+            self.counters.record_alloc(Self::adjust(layout).0);
+        }
+        p
+    }
+
+    /// Allocates a block as for `malloc`, without counting it
+    /// in the statistics.
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
         let (size, align) = Self::adjust(layout);
         let p = self.alloc_quick(size, align);
         p.or_else(|| self.alloc_tail(size, align)).map(|p| p.as_ptr()).unwrap_or(ptr::null_mut())
     }
 
+    /// Like `malloc`, but the block is zeroed.
+    pub fn malloc_zeroed(&mut self, layout: Layout) -> *mut u8 {
+        let p = self.malloc(layout);
+        if !p.is_null() {
+            unsafe { ptr::write_bytes(p, 0u8, layout.size()) };
+        }
+        p
+    }
+
     /// Adjusts the given layout so that blocks allocated from
     /// one of the quick lists are appropriately sized and
     /// aligned.  Otherwise, returns the original size and
@@ -233,7 +332,7 @@ impl QuickFit {
     /// misc list.
     fn alloc_quick(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
         if size <= MAX_QUICK_SIZE && align == size {
-            let k: usize = size.ilog2() as usize - ALLOC_UNIT_SHIFT;
+            let k: usize = log2_floor(size) as usize - ALLOC_UNIT_SHIFT;
             let (node, list) = Self::head(self.qlists[k].take());
             self.qlists[k] = list;
             node.map(|header| unsafe { header.as_ref() }.addr)
@@ -263,7 +362,7 @@ impl QuickFit {
     /// to the minimum allocation unit into the quick lists
     /// until it is.
     fn alloc_tail(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
-        let (prefix, block) = { self.tail.try_alloc(size, align)? };
+        let (prefix, block) = { self.tail.try_alloc(align, size)? };
         self.free_prefix(prefix);
         Some(block.ptr)
     }
@@ -295,7 +394,7 @@ impl QuickFit {
             let size = 1 << (k + ALLOC_UNIT_SHIFT);
             if prefix.len() >= size && ptr.align_offset(size) == 0 {
                 let (_, rest) = prefix.split_at_mut(size)?;
-                self.free(ptr, Layout::from_size_align(size, size).unwrap());
+                self.release(ptr, Layout::from_size_align(size, size).unwrap());
                 return (rest.len() >= MIN_ALLOC_SIZE).then_some(rest);
             }
         }
@@ -354,12 +453,21 @@ impl QuickFit {
     /// quick lists, it is; otherwise, it is treated as a misc
     /// block and freed there.
     pub fn free(&mut self, block: *mut u8, layout: Layout) {
+        if !block.is_null() {
+            self.counters.record_free(Self::adjust(layout).0);
+        }
+        self.release(block, layout);
+    }
+
+    /// Frees a block as for `free`, without counting it in the
+    /// statistics.
+    fn release(&mut self, block: *mut u8, layout: Layout) {
         let Some(block) = NonNull::new(block) else {
             return;
         };
         let (size, align) = Self::adjust(layout);
         if size <= MAX_QUICK_SIZE && align == size {
-            let k: usize = size.ilog2() as usize - ALLOC_UNIT_SHIFT;
+            let k: usize = log2_floor(size) as usize - ALLOC_UNIT_SHIFT;
             let header = Header::new(block, size, align, self.qlists[k].take());
             assert_eq!(block.align_offset(mem::align_of::<Header>()), 0);
             let p = block.cast::<Header>();
@@ -390,7 +498,7 @@ impl QuickFit {
         let mut header = self
             .unlink_allocated_misc(block)
             .or_else(|| {
-                let hblock = self.malloc(Layout::new::<Header>()).cast::<Header>();
+                let hblock = self.alloc(Layout::new::<Header>()).cast::<Header>();
                 let hblock = hblock
                     .is_null()
                     .then(|| {
@@ -476,9 +584,121 @@ impl QuickFit {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Zeroed, page aligned memory for the allocators under test.  It's
+    /// leaked, so it outlives the allocator.
+    fn test_block(size: usize) -> Block {
+        let heap =
+            unsafe { std::alloc::alloc_zeroed(Layout::from_size_align(size, 4096).unwrap()) };
+        assert!(!heap.is_null());
+        unsafe { Block::new_from_raw_parts(heap, size) }
+    }
+
+    /// A QuickFit over a new heap of size bytes
+    fn test_quickfit(size: usize) -> QuickFit {
+        QuickFit::new(BumpAlloc::new(test_block(size)))
+    }
+
+    #[test]
+    fn bump_alloc_usage() {
+        let bump = BumpAlloc::new(test_block(256));
+        assert_eq!((bump.used_bytes(), bump.remaining_bytes()), (0, 256));
+
+        bump.try_alloc(1, 10).unwrap();
+        assert_eq!((bump.used_bytes(), bump.remaining_bytes()), (10, 246));
+
+        // Alignment padding counts as used
+        let (prefix, _) = bump.try_alloc(32, 32).unwrap();
+        assert_eq!(prefix.len(), 22);
+        assert_eq!((bump.used_bytes(), bump.remaining_bytes()), (64, 192));
+
+        assert!(bump.try_alloc(1, 193).is_none());
+        assert_eq!(bump.used_bytes(), 64);
+
+        bump.reset();
+        assert_eq!((bump.used_bytes(), bump.remaining_bytes()), (0, 256));
+        assert!(bump.try_alloc(1, 256).is_some());
+        assert_eq!(bump.remaining_bytes(), 0);
+    }
+
+    #[test]
+    fn bump_alloc_allocate_honours_layout() {
+        let bump = BumpAlloc::new(test_block(256));
+        bump.try_alloc(1, 1).unwrap();
+
+        // Both the size and alignment of the layout must be used, and 48
+        // isn't a valid alignment
+        let block = bump.allocate(Layout::from_size_align(48, 16).unwrap()).unwrap();
+        assert_eq!(block.len(), 48);
+        assert_eq!(block.cast::<u8>().as_ptr().addr() % 16, 0);
+        assert_eq!(bump.used_bytes(), 64);
+    }
+
+    #[test]
+    fn quickfit_large_blocks_dont_overlap() {
+        let mut quick = test_quickfit(65536);
+
+        // Too big for the quick lists, so these come from the tail with the
+        // layout's own size and alignment
+        let layout = Layout::from_size_align(20000, 64).unwrap();
+        let p1 = quick.malloc(layout);
+        let p2 = quick.malloc(layout);
+        assert!(!p1.is_null() && !p2.is_null());
+        assert_eq!(p1.addr() % 64, 0);
+        assert!(p2.addr() >= p1.addr() + 20000);
+    }
+
+    #[test]
+    fn quickfit_malloc_zeroed() {
+        let mut quick = test_quickfit(4096);
+
+        // Dirty a block, free it, then get it back zeroed from the quick list
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let p = quick.malloc(layout);
+        assert!(!p.is_null());
+        unsafe { ptr::write_bytes(p, 0xaa, layout.size()) };
+        quick.free(p, layout);
+
+        let z = quick.malloc_zeroed(layout);
+        assert_eq!(z, p);
+        let block = unsafe { core::slice::from_raw_parts(z, layout.size()) };
+        assert!(block.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn quickfit_stats() {
+        let mut quick = test_quickfit(8192);
+
+        // Sizes are rounded up to the quick list block sizes
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(1024, 8).unwrap();
+        let a = quick.malloc(small);
+        let b = quick.malloc(large);
+        let stats = quick.stats();
+        assert_eq!((stats.total_allocs, stats.total_frees), (2, 0));
+        assert_eq!(stats.current_alloc_bytes, 128 + 1024);
+
+        quick.free(b, large);
+        quick.free(core::ptr::null_mut(), large);
+        let stats = quick.stats();
+        assert_eq!((stats.total_allocs, stats.total_frees), (2, 1));
+        assert_eq!(stats.current_alloc_bytes, 128);
+        assert_eq!(stats.peak_alloc_bytes, 128 + 1024);
+
+        quick.free(a, small);
+        assert_eq!(quick.stats().current_alloc_bytes, 0);
+    }
+}
+
+#[cfg(not(test))]
+pub use global::heap_stats;
+
 #[cfg(not(test))]
 mod global {
-    use super::{Block, BumpAlloc, QuickFit};
+    use super::{Block, BumpAlloc, QuickFit, QuickFitStats};
     use alloc::alloc::{GlobalAlloc, Layout};
     use core::mem;
     use core::ptr;
@@ -517,6 +737,9 @@ mod global {
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
             self.with_allocator(|quick| quick.malloc(layout))
         }
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            self.with_allocator(|quick| quick.malloc_zeroed(layout))
+        }
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
             self.with_allocator(|quick| quick.free(ptr, layout));
         }
@@ -525,6 +748,12 @@ mod global {
         }
     }
 
+    /// Returns a snapshot of the global heap's allocation
+    /// statistics.
+    pub fn heap_stats() -> QuickFitStats {
+        GLOBAL_ALLOCATOR.with_allocator(|quick| quick.stats())
+    }
+
     #[global_allocator]
     static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(AtomicPtr::new({
         static mut HEAP: GlobalHeap = GlobalHeap::new();
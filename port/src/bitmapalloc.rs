@@ -3,8 +3,13 @@
 /// Benefits of the current implementation:
 ///  - Doesn't require any allocations, so can be used without fear while
 ///    manipulating the page tables.
+///
 /// Downsides:
 ///  - Can't be dynamically resized.
+///
+/// `RefCountedPageAlloc` wraps the allocator with a reference count per page,
+/// so a page can be shared and is only freed once the last reference is
+/// dropped.
 use core::fmt;
 
 use crate::mem::{PhysAddr, PhysRange};
@@ -47,6 +52,7 @@ pub enum BitmapPageAllocError {
     MisalignedAddr,
     OutOfSpace,
     NotAllocated,
+    TooManyRefs,
 }
 
 /// Allocator where each page is represented by a single bit.
@@ -58,6 +64,7 @@ pub struct BitmapPageAlloc<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: us
     alloc_page_size: usize,    // Size of pages represented by single bit
     end: PhysAddr,             // Upper bound of physical memory
     next_pa_to_scan: PhysAddr, // PhysAddr from which to start scanning for next allocation
+    zero_on_free: Option<fn(PhysAddr)>, // Clears a page's contents as it's freed
 }
 
 impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
@@ -70,9 +77,19 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
             alloc_page_size,
             end,
             next_pa_to_scan: PhysAddr::new(0),
+            zero_on_free: None,
         }
     }
 
+    /// Clear pages as they're deallocated, so their contents can't leak into
+    /// whoever allocates them next.  The allocator can't reach physical memory
+    /// itself, so `zero_page` must clear the page at the given address.
+    /// Pages freed in bulk by `mark_free` or `free_unused_ranges` aren't
+    /// cleared, so this is only worth enabling once the allocator is set up.
+    pub fn set_zero_on_free(&mut self, zero_page: Option<fn(PhysAddr)>) {
+        self.zero_on_free = zero_page;
+    }
+
     /// Returns number of physical bytes a single bitmap can cover.
     const fn bytes_per_bitmap_byte(&self) -> usize {
         8 * self.alloc_page_size
@@ -84,7 +101,7 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
     }
 
     /// Returns number of physical bytes covered by all bitmaps.
-    const fn max_bytes(&self) -> usize {
+    pub const fn max_bytes(&self) -> usize {
         NUM_BITMAPS * self.bytes_per_bitmap()
     }
 
@@ -102,23 +119,26 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
 
     /// Free unused pages in mem that aren't covered by the memory map.  Assumes
     /// that custom_map is sorted and that available_mem can be used to set the
-    /// upper bound of the allocator.
+    /// upper bound of the allocator.  Memory in several regions can be freed
+    /// by calling this once per region, in ascending order.
     pub fn free_unused_ranges<'a>(
         &mut self,
         available_mem: &PhysRange,
         used_ranges: impl Iterator<Item = &'a PhysRange>,
     ) -> Result<(), BitmapPageAllocError> {
+        // The ranges freed are all within available_mem, which may be past
+        // the end set by an earlier region
         let mut next_start = available_mem.start();
         for range in used_ranges {
             if next_start < range.0.start {
-                self.mark_free(&PhysRange::new(next_start, range.0.start))?;
+                self.mark_range(&PhysRange::new(next_start, range.0.start), false, false)?;
             }
             if next_start < range.0.end {
                 next_start = range.0.end;
             }
         }
         if next_start < available_mem.end() {
-            self.mark_free(&PhysRange::new(next_start, available_mem.end()))?;
+            self.mark_range(&PhysRange::new(next_start, available_mem.end()), false, false)?;
         }
 
         self.end = available_mem.0.end;
@@ -154,6 +174,33 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         }
     }
 
+    /// Try to allocate num_pages physically contiguous pages, returning the
+    /// range they cover.  Takes the first large enough run of free pages,
+    /// checking page by page, so is slower than `allocate`.
+    pub fn allocate_contiguous(
+        &mut self,
+        num_pages: usize,
+    ) -> Result<PhysRange, BitmapPageAllocError> {
+        debug_assert!(num_pages > 0);
+        let bits_per_bitmap = BITMAP_SIZE_BYTES * 8;
+        let total_pages = self.end.addr() as usize / self.alloc_page_size;
+
+        let mut run_start = 0;
+        for page in 0..total_pages {
+            if self.bitmaps[page / bits_per_bitmap].is_set(page % bits_per_bitmap) {
+                run_start = page + 1;
+            } else if page + 1 - run_start == num_pages {
+                let range = PhysRange::with_len(
+                    (run_start * self.alloc_page_size) as u64,
+                    num_pages * self.alloc_page_size,
+                );
+                self.mark_allocated(&range)?;
+                return Ok(range);
+            }
+        }
+        Err(BitmapPageAllocError::OutOfSpace)
+    }
+
     /// Deallocate the page corresponding to the given PhysAddr.
     pub fn deallocate(&mut self, pa: PhysAddr) -> Result<(), BitmapPageAllocError> {
         if pa > self.end {
@@ -166,7 +213,10 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         if !bitmap.is_set(8 * byte_idx + bit_idx) {
             return Err(BitmapPageAllocError::NotAllocated);
         }
-        bitmap.set(bit_idx, false);
+        if let Some(zero_page) = self.zero_on_free {
+            zero_page(pa);
+        }
+        bitmap.set(8 * byte_idx + bit_idx, false);
 
         self.next_pa_to_scan = pa; // Next allocation will reuse this
 
@@ -322,9 +372,99 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> fmt::Debug
     }
 }
 
+/// Bitmap page allocator with a reference count for each page.  The counts
+/// are kept apart from the allocator, so a static allocator's counts can be
+/// in a zeroed static, rather than taking up space in the kernel image.
+pub struct RefCountedPageAlloc<'r, const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> {
+    alloc: BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>,
+    refs: &'r mut [u16],
+}
+
+impl<'r, const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
+    RefCountedPageAlloc<'r, NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    /// refs must be zeroed, and large enough to hold a count for every page
+    /// the bitmaps can represent.
+    pub const fn new_all_allocated(alloc_page_size: usize, refs: &'r mut [u16]) -> Self {
+        assert!(refs.len() >= NUM_BITMAPS * BITMAP_SIZE_BYTES * 8);
+        Self { alloc: BitmapPageAlloc::new_all_allocated(alloc_page_size), refs }
+    }
+
+    /// Clear pages as they're freed.  See `BitmapPageAlloc::set_zero_on_free`.
+    pub fn set_zero_on_free(&mut self, zero_page: Option<fn(PhysAddr)>) {
+        self.alloc.set_zero_on_free(zero_page);
+    }
+
+    /// Mark the pages in the given physical range as free.  These pages
+    /// aren't reference counted until they're allocated.
+    pub fn mark_free(&mut self, range: &PhysRange) -> Result<(), BitmapPageAllocError> {
+        self.alloc.mark_free(range)
+    }
+
+    /// Free unused pages in mem that aren't covered by the memory map.
+    pub fn free_unused_ranges<'a>(
+        &mut self,
+        available_mem: &PhysRange,
+        used_ranges: impl Iterator<Item = &'a PhysRange>,
+    ) -> Result<(), BitmapPageAllocError> {
+        self.alloc.free_unused_ranges(available_mem, used_ranges)
+    }
+
+    /// Try to allocate the next available page, with a reference count of 1.
+    pub fn allocate(&mut self) -> Result<PhysAddr, BitmapPageAllocError> {
+        let pa = self.alloc.allocate()?;
+        let idx = self.ref_idx(pa)?;
+        self.refs[idx] = 1;
+        Ok(pa)
+    }
+
+    /// Add a reference to an allocated page, returning the new count.
+    pub fn incref(&mut self, pa: PhysAddr) -> Result<usize, BitmapPageAllocError> {
+        let idx = self.ref_idx(pa)?;
+        let refs = &mut self.refs[idx];
+        if *refs == 0 {
+            return Err(BitmapPageAllocError::NotAllocated);
+        }
+        *refs = refs.checked_add(1).ok_or(BitmapPageAllocError::TooManyRefs)?;
+        Ok(*refs as usize)
+    }
+
+    /// Drop a reference to an allocated page, returning the new count.  The
+    /// page is deallocated once the count reaches zero.
+    pub fn decref(&mut self, pa: PhysAddr) -> Result<usize, BitmapPageAllocError> {
+        let idx = self.ref_idx(pa)?;
+        if self.refs[idx] == 0 {
+            return Err(BitmapPageAllocError::NotAllocated);
+        }
+        if self.refs[idx] == 1 {
+            self.alloc.deallocate(pa)?;
+        }
+        self.refs[idx] -= 1;
+        Ok(self.refs[idx] as usize)
+    }
+
+    /// Return a tuple of (bytes used, total bytes available) based on the page allocator.
+    pub fn usage_bytes(&self) -> (usize, usize) {
+        self.alloc.usage_bytes()
+    }
+
+    fn ref_idx(&self, pa: PhysAddr) -> Result<usize, BitmapPageAllocError> {
+        if pa.addr() % self.alloc.alloc_page_size as u64 != 0 {
+            return Err(BitmapPageAllocError::MisalignedAddr);
+        }
+        let idx = pa.addr() as usize / self.alloc.alloc_page_size;
+        if pa >= self.alloc.end || idx >= self.refs.len() {
+            return Err(BitmapPageAllocError::OutOfBounds);
+        }
+        Ok(idx)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core::ops::Range;
+    use std::sync::Mutex;
 
     #[test]
     fn bitmap_new() {
@@ -417,6 +557,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn bitmappagealloc_deallocate_past_first_byte() -> Result<(), BitmapPageAllocError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+
+        // Page 9 is bit 1 of the second byte of the first bitmap.  Only its
+        // own bit is cleared, not bit 1 of the first byte.
+        alloc.deallocate(PhysAddr::new(36))?;
+        assert_eq!(alloc.bytes(), [0xff, 0xfd, 0xff, 0xff]);
+
+        // So page 1 is still allocated, and can be freed
+        alloc.deallocate(PhysAddr::new(4))?;
+        assert_eq!(alloc.bytes(), [0xfd, 0xfd, 0xff, 0xff]);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_free_unused_ranges_per_region() -> Result<(), BitmapPageAllocError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+
+        // Two regions with a hole between them, and a used range in each
+        let used = [PhysRange::with_end(0, 8), PhysRange::with_end(96, 100)];
+        alloc.free_unused_ranges(&PhysRange::with_end(0, 32), used.iter())?;
+        alloc.free_unused_ranges(&PhysRange::with_end(64, 112), used.iter())?;
+        assert_eq!(alloc.bytes(), [0x03, 0xff, 0x00, 0xf1]);
+        assert_eq!(alloc.usage_bytes(), (44, 112));
+        Ok(())
+    }
+
     #[test]
     fn physaddr_as_indices() {
         let alloc = BitmapPageAlloc::<2, 4096>::new_all_allocated(4096);
@@ -446,4 +616,112 @@ mod tests {
         assert_eq!(alloc.indices_as_physaddr(1, 0, 0), PhysAddr::new(bytes_per_bitmap));
         assert_eq!(alloc.indices_as_physaddr(1, 1, 1), PhysAddr::new(bytes_per_bitmap + 4096 * 9));
     }
+
+    #[test]
+    fn bitmappagealloc_allocate_contiguous() -> Result<(), BitmapPageAllocError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        alloc.mark_free(&PhysRange::with_end(0, 128))?;
+
+        // Leave holes too small for 3 pages at 4 and 16, and a run spanning
+        // the two bitmaps at 60
+        alloc.mark_allocated(&PhysRange::with_end(0, 4))?;
+        alloc.mark_allocated(&PhysRange::with_end(12, 16))?;
+        alloc.mark_allocated(&PhysRange::with_end(24, 60))?;
+        alloc.mark_allocated(&PhysRange::with_end(72, 128))?;
+
+        let range = alloc.allocate_contiguous(3)?;
+        assert_eq!((range.start().addr(), range.end().addr()), (60, 72));
+        assert!(matches!(alloc.allocate_contiguous(3), Err(BitmapPageAllocError::OutOfSpace)));
+        let range = alloc.allocate_contiguous(2)?;
+        assert_eq!((range.start().addr(), range.end().addr()), (4, 12));
+        assert_eq!(alloc.usage_bytes(), (120, 128));
+        Ok(())
+    }
+
+    #[test]
+    fn refcountedpagealloc_refs() -> Result<(), BitmapPageAllocError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        let mut refs = [0; 32];
+        let mut alloc = RefCountedPageAlloc::<2, 2>::new_all_allocated(4, &mut refs);
+        alloc.free_unused_ranges(&PhysRange::with_end(0, 128), [].iter())?;
+
+        let pa = alloc.allocate()?;
+        assert_eq!(alloc.incref(pa)?, 2);
+        assert_eq!(alloc.incref(pa)?, 3);
+        assert_eq!(alloc.decref(pa)?, 2);
+        assert_eq!(alloc.decref(pa)?, 1);
+
+        // Pages that haven't been allocated can't be referenced
+        let unallocated = PhysAddr::new(64);
+        assert_eq!(alloc.incref(unallocated).unwrap_err(), BitmapPageAllocError::NotAllocated);
+        assert_eq!(alloc.decref(unallocated).unwrap_err(), BitmapPageAllocError::NotAllocated);
+        assert_eq!(
+            alloc.incref(PhysAddr::new(6)).unwrap_err(),
+            BitmapPageAllocError::MisalignedAddr
+        );
+        assert_eq!(
+            alloc.incref(PhysAddr::new(128)).unwrap_err(),
+            BitmapPageAllocError::OutOfBounds
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refcountedpagealloc_frees_at_zero() -> Result<(), BitmapPageAllocError> {
+        let mut refs = [0; 32];
+        let mut alloc = RefCountedPageAlloc::<2, 2>::new_all_allocated(4, &mut refs);
+        alloc.free_unused_ranges(&PhysRange::with_end(0, 128), [].iter())?;
+
+        // Use a page beyond the first byte of the bitmap
+        alloc.alloc.mark_allocated(&PhysRange::with_end(0, 40))?;
+        let pa = alloc.allocate()?;
+        assert_eq!(pa, PhysAddr::new(40));
+        alloc.incref(pa)?;
+        assert_eq!(alloc.usage_bytes(), (44, 128));
+
+        // Still referenced, so still allocated
+        assert_eq!(alloc.decref(pa)?, 1);
+        assert_eq!(alloc.usage_bytes(), (44, 128));
+
+        // Last reference dropped, so the page is freed and can be reused
+        assert_eq!(alloc.decref(pa)?, 0);
+        assert_eq!(alloc.usage_bytes(), (40, 128));
+        assert_eq!(alloc.decref(pa).unwrap_err(), BitmapPageAllocError::NotAllocated);
+        assert_eq!(alloc.allocate()?, pa);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_zero_on_free() -> Result<(), BitmapPageAllocError> {
+        // Stands in for physical memory: 32 pages of 4 bytes
+        static MEM: Mutex<[u8; 128]> = Mutex::new([0; 128]);
+        fn page(pa: PhysAddr) -> Range<usize> {
+            pa.addr() as usize..pa.addr() as usize + 4
+        }
+        fn zero_page(pa: PhysAddr) {
+            MEM.lock().unwrap()[page(pa)].fill(0);
+        }
+
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.set_zero_on_free(Some(zero_page));
+
+        // Dirty a page and free it
+        let pa = alloc.allocate()?;
+        MEM.lock().unwrap()[page(pa)].fill(0xaa);
+        alloc.deallocate(pa)?;
+
+        // The same frame comes back, cleared
+        assert_eq!(alloc.allocate()?, pa);
+        assert_eq!(MEM.lock().unwrap()[page(pa)], [0; 4]);
+
+        // Without zero_on_free, the contents are left alone
+        alloc.set_zero_on_free(None);
+        MEM.lock().unwrap()[page(pa)].fill(0xaa);
+        alloc.deallocate(pa)?;
+        assert_eq!(alloc.allocate()?, pa);
+        assert_eq!(MEM.lock().unwrap()[page(pa)], [0xaa; 4]);
+        Ok(())
+    }
 }
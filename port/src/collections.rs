@@ -0,0 +1,145 @@
+//! Collections that don't need the heap.
+
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::slice;
+
+/// A vector with a fixed capacity of `N`, stored inline.  Useful for lists
+/// with a known maximum size before the heap is available.
+pub struct FixedVec<T, const N: usize> {
+    data: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> FixedVec<T, N> {
+    pub const fn new() -> Self {
+        Self { data: [const { MaybeUninit::uninit() }; N], len: 0 }
+    }
+
+    /// Append a value.  If the vector is full, the value is handed back.
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(val);
+        }
+        self.data[self.len].write(val);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the last value, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.data[self.len].assume_init_read() })
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        self.as_slice().get(i)
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Drop all the values.
+    pub fn clear(&mut self) {
+        let len = self.len;
+        // Reset the length first, so a panicking drop can't lead to a double
+        // drop
+        self.len = 0;
+        unsafe { ptr::drop_in_place(MaybeUninit::slice_assume_init_mut(&mut self.data[..len])) };
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { MaybeUninit::slice_assume_init_ref(&self.data[..self.len]) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { MaybeUninit::slice_assume_init_mut(&mut self.data[..self.len]) }
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for FixedVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a FixedVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn capacity() {
+        let mut v = FixedVec::<u32, 2>::new();
+        assert!(v.is_empty());
+        assert_eq!(v.push(1), Ok(()));
+        assert_eq!(v.push(2), Ok(()));
+        assert!(v.is_full());
+        assert_eq!(v.push(3), Err(3));
+        assert_eq!(v.len(), 2);
+
+        assert_eq!(v.pop(), Some(2));
+        assert_eq!(v.push(3), Ok(()));
+        assert_eq!(v.get(1), Some(&3));
+        assert_eq!(v.get(2), None);
+    }
+
+    #[test]
+    fn iteration_order() {
+        let mut v = FixedVec::<u32, 8>::new();
+        for i in 0..5 {
+            v.push(i).unwrap();
+        }
+        assert_eq!(v.iter().copied().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+
+        v.as_mut_slice().reverse();
+        assert_eq!(v.pop(), Some(0));
+        assert_eq!((&v).into_iter().copied().collect::<Vec<_>>(), [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn clear_drops_values() {
+        let val = Rc::new(());
+        let mut v = FixedVec::<Rc<()>, 4>::new();
+        v.push(val.clone()).unwrap();
+        v.push(val.clone()).unwrap();
+        v.clear();
+        assert!(v.is_empty());
+        assert_eq!(Rc::strong_count(&val), 1);
+
+        v.push(val.clone()).unwrap();
+        drop(v);
+        assert_eq!(Rc::strong_count(&val), 1);
+    }
+}
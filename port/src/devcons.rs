@@ -1,3 +1,4 @@
+use crate::collections::FixedVec;
 use crate::mcslock::{Lock, LockNode};
 use core::fmt;
 
@@ -18,9 +19,46 @@ const CTLU: u8 = ctrl(b'U');
 
 pub trait Uart {
     fn putb(&self, b: u8);
+
+    /// Return a received byte if one is available.  UARTs that don't support
+    /// receiving always return None.
+    fn try_getb(&self) -> Option<u8> {
+        None
+    }
+
+    /// Wait until every byte written has been sent, such as before a reset.
+    /// UARTs that can't tell return straight away.
+    fn flush(&self) {}
+}
+
+/// Somewhere console output can be sent, in addition to the console UART.
+/// Every UART is a sink.
+pub trait ConsoleSink {
+    fn putb(&self, b: u8);
 }
 
+impl<T: Uart + ?Sized> ConsoleSink for T {
+    fn putb(&self, b: u8) {
+        Uart::putb(self, b);
+    }
+}
+
+/// Most sinks that can be added with `add_sink`
+pub const MAX_SINKS: usize = 4;
+
 static CONS: Lock<Option<&'static mut dyn Uart>> = Lock::new("cons", None);
+static SINKS: Lock<FixedVec<&'static dyn ConsoleSink, MAX_SINKS>> =
+    Lock::new("cons_sinks", FixedVec::new());
+
+/// Also write console output to sink, as well as the console UART.  Useful
+/// when bringing up a board, where it's not clear which output works.  Input
+/// only ever comes from the console UART.  If there are already `MAX_SINKS`
+/// sinks, the sink is handed back.
+pub fn add_sink(sink: &'static dyn ConsoleSink) -> Result<(), &'static dyn ConsoleSink> {
+    let node = LockNode::new();
+    let mut sinks = SINKS.lock(&node);
+    sinks.push(sink)
+}
 
 /// Console is what should be used in almost all cases, as it ensures threadsafe
 /// use of the console.
@@ -40,12 +78,40 @@ impl Console {
 
     pub fn putstr(&mut self, s: &str) {
         // XXX: Just for testing.
+        self.putbytes(s.as_bytes());
+    }
 
+    /// Write raw bytes, which needn't be valid UTF-8, to the console UART
+    /// and any other sinks.  Panics if the console UART hasn't been set up.
+    pub fn putbytes(&mut self, bytes: &[u8]) {
         let node = LockNode::new();
-        let mut uart_guard = CONS.lock(&node);
-        let uart = uart_guard.as_deref_mut().unwrap();
-        for b in s.bytes() {
+        let uart_guard = CONS.lock(&node);
+        let uart = uart_guard.as_deref().expect("console UART not set up");
+        let sinks_node = LockNode::new();
+        let sinks = SINKS.lock(&sinks_node);
+        for &b in bytes {
             putb(uart, b);
+            for &sink in sinks.iter() {
+                putb(sink, b);
+            }
+        }
+    }
+
+    /// Return a received byte if one is available.
+    pub fn try_getb(&mut self) -> Option<u8> {
+        let node = LockNode::new();
+        let uart_guard = CONS.lock(&node);
+        uart_guard.as_deref().and_then(|uart| uart.try_getb())
+    }
+
+    /// Block until a byte is received.  The console lock isn't held while
+    /// waiting, so output isn't blocked.
+    pub fn getb(&mut self) -> u8 {
+        loop {
+            if let Some(b) = self.try_getb() {
+                return b;
+            }
+            core::hint::spin_loop();
         }
     }
 }
@@ -79,9 +145,14 @@ where
         // XXX: Just for testing.
 
         for b in s.bytes() {
-            putb(&mut self.uart, b);
+            putb(&self.uart, b);
         }
     }
+
+    /// Wait until the UART has sent everything written to it
+    pub fn flush(&self) {
+        self.uart.flush();
+    }
 }
 
 impl<T> fmt::Write for PanicConsole<T>
@@ -114,12 +185,70 @@ macro_rules! print {
     }};
 }
 
-fn putb(uart: &mut dyn Uart, b: u8) {
+fn putb<S: ConsoleSink + ?Sized>(sink: &S, b: u8) {
     if b == b'\n' {
-        uart.putb(b'\r');
+        sink.putb(b'\r');
     } else if b == BACKSPACE {
-        uart.putb(b);
-        uart.putb(b' ');
+        sink.putb(b);
+        sink.putb(b' ');
+    }
+    sink.putb(b);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records what's written to it
+    struct TestSink(Lock<FixedVec<u8, 16>>);
+
+    impl Uart for TestSink {
+        fn putb(&self, b: u8) {
+            let node = LockNode::new();
+            let _ = self.0.lock(&node).push(b);
+        }
+    }
+
+    /// A console UART that records to a TestSink
+    struct TestUart(&'static TestSink);
+
+    impl Uart for TestUart {
+        fn putb(&self, b: u8) {
+            Uart::putb(self.0, b);
+        }
+    }
+
+    impl TestSink {
+        const fn new() -> Self {
+            Self(Lock::new("testsink", FixedVec::new()))
+        }
+
+        fn written(&self) -> Vec<u8> {
+            let node = LockNode::new();
+            let written = self.0.lock(&node);
+            written.iter().copied().collect()
+        }
+    }
+
+    // The sinks are global, so everything that adds them is in one test
+    #[test]
+    fn output_goes_to_all_sinks() {
+        static UART: TestSink = TestSink::new();
+        static SINK1: TestSink = TestSink::new();
+        static SINK2: TestSink = TestSink::new();
+        let mut cons = Console::new(|| Box::leak(Box::new(TestUart(&UART))));
+        assert!(add_sink(&SINK1).is_ok());
+        assert!(add_sink(&SINK2).is_ok());
+
+        cons.putbytes(b"hi\n");
+        assert_eq!(UART.written(), b"hi\r\n");
+        assert_eq!(SINK1.written(), b"hi\r\n");
+        assert_eq!(SINK2.written(), b"hi\r\n");
+
+        static SPARE: TestSink = TestSink::new();
+        for _ in 2..MAX_SINKS {
+            assert!(add_sink(&SPARE).is_ok());
+        }
+        assert!(add_sink(&SPARE).is_err());
     }
-    uart.putb(b);
 }
@@ -1,7 +1,10 @@
 #![allow(clippy::too_long_first_doc_paragraph)]
 
+use crate::collections::FixedVec;
+use crate::mem::PhysRange;
 use core::{
     ffi::CStr,
+    fmt,
     mem::{self, MaybeUninit},
 };
 
@@ -148,6 +151,18 @@ impl<'a> DeviceTree<'a> {
         Self::inline_str(self.structs(), node.name_start)
     }
 
+    /// Return the full path of the node, e.g. /cpus/cpu@0, by walking up to
+    /// the root.  Returns None for nodes nested deeper than MAX_PATH_DEPTH.
+    pub fn full_path(&self, node: &Node) -> Option<NodePath<'_>> {
+        let mut names = FixedVec::new();
+        let mut curr = *node;
+        while !curr.is_root() {
+            names.push(self.node_name(&curr)?).ok()?;
+            curr = self.parent(&curr)?;
+        }
+        Some(NodePath { names })
+    }
+
     pub fn property(&self, node: &Node, prop_name: &str) -> Option<Property> {
         self.properties(node).find(|p| self.property_name(p) == Some(prop_name))
     }
@@ -161,11 +176,24 @@ impl<'a> DeviceTree<'a> {
         self.structs().get(prop.value_start..value_end)
     }
 
+    /// Return the property value as a string, excluding the null terminator.
+    /// For string list values, only the first string is returned.
+    pub fn property_value_as_str(&self, prop: &Property) -> Option<&str> {
+        let value_end = prop.value_start + prop.value_len;
+        self.structs().get(prop.value_start..value_end).and_then(|bytes| Self::inline_str(bytes, 0))
+    }
+
     pub fn property_value_as_u32(&self, prop: &Property) -> Option<u32> {
         let value_end = prop.value_start + prop.value_len;
         self.structs().get(prop.value_start..value_end).and_then(bytes_to_u32)
     }
 
+    /// Return a two cell property value as a u64
+    pub fn property_value_as_u64(&self, prop: &Property) -> Option<u64> {
+        let value_end = prop.value_start + prop.value_len;
+        self.structs().get(prop.value_start..value_end).and_then(bytes_to_u64)
+    }
+
     pub fn property_value_as_u32_iter(&self, prop: &Property) -> impl Iterator<Item = u32> + '_ {
         let mut value_i = prop.value_start;
         let value_end = prop.value_start + prop.value_len;
@@ -339,6 +367,37 @@ impl<'a> DeviceTree<'a> {
         })
     }
 
+    /// Iterate over the RAM banks described by the memory nodes, as (base,
+    /// size) tuples.  Memory nodes are children of the root named `memory` or
+    /// `memory@...`, or with a device_type of "memory".  Firmware may leave a
+    /// bank's size as 0 for the bootloader to fill in, so these are included.
+    pub fn memory_nodes(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let memory_nodes = self.root().into_iter().flat_map(|root| {
+            self.children(&root).filter(|node| {
+                let name = self.node_name(node).unwrap_or("");
+                name == "memory"
+                    || name.starts_with("memory@")
+                    || self
+                        .property(node, "device_type")
+                        .and_then(|prop| self.property_value_as_str(&prop))
+                        == Some("memory")
+            })
+        });
+        memory_nodes
+            .flat_map(|node| self.property_translated_reg_iter(node))
+            .filter_map(|reg| reg.regblock())
+            .map(|reg| (reg.addr, reg.len.unwrap_or(0)))
+    }
+
+    /// Iterate over the RAM banks described by the memory nodes, as physical
+    /// ranges, for bounding the page allocator.  Unlike `memory_nodes`, banks
+    /// with no size are skipped.
+    pub fn memory_ranges(&self) -> impl Iterator<Item = PhysRange> + '_ {
+        self.memory_nodes()
+            .filter(|&(_, size)| size != 0)
+            .map(|(base, size)| PhysRange::with_len(base, size as usize))
+    }
+
     fn property_value_contains(&self, prop: &Property, bytes_to_find: &str) -> bool {
         if let Some(uninit_value) = self.property_value_bytes(prop) {
             let init_value = unsafe { MaybeUninit::slice_assume_init_ref(uninit_value) };
@@ -384,6 +443,50 @@ impl<'a> DeviceTree<'a> {
         self.root().and_then(|node| find_subpath(self, &mut path_iter, &node, next_path_element))
     }
 
+    /// Return the node for a full path, in the form used by properties such as
+    /// /chosen/stdout-path.  Anything after a ':' is options (e.g. the baud
+    /// rate) and is ignored.  A path not starting with '/' begins with the name
+    /// of an alias in /aliases.
+    pub fn find_node_by_full_path(&self, path: &str) -> Option<Node> {
+        let path = path.split(':').next()?;
+        if path == "/" {
+            return self.root();
+        }
+        if path.starts_with('/') {
+            return self.find_by_path(path);
+        }
+
+        let mut path_iter = path.split_terminator('/');
+        let alias = path_iter.next()?;
+        let aliases = self.find_by_path("/aliases")?;
+        let alias_path =
+            self.property(&aliases, alias).and_then(|prop| self.property_value_as_str(&prop))?;
+        let mut node = self.find_by_path(alias_path)?;
+        for name in path_iter {
+            node = self.children(&node).find(|child| self.node_name(child) == Some(name))?;
+        }
+        Some(node)
+    }
+
+    /// Iterate over the CPUs under /cpus.  Only nodes with a device_type of
+    /// "cpu" are included, and those with a status of "disabled" are skipped.
+    pub fn cpu_nodes(&'a self) -> impl Iterator<Item = CpuNode<'a>> + 'a {
+        let prop_str = |node: &Node, name: &str| {
+            self.property(node, name).and_then(|prop| self.property_value_as_str(&prop))
+        };
+        self.find_by_path("/cpus")
+            .into_iter()
+            .flat_map(|cpus| self.children(&cpus))
+            .filter(move |node| {
+                prop_str(node, "device_type") == Some("cpu")
+                    && prop_str(node, "status") != Some("disabled")
+            })
+            .filter_map(move |node| {
+                let hart_id = self.property_reg_iter(node).next()?.addr;
+                Some(CpuNode { hart_id, compatible: prop_str(&node, "compatible") })
+            })
+    }
+
     /// Return the first node matching the compatible string 'comp'
     pub fn find_compatible(&'a self, comp: &'a str) -> impl Iterator<Item = Node> + 'a {
         // Iterate over all nodes.  For each node, iterate over all properties until we find a 'compatible'
@@ -690,6 +793,36 @@ impl Node {
     }
 }
 
+/// A CPU from /cpus
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CpuNode<'a> {
+    /// The CPU's reg: the hart ID on RISC-V, or the MPIDR affinity on ARM
+    pub hart_id: u64,
+    pub compatible: Option<&'a str>,
+}
+
+/// Maximum depth of node that `DeviceTree::full_path` can describe
+pub const MAX_PATH_DEPTH: usize = 16;
+
+/// Full path of a node, which displays as the node names from the root down,
+/// separated by '/'.
+pub struct NodePath<'a> {
+    // Names from the node up to, but not including, the root
+    names: FixedVec<&'a str, MAX_PATH_DEPTH>,
+}
+
+impl fmt::Display for NodePath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.names.is_empty() {
+            return write!(f, "/");
+        }
+        for name in self.names.iter().rev() {
+            write!(f, "/{name}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Property {
     start: usize,       // Start index in structs of node (Start of FDT_BEGIN_NODE)
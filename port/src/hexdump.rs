@@ -0,0 +1,91 @@
+//! Hex dumps of memory, for debugging
+//!
+//! Each line is the offset (or address) of its first byte, up to 16 bytes in
+//! hex, and the same bytes as ASCII, with anything unprintable shown as `.`:
+//!
+//! ```text
+//! 00000000: 72 39 20 74 65 73 74 20 64 69 73 6b 0a 00 00 00 | r9 test disk....
+//! ```
+
+use core::fmt;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Print len bytes of memory starting at addr, labelled with their addresses.
+///
+/// # Safety
+///
+/// The whole range must be mapped and readable.
+pub unsafe fn hexdump(addr: usize, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    print_lines(addr, bytes);
+}
+
+/// Print the bytes, labelled with their offsets in the slice
+pub fn hexdump_slice(bytes: &[u8]) {
+    print_lines(0, bytes);
+}
+
+fn print_lines(base: usize, bytes: &[u8]) {
+    for line in lines(base, bytes) {
+        crate::println!("{line}");
+    }
+}
+
+fn lines(base: usize, bytes: &[u8]) -> impl Iterator<Item = Line<'_>> {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(move |(i, bytes)| Line { offset: base + i * BYTES_PER_LINE, bytes })
+}
+
+/// A single line of a dump, of up to 16 bytes
+struct Line<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}:", self.offset)?;
+        for b in self.bytes {
+            write!(f, " {b:02x}")?;
+        }
+        // Pad a short final line so the ASCII lines up
+        for _ in self.bytes.len()..BYTES_PER_LINE {
+            f.write_str("   ")?;
+        }
+        f.write_str(" | ")?;
+        for &b in self.bytes {
+            let c = if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+            write!(f, "{c}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(base: usize, bytes: &[u8]) -> Vec<String> {
+        lines(base, bytes).map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn dump_lines() {
+        let bytes = b"r9 test disk\n\0\0\0hello";
+        assert_eq!(
+            dump(0, bytes),
+            [
+                "00000000: 72 39 20 74 65 73 74 20 64 69 73 6b 0a 00 00 00 | r9 test disk....",
+                "00000010: 68 65 6c 6c 6f                                  | hello",
+            ]
+        );
+        assert_eq!(
+            dump(0xffff_0000, &bytes[..2]),
+            ["ffff0000: 72 39                                           | r9"]
+        );
+        assert!(dump(0, &[]).is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+//! In-kernel tests, for code that can only be exercised on the target.
+//!
+//! An arch built with its `qemu_test` feature runs its list of tests after
+//! boot instead of carrying on as normal, then reports the result to QEMU,
+//! which exits with a status `cargo xtask qemu-test` can check.  The exit
+//! protocol is:
+//!
+//! - x86_64 writes the `ExitCode` to the `isa-debug-exit` device on port
+//!   `0xf4`.  QEMU exits with `(code << 1) | 1`, so 33 for success and 35 for
+//!   failure.
+//! - aarch64 and riscv64 make a semihosting `SYS_EXIT` call with the
+//!   `ExitCode` as the subcode, and QEMU exits with it, so 0 for success and 1
+//!   for failure.
+//!
+//! A test fails by panicking.  The panic handler reports failure, so a run
+//! stops at the first failing test.
+
+use crate::println;
+
+/// Result of a test run, as reported to QEMU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    Failure,
+}
+
+/// A named kernel test
+pub struct KernelTest {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// Run each of the tests in turn, printing its name as it goes.  Only
+/// returns if all the tests pass.
+pub fn run_tests(tests: &[KernelTest]) -> ExitCode {
+    println!("running {} kernel tests", tests.len());
+    for test in tests {
+        println!("test {} ...", test.name);
+        (test.run)();
+        println!("test {} ok", test.name);
+    }
+    println!("all kernel tests passed");
+    ExitCode::Success
+}
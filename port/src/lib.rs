@@ -10,8 +10,22 @@ extern crate alloc;
 
 pub mod allocator;
 pub mod bitmapalloc;
+pub mod collections;
 pub mod dat;
 pub mod devcons;
 pub mod fdt;
+pub mod hexdump;
+pub mod ktest;
+pub mod log;
+pub mod maths;
 pub mod mcslock;
 pub mod mem;
+pub mod once;
+pub mod pagecache;
+pub mod ringbuf;
+pub mod rwlock;
+pub mod semihosting;
+pub mod syscall;
+pub mod uart16550;
+
+pub use hexdump::{hexdump, hexdump_slice};
@@ -0,0 +1,133 @@
+//! Levelled logging to the console
+//!
+//! `error!`, `warn!`, `info!`, `debug!` and `trace!` print like `println!`,
+//! prefixed with the level, but only if the level is enabled.  The check is
+//! made before the arguments are formatted or even evaluated, so disabled
+//! messages cost a single atomic load.  `println!` is unaffected and always
+//! prints.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(usize)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+/// Most verbose level that's printed
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Print messages at level and above, and skip the more verbose ones
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// Whether messages at level are printed
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Print a message.  Use the macros, which check the level first.
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    crate::println!("{level}: {args}");
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::log(level, format_args!($($arg)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The level is global, so everything that changes it is in one test
+    #[test]
+    fn levels() {
+        assert_eq!(level(), Level::Info);
+        assert!(enabled(Level::Error));
+        assert!(enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+
+        // Disabled messages don't evaluate their arguments
+        let mut evaluated = false;
+        crate::debug!("{}", {
+            evaluated = true;
+            0
+        });
+        crate::trace!("{}", {
+            evaluated = true;
+            0
+        });
+        assert!(!evaluated);
+
+        set_level(Level::Trace);
+        assert_eq!(level(), Level::Trace);
+        assert!(enabled(Level::Trace));
+
+        set_level(Level::Error);
+        assert!(enabled(Level::Error));
+        assert!(!enabled(Level::Warn));
+
+        set_level(Level::Info);
+    }
+}
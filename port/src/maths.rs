@@ -0,0 +1,227 @@
+//! Integer helpers for alignment, powers of two, common divisors and
+//! multiples, and UART baud rate divisors.
+
+/// Round val up to a multiple of align, which must be a power of two.
+pub const fn align_up(val: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    (val + align - 1) & !(align - 1)
+}
+
+/// Round val down to a multiple of align, which must be a power of two.
+pub const fn align_down(val: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    val & !(align - 1)
+}
+
+/// Largest k such that 2^k <= n.  n must be non-zero.
+pub const fn log2_floor(n: usize) -> u32 {
+    n.ilog2()
+}
+
+/// Smallest k such that 2^k >= n.  n must be non-zero.
+pub const fn log2_ceil(n: usize) -> u32 {
+    if n == 1 {
+        0
+    } else {
+        log2_floor(n - 1) + 1
+    }
+}
+
+/// Greatest common divisor.  gcd(n, 0) is n, so gcd(0, 0) is 0.
+pub const fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Least common multiple, or None if it doesn't fit in a u64.  If either
+/// value is 0, the result is 0.
+pub const fn lcm(a: u64, b: u64) -> Option<u64> {
+    if a == 0 || b == 0 {
+        return Some(0);
+    }
+    (a / gcd(a, b)).checked_mul(b)
+}
+
+/// Integer and fractional baud rate divisors for a PL011, from its reference
+/// clock.  The divisor is clk_hz / (16 * baud), with the fractional part in
+/// 64ths, rounded to the nearest.  The integer part is limited to the 16 bits
+/// of IBRD.  Panics if baud is 0.
+pub const fn baud_divisors(clk_hz: u32, baud: u32) -> (u16, u8) {
+    assert!(baud > 0, "baud rate must be non-zero");
+    // Work in 64ths: clk_hz * 64 / (16 * baud)
+    let divisor_64ths = (clk_hz as u64 * 4 + baud as u64 / 2) / baud as u64;
+    let int = divisor_64ths >> 6;
+    if int > u16::MAX as u64 {
+        return (u16::MAX, 0);
+    }
+    (int as u16, (divisor_64ths & 0x3f) as u8)
+}
+
+/// Value of the Raspberry Pi mini UART's baud rate register, given the
+/// system (VPU core) clock.  The baud rate is
+/// clk_hz / (8 * (baud_reg + 1)).  Panics if baud is 0.
+pub const fn mini_uart_baud(clk_hz: u32, baud: u32) -> u32 {
+    assert!(baud > 0, "baud rate must be non-zero");
+    ((clk_hz as u64 / (8 * baud as u64)) as u32).saturating_sub(1)
+}
+
+/// Divisor for a 16550 compatible UART, given its input clock, rounded to the
+/// nearest.  The baud rate is clk_hz / (16 * divisor).  The divisor is limited
+/// to the 16 bits of DLL and DLM, and is at least 1.  Panics if baud is 0.
+pub const fn uart16550_divisor(clk_hz: u32, baud: u32) -> u16 {
+    assert!(baud > 0, "baud rate must be non-zero");
+    let div = 16 * baud as u64;
+    let divisor = (clk_hz as u64 + div / 2) / div;
+    if divisor == 0 {
+        1
+    } else if divisor > u16::MAX as u64 {
+        u16::MAX
+    } else {
+        divisor as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simple xorshift, so the property tests cover a spread of values
+    /// without needing an external crate
+    fn random_values(count: usize) -> impl Iterator<Item = usize> {
+        let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..count).map(move |_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as usize
+        })
+    }
+
+    #[test]
+    fn align() {
+        assert_eq!(align_up(0, 4096), 0);
+        assert_eq!(align_up(1, 4096), 4096);
+        assert_eq!(align_up(4096, 4096), 4096);
+        assert_eq!(align_down(4095, 4096), 0);
+        assert_eq!(align_down(8193, 4096), 8192);
+        assert_eq!(align_up(7, 1), 7);
+    }
+
+    #[test]
+    fn align_properties() {
+        for (i, x) in random_values(10000).enumerate() {
+            let a = 1 << (i % 32);
+            // Keep clear of the top so align_up can't overflow
+            let x = x >> 1;
+            let up = align_up(x, a);
+            let down = align_down(x, a);
+            assert_eq!(align_down(up, a), up);
+            assert_eq!(align_up(down, a), down);
+            assert!(down <= x && x <= up);
+            assert!(up - down == 0 || up - down == a);
+        }
+    }
+
+    #[test]
+    fn log2() {
+        assert_eq!(log2_floor(1), 0);
+        assert_eq!(log2_ceil(1), 0);
+        assert_eq!(log2_floor(2), 1);
+        assert_eq!(log2_ceil(3), 2);
+        assert_eq!(log2_floor(4096), 12);
+        assert_eq!(log2_ceil(4097), 13);
+        assert_eq!(log2_floor(usize::MAX), usize::BITS - 1);
+        assert_eq!(log2_ceil(usize::MAX), usize::BITS);
+    }
+
+    #[test]
+    fn gcd_lcm() {
+        assert_eq!(gcd(0, 0), 0);
+        assert_eq!(gcd(12, 0), 12);
+        assert_eq!(gcd(0, 12), 12);
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(17, 5), 1);
+        assert_eq!(gcd(4096, 1 << 21), 4096);
+
+        assert_eq!(lcm(0, 0), Some(0));
+        assert_eq!(lcm(0, 7), Some(0));
+        assert_eq!(lcm(4, 6), Some(12));
+        assert_eq!(lcm(4096, 1 << 21), Some(1 << 21));
+        assert_eq!(lcm(1 << 63, 1 << 63), Some(1 << 63));
+        assert_eq!(lcm(1 << 63, 3), None);
+        assert_eq!(lcm(u64::MAX, u64::MAX - 1), None);
+
+        // Usable in const contexts
+        const L: Option<u64> = lcm(4096, 4095);
+        assert_eq!(L, Some(4096 * 4095));
+    }
+
+    #[test]
+    fn gcd_lcm_properties() {
+        for (a, b) in random_values(10000).map(|x| ((x as u64) >> 40, (x as u64) & 0xff_ffff)) {
+            let g = gcd(a, b);
+            if g == 0 {
+                assert!(a == 0 && b == 0);
+                continue;
+            }
+            assert_eq!(a % g, 0);
+            assert_eq!(b % g, 0);
+            assert_eq!(gcd(a / g, b / g), 1);
+            assert_eq!(lcm(a, b), Some(a / g * b));
+        }
+    }
+
+    #[test]
+    fn log2_properties() {
+        for n in random_values(10000).map(|x| (x >> 1).max(1)) {
+            let floor = log2_floor(n);
+            let ceil = log2_ceil(n);
+            assert!(1 << floor <= n && n < 1 << (floor + 1));
+            assert!(n <= 1 << ceil);
+            assert_eq!(ceil - floor, if n.is_power_of_two() { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn pl011_baud_divisors() {
+        // 3MHz / (16 * 115200) = 1.627, with 0.627 * 64 = 40.1
+        assert_eq!(baud_divisors(3_000_000, 115200), (1, 40));
+        // 48MHz / (16 * 115200) = 26.042, with 0.042 * 64 = 2.67
+        assert_eq!(baud_divisors(48_000_000, 115200), (26, 3));
+        assert_eq!(baud_divisors(3_000_000, 9600), (19, 34));
+        // Exact divisors have no fractional part
+        assert_eq!(baud_divisors(48_000_000, 3_000_000), (1, 0));
+        // Rounding can carry into the integer part: 4.9999 is 5 and 0/64
+        assert_eq!(baud_divisors(3_999_999, 50_000), (5, 0));
+        // Too slow for IBRD
+        assert_eq!(baud_divisors(u32::MAX, 1), (u16::MAX, 0));
+    }
+
+    #[test]
+    fn mini_uart_baud_reg() {
+        // Pi 3 and 4 core clocks of 250MHz and 500MHz
+        assert_eq!(mini_uart_baud(250_000_000, 115200), 270);
+        assert_eq!(mini_uart_baud(500_000_000, 115200), 541);
+        assert_eq!(mini_uart_baud(250_000_000, 9600), 3254);
+        assert_eq!(mini_uart_baud(1_000_000, 1_000_000), 0);
+        // 8 * baud doesn't overflow
+        assert_eq!(mini_uart_baud(250_000_000, u32::MAX), 0);
+    }
+
+    #[test]
+    fn uart16550_divisors() {
+        // The PC UART clock of 1.8432MHz divides exactly
+        assert_eq!(uart16550_divisor(1_843_200, 115200), 1);
+        assert_eq!(uart16550_divisor(1_843_200, 9600), 12);
+        // 24MHz / (16 * 115200) = 13.02
+        assert_eq!(uart16550_divisor(24_000_000, 115200), 13);
+        // 24MHz / (16 * 9600) = 156.25, and 24MHz / (16 * 1000000) = 1.5
+        assert_eq!(uart16550_divisor(24_000_000, 9600), 156);
+        assert_eq!(uart16550_divisor(24_000_000, 1_000_000), 2);
+        // Too fast or too slow for the divisor latch
+        assert_eq!(uart16550_divisor(1_000, 115200), 1);
+        assert_eq!(uart16550_divisor(u32::MAX, 1), u16::MAX);
+    }
+}
@@ -9,7 +9,8 @@
 
 use core::cell::UnsafeCell;
 use core::hint;
-use core::marker::{Send, Sized, Sync};
+use core::marker::{PhantomData, Send, Sized, Sync};
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
 use core::ptr;
 use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
@@ -124,3 +125,117 @@ impl<T: ?Sized> Drop for LockGuard<'_, T> {
         unsafe { &mut *self.lock.get() }.unlock(self.node);
     }
 }
+
+/// Arch hooks for masking interrupts on the current CPU, as used by IrqLock.
+pub trait Interrupts {
+    /// Interrupt state saved by disable, e.g. the previous mask.
+    type State: Copy;
+
+    /// Disables interrupts, returning the previous state.
+    fn disable() -> Self::State;
+
+    /// Restores the state returned by disable.
+    fn restore(state: Self::State);
+}
+
+/// A Lock that disables interrupts on the current CPU while it's
+/// held, so it can be shared with interrupt handlers.  Otherwise an
+/// interrupt taken while the lock is held could spin forever trying
+/// to take it.
+pub struct IrqLock<T: ?Sized, I: Interrupts> {
+    _interrupts: PhantomData<fn() -> I>,
+    lock: Lock<T>,
+}
+
+impl<T, I: Interrupts> IrqLock<T, I> {
+    pub const fn new(name: &'static str, data: T) -> IrqLock<T, I> {
+        IrqLock { _interrupts: PhantomData, lock: Lock::new(name, data) }
+    }
+
+    pub fn lock<'a>(&'a self, node: &'a LockNode) -> IrqLockGuard<'a, T, I> {
+        let state = I::disable();
+        IrqLockGuard { guard: ManuallyDrop::new(self.lock.lock(node)), state }
+    }
+}
+
+pub struct IrqLockGuard<'a, T: ?Sized + 'a, I: Interrupts> {
+    guard: ManuallyDrop<LockGuard<'a, T>>,
+    state: I::State,
+}
+
+impl<T, I: Interrupts> Deref for IrqLockGuard<'_, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T, I: Interrupts> DerefMut for IrqLockGuard<'_, T, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized, I: Interrupts> Drop for IrqLockGuard<'_, T, I> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come in again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        I::restore(self.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+    static LOCK: IrqLock<u32, TestInterrupts> = IrqLock::new("test", 0);
+    static HELD_ON_RESTORE: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestInterrupts;
+
+    impl Interrupts for TestInterrupts {
+        type State = bool;
+
+        fn disable() -> bool {
+            ENABLED.swap(false, Ordering::Relaxed)
+        }
+
+        fn restore(enabled: bool) {
+            // Record whether the lock was still held when interrupts were
+            // restored
+            if !unsafe { &*LOCK.lock.lock.get() }.queue.load(Ordering::Relaxed).is_null() {
+                HELD_ON_RESTORE.fetch_add(1, Ordering::Relaxed);
+            }
+            ENABLED.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn irq_lock_masks_interrupts() {
+        {
+            let node = LockNode::new();
+            let mut data = LOCK.lock(&node);
+            *data += 1;
+            assert!(!ENABLED.load(Ordering::Relaxed));
+
+            // Nested locks leave interrupts disabled until the outer one is
+            // released
+            static INNER: IrqLock<u32, TestInterrupts> = IrqLock::new("inner", 0);
+            {
+                let node = LockNode::new();
+                let _inner = INNER.lock(&node);
+            }
+            assert!(!ENABLED.load(Ordering::Relaxed));
+        }
+        assert!(ENABLED.load(Ordering::Relaxed));
+
+        // Only the inner restore happened with LOCK held
+        assert_eq!(HELD_ON_RESTORE.load(Ordering::Relaxed), 1);
+
+        let node = LockNode::new();
+        assert_eq!(*LOCK.lock(&node), 1);
+    }
+}
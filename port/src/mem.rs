@@ -1,4 +1,5 @@
 use crate::fdt::RegBlock;
+use crate::maths::{align_down, align_up};
 use core::{
     cmp::{max, min},
     fmt,
@@ -33,6 +34,16 @@ impl VirtRange {
     pub fn end(&self) -> usize {
         self.0.end
     }
+
+    /// Round the start down and the end up to a multiple of align, which
+    /// must be a power of two.  If rounding up the end would overflow, it's
+    /// clamped to usize::MAX.
+    pub fn align_expand(&self, align: usize) -> VirtRange {
+        assert!(align.is_power_of_two());
+        let start = align_down(self.0.start, align);
+        let end = self.0.end.checked_next_multiple_of(align).unwrap_or(usize::MAX);
+        VirtRange(start..end)
+    }
 }
 
 impl From<&RegBlock> for VirtRange {
@@ -58,12 +69,23 @@ impl PhysAddr {
 
     pub const fn round_up(&self, step: u64) -> PhysAddr {
         assert!(step.is_power_of_two());
-        PhysAddr((self.0 + step - 1) & !(step - 1))
+        PhysAddr(align_up(self.0 as usize, step as usize) as u64)
     }
 
     pub const fn round_down(&self, step: u64) -> PhysAddr {
         assert!(step.is_power_of_two());
-        PhysAddr(self.0 & !(step - 1))
+        PhysAddr(align_down(self.0 as usize, step as usize) as u64)
+    }
+
+    /// Is the address a multiple of align, which must be a power of two?
+    pub const fn is_aligned(&self, align: u64) -> bool {
+        assert!(align.is_power_of_two());
+        self.0 & (align - 1) == 0
+    }
+
+    /// Round down to a multiple of align, which must be a power of two
+    pub const fn align_down(&self, align: u64) -> PhysAddr {
+        self.round_down(align)
     }
 }
 
@@ -147,9 +169,47 @@ impl PhysRange {
         (startpa..endpa).step_by(step_size)
     }
 
+    /// Split the range at each multiple of size, which must be a power of
+    /// two.  The first and last chunks are smaller if the range isn't
+    /// aligned to size.
+    pub fn chunks_by_size(&self, size: usize) -> impl Iterator<Item = PhysRange> {
+        debug_assert!(size.is_power_of_two());
+        let mut start = self.start();
+        let end = self.end();
+        core::iter::from_fn(move || {
+            if start >= end {
+                return None;
+            }
+            let chunk_end = start
+                .align_down(size as u64)
+                .addr()
+                .checked_add(size as u64)
+                .map_or(end, |next| min(PhysAddr(next), end));
+            let chunk = Self(start..chunk_end);
+            start = chunk_end;
+            Some(chunk)
+        })
+    }
+
     pub fn add(&self, other: &PhysRange) -> Self {
         Self(min(self.0.start, other.0.start)..max(self.0.end, other.0.end))
     }
+
+    /// Return the range covered by both self and other, or None if they don't
+    /// overlap.  Ranges that only touch don't overlap.
+    pub fn intersection(&self, other: &PhysRange) -> Option<PhysRange> {
+        let start = max(self.0.start, other.0.start);
+        let end = min(self.0.end, other.0.end);
+        if start < end {
+            Some(Self(start..end))
+        } else {
+            None
+        }
+    }
+
+    pub fn overlaps(&self, other: &PhysRange) -> bool {
+        self.intersection(other).is_some()
+    }
 }
 
 impl fmt::Display for PhysRange {
@@ -166,6 +226,27 @@ impl From<&RegBlock> for PhysRange {
     }
 }
 
+/// Sort ranges by start address, and merge any that overlap or are adjacent,
+/// returning the merged ranges as a prefix of the slice.  Empty ranges are
+/// dropped.  The result is sorted and non-overlapping, as free_unused_ranges
+/// expects.
+pub fn coalesce_ranges(ranges: &mut [PhysRange]) -> &[PhysRange] {
+    ranges.sort_unstable_by_key(|range| range.start());
+    let mut n = 0;
+    for i in 0..ranges.len() {
+        if ranges[i].size() == 0 {
+            continue;
+        }
+        if n > 0 && ranges[i].start() <= ranges[n - 1].end() {
+            ranges[n - 1].0.end = max(ranges[n - 1].end(), ranges[i].end());
+        } else {
+            ranges.swap(n, i);
+            n += 1;
+        }
+    }
+    &ranges[..n]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,4 +274,133 @@ mod tests {
         let pas = range.step_by_rounded(PAGE_SIZE_2M).collect::<Vec<PhysAddr>>();
         assert_eq!(pas, [PhysAddr::new(0x3f000000), PhysAddr::new(0x3f000000 + 2 * 1024 * 1024)]);
     }
+
+    #[test]
+    fn physrange_chunks_by_size() {
+        fn chunks(range: PhysRange, size: usize) -> Vec<(u64, u64)> {
+            range.chunks_by_size(size).map(|c| (c.start().addr(), c.end().addr())).collect()
+        }
+
+        // Aligned bounds give whole chunks
+        let range = PhysRange::with_len(0x20_0000, 2 * PAGE_SIZE_2M);
+        assert_eq!(chunks(range, PAGE_SIZE_2M), [(0x20_0000, 0x40_0000), (0x40_0000, 0x60_0000)]);
+
+        // Unaligned bounds give a smaller first and last chunk
+        let range = PhysRange::with_end(0x1f_f000, 0x40_1000);
+        assert_eq!(
+            chunks(range, PAGE_SIZE_2M),
+            [(0x1f_f000, 0x20_0000), (0x20_0000, 0x40_0000), (0x40_0000, 0x40_1000)]
+        );
+
+        // Within a single chunk
+        let range = PhysRange::with_end(0x1800, 0x1900);
+        assert_eq!(chunks(range, PAGE_SIZE_4K), [(0x1800, 0x1900)]);
+
+        // Empty ranges have no chunks
+        assert!(chunks(PhysRange::with_len(0x1000, 0), PAGE_SIZE_4K).is_empty());
+
+        // The top of the address space doesn't overflow
+        let range = PhysRange::with_end(u64::MAX - 0x1fff, u64::MAX);
+        assert_eq!(
+            chunks(range, PAGE_SIZE_4K),
+            [(u64::MAX - 0x1fff, u64::MAX - 0xfff), (u64::MAX - 0xfff, u64::MAX)]
+        );
+    }
+
+    #[test]
+    fn physrange_intersection() {
+        let range = PhysRange::with_end(0x1000, 0x5000);
+
+        // Overlapping
+        let other = PhysRange::with_end(0x4000, 0x8000);
+        let i = range.intersection(&other).unwrap();
+        assert_eq!((i.start(), i.end()), (PhysAddr::new(0x4000), PhysAddr::new(0x5000)));
+        assert!(range.overlaps(&other) && other.overlaps(&range));
+
+        // Nested
+        let other = PhysRange::with_end(0x2000, 0x3000);
+        let i = range.intersection(&other).unwrap();
+        assert_eq!((i.start(), i.end()), (PhysAddr::new(0x2000), PhysAddr::new(0x3000)));
+        let i = other.intersection(&range).unwrap();
+        assert_eq!((i.start(), i.end()), (PhysAddr::new(0x2000), PhysAddr::new(0x3000)));
+
+        // Touching
+        assert!(range.intersection(&PhysRange::with_end(0x5000, 0x6000)).is_none());
+        assert!(!PhysRange::with_end(0, 0x1000).overlaps(&range));
+
+        // Disjoint
+        assert!(range.intersection(&PhysRange::with_end(0x8000, 0x9000)).is_none());
+        assert!(!range.overlaps(&PhysRange::with_end(0, 0x800)));
+
+        // Empty
+        assert!(!range.overlaps(&PhysRange::with_len(0x2000, 0)));
+    }
+
+    #[test]
+    fn coalesce() {
+        fn bounds(ranges: &[PhysRange]) -> Vec<(u64, u64)> {
+            ranges.iter().map(|r| (r.start().addr(), r.end().addr())).collect()
+        }
+
+        // Adjacent, overlapping, nested and unsorted
+        let mut ranges = [
+            PhysRange::with_end(0x8000, 0x9000),
+            PhysRange::with_end(0x1000, 0x2000),
+            PhysRange::with_end(0x2000, 0x3000),
+            PhysRange::with_end(0x2800, 0x4000),
+            PhysRange::with_end(0x3000, 0x3800),
+            PhysRange::with_end(0x6000, 0x7000),
+        ];
+        assert_eq!(
+            bounds(coalesce_ranges(&mut ranges)),
+            [(0x1000, 0x4000), (0x6000, 0x7000), (0x8000, 0x9000)]
+        );
+
+        // Empty ranges are dropped
+        let mut ranges = [
+            PhysRange::with_len(0x5000, 0),
+            PhysRange::with_end(0x1000, 0x2000),
+            PhysRange::with_len(0x1800, 0),
+        ];
+        assert_eq!(bounds(coalesce_ranges(&mut ranges)), [(0x1000, 0x2000)]);
+
+        assert!(coalesce_ranges(&mut []).is_empty());
+    }
+
+    #[test]
+    fn physaddr_alignment() {
+        let pa = PhysAddr::new(0x20_0000);
+        assert!(pa.is_aligned(PAGE_SIZE_4K as u64));
+        assert!(pa.is_aligned(PAGE_SIZE_2M as u64));
+        assert!(!pa.is_aligned(PAGE_SIZE_1G as u64));
+        assert_eq!(pa.align_down(PAGE_SIZE_2M as u64), pa);
+
+        let pa = PhysAddr::new(0x20_1234);
+        assert!(!pa.is_aligned(PAGE_SIZE_4K as u64));
+        assert_eq!(pa.align_down(PAGE_SIZE_4K as u64), PhysAddr::new(0x20_1000));
+        assert_eq!(pa.align_down(PAGE_SIZE_2M as u64), PhysAddr::new(0x20_0000));
+
+        assert!(PhysAddr::new(0).is_aligned(PAGE_SIZE_1G as u64));
+    }
+
+    #[test]
+    fn virtrange_align_expand() {
+        // Already aligned
+        let range = VirtRange(0x1000..0x3000).align_expand(PAGE_SIZE_4K);
+        assert_eq!(range.0, 0x1000..0x3000);
+
+        // Mid-page
+        let range = VirtRange(0x1234..0x2001).align_expand(PAGE_SIZE_4K);
+        assert_eq!(range.0, 0x1000..0x3000);
+        let range = VirtRange(0x1234..0x1235).align_expand(PAGE_SIZE_2M);
+        assert_eq!(range.0, 0..0x20_0000);
+
+        // The end can round up to the last page...
+        let range = VirtRange(0xffff_ffff_ffff_e001..0xffff_ffff_ffff_e001).align_expand(0x1000);
+        assert_eq!(range.0, 0xffff_ffff_ffff_e000..0xffff_ffff_ffff_f000);
+
+        // ...but not past the end of the address space
+        let range = VirtRange(0xffff_ffff_ffff_f001..usize::MAX).align_expand(0x1000);
+        assert_eq!(range.0, 0xffff_ffff_ffff_f000..usize::MAX);
+    }
 }
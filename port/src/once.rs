@@ -0,0 +1,105 @@
+//! One-time initialisation
+//!
+//! `Once` holds a value that's set exactly once and can then be read without
+//! taking a lock, so it's safe to use from interrupt context once set.
+
+use core::cell::UnsafeCell;
+use core::hint;
+use core::marker::{Send, Sync};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const UNINITIALIZED: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INITIALIZED: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Once<T> {
+        Once { state: AtomicU8::new(UNINITIALIZED), data: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Initialise the value with `f` if it hasn't been already, and return a
+    /// reference to it.  If another caller is initialising it, wait for them
+    /// to finish; only one call of `f` ever runs.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(UNINITIALIZED, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe { (*self.data.get()).write(f()) };
+            self.state.store(INITIALIZED, Ordering::Release);
+        } else {
+            while self.state.load(Ordering::Acquire) != INITIALIZED {
+                hint::spin_loop();
+            }
+        }
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    /// Return the value if it's been initialised.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == INITIALIZED {
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == INITIALIZED {
+            unsafe { self.data.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn initialised_once() {
+        let once = Once::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test]
+    fn concurrent_call_once() {
+        let once = Once::new();
+        let calls = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (once, calls) = (&once, &calls);
+                s.spawn(move || {
+                    let v = *once.call_once(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        i
+                    });
+                    assert_eq!(once.get(), Some(&v));
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,245 @@
+//! Per-CPU page caches
+//!
+//! A `PageCache` sits in front of a global allocator, such as a locked
+//! `BitmapPageAlloc`, moving pages to and from it in batches so that most
+//! allocations and frees don't need to take its lock.  Each CPU should have its own cache, and must mask
+//! interrupts while using it if interrupt handlers can allocate pages.
+//!
+//! Pages freed into a cache aren't checked against the global allocator until
+//! they're returned to it, so double frees are only caught then.
+
+use crate::bitmapalloc::{BitmapPageAlloc, BitmapPageAllocError};
+use crate::collections::FixedVec;
+use crate::mcslock::{Lock, LockNode};
+use crate::mem::PhysAddr;
+
+/// The allocator behind a `PageCache`.  Pages move to and from it in
+/// batches, so an allocator behind a lock need only take it once per batch.
+pub trait PageSource {
+    /// Allocate up to `count` pages, passing each to `f`.  Only fails if no
+    /// pages at all could be had.
+    fn allocate_batch(
+        &self,
+        count: usize,
+        f: impl FnMut(PhysAddr),
+    ) -> Result<(), BitmapPageAllocError>;
+
+    /// Free each of `pages`, stopping at the first error.
+    fn deallocate_batch(
+        &self,
+        pages: impl Iterator<Item = PhysAddr>,
+    ) -> Result<(), BitmapPageAllocError>;
+}
+
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> PageSource
+    for Lock<BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>>
+{
+    fn allocate_batch(
+        &self,
+        count: usize,
+        mut f: impl FnMut(PhysAddr),
+    ) -> Result<(), BitmapPageAllocError> {
+        let node = LockNode::new();
+        let mut lock = self.lock(&node);
+        for i in 0..count {
+            match lock.allocate() {
+                Ok(pa) => f(pa),
+                Err(_) if i > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn deallocate_batch(
+        &self,
+        pages: impl Iterator<Item = PhysAddr>,
+    ) -> Result<(), BitmapPageAllocError> {
+        let node = LockNode::new();
+        let mut lock = self.lock(&node);
+        for pa in pages {
+            lock.deallocate(pa)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct PageCache<const N: usize> {
+    pages: FixedVec<PhysAddr, N>,
+}
+
+impl<const N: usize> PageCache<N> {
+    /// Number of pages moved to or from the global allocator at once
+    const BATCH: usize = N / 2;
+
+    pub const fn new() -> Self {
+        assert!(N >= 2);
+        Self { pages: FixedVec::new() }
+    }
+
+    /// Take a page from the cache, first refilling it from the global
+    /// allocator if it's empty.
+    pub fn allocate(&mut self, global: &impl PageSource) -> Result<PhysAddr, BitmapPageAllocError> {
+        if self.pages.is_empty() {
+            global.allocate_batch(Self::BATCH, |pa| {
+                let _ = self.pages.push(pa);
+            })?;
+        }
+        self.pages.pop().ok_or(BitmapPageAllocError::OutOfSpace)
+    }
+
+    /// Put a page in the cache, first returning a batch to the global
+    /// allocator if it's full.
+    pub fn deallocate(
+        &mut self,
+        global: &impl PageSource,
+        pa: PhysAddr,
+    ) -> Result<(), BitmapPageAllocError> {
+        if self.pages.is_full() {
+            self.drain(global, Self::BATCH)?;
+        }
+        self.pages.push(pa).map_err(|_| BitmapPageAllocError::OutOfSpace)
+    }
+
+    /// Return all the cached pages to the global allocator.
+    pub fn flush(&mut self, global: &impl PageSource) -> Result<(), BitmapPageAllocError> {
+        self.drain(global, self.pages.len())
+    }
+
+    /// Number of pages in the cache
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    fn drain(
+        &mut self,
+        global: &impl PageSource,
+        count: usize,
+    ) -> Result<(), BitmapPageAllocError> {
+        global.deallocate_batch(core::iter::from_fn(|| self.pages.pop()).take(count))
+    }
+}
+
+impl<const N: usize> Default for PageCache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::PhysRange;
+    use core::cell::Cell;
+
+    /// 128 pages of 4 bytes, all free, counting the batches moved
+    struct CountingSource {
+        global: Lock<BitmapPageAlloc<1, 16>>,
+        batches: Cell<usize>,
+    }
+
+    impl CountingSource {
+        fn new() -> Self {
+            let mut alloc = BitmapPageAlloc::<1, 16>::new_all_allocated(4);
+            alloc.mark_free(&PhysRange::with_end(0, 512)).unwrap();
+            Self { global: Lock::new("global", alloc), batches: Cell::new(0) }
+        }
+
+        fn usage_bytes(&self) -> (usize, usize) {
+            let node = LockNode::new();
+            let lock = self.global.lock(&node);
+            lock.usage_bytes()
+        }
+    }
+
+    impl PageSource for CountingSource {
+        fn allocate_batch(
+            &self,
+            count: usize,
+            f: impl FnMut(PhysAddr),
+        ) -> Result<(), BitmapPageAllocError> {
+            self.batches.set(self.batches.get() + 1);
+            self.global.allocate_batch(count, f)
+        }
+
+        fn deallocate_batch(
+            &self,
+            pages: impl Iterator<Item = PhysAddr>,
+        ) -> Result<(), BitmapPageAllocError> {
+            self.batches.set(self.batches.get() + 1);
+            self.global.deallocate_batch(pages)
+        }
+    }
+
+    #[test]
+    fn refill_and_drain() -> Result<(), BitmapPageAllocError> {
+        let global = CountingSource::new();
+        let mut cache = PageCache::<8>::new();
+
+        // Pages are taken from the global allocator in batches of 4
+        let mut pages = Vec::new();
+        for _ in 0..9 {
+            pages.push(cache.allocate(&global)?);
+        }
+        assert_eq!(global.batches.get(), 3);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(global.usage_bytes(), (48, 512));
+
+        // Freeing into a cache with room doesn't touch the global allocator
+        for pa in pages.drain(..5) {
+            cache.deallocate(&global, pa)?;
+        }
+        assert_eq!(cache.len(), 8);
+        assert_eq!(global.batches.get(), 3);
+
+        // Once full, a batch goes back to make room
+        cache.deallocate(&global, pages.pop().unwrap())?;
+        assert_eq!(cache.len(), 5);
+        assert_eq!(global.batches.get(), 4);
+        assert_eq!(global.usage_bytes(), (32, 512));
+
+        // Only the 3 pages still in use are left allocated
+        cache.flush(&global)?;
+        assert!(cache.is_empty());
+        assert_eq!(global.usage_bytes(), (12, 512));
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_space() -> Result<(), BitmapPageAllocError> {
+        let global = CountingSource::new();
+        let mut cache = PageCache::<16>::new();
+        for _ in 0..128 {
+            cache.allocate(&global)?;
+        }
+        assert_eq!(cache.allocate(&global), Err(BitmapPageAllocError::OutOfSpace));
+        Ok(())
+    }
+
+    #[test]
+    fn fewer_global_locks() -> Result<(), BitmapPageAllocError> {
+        // A workload that allocates and frees a few pages at a time would
+        // take the global lock for every page without a cache
+        const ROUNDS: usize = 1000;
+        const PAGES_PER_ROUND: usize = 8;
+        let uncached_locks = ROUNDS * PAGES_PER_ROUND * 2;
+
+        let global = CountingSource::new();
+        let mut cache = PageCache::<32>::new();
+        let mut pages = [PhysAddr::new(0); PAGES_PER_ROUND];
+        for _ in 0..ROUNDS {
+            for pa in pages.iter_mut() {
+                *pa = cache.allocate(&global)?;
+            }
+            for pa in pages {
+                cache.deallocate(&global, pa)?;
+            }
+        }
+        assert!(global.batches.get() * 100 < uncached_locks);
+        Ok(())
+    }
+}
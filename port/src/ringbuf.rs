@@ -0,0 +1,256 @@
+//! Fixed capacity ring buffers
+//!
+//! `RingBuf` is a plain FIFO for use behind a lock or from a single context.
+//! `AtomicRingBuf` can be shared between a single producer and a single
+//! consumer, e.g. an interrupt handler and the code draining it, without a
+//! lock.  Neither allocates.
+//!
+//! Both track head and tail as positions modulo 2N rather than N, so full and
+//! empty can be told apart without wasting a slot.
+
+use core::cell::UnsafeCell;
+use core::marker::{Send, Sync};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of elements between head and tail, where both are positions
+/// modulo 2N.
+const fn distance<const N: usize>(head: usize, tail: usize) -> usize {
+    (tail + 2 * N - head) % (2 * N)
+}
+
+pub struct RingBuf<T, const N: usize> {
+    data: [MaybeUninit<T>; N],
+    head: usize,
+    tail: usize,
+}
+
+impl<T, const N: usize> RingBuf<T, N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "RingBuf capacity must be non-zero") };
+        Self { data: [const { MaybeUninit::uninit() }; N], head: 0, tail: 0 }
+    }
+
+    /// Add a value to the back of the buffer.  Returns false, dropping the
+    /// value, if the buffer is full.
+    pub fn push(&mut self, val: T) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.data[self.tail % N].write(val);
+        self.tail = (self.tail + 1) % (2 * N);
+        true
+    }
+
+    /// Remove the value at the front of the buffer, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let val = unsafe { self.data[self.head % N].assume_init_read() };
+        self.head = (self.head + 1) % (2 * N);
+        Some(val)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    pub fn len(&self) -> usize {
+        distance::<N>(self.head, self.tail)
+    }
+}
+
+impl<T, const N: usize> Default for RingBuf<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for RingBuf<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// Lock-free single-producer single-consumer ring buffer.  The producer owns
+/// `tail` and the consumer owns `head`; each only reads the other's.
+pub struct AtomicRingBuf<T, const N: usize> {
+    data: UnsafeCell<[MaybeUninit<T>; N]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Send for AtomicRingBuf<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for AtomicRingBuf<T, N> {}
+
+impl<T, const N: usize> AtomicRingBuf<T, N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "AtomicRingBuf capacity must be non-zero") };
+        Self {
+            data: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a value to the back of the buffer.  Returns false, dropping the
+    /// value, if the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// Only one producer may push at a time.
+    pub unsafe fn push(&self, val: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if distance::<N>(head, tail) == N {
+            return false;
+        }
+        unsafe { (*self.data.get())[tail % N].write(val) };
+        self.tail.store((tail + 1) % (2 * N), Ordering::Release);
+        true
+    }
+
+    /// Remove the value at the front of the buffer, if any.
+    ///
+    /// # Safety
+    ///
+    /// Only one consumer may pop at a time.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let val = unsafe { (*self.data.get())[head % N].assume_init_read() };
+        self.head.store((head + 1) % (2 * N), Ordering::Release);
+        Some(val)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Number of values in the buffer.  This may be out of date by the time
+    /// it's returned if the producer or consumer is running concurrently.
+    pub fn len(&self) -> usize {
+        distance::<N>(self.head.load(Ordering::Acquire), self.tail.load(Ordering::Acquire))
+    }
+}
+
+impl<T, const N: usize> Default for AtomicRingBuf<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for AtomicRingBuf<T, N> {
+    fn drop(&mut self) {
+        // We have exclusive access, so we're both producer and consumer
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn push_and_pop() {
+        let mut rb = RingBuf::<u32, 3>::new();
+        assert!(rb.is_empty());
+        assert_eq!(rb.pop(), None);
+
+        assert!(rb.push(1));
+        assert!(rb.push(2));
+        assert!(rb.push(3));
+        assert!(rb.is_full());
+        assert!(!rb.push(4));
+        assert_eq!(rb.len(), 3);
+
+        assert_eq!(rb.pop(), Some(1));
+        assert!(rb.push(4));
+        assert_eq!(rb.pop(), Some(2));
+        assert_eq!(rb.pop(), Some(3));
+        assert_eq!(rb.pop(), Some(4));
+        assert_eq!(rb.pop(), None);
+        assert!(rb.is_empty());
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut rb = RingBuf::<usize, 3>::new();
+        for i in 0..20 {
+            assert!(rb.push(i));
+            assert!(rb.push(i + 100));
+            assert_eq!(rb.len(), 2);
+            assert_eq!(rb.pop(), Some(i));
+            assert_eq!(rb.pop(), Some(i + 100));
+        }
+    }
+
+    #[test]
+    fn drops_remaining_values() {
+        let val = Rc::new(());
+        {
+            let mut rb = RingBuf::<Rc<()>, 4>::new();
+            rb.push(val.clone());
+            rb.push(val.clone());
+            assert_eq!(Rc::strong_count(&val), 3);
+        }
+        assert_eq!(Rc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn atomic_spsc() {
+        const COUNT: usize = 100_000;
+        let rb = AtomicRingBuf::<usize, 16>::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..COUNT {
+                    while !unsafe { rb.push(i) } {
+                        thread::yield_now();
+                    }
+                }
+            });
+            s.spawn(|| {
+                // Values must arrive in order with none lost
+                for i in 0..COUNT {
+                    loop {
+                        if let Some(v) = unsafe { rb.pop() } {
+                            assert_eq!(v, i);
+                            break;
+                        }
+                        thread::yield_now();
+                    }
+                }
+            });
+        });
+        assert!(rb.is_empty());
+    }
+
+    #[test]
+    fn atomic_full_and_empty() {
+        let rb = AtomicRingBuf::<u8, 2>::new();
+        unsafe {
+            assert_eq!(rb.pop(), None);
+            assert!(rb.push(1));
+            assert!(rb.push(2));
+            assert!(rb.is_full());
+            assert!(!rb.push(3));
+            assert_eq!(rb.pop(), Some(1));
+            assert_eq!(rb.pop(), Some(2));
+        }
+        assert!(rb.is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+//! Reader-writer spin lock
+//!
+//! Any number of readers may hold the lock at once, or a single writer.
+//! Writers take priority: once a writer is waiting, new readers wait until
+//! it has been and gone, so a steady stream of readers can't starve it.
+//! This also means a reader mustn't take the read lock again while holding
+//! it, as a writer arriving in between would deadlock both.
+
+use core::cell::UnsafeCell;
+use core::hint;
+use core::marker::{Send, Sized, Sync};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Set in the state when a writer holds the lock.  The rest of the state is
+/// the number of readers.
+const WRITER: u32 = 1 << 31;
+
+pub struct RwLock<T: ?Sized> {
+    _name: &'static str,
+    state: AtomicU32,
+    waiting_writers: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(name: &'static str, data: T) -> RwLock<T> {
+        RwLock {
+            _name: name,
+            state: AtomicU32::new(0),
+            waiting_writers: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take the lock for reading, waiting while a writer holds it or is
+    /// waiting for it.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0
+                && self.waiting_writers.load(Ordering::Relaxed) == 0
+                && self
+                    .state
+                    .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return RwLockReadGuard { lock: self };
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Take the lock for writing, waiting for any readers or writer holding
+    /// it to release it.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        RwLockWriteGuard { lock: self }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn concurrent_readers() {
+        // All the readers must hold the lock at the same time to get past
+        // the barrier
+        let lock = RwLock::new("test", 42);
+        let barrier = Barrier::new(4);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let data = lock.read();
+                    barrier.wait();
+                    assert_eq!(*data, 42);
+                });
+            }
+        });
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn writer_is_exclusive() {
+        let lock = RwLock::new("test", 0u64);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut data = lock.write();
+                        // Non-atomic read-modify-write, so lost updates would
+                        // show up in the total
+                        let v = *data;
+                        hint::spin_loop();
+                        *data = v + 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let data = lock.read();
+                        assert_eq!(lock.state.load(Ordering::Relaxed) & WRITER, 0);
+                        let _ = *data;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.read(), 4000);
+    }
+
+    #[test]
+    fn waiting_writer_blocks_new_readers() {
+        let lock = RwLock::new("test", 0);
+        let reader = lock.read();
+        thread::scope(|s| {
+            s.spawn(|| *lock.write() = 1);
+            while lock.waiting_writers.load(Ordering::Relaxed) == 0 {
+                hint::spin_loop();
+            }
+            s.spawn(|| {
+                // Only gets in once the writer is done
+                assert_eq!(*lock.read(), 1);
+            });
+            drop(reader);
+        });
+    }
+}
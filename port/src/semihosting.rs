@@ -0,0 +1,77 @@
+//! Semihosting, for talking to QEMU (or a debugger) when it's enabled with
+//! `-semihosting`.  The operations are the same on every architecture, and
+//! only the instructions that trap to the host differ, so each port provides
+//! those as Semihosting::call.  Without semihosting the trap isn't handled,
+//! so these must only be used when we know semihosting is available.
+
+const SYS_WRITE0: usize = 0x04;
+const SYS_EXIT: usize = 0x18;
+
+/// Reason passed to `SYS_EXIT` for a normal exit with a status
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Longest chunk of a string written with a single `SYS_WRITE0`
+const WRITE_CHUNK: usize = 64;
+
+pub trait Semihosting {
+    /// Make a semihosting call with a pointer to its parameters, which the
+    /// host reads, returning the host's result
+    fn call(op: usize, param: *const u8) -> usize;
+
+    /// Exit with the given status, which QEMU uses as its own exit status
+    fn exit(code: u32) -> ! {
+        let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, code as u64];
+        Self::call(SYS_EXIT, block.as_ptr().cast());
+
+        // Only reached if the host didn't exit
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+
+    /// Write a string to the host's console.  `SYS_WRITE0` takes a NUL
+    /// terminated string, so it's copied in chunks into a terminated buffer.
+    /// Output stops at any NUL in s.
+    fn write_str(s: &str) {
+        let mut buf = [0u8; WRITE_CHUNK + 1];
+        for chunk in s.as_bytes().chunks(WRITE_CHUNK) {
+            buf[..chunk.len()].copy_from_slice(chunk);
+            buf[chunk.len()] = 0;
+            Self::call(SYS_WRITE0, buf.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::ffi::CStr;
+
+    thread_local! {
+        static WRITES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records the strings passed to SYS_WRITE0
+    struct MockHost;
+
+    impl Semihosting for MockHost {
+        fn call(op: usize, param: *const u8) -> usize {
+            assert_eq!(op, SYS_WRITE0);
+            let s = unsafe { CStr::from_ptr(param.cast()) };
+            WRITES.with_borrow_mut(|w| w.push(s.to_str().unwrap().to_string()));
+            0
+        }
+    }
+
+    #[test]
+    fn write_str_in_terminated_chunks() {
+        let s = "0123456789".repeat(13);
+        MockHost::write_str(&s);
+        let writes = WRITES.take();
+        assert_eq!(writes.iter().map(String::len).collect::<Vec<_>>(), [64, 64, 2]);
+        assert_eq!(writes.concat(), s);
+
+        MockHost::write_str("");
+        assert!(WRITES.take().is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+//! System call dispatch.
+//!
+//! Each arch has its own way into the kernel, which passes the syscall
+//! number and up to 6 arguments to `dispatch`, and returns its result to
+//! the caller.  Negative results indicate an error.
+
+use crate::devcons::Console;
+use crate::println;
+use num_enum::TryFromPrimitive;
+
+/// Unknown syscall number
+pub const ENOSYS: isize = -1;
+/// Bad file descriptor
+pub const EBADF: isize = -2;
+
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(usize)]
+pub enum Syscall {
+    Exit = 0,
+    Write = 1,
+    GetPid = 2,
+}
+
+type SyscallHandler = fn(&[usize; 6]) -> isize;
+
+/// Handlers, indexed by Syscall
+static SYSCALLS: [SyscallHandler; 3] = [sys_exit, sys_write, sys_getpid];
+
+/// Call the handler for syscall num with the given arguments
+pub fn dispatch(num: usize, args: &[usize; 6]) -> isize {
+    Syscall::try_from(num).map_or(ENOSYS, |syscall| SYSCALLS[syscall as usize](args))
+}
+
+/// exit(status)
+/// There's no process to return to yet, so just stop here.
+fn sys_exit(args: &[usize; 6]) -> isize {
+    println!("exit({})", args[0] as isize);
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+/// write(fd, buf, len)
+/// Only stdout and stderr are supported, and both go to the console.
+fn sys_write(args: &[usize; 6]) -> isize {
+    let [fd, buf, len, ..] = *args;
+    if fd != STDOUT && fd != STDERR {
+        return EBADF;
+    }
+    if len == 0 {
+        return 0;
+    }
+    // TODO Validate the buffer once we have user address spaces
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    let mut cons = Console {};
+    cons.putbytes(bytes);
+    len as isize
+}
+
+/// getpid()
+/// Everything runs as the kernel for now, which is pid 0.
+fn sys_getpid(_args: &[usize; 6]) -> isize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_syscalls() {
+        assert_eq!(dispatch(Syscall::GetPid as usize, &[0; 6]), 0);
+        assert_eq!(dispatch(Syscall::Write as usize, &[0, 0, 0, 0, 0, 0]), EBADF);
+        assert_eq!(dispatch(Syscall::Write as usize, &[STDOUT, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(dispatch(1000, &[0; 6]), ENOSYS);
+    }
+}
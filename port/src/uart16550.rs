@@ -0,0 +1,256 @@
+//! Registers and line handling for 8250/16550 compatible UARTs.
+//!
+//! The registers and how they're used are the same everywhere, but how
+//! they're reached isn't: x86_64 uses port I/O, and other machines MMIO,
+//! sometimes with each register in its own word.  Each port provides that
+//! access by implementing Uart16550Regs.
+
+use bitstruct::bitstruct;
+use core::fmt;
+
+use crate::devcons::Uart;
+use crate::maths::uart16550_divisor;
+
+// Register numbers.  Some share a number, and are selected by the direction
+// of the access or by the divisor latch access bit (DLAB) in LCR.
+pub const RBR: usize = 0; // Receive buffer (read)
+pub const THR: usize = 0; // Transmit holding (write)
+pub const DLL: usize = 0; // Divisor latch low (DLAB set)
+pub const IER: usize = 1; // Interrupt enable
+pub const DLM: usize = 1; // Divisor latch high (DLAB set)
+pub const FCR: usize = 2; // FIFO control (write)
+pub const LCR: usize = 3; // Line control
+pub const MCR: usize = 4; // Modem control
+pub const LSR: usize = 5; // Line status
+pub const SCR: usize = 7; // Scratch
+
+/// 8 data bits, no parity, 1 stop bit
+pub const LCR_8N1: u8 = 0x03;
+/// Divisor latch access bit
+pub const LCR_DLAB: u8 = 0x80;
+/// Enable and clear both FIFOs, interrupting on every received byte
+pub const FCR_ENABLE_CLEAR: u8 = 0x07;
+/// Assert DTR and RTS
+pub const MCR_DTR_RTS: u8 = 0x03;
+/// Interrupt when received data is available
+pub const IER_RX_AVAILABLE: u8 = 0x01;
+
+bitstruct! {
+    /// Line Status Register
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct LineStatus(pub u8) {
+        pub data_ready: bool = 0;
+        pub overrun_error: bool = 1;
+        pub parity_error: bool = 2;
+        pub framing_error: bool = 3;
+        pub break_interrupt: bool = 4;
+        pub thr_empty: bool = 5;
+        pub transmitter_empty: bool = 6;
+        pub fifo_error: bool = 7;
+    }
+}
+
+impl LineStatus {
+    /// True if any receive errors have occurred since the LSR was last read
+    pub fn has_error(&self) -> bool {
+        self.overrun_error() || self.parity_error() || self.framing_error() || self.fifo_error()
+    }
+}
+
+impl fmt::Debug for LineStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LineStatus")
+            .field("data_ready", &self.data_ready())
+            .field("overrun_error", &self.overrun_error())
+            .field("parity_error", &self.parity_error())
+            .field("framing_error", &self.framing_error())
+            .field("break_interrupt", &self.break_interrupt())
+            .field("thr_empty", &self.thr_empty())
+            .field("transmitter_empty", &self.transmitter_empty())
+            .field("fifo_error", &self.fifo_error())
+            .finish()
+    }
+}
+
+/// Access to a UART's registers, by register number
+pub trait Uart16550Regs {
+    fn read(&self, reg: usize) -> u8;
+    fn write(&self, reg: usize, val: u8);
+
+    /// Wait until LCR can be written.  Most UARTs accept writes at any time,
+    /// so by default this doesn't wait.
+    fn wait_lcr_writable(&self) {}
+}
+
+pub struct Uart16550<R> {
+    regs: R,
+}
+
+impl<R: Uart16550Regs> Uart16550<R> {
+    pub const fn new(regs: R) -> Self {
+        Uart16550 { regs }
+    }
+
+    pub fn regs(&self) -> &R {
+        &self.regs
+    }
+
+    /// Whether there's a UART there.  Reads with nothing there usually return
+    /// 0xff, so this checks a value written to the scratch register reads
+    /// back.
+    pub fn is_present(&self) -> bool {
+        self.regs.write(SCR, 0x5a);
+        self.regs.read(SCR) == 0x5a
+    }
+
+    /// Set the UART to 8N1 at baud, given its input clock, with the FIFOs
+    /// enabled and cleared, DTR and RTS asserted, and the interrupts in ier
+    /// enabled.
+    pub fn init(&self, clock_hz: u32, baud: u32, ier: u8) {
+        let divisor = uart16550_divisor(clock_hz, baud);
+        self.regs.write(IER, 0);
+        self.regs.write(FCR, FCR_ENABLE_CLEAR);
+        self.regs.wait_lcr_writable();
+        self.regs.write(LCR, LCR_8N1 | LCR_DLAB);
+        self.regs.write(DLL, divisor as u8);
+        self.regs.write(DLM, (divisor >> 8) as u8);
+        self.regs.write(LCR, LCR_8N1);
+        self.regs.write(MCR, MCR_DTR_RTS);
+        self.regs.write(IER, ier);
+    }
+
+    /// Read the line status.  Note that reading clears the error bits.
+    pub fn line_status(&self) -> LineStatus {
+        LineStatus(self.regs.read(LSR))
+    }
+
+    /// Wait until the transmit holding register is empty, then send b
+    pub fn write_byte(&self, b: u8) {
+        while !self.line_status().thr_empty() {
+            core::hint::spin_loop();
+        }
+        self.regs.write(THR, b);
+    }
+
+    /// True if there's a received byte waiting
+    pub fn has_input(&self) -> bool {
+        self.line_status().data_ready()
+    }
+
+    /// Return a received byte if one is available
+    pub fn try_getc(&self) -> Option<u8> {
+        if self.has_input() {
+            Some(self.regs.read(RBR))
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: Uart16550Regs> Uart for Uart16550<R> {
+    fn putb(&self, b: u8) {
+        self.write_byte(b);
+    }
+
+    fn try_getb(&self) -> Option<u8> {
+        self.try_getc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Registers in memory, with the divisor latch behind DLAB and a log of
+    /// the writes
+    #[derive(Default)]
+    struct MockRegs {
+        regs: RefCell<[u8; 8]>,
+        divisor: RefCell<[u8; 2]>,
+        writes: RefCell<Vec<(usize, u8)>>,
+    }
+
+    impl MockRegs {
+        fn dlab(&self) -> bool {
+            self.regs.borrow()[LCR] & LCR_DLAB != 0
+        }
+    }
+
+    impl Uart16550Regs for MockRegs {
+        fn read(&self, reg: usize) -> u8 {
+            match reg {
+                DLL | DLM if self.dlab() => self.divisor.borrow()[reg],
+                _ => self.regs.borrow()[reg],
+            }
+        }
+
+        fn write(&self, reg: usize, val: u8) {
+            self.writes.borrow_mut().push((reg, val));
+            match reg {
+                DLL | DLM if self.dlab() => self.divisor.borrow_mut()[reg] = val,
+                _ => self.regs.borrow_mut()[reg] = val,
+            }
+        }
+    }
+
+    #[test]
+    fn decode_line_status() {
+        let lsr = LineStatus(0x61);
+        assert!(lsr.data_ready());
+        assert!(lsr.thr_empty());
+        assert!(!lsr.has_error());
+
+        let lsr = LineStatus(0x0e);
+        assert!(!lsr.data_ready());
+        assert!(lsr.overrun_error());
+        assert!(lsr.parity_error());
+        assert!(lsr.framing_error());
+        assert!(lsr.has_error());
+    }
+
+    #[test]
+    fn init_sets_divisor_and_line() {
+        let uart = Uart16550::new(MockRegs::default());
+        assert!(uart.is_present());
+
+        uart.init(1_843_200, 9600, IER_RX_AVAILABLE);
+        assert_eq!(*uart.regs().divisor.borrow(), [12, 0]);
+        let regs = *uart.regs().regs.borrow();
+        assert_eq!(regs[LCR], LCR_8N1);
+        assert_eq!(regs[FCR], FCR_ENABLE_CLEAR);
+        assert_eq!(regs[MCR], MCR_DTR_RTS);
+        assert_eq!(regs[IER], IER_RX_AVAILABLE);
+
+        // Interrupts are off while the divisor is changed
+        let writes = uart.regs().writes.borrow();
+        let dlab = writes.iter().position(|&w| w == (LCR, LCR_8N1 | LCR_DLAB)).unwrap();
+        assert_eq!(writes.iter().position(|&w| w == (IER, 0)), Some(1));
+        assert!(dlab > 1);
+    }
+
+    #[test]
+    fn polled_io() {
+        let uart = Uart16550::new(MockRegs::default());
+        let set = |reg: usize, val: u8| uart.regs().regs.borrow_mut()[reg] = val;
+        assert!(!uart.has_input());
+        assert_eq!(uart.try_getc(), None);
+
+        set(LSR, 0x20); // THR empty
+        uart.write_byte(b'r');
+        assert_eq!(uart.regs().regs.borrow()[THR], b'r');
+
+        set(RBR, b'x');
+        set(LSR, 0x61); // Data ready, THR and transmitter empty
+        assert!(uart.has_input());
+        assert_eq!(uart.try_getc(), Some(b'x'));
+        assert!(!uart.line_status().has_error());
+
+        set(LSR, 0x0a); // Overrun and framing errors
+        let lsr = uart.line_status();
+        assert!(lsr.has_error());
+        assert!(lsr.overrun_error());
+        assert!(lsr.framing_error());
+        assert_eq!(uart.try_getc(), None);
+    }
+}
@@ -1,6 +1,7 @@
-use port::fdt::{DeviceTree, Range, RangeMapping, RegBlock, TranslatedReg};
+use port::fdt::{CpuNode, DeviceTree, Range, RangeMapping, RegBlock, TranslatedReg};
 
 static TEST1_DTB: &[u8] = include_bytes!("../lib/test/fdt/test1.dtb");
+static TEST2_DTB: &[u8] = include_bytes!("../lib/test/fdt/test2.dtb");
 
 #[test]
 fn find_by_path() {
@@ -21,6 +22,63 @@ fn find_by_path() {
     assert_eq!(dt.find_by_path("/reserved-memory/foo"), None);
 }
 
+#[test]
+fn find_node_by_full_path() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    assert_eq!(dt.find_node_by_full_path("/"), dt.root());
+
+    let cpu0 = dt.find_node_by_full_path("/cpus/cpu@0").unwrap();
+    assert_eq!(dt.node_name(&cpu0).unwrap(), "cpu@0");
+    assert_eq!(dt.node_name(&dt.parent(&cpu0).unwrap()).unwrap(), "cpus");
+
+    // stdout-path style, with options and aliases
+    let uart = dt.find_by_path("/soc/serial@7e215040");
+    assert!(uart.is_some());
+    assert_eq!(dt.find_node_by_full_path("/soc/serial@7e215040:115200n8"), uart);
+    assert_eq!(dt.find_node_by_full_path("serial0"), uart);
+    assert_eq!(dt.find_node_by_full_path("serial0:115200n8"), uart);
+    let uart1 = dt.find_node_by_full_path("/soc/serial@7e201000");
+    assert!(uart1.is_some());
+    assert_eq!(dt.find_node_by_full_path("serial1"), uart1);
+
+    assert_eq!(dt.find_node_by_full_path("/cpus/cpu@9"), None);
+    assert_eq!(dt.find_node_by_full_path("nosuchalias"), None);
+}
+
+#[test]
+fn full_path() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    let root = dt.root().unwrap();
+    assert_eq!(dt.full_path(&root).unwrap().to_string(), "/");
+
+    let cpu0 = dt.find_by_path("/cpus/cpu@0").unwrap();
+    assert_eq!(dt.full_path(&cpu0).unwrap().to_string(), "/cpus/cpu@0");
+
+    let cma = dt.find_by_path("/reserved-memory/linux,cma").unwrap();
+    assert_eq!(dt.full_path(&cma).unwrap().to_string(), "/reserved-memory/linux,cma");
+
+    // Round trips through find_node_by_full_path
+    let uart = dt.find_node_by_full_path("serial0").unwrap();
+    let path = dt.full_path(&uart).unwrap().to_string();
+    assert_eq!(path, "/soc/serial@7e215040");
+    assert_eq!(dt.find_node_by_full_path(&path), Some(uart));
+}
+
+#[test]
+fn cpu_nodes() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    let cpus = dt.cpu_nodes().collect::<Vec<_>>();
+    assert_eq!(
+        cpus,
+        (0..4)
+            .map(|hart_id| CpuNode { hart_id, compatible: Some("arm,cortex-a53") })
+            .collect::<Vec<_>>()
+    );
+}
+
 #[test]
 fn traverse_tree() {
     let dt = DeviceTree::new(TEST1_DTB).unwrap();
@@ -29,7 +87,7 @@ fn traverse_tree() {
     assert_eq!(dt.node_name(&root).unwrap(), "");
     assert_eq!(root.depth(), 0);
 
-    let aliases = dt.children(&root).nth(0).unwrap();
+    let aliases = dt.children(&root).next().unwrap();
     assert_eq!(dt.node_name(&aliases).unwrap(), "aliases");
     assert_eq!(aliases.depth(), 1);
 
@@ -187,3 +245,58 @@ fn get_translated_reg() {
         vec![TranslatedReg::Translated(RegBlock { addr: 0x3f20_1000, len: Some(0x200) })]
     );
 }
+
+#[test]
+fn memory_nodes() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    // The size is left for the firmware to fill in, and reserved-memory
+    // isn't RAM
+    assert_eq!(dt.memory_nodes().collect::<Vec<_>>(), vec![(0, 0)]);
+}
+
+#[test]
+fn memory_ranges() {
+    // Empty banks are skipped
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    assert_eq!(dt.memory_ranges().count(), 0);
+
+    // Two memory nodes, the second with two banks
+    let dt = DeviceTree::new(TEST2_DTB).unwrap();
+    assert_eq!(
+        dt.memory_ranges().map(|r| (r.start().addr(), r.size())).collect::<Vec<_>>(),
+        vec![
+            (0x8000_0000, 0x4000_0000),
+            (0x1_0000_0000, 0x2000_0000),
+            (0x2_0000_0000, 0x1000_0000)
+        ]
+    );
+}
+
+#[test]
+fn property_value_as_u64() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    // cpu-release-addr = <0x00 0xe0>
+    let cpu1 = dt.find_by_path("/cpus/cpu@1").unwrap();
+    let release_addr = dt.property(&cpu1, "cpu-release-addr").unwrap();
+    assert_eq!(dt.property_value_as_u64(&release_addr), Some(0xe0));
+
+    // Single cell values are too short
+    let reg = dt.property(&cpu1, "reg").unwrap();
+    assert_eq!(dt.property_value_as_u64(&reg), None);
+}
+
+#[test]
+fn property_value_as_str() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+
+    let aliases = dt.find_by_path("/aliases").unwrap();
+    let serial0 = dt.property(&aliases, "serial0").unwrap();
+    assert_eq!(dt.property_value_as_str(&serial0), Some("/soc/serial@7e215040"));
+
+    // Only the first string of a string list is returned
+    let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
+    let compatible = dt.property(&uart, "compatible").unwrap();
+    assert_eq!(dt.property_value_as_str(&compatible), Some("arm,pl011"));
+}
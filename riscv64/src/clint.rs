@@ -0,0 +1,118 @@
+//! Core Local Interruptor
+//!
+//! The CLINT provides the per-hart machine timer compare registers
+//! (mtimecmp) and software interrupt pending bits (msip), along with the
+//! shared mtime counter.  The registers are only writable from M-mode, and
+//! r9 runs in S-mode under SBI firmware, so the tick itself is programmed via
+//! SBI set_timer and taken as a supervisor timer interrupt.
+
+#![allow(dead_code)]
+
+use crate::sbi;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use port::fdt::{DeviceTree, RegBlock};
+
+const MSIP_BASE: usize = 0x0;
+const MTIMECMP_BASE: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+/// Used if the devicetree doesn't have a timebase-frequency
+const DEFAULT_TIMEBASE_HZ: u64 = 10_000_000;
+
+/// Number of timer ticks taken since the timer was started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interval in timebase units
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clint {
+    reg: RegBlock,
+}
+
+impl Clint {
+    /// Find the CLINT in the devicetree
+    pub fn from_dt(dt: &DeviceTree) -> Option<Clint> {
+        dt.find_compatible("riscv,clint0")
+            .next()
+            .or_else(|| dt.find_compatible("sifive,clint0").next())
+            .and_then(|clint| dt.property_translated_reg_iter(clint).next())
+            .and_then(|reg| reg.regblock())
+            .map(|reg| Clint { reg })
+    }
+
+    fn addr(&self, offset: usize) -> usize {
+        self.reg.addr as usize + offset
+    }
+
+    /// Set the machine timer compare register for the hart.  M-mode only.
+    pub fn set_timer(&self, hart: usize, deadline: u64) {
+        let ptr = self.addr(MTIMECMP_BASE + hart * 8) as *mut u64;
+        unsafe { write_volatile(ptr, deadline) }
+    }
+
+    /// Clear the machine software interrupt for the hart.  M-mode only.
+    pub fn clear_msip(&self, hart: usize) {
+        let ptr = self.addr(MSIP_BASE + hart * 4) as *mut u32;
+        unsafe { write_volatile(ptr, 0) }
+    }
+
+    /// Read the machine timer counter
+    pub fn mtime(&self) -> u64 {
+        let ptr = self.addr(MTIME) as *const u64;
+        unsafe { read_volatile(ptr) }
+    }
+}
+
+/// Return the frequency of the time counter from the devicetree
+pub fn timebase_frequency(dt: &DeviceTree) -> u64 {
+    dt.find_by_path("/cpus")
+        .and_then(|cpus| dt.property(&cpus, "timebase-frequency"))
+        .and_then(|prop| dt.property_value_as_u32(&prop))
+        .map_or(DEFAULT_TIMEBASE_HZ, |hz| hz as u64)
+}
+
+/// Read the time CSR, which mirrors mtime
+fn rdtime() -> u64 {
+    #[cfg(not(test))]
+    {
+        let time: u64;
+        unsafe { core::arch::asm!("rdtime {time}", time = out(reg) time) };
+        time
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Number of timer ticks taken so far
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Start a periodic timer interrupt, every interval timebase units.
+pub fn start_timer(interval: u64) {
+    TICK_INTERVAL.store(interval, Ordering::Relaxed);
+    sbi::_set_timer((rdtime() + interval) as usize);
+
+    #[cfg(not(test))]
+    unsafe {
+        // Enable supervisor timer interrupts (sie.STIE) and interrupts in
+        // general (sstatus.SIE).  trap::init must have set the trap vector.
+        core::arch::asm!(
+            "li {tmp}, 1 << 5",
+            "csrs sie, {tmp}",
+            "csrsi sstatus, 1 << 1",
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// Called from trap on a supervisor timer interrupt.  Setting the next
+/// deadline clears the pending interrupt.  Nothing here may print: the
+/// console lock isn't taken with interrupts masked, so the interrupted code
+/// could be holding it.
+pub fn timer_handler() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    sbi::_set_timer((rdtime() + TICK_INTERVAL.load(Ordering::Relaxed)) as usize);
+}
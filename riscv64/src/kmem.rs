@@ -0,0 +1,95 @@
+//! Kernel image layout.  The kernel runs at its physical address, so
+//! virtual and physical addresses are the same.
+
+use crate::memory::KERNEL_MODE;
+use port::mem::{PhysAddr, PhysRange};
+
+// These map to definitions in kernel.ld
+extern "C" {
+    static text: [u64; 0];
+    static etext: [u64; 0];
+    static rodata: [u64; 0];
+    static erodata: [u64; 0];
+    static data: [u64; 0];
+    static edata: [u64; 0];
+    static bss: [u64; 0];
+    static end: [u64; 0];
+}
+
+fn text_addr() -> usize {
+    unsafe { text.as_ptr().addr() }
+}
+
+fn etext_addr() -> usize {
+    unsafe { etext.as_ptr().addr() }
+}
+
+fn rodata_addr() -> usize {
+    unsafe { rodata.as_ptr().addr() }
+}
+
+fn erodata_addr() -> usize {
+    unsafe { erodata.as_ptr().addr() }
+}
+
+fn data_addr() -> usize {
+    unsafe { data.as_ptr().addr() }
+}
+
+fn edata_addr() -> usize {
+    unsafe { edata.as_ptr().addr() }
+}
+
+fn bss_addr() -> usize {
+    unsafe { bss.as_ptr().addr() }
+}
+
+fn end_addr() -> usize {
+    unsafe { end.as_ptr().addr() }
+}
+
+pub fn text_range() -> PhysRange {
+    PhysRange(from_virt_to_physaddr(text_addr())..from_virt_to_physaddr(etext_addr()))
+}
+
+pub fn rodata_range() -> PhysRange {
+    PhysRange(from_virt_to_physaddr(rodata_addr())..from_virt_to_physaddr(erodata_addr()))
+}
+
+pub fn data_range() -> PhysRange {
+    PhysRange(from_virt_to_physaddr(data_addr())..from_virt_to_physaddr(edata_addr()))
+}
+
+pub fn bss_range() -> PhysRange {
+    PhysRange(from_virt_to_physaddr(bss_addr())..from_virt_to_physaddr(end_addr()))
+}
+
+/// Lowest physical address that can't be mapped at the same virtual
+/// address, as it would be past the lower half of the kernel's address space
+const MAX_IDENTITY_PA: u64 = 1 << (KERNEL_MODE.va_bits() - 1);
+
+pub const fn physaddr_as_virt(pa: PhysAddr) -> usize {
+    debug_assert!(pa.addr() < MAX_IDENTITY_PA);
+    pa.addr() as usize
+}
+
+/// Virtual address of pa, or None if it's beyond what the kernel can map
+pub const fn physaddr_as_virt_checked(pa: PhysAddr) -> Option<usize> {
+    if pa.addr() < MAX_IDENTITY_PA {
+        Some(pa.addr() as usize)
+    } else {
+        None
+    }
+}
+
+pub const fn physaddr_as_ptr_mut<T>(pa: PhysAddr) -> *mut T {
+    physaddr_as_virt(pa) as *mut T
+}
+
+pub const fn from_virt_to_physaddr(va: usize) -> PhysAddr {
+    PhysAddr::new(va as u64)
+}
+
+pub fn from_ptr_to_physaddr<T>(a: *const T) -> PhysAddr {
+    from_virt_to_physaddr(a.addr())
+}
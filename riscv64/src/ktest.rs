@@ -0,0 +1,53 @@
+//! Kernel tests run under QEMU with `cargo xtask qemu-test`.  See
+//! `port::ktest` for the exit protocol.
+
+extern crate alloc;
+
+use crate::pagealloc;
+use crate::semihosting::Semihost;
+use alloc::vec::Vec;
+use port::ktest::{run_tests, ExitCode, KernelTest};
+use port::semihosting::Semihosting;
+
+const TESTS: &[KernelTest] = &[
+    KernelTest { name: "heap_alloc", run: heap_alloc },
+    KernelTest { name: "page_alloc_usage", run: page_alloc_usage },
+    KernelTest { name: "page_alloc", run: page_alloc },
+];
+
+/// Run the kernel tests and report the result to QEMU.  Never returns, but
+/// isn't `-> !` so the rest of main9 doesn't warn as unreachable.
+pub fn run() {
+    exit(run_tests(TESTS))
+}
+
+/// Exit QEMU through semihosting, with the code as its exit status.  QEMU
+/// must be run with `-semihosting`.
+pub fn exit(code: ExitCode) -> ! {
+    Semihost::exit(code as u32)
+}
+
+fn heap_alloc() {
+    let v: Vec<u64> = (0..1000).collect();
+    assert_eq!(v.iter().sum::<u64>(), 499500);
+}
+
+fn page_alloc_usage() {
+    let (used, total) = pagealloc::usage_bytes();
+    assert!(total > 0);
+    assert!(used < total);
+}
+
+fn page_alloc() {
+    let (used, _) = pagealloc::usage_bytes();
+    let pa = pagealloc::allocate().unwrap();
+    assert_eq!(pa.addr() % 4096, 0);
+    assert_eq!(pagealloc::usage_bytes().0, used + 4096);
+
+    // Freed pages are handed out again
+    pagealloc::deallocate(pa).unwrap();
+    assert_eq!(pagealloc::usage_bytes().0, used);
+    let pa2 = pagealloc::allocate().unwrap();
+    assert_eq!(pa2.addr(), pa.addr());
+    pagealloc::deallocate(pa2).unwrap();
+}
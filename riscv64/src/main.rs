@@ -5,30 +5,171 @@
 #![allow(clippy::upper_case_acronyms)]
 #![forbid(unsafe_op_in_unsafe_fn)]
 
+mod clint;
+mod kmem;
+#[cfg(feature = "qemu_test")]
+mod ktest;
+mod memory;
+mod pagealloc;
 mod platform;
+mod plic;
+mod pmp;
 mod runtime;
 mod sbi;
+#[cfg(feature = "qemu_test")]
+mod semihosting;
+mod trap;
 mod uart16550;
+mod virtio;
 
 use port::println;
 
+use crate::kmem::physaddr_as_virt_checked;
+use crate::memory::PageTable;
 use crate::platform::{devcons, platform_init};
+use crate::virtio::block::{VirtioBlk, SECTOR_SIZE};
+use crate::virtio::mmio::{DeviceType, VirtioMmio};
+use core::cell::SyncUnsafeCell;
 use port::fdt::DeviceTree;
+use port::mem::{PhysAddr, PhysRange};
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("l.S"));
 
+/// Number of secondary hart stacks reserved in l.S
+const MAX_HARTS: usize = 8;
+
+static KERNEL_PAGE_TABLE: SyncUnsafeCell<PageTable> = SyncUnsafeCell::new(PageTable::empty());
+
+/// Registers of the devices the kernel uses, which need mapping
+fn mmio_ranges<'a>(dt: &'a DeviceTree<'a>) -> impl Iterator<Item = PhysRange> + 'a {
+    [
+        "ns16550a",
+        "snps,dw-apb-uart",
+        "riscv,plic0",
+        "sifive,plic-1.0.0",
+        "riscv,clint0",
+        "sifive,clint0",
+        "virtio,mmio",
+    ]
+    .into_iter()
+    .flat_map(|comp| dt.find_compatible(comp))
+    .filter_map(|node| dt.property_translated_reg_iter(node).next())
+    .filter_map(|reg| reg.regblock())
+    .map(|reg| PhysRange::from(&reg))
+}
+
+/// Start all harts in the devicetree other than the boot hart
+fn start_secondary_harts(dt: &DeviceTree, boot_hartid: usize) {
+    #[cfg(not(test))]
+    extern "C" {
+        fn secondary_start();
+    }
+    #[cfg(not(test))]
+    let start_addr = secondary_start as usize;
+    #[cfg(test)]
+    let start_addr = 0;
+
+    for cpu in dt.cpu_nodes() {
+        let hartid = cpu.hart_id as usize;
+        if hartid == boot_hartid {
+            continue;
+        }
+        if hartid >= MAX_HARTS {
+            println!("Not starting HART {hartid}: no stack");
+            continue;
+        }
+        if let Err(err) = sbi::hart_start(hartid, start_addr, 0) {
+            println!("Failed to start HART {hartid}: {err}");
+        }
+    }
+}
+
+/// Check a virtio block device works by reading its first block
+fn read_first_block(dev: VirtioMmio) {
+    let Some(blk) = VirtioBlk::new(dev) else {
+        println!("  couldn't set up block device");
+        return;
+    };
+    let mut buf = [0u8; SECTOR_SIZE];
+    match blk.read_block(0, &mut buf) {
+        Ok(()) => {
+            println!("  {} sectors, block 0 starts:", blk.capacity());
+            port::hexdump_slice(&buf[..32]);
+        }
+        Err(err) => println!("  reading block 0 failed: {err}"),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn secondary_main(hartid: usize) -> ! {
+    println!("HART {hartid} started");
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("wfi")
+        };
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn main9(hartid: usize, dtb_ptr: usize) -> ! {
     let dt = unsafe { DeviceTree::from_usize(dtb_ptr).unwrap() };
     crate::devcons::init(&dt);
-    platform_init();
+    trap::init();
+    platform_init(&dt, hartid);
 
     println!();
     println!("r9 from the Internet");
     println!("Domain0 Boot HART = {hartid}");
     println!("DTB found at: {dtb_ptr:#x}");
 
+    match unsafe { memory::detect_paging_mode() } {
+        Some(mode) => println!("Paging: up to {mode:?}, kernel uses {:?}", memory::KERNEL_MODE),
+        None => panic!("{:?} paging isn't supported", memory::KERNEL_MODE),
+    }
+
+    // Map the kernel, DTB, devices and RAM at their physical addresses, and
+    // turn on paging
+    let kpage_table = unsafe { &mut *KERNEL_PAGE_TABLE.get() };
+    let dtb_range = PhysRange::with_len(dtb_ptr as u64, dt.size());
+    let dtb_last = PhysAddr::new(dtb_range.end().addr() - 1);
+    if physaddr_as_virt_checked(dtb_last).is_none() {
+        panic!("DTB {dtb_range} is beyond the kernel's address space");
+    }
+    memory::init(kpage_table, &dt, dtb_range, mmio_ranges(&dt));
+    unsafe { memory::switch(kpage_table) };
+
+    let (used, total) = pagealloc::usage_bytes();
+    println!("Memory usage: {used:#x} used of {total:#x}");
+
+    #[cfg(feature = "qemu_test")]
+    ktest::run();
+
+    start_secondary_harts(&dt, hartid);
+
+    for dev in
+        dt.find_compatible("virtio,mmio").filter_map(|node| VirtioMmio::from_dt_node(&dt, node))
+    {
+        println!("virtio {:?} at {:#x}", dev.device_type(), dev.addr());
+        if dev.device_type() == DeviceType::Block {
+            read_first_block(dev);
+        }
+    }
+
+    // Tick every 10ms, and wait for a few ticks before shutting down
+    if let Some(clint) = clint::Clint::from_dt(&dt) {
+        println!("{clint:x?}");
+    }
+    clint::start_timer(clint::timebase_frequency(&dt) / 100);
+    while clint::ticks() < 5 {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("wfi")
+        };
+    }
+    println!("{} timer ticks", clint::ticks());
+
     #[cfg(not(test))]
     sbi::shutdown();
     #[cfg(test)]
@@ -0,0 +1,713 @@
+//! RISC-V page tables
+//!
+//! Sv39 and Sv48 share the same page table entry format, and both use 4KiB
+//! tables of 512 entries.  They differ only in the number of levels, and so
+//! the number of 9 bit VPN fields in a virtual address: 3 for Sv39, 4 for
+//! Sv48.
+//!
+//! The kernel page tables use Sv39.  The kernel runs at its physical address,
+//! so the tables map virtual addresses to the same physical addresses, and
+//! tables are found by their physical address while walking.
+
+use crate::kmem::{
+    bss_range, data_range, from_ptr_to_physaddr, physaddr_as_ptr_mut, physaddr_as_virt,
+    rodata_range, text_range,
+};
+use crate::pagealloc;
+use bitstruct::bitstruct;
+use core::cell::SyncUnsafeCell;
+use core::fmt;
+use core::ptr::write_volatile;
+use port::fdt::DeviceTree;
+use port::mem::{coalesce_ranges, PhysAddr, PhysRange, PAGE_SIZE_1G, PAGE_SIZE_2M};
+
+#[cfg(not(test))]
+use port::println;
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE_4K: usize = 1 << PAGE_SHIFT;
+const VPN_BITS: usize = 9;
+const ENTRIES_PER_TABLE: usize = 1 << VPN_BITS;
+
+const SATP_MODE_SHIFT: u64 = 60;
+const SATP_ASID_SHIFT: u64 = 44;
+const SATP_PPN_MASK: u64 = (1 << SATP_ASID_SHIFT) - 1;
+
+/// The paging mode used for the kernel page tables
+pub const KERNEL_MODE: PagingMode = PagingMode::Sv39;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    #[allow(dead_code)]
+    Page1G,
+}
+
+impl PageSize {
+    const fn size(&self) -> usize {
+        match self {
+            PageSize::Page4K => PAGE_SIZE_4K,
+            PageSize::Page2M => PAGE_SIZE_2M,
+            PageSize::Page1G => PAGE_SIZE_1G,
+        }
+    }
+
+    /// Level of the table holding leaf entries for this page size, where
+    /// level 0 is the last table in the walk
+    const fn level(&self) -> usize {
+        match self {
+            PageSize::Page4K => 0,
+            PageSize::Page2M => 1,
+            PageSize::Page1G => 2,
+        }
+    }
+}
+
+bitstruct! {
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct Entry(pub u64) {
+        pub valid: bool = 0;
+        pub readable: bool = 1;
+        pub writable: bool = 2;
+        pub executable: bool = 3;
+        pub user: bool = 4;
+        pub global: bool = 5;
+        pub accessed: bool = 6;
+        pub dirty: bool = 7;
+        pub rsw: u8 = 8..10;
+        pub ppn: u64 = 10..54;
+    }
+}
+
+impl Entry {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Kernel leaf entries are global, and have A and D preset so that
+    /// hardware without A/D updating doesn't fault on first access.
+    fn kernel_leaf() -> Self {
+        Entry(0).with_valid(true).with_global(true).with_accessed(true).with_dirty(true)
+    }
+
+    pub fn rw_kernel_data() -> Self {
+        Self::kernel_leaf().with_readable(true).with_writable(true)
+    }
+
+    pub fn ro_kernel_data() -> Self {
+        Self::kernel_leaf().with_readable(true)
+    }
+
+    pub fn ro_kernel_text() -> Self {
+        Self::kernel_leaf().with_readable(true).with_executable(true)
+    }
+
+    /// Without Svpbmt there are no memory types in the PTE, so devices are
+    /// mapped like data.  PMAs make the device ranges uncached.
+    pub fn kernel_device() -> Self {
+        Self::kernel_leaf().with_readable(true).with_writable(true)
+    }
+
+    /// A pointer to the next level table at pa
+    fn table(pa: PhysAddr) -> Self {
+        Entry(0).with_valid(true).with_phys_addr(pa.addr())
+    }
+
+    /// A valid entry with none of R, W or X set points to the next level table
+    pub fn is_table(&self) -> bool {
+        self.valid() && !(self.readable() || self.writable() || self.executable())
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.valid() && !self.is_table()
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.ppn() << PAGE_SHIFT
+    }
+
+    pub fn with_phys_addr(self, pa: u64) -> Self {
+        self.with_ppn(pa >> PAGE_SHIFT)
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Entry {:#018x} pa:{:#x} ", self.0, self.phys_addr())?;
+        for (set, c) in [
+            (self.valid(), 'V'),
+            (self.readable(), 'R'),
+            (self.writable(), 'W'),
+            (self.executable(), 'X'),
+            (self.user(), 'U'),
+            (self.global(), 'G'),
+            (self.accessed(), 'A'),
+            (self.dirty(), 'D'),
+        ] {
+            write!(f, "{}", if set { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Virtual memory scheme, as set in the MODE field of satp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+}
+
+impl PagingMode {
+    /// Number of page table levels to walk
+    pub const fn levels(&self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+
+    /// Number of significant bits in a virtual address
+    pub const fn va_bits(&self) -> usize {
+        PAGE_SHIFT + VPN_BITS * self.levels()
+    }
+
+    /// Value of the satp MODE field
+    pub const fn satp_mode(&self) -> u64 {
+        match self {
+            PagingMode::Sv39 => 0x8,
+            PagingMode::Sv48 => 0x9,
+        }
+    }
+
+    fn from_satp(satp: u64) -> Option<PagingMode> {
+        match satp >> SATP_MODE_SHIFT {
+            0x8 => Some(PagingMode::Sv39),
+            0x9 => Some(PagingMode::Sv48),
+            _ => None,
+        }
+    }
+
+    /// Build a satp value for the root table at physical address root_pa
+    pub const fn satp(&self, asid: u16, root_pa: u64) -> u64 {
+        (self.satp_mode() << SATP_MODE_SHIFT)
+            | ((asid as u64) << SATP_ASID_SHIFT)
+            | ((root_pa >> PAGE_SHIFT as u64) & SATP_PPN_MASK)
+    }
+
+    /// Virtual addresses must have all bits above va_bits equal to the top
+    /// valid bit.
+    pub fn is_canonical(&self, va: usize) -> bool {
+        let shift = usize::BITS as usize - self.va_bits();
+        (((va << shift) as isize) >> shift) as usize == va
+    }
+
+    /// VPN field for the given level, where level 0 is the leaf table
+    pub fn vpn(&self, va: usize, level: usize) -> usize {
+        debug_assert!(level < self.levels());
+        (va >> (PAGE_SHIFT + VPN_BITS * level)) & (ENTRIES_PER_TABLE - 1)
+    }
+
+    /// VPN fields for all levels, from level 0 up.  Unused levels are 0.
+    #[allow(dead_code)]
+    pub fn vpns(&self, va: usize) -> [usize; 4] {
+        let mut vpns = [0; 4];
+        for (level, vpn) in vpns.iter_mut().enumerate().take(self.levels()) {
+            *vpn = self.vpn(va, level);
+        }
+        vpns
+    }
+}
+
+/// Returns a tuple of Sv39 page table indices for the given virtual address,
+/// from the root table down
+#[cfg(test)]
+fn va_indices(va: usize) -> (usize, usize, usize) {
+    (KERNEL_MODE.vpn(va, 2), KERNEL_MODE.vpn(va, 1), KERNEL_MODE.vpn(va, 0))
+}
+
+#[derive(Debug)]
+pub enum PageTableError {
+    AllocationFailed,
+    #[allow(dead_code)]
+    AlreadyMapped,
+    EntryIsNotTable,
+    NonCanonicalAddress,
+    PhysRangeIsZero,
+}
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+pub struct Table {
+    entries: [Entry; ENTRIES_PER_TABLE],
+}
+
+pub type PageTable = Table;
+
+impl Table {
+    pub const fn empty() -> Self {
+        Self { entries: [Entry::empty(); ENTRIES_PER_TABLE] }
+    }
+
+    pub fn entry(&self, index: usize) -> Entry {
+        self.entries[index]
+    }
+
+    pub fn entry_mut(&mut self, index: usize) -> &mut Entry {
+        &mut self.entries[index]
+    }
+
+    /// Return the next table in the walk for va, from the entry in this
+    /// table at level.  If it doesn't exist, create it in a page from
+    /// alloc_page.
+    fn next_mut(
+        &mut self,
+        level: usize,
+        va: usize,
+        alloc_page: &mut dyn FnMut() -> Option<PhysAddr>,
+    ) -> Result<&mut Table, PageTableError> {
+        let index = KERNEL_MODE.vpn(va, level);
+        let mut entry = self.entries[index];
+        if !entry.valid() {
+            // Create a new page table and write the entry into the parent table
+            let table_pa = alloc_page().ok_or(PageTableError::AllocationFailed)?;
+            unsafe { write_volatile(physaddr_as_ptr_mut::<Table>(table_pa), Table::empty()) };
+            entry = Entry::table(table_pa);
+            unsafe { write_volatile(&mut self.entries[index], entry) };
+        }
+        if !entry.is_table() {
+            return Err(PageTableError::EntryIsNotTable);
+        }
+        Ok(unsafe { &mut *physaddr_as_ptr_mut::<Table>(PhysAddr::new(entry.phys_addr())) })
+    }
+
+    /// Ensure there's a mapping from va to entry, creating any intermediate
+    /// page tables that don't already exist in pages from alloc_page, which
+    /// must be accessible at their physical addresses.  If a mapping already
+    /// exists, replace it.
+    pub fn map_to(
+        &mut self,
+        entry: Entry,
+        va: usize,
+        page_size: PageSize,
+        mut alloc_page: impl FnMut() -> Option<PhysAddr>,
+    ) -> Result<(), PageTableError> {
+        if !KERNEL_MODE.is_canonical(va) {
+            return Err(PageTableError::NonCanonicalAddress);
+        }
+        let mut table = self;
+        for level in (page_size.level() + 1..KERNEL_MODE.levels()).rev() {
+            table = table.next_mut(level, va, &mut alloc_page)?;
+        }
+        let dest_entry = table.entry_mut(KERNEL_MODE.vpn(va, page_size.level()));
+        unsafe {
+            write_volatile(dest_entry, entry);
+            invalidate_tlb_va(va);
+        }
+        Ok(())
+    }
+
+    /// Map the 4KiB page at va to pa, with the permissions and attributes of
+    /// flags.  Any missing intermediate tables are created in pages from
+    /// alloc_page, which must be accessible at their physical addresses.
+    /// Unlike map_to, an existing mapping isn't replaced.
+    #[allow(dead_code)]
+    pub fn map(
+        &mut self,
+        va: usize,
+        pa: PhysAddr,
+        flags: Entry,
+        mut alloc_page: impl FnMut() -> Option<PhysAddr>,
+    ) -> Result<(), PageTableError> {
+        if !KERNEL_MODE.is_canonical(va) {
+            return Err(PageTableError::NonCanonicalAddress);
+        }
+        let mut table = self;
+        for level in (1..KERNEL_MODE.levels()).rev() {
+            table = table.next_mut(level, va, &mut alloc_page)?;
+        }
+        let dest_entry = table.entry_mut(KERNEL_MODE.vpn(va, 0));
+        if dest_entry.valid() {
+            return Err(PageTableError::AlreadyMapped);
+        }
+        unsafe {
+            write_volatile(dest_entry, flags.with_phys_addr(pa.addr()));
+            invalidate_tlb_va(va);
+        }
+        Ok(())
+    }
+
+    /// Map the physical range using the requested page size, with any new
+    /// tables from alloc_page, as for map_to.
+    /// This aligns on page size boundaries, and rounds the requested range so
+    /// that both the alignment requirements are met and the requested range are
+    /// covered.
+    pub fn map_phys_range(
+        &mut self,
+        range: &PhysRange,
+        entry: Entry,
+        page_size: PageSize,
+        mut alloc_page: impl FnMut() -> Option<PhysAddr>,
+    ) -> Result<(usize, usize), PageTableError> {
+        let mut startva = None;
+        let mut endva = 0;
+        for pa in range.step_by_rounded(page_size.size()) {
+            let va = physaddr_as_virt(pa);
+            self.map_to(entry.with_phys_addr(pa.addr()), va, page_size, &mut alloc_page)?;
+            startva.get_or_insert(va);
+            endva = va + page_size.size();
+        }
+        startva.map(|startva| (startva, endva)).ok_or(PageTableError::PhysRangeIsZero)
+    }
+
+    /// Walk the tables from this root for va, returning the leaf entry if
+    /// it's mapped.  Assumes table physical addresses are directly accessible.
+    pub fn translate(&self, mode: PagingMode, va: usize) -> Option<Entry> {
+        if !mode.is_canonical(va) {
+            return None;
+        }
+        let mut table = self;
+        for level in (0..mode.levels()).rev() {
+            let entry = table.entry(mode.vpn(va, level));
+            if !entry.valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some(entry);
+            }
+            table = unsafe { &*physaddr_as_ptr_mut::<Table>(PhysAddr::new(entry.phys_addr())) };
+        }
+        None
+    }
+}
+
+fn read_satp() -> u64 {
+    #[cfg(not(test))]
+    {
+        let satp: u64;
+        unsafe { core::arch::asm!("csrr {satp}, satp", satp = out(reg) satp) };
+        satp
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Write satp and flush the TLB
+///
+/// # Safety
+///
+/// The root table must map the code that's currently executing, or paging
+/// must be turned off.
+#[allow(unused_variables)]
+pub unsafe fn write_satp(satp: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("csrw satp, {satp}", "sfence.vma", satp = in(reg) satp);
+    }
+}
+
+/// Invalidate any TLB entries for the page containing va on this hart
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_va(va: usize) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("sfence.vma {va}, zero", va = in(reg) va);
+    }
+}
+
+/// Map the RAM in bank that isn't in used_ranges and isn't already mapped,
+/// at its physical addresses, so pages from the page allocator can be used
+/// once paging is on.  Whole free 2MiB pages are mapped as such, to keep the
+/// number of tables down.
+fn map_free_ram(
+    kpage_table: &mut PageTable,
+    bank: &PhysRange,
+    used_ranges: &[PhysRange],
+    mut alloc_page: impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), PageTableError> {
+    let is_free = |range: &PhysRange| !used_ranges.iter().any(|used| used.overlaps(range));
+    let mut pa = bank.start().round_up(PAGE_SIZE_4K as u64);
+    let end = bank.end().round_down(PAGE_SIZE_4K as u64);
+    while pa < end {
+        let page_2m = PhysRange::with_len(pa.addr(), PAGE_SIZE_2M);
+        let page_size =
+            if pa.addr() % PAGE_SIZE_2M as u64 == 0 && page_2m.end() <= end && is_free(&page_2m) {
+                PageSize::Page2M
+            } else {
+                PageSize::Page4K
+            };
+        let page = PhysRange::with_len(pa.addr(), page_size.size());
+        let va = physaddr_as_virt(pa);
+        if is_free(&page) && kpage_table.translate(KERNEL_MODE, va).is_none() {
+            let entry = Entry::rw_kernel_data().with_phys_addr(pa.addr());
+            kpage_table.map_to(entry, va, page_size, &mut alloc_page)?;
+        }
+        pa = page.end();
+    }
+    Ok(())
+}
+
+/// Make the RAM in the devicetree available to the page allocator, apart
+/// from what the firmware, kernel and DTB use.  Then map the kernel image,
+/// the DTB, the device registers and the rest of RAM into kpage_table, all at
+/// their physical addresses, with the tables from the page allocator.
+pub fn init(
+    kpage_table: &mut PageTable,
+    dt: &DeviceTree,
+    dtb_range: PhysRange,
+    mmio: impl IntoIterator<Item = PhysRange>,
+) {
+    let kernel_range = text_range().add(&bss_range());
+    if dtb_range.overlaps(&kernel_range) {
+        panic!("DTB {dtb_range} overlaps the kernel {kernel_range}");
+    }
+    let Some(kernel_bank) = dt.memory_ranges().find(|bank| bank.overlaps(&kernel_range)) else {
+        panic!("The kernel {kernel_range} isn't in any memory node in the devicetree");
+    };
+
+    // The SBI firmware occupies the RAM below the kernel.  The kernel and DTB
+    // are mapped read only in places, so none of the pages their mappings
+    // cover can be handed out.
+    let mut used_ranges = [
+        PhysRange::new(kernel_bank.start(), text_range().start()),
+        PhysRange::new(kernel_range.start(), kernel_range.end().round_up(PAGE_SIZE_2M as u64)),
+        PhysRange::new(
+            dtb_range.start().round_down(PAGE_SIZE_4K as u64),
+            dtb_range.end().round_up(PAGE_SIZE_4K as u64),
+        ),
+    ];
+    let used_ranges = coalesce_ranges(&mut used_ranges);
+    if let Err(err) = pagealloc::init(dt, used_ranges) {
+        panic!("Couldn't mark unused pages as free: err: {:?}", err);
+    }
+    let mut alloc_page = || pagealloc::allocate().ok();
+
+    // The linker script aligns each section to 2MiB
+    let kernel_map = [
+        ("Kernel Text", text_range(), Entry::ro_kernel_text(), PageSize::Page2M),
+        ("Kernel RO Data", rodata_range(), Entry::ro_kernel_data(), PageSize::Page2M),
+        ("Kernel Data", data_range(), Entry::rw_kernel_data(), PageSize::Page2M),
+        ("Kernel BSS", bss_range(), Entry::rw_kernel_data(), PageSize::Page2M),
+        ("DTB", dtb_range, Entry::ro_kernel_data(), PageSize::Page4K),
+    ];
+    let device_map =
+        mmio.into_iter().map(|range| ("MMIO", range, Entry::kernel_device(), PageSize::Page4K));
+
+    #[cfg(not(test))]
+    println!("Memory map:");
+    for (name, range, flags, page_size) in kernel_map.into_iter().chain(device_map) {
+        let mapped_range = kpage_table
+            .map_phys_range(&range, flags, page_size, &mut alloc_page)
+            .expect("init mapping failed");
+
+        #[cfg(not(test))]
+        println!(
+            "  {:14}{} to {:#018x}..{:#018x} flags: {:?} page_size: {:?}",
+            name, range, mapped_range.0, mapped_range.1, flags, page_size
+        );
+        #[cfg(test)]
+        let _ = (name, mapped_range);
+    }
+
+    for bank in dt.memory_ranges() {
+        map_free_ram(kpage_table, &bank, used_ranges, &mut alloc_page).expect("RAM mapping failed");
+        #[cfg(not(test))]
+        println!("  {:14}{} flags: {:?}", "RAM", bank, Entry::rw_kernel_data());
+    }
+}
+
+/// Start translating with kpage_table
+///
+/// # Safety
+///
+/// kpage_table must map the kernel, as init does.
+pub unsafe fn switch(kpage_table: &PageTable) {
+    let root_pa = from_ptr_to_physaddr(kpage_table);
+    unsafe { write_satp(KERNEL_MODE.satp(0, root_pa.addr())) };
+}
+
+/// Root table used only while detect_paging_mode probes satp
+static PROBE_TABLE: SyncUnsafeCell<Table> = SyncUnsafeCell::new(Table::empty());
+
+/// Find the largest supported paging mode.  Writes to satp with an
+/// unsupported mode have no effect, so we write Sv48, then Sv39, and see
+/// which sticks.  satp is restored before returning.
+///
+/// A supported mode turns paging on straight away, so the probe root maps
+/// the kernel's 1GiB pages at their physical addresses.  Entry 0 points back
+/// at the root, so under Sv48, where the kernel's VAs all have a level 3
+/// index of 0, the root is also the level 2 table and the mapping is the
+/// same.  A kernel in the first 1GiB needs entry 0 itself, so only Sv39 is
+/// probed.
+///
+/// # Safety
+///
+/// The kernel must be running at its physical address, and nothing on this
+/// hart may depend on the current translation until this returns.
+pub unsafe fn detect_paging_mode() -> Option<PagingMode> {
+    let kernel_range = text_range().add(&bss_range());
+    let first_gib = kernel_range.start().addr() / PAGE_SIZE_1G as u64;
+    let last_gib = (kernel_range.end().addr() - 1) / PAGE_SIZE_1G as u64;
+
+    let root = unsafe { &mut *PROBE_TABLE.get() };
+    *root = Table::empty();
+    for gib in first_gib..=last_gib {
+        let leaf = Entry::kernel_leaf().with_readable(true).with_writable(true);
+        *root.entry_mut(gib as usize) =
+            leaf.with_executable(true).with_phys_addr(gib * PAGE_SIZE_1G as u64);
+    }
+    let modes: &[PagingMode] = if first_gib > 0 {
+        let root_pa = from_ptr_to_physaddr(root);
+        *root.entry_mut(0) = Entry::table(root_pa);
+        &[PagingMode::Sv48, PagingMode::Sv39]
+    } else {
+        &[PagingMode::Sv39]
+    };
+
+    let root_pa = from_ptr_to_physaddr(root).addr();
+    let saved = read_satp();
+    let mut detected = None;
+    for &mode in modes {
+        unsafe { write_satp(mode.satp(0, root_pa)) };
+        if PagingMode::from_satp(read_satp()) == Some(mode) {
+            detected = Some(mode);
+            break;
+        }
+    }
+    unsafe { write_satp(saved) };
+    detected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sv48_vpns() {
+        let va = (0x1a5 << 39) | (0x0f3 << 30) | (0x12c << 21) | (0x0a7 << 12) | 0x123;
+        assert_eq!(PagingMode::Sv48.vpns(va), [0x0a7, 0x12c, 0x0f3, 0x1a5]);
+        assert_eq!(PagingMode::Sv39.vpns(va), [0x0a7, 0x12c, 0x0f3, 0]);
+        assert!(!PagingMode::Sv48.is_canonical(va));
+        assert!(PagingMode::Sv48.is_canonical(va | 0xffff_0000_0000_0000));
+        assert!(PagingMode::Sv48.is_canonical(0x0000_7fff_ffff_ffff));
+        assert!(!PagingMode::Sv39.is_canonical(0x0000_7fff_ffff_ffff));
+    }
+
+    #[test]
+    fn satp() {
+        assert_eq!(PagingMode::Sv48.satp(1, 0x8020_0000), 0x9000_1000_0008_0200);
+        assert_eq!(PagingMode::Sv39.satp(0, 0x8020_0000), 0x8000_0000_0008_0200);
+        assert_eq!(PagingMode::from_satp(0x9000_1000_0008_0200), Some(PagingMode::Sv48));
+        assert_eq!(PagingMode::from_satp(0), None);
+    }
+
+    #[test]
+    fn can_break_down_va() {
+        assert_eq!(va_indices(0x8020_0000), (2, 1, 0));
+        assert_eq!(va_indices(0x1000_0000), (0, 128, 0));
+        assert_eq!(va_indices(0x0c20_1000), (0, 97, 1));
+        // The top of the Sv39 address space
+        assert_eq!(va_indices(0xffff_ffff_ffff_f000), (511, 511, 511));
+        assert_eq!(va_indices(0x0000_003f_ffff_f000), (255, 511, 511));
+    }
+
+    /// Allocates pages for tables from a static array
+    fn table_allocator<const N: usize>(
+        tables: &'static SyncUnsafeCell<[Table; N]>,
+    ) -> impl FnMut() -> Option<PhysAddr> {
+        let mut next = 0;
+        move || {
+            let table = unsafe { (*tables.get()).get_mut(next)? };
+            next += 1;
+            Some(from_ptr_to_physaddr(table))
+        }
+    }
+
+    #[test]
+    fn map_and_translate() {
+        static TABLES: SyncUnsafeCell<[Table; 4]> = SyncUnsafeCell::new([Table::empty(); 4]);
+        let mut alloc_page = table_allocator(&TABLES);
+        let mut root = Table::empty();
+        let range = PhysRange::with_len(0x8020_0000, 0x3000);
+        let (start, end) = root
+            .map_phys_range(&range, Entry::rw_kernel_data(), PageSize::Page4K, &mut alloc_page)
+            .unwrap();
+        assert_eq!((start, end), (0x8020_0000, 0x8020_3000));
+
+        let pte = root.translate(KERNEL_MODE, 0x8020_2000).unwrap();
+        assert_eq!(pte.phys_addr(), 0x8020_2000);
+        assert!(pte.readable() && pte.writable() && !pte.executable());
+        assert!(root.translate(KERNEL_MODE, 0x8020_3000).is_none());
+
+        // A 2MiB mapping in the same 1GiB region shares the level 1 table
+        root.map_to(
+            Entry::ro_kernel_text().with_phys_addr(0x8040_0000),
+            0x8040_0000,
+            PageSize::Page2M,
+            &mut alloc_page,
+        )
+        .unwrap();
+        let pte = root.translate(KERNEL_MODE, 0x8041_2000).unwrap();
+        assert_eq!(pte.phys_addr(), 0x8040_0000);
+        assert!(pte.executable());
+
+        // Can't map a 4KiB page inside a 2MiB page
+        assert!(matches!(
+            root.map_to(Entry::rw_kernel_data(), 0x8040_1000, PageSize::Page4K, &mut alloc_page),
+            Err(PageTableError::EntryIsNotTable)
+        ));
+        assert!(matches!(
+            root.map_phys_range(
+                &PhysRange::with_len(0x1000, 0),
+                Entry::rw_kernel_data(),
+                PageSize::Page4K,
+                &mut alloc_page
+            ),
+            Err(PageTableError::PhysRangeIsZero)
+        ));
+    }
+
+    #[test]
+    fn map_with_allocator() {
+        static TABLES: SyncUnsafeCell<[Table; 4]> = SyncUnsafeCell::new([Table::empty(); 4]);
+        let next = core::cell::Cell::new(0);
+        let mut alloc_page = || {
+            let table = unsafe { (*TABLES.get()).get_mut(next.get())? };
+            next.set(next.get() + 1);
+            Some(from_ptr_to_physaddr(table))
+        };
+
+        let mut root = Table::empty();
+        let pa = PhysAddr::new(0x8030_0000);
+        root.map(0x4000_1000, pa, Entry::rw_kernel_data(), &mut alloc_page).unwrap();
+        let pte = root.translate(KERNEL_MODE, 0x4000_1000).unwrap();
+        assert_eq!(pte.phys_addr(), 0x8030_0000);
+        assert!(pte.writable());
+
+        // The next page shares the tables, but the same page can't be mapped
+        // twice
+        root.map(0x4000_2000, pa, Entry::ro_kernel_data(), &mut alloc_page).unwrap();
+        assert!(matches!(
+            root.map(0x4000_1000, pa, Entry::ro_kernel_data(), &mut alloc_page),
+            Err(PageTableError::AlreadyMapped)
+        ));
+        assert_eq!(next.get(), 2);
+
+        // A different 1GiB region needs two more tables, and then there are
+        // none left
+        root.map(0x8000_0000, pa, Entry::ro_kernel_data(), &mut alloc_page).unwrap();
+        assert!(matches!(
+            root.map(0xc000_0000, pa, Entry::ro_kernel_data(), &mut alloc_page),
+            Err(PageTableError::AllocationFailed)
+        ));
+    }
+
+    #[test]
+    fn pte() {
+        let pte = Entry::empty().with_valid(true).with_phys_addr(0x8020_0000);
+        assert!(pte.is_table());
+        assert_eq!(pte.phys_addr(), 0x8020_0000);
+        assert_eq!(pte.0, (0x80200 << 10) | 1);
+        assert!(pte.with_readable(true).is_leaf());
+    }
+}
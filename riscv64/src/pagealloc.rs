@@ -0,0 +1,100 @@
+/// This module acts as an interface between the portable allocator and the
+/// arch-specific use of it.
+///
+/// The allocator starts with everything marked as in use.  Before the kernel
+/// page tables are built, `init` marks the RAM found in the devicetree as
+/// available, except for the ranges the kernel, firmware and DTB occupy, so
+/// the tables can be allocated from it.
+use port::bitmapalloc::BitmapPageAlloc;
+use port::bitmapalloc::BitmapPageAllocError;
+use port::fdt::DeviceTree;
+use port::mem::{PhysAddr, PhysRange};
+use port::println;
+use port::{
+    mcslock::{Lock, LockNode},
+    mem::PAGE_SIZE_4K,
+};
+
+/// Set up bitmap page allocator assuming everything is allocated.  32 bitmaps
+/// of 4KiB cover the first 4GiB of physical memory.
+static PAGE_ALLOC: Lock<BitmapPageAlloc<32, PAGE_SIZE_4K>> = Lock::new(
+    "page_alloc",
+    const { BitmapPageAlloc::<32, PAGE_SIZE_4K>::new_all_allocated(PAGE_SIZE_4K) },
+);
+
+/// Most banks of RAM used from the devicetree
+const MAX_BANKS: usize = 8;
+
+/// Free the RAM in all the devicetree's memory nodes, except for used_ranges,
+/// which must be sorted.  Memory beyond what the bitmaps cover is left unused.
+pub fn init(dt: &DeviceTree, used_ranges: &[PhysRange]) -> Result<(), BitmapPageAllocError> {
+    let mut banks: [PhysRange; MAX_BANKS] = core::array::from_fn(|_| PhysRange::with_end(0, 0));
+    let mut num_banks = 0;
+    for bank in dt.memory_ranges() {
+        println!("RAM: {bank} ({:#x})", bank.size());
+        if num_banks == MAX_BANKS {
+            println!("Page allocator can't use {bank}, beyond the first {MAX_BANKS} banks");
+            continue;
+        }
+        banks[num_banks] = bank;
+        num_banks += 1;
+    }
+    // The allocator needs the banks in ascending order
+    let banks = &mut banks[..num_banks];
+    banks.sort_unstable_by_key(|bank| bank.start());
+
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    let max_pa = PhysAddr::new(page_alloc.max_bytes() as u64);
+    for bank in banks.iter() {
+        let usable_mem = PhysRange::new(bank.start().min(max_pa), bank.end().min(max_pa));
+        if usable_mem.end() < bank.end() {
+            let dropped = PhysRange::new(usable_mem.end(), bank.end());
+            println!(
+                "Page allocator can't use {dropped} ({:#x}), beyond its bitmaps",
+                dropped.size()
+            );
+        }
+        if usable_mem.size() > 0 {
+            let used = used_ranges.iter().filter(|used| used.overlaps(&usable_mem));
+            page_alloc.free_unused_ranges(&usable_mem, used)?;
+        }
+    }
+    Ok(())
+}
+
+/// Try to allocate a page.  memory::init maps all RAM, so once it's run, the
+/// page can be used at its physical address.
+pub fn allocate() -> Result<PhysAddr, BitmapPageAllocError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.allocate()
+}
+
+/// Free the page at pa, which must have come from allocate.
+pub fn deallocate(pa: PhysAddr) -> Result<(), BitmapPageAllocError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.deallocate(pa)
+}
+
+/// Try to allocate num_pages physically contiguous pages, such as for DMA.
+/// Like allocate, the pages are at their physical addresses.
+pub fn allocate_contiguous(num_pages: usize) -> Result<PhysRange, BitmapPageAllocError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.allocate_contiguous(num_pages)
+}
+
+/// Return a tuple of (bytes used, total bytes available) based on the page allocator.
+pub fn usage_bytes() -> (usize, usize) {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.usage_bytes()
+}
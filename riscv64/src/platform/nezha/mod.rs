@@ -1,3 +1,6 @@
 pub mod devcons;
+pub mod uart;
 
-pub fn platform_init() {}
+use port::fdt::DeviceTree;
+
+pub fn platform_init(_dt: &DeviceTree, _hartid: usize) {}
@@ -0,0 +1,38 @@
+//! Allwinner D1 UART
+//!
+//! The D1's UARTs are Synopsys DesignWare APB UARTs, which are 16550
+//! compatible, but with each register in its own 32 bit word.
+//! https://github.com/mangopi-sbc/mq-pro/blob/main/docs/D1-H_User_Manual_V1.0.pdf
+
+use crate::uart16550::{MmioRegs, Uart16550};
+use port::devcons::Uart;
+use port::fdt::DeviceTree;
+
+/// UART0, which the boot firmware leaves set up as the console
+pub const EARLY_UART_BASE: usize = 0x0250_0000;
+
+/// The D1 devicetrees give the UARTs a `clocks` property referring to the
+/// CCU, not a clock-frequency, and the kernel doesn't drive the CCU.  The
+/// UARTs are clocked from APB1, which the boot firmware runs from the 24MHz
+/// oscillator.
+pub const DEFAULT_CLOCK_HZ: u32 = 24_000_000;
+
+/// The first `snps,dw-apb-uart` in the devicetree, or UART0 as set up by the
+/// boot firmware if there isn't one
+pub fn console_uart(dt: &DeviceTree) -> Uart16550 {
+    dt.find_compatible("snps,dw-apb-uart")
+        .next()
+        .and_then(|node| Uart16550::from_dt(dt, node, DEFAULT_CLOCK_HZ, true))
+        .unwrap_or_else(early_uart)
+}
+
+fn early_uart() -> Uart16550 {
+    Uart16550::new(MmioRegs::new(EARLY_UART_BASE, 2, 4, true), DEFAULT_CLOCK_HZ)
+}
+
+/// Write to UART0 at its physical address, relying on the boot firmware
+/// having set it up.  For debugging before the devicetree is available.
+#[allow(dead_code)]
+pub fn early_write(b: u8) {
+    early_uart().putb(b);
+}
@@ -3,23 +3,26 @@
 use core::cell::SyncUnsafeCell;
 use core::mem::MaybeUninit;
 
+use crate::plic;
 use crate::uart16550::Uart16550;
 use port::{devcons::Console, fdt::DeviceTree};
 
+/// Input clock assumed for the UART if the devicetree doesn't give one.  QEMU
+/// ignores the divisor.
+const UART_CLOCK_HZ: u32 = 2_227_900;
+
+static CONS: SyncUnsafeCell<MaybeUninit<Uart16550>> = SyncUnsafeCell::new(MaybeUninit::uninit());
+
 pub fn init(dt: &DeviceTree) {
-    let ns16550a_reg = dt
+    let mut uart = dt
         .find_compatible("ns16550a")
         .next()
-        .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-        .and_then(|reg| reg.regblock())
+        .and_then(|node| Uart16550::from_dt(dt, node, UART_CLOCK_HZ, false))
         .unwrap();
 
     Console::new(|| {
-        let mut uart = Uart16550::new(ns16550a_reg);
         uart.init(115_200);
 
-        static CONS: SyncUnsafeCell<MaybeUninit<Uart16550>> =
-            SyncUnsafeCell::new(MaybeUninit::uninit());
         unsafe {
             let cons = &mut *CONS.get();
             cons.write(uart);
@@ -27,3 +30,21 @@ pub fn init(dt: &DeviceTree) {
         }
     });
 }
+
+/// Take console input via the PLIC rather than polling.  Must be called after
+/// init and plic::init.
+pub fn enable_rx_interrupt(dt: &DeviceTree) {
+    let irq = dt
+        .find_compatible("ns16550a")
+        .next()
+        .and_then(|uart| dt.property(&uart, "interrupts"))
+        .and_then(|prop| dt.property_value_as_u32(&prop));
+    if let Some(irq) = irq {
+        plic::register_handler(irq, uart_interrupt);
+    }
+}
+
+fn uart_interrupt(_source: u32) {
+    let uart = unsafe { (*CONS.get()).assume_init_ref() };
+    uart.handle_interrupt();
+}
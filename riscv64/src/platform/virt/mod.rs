@@ -1,3 +1,15 @@
 pub mod devcons;
 
-pub fn platform_init() {}
+use crate::plic;
+#[cfg(feature = "machine_mode")]
+use crate::pmp;
+use port::fdt::DeviceTree;
+
+pub fn platform_init(dt: &DeviceTree, hartid: usize) {
+    // SBI firmware configures PMP when we're running in S-mode
+    #[cfg(feature = "machine_mode")]
+    pmp::configure_default();
+    plic::init(dt, hartid);
+    devcons::enable_rx_interrupt(dt);
+    plic::enable_external_interrupts();
+}
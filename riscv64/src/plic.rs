@@ -0,0 +1,267 @@
+//! Platform-Level Interrupt Controller
+//!
+//! https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+//!
+//! Interrupt sources are numbered from 1 (0 means no interrupt).  Each hart
+//! has one context per privilege mode that can take interrupts.  The mapping
+//! of contexts to harts comes from the interrupts-extended property of the
+//! PLIC node: entry N is a (phandle, irq) pair naming the hart interrupt
+//! controller and the local interrupt that context N raises.
+
+#![allow(dead_code)]
+
+use crate::trap::LocalInterrupts;
+use core::ptr::{read_volatile, write_volatile};
+use port::fdt::{DeviceTree, RegBlock};
+use port::mcslock::{IrqLock, LockNode};
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_CONTEXT_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM_COMPLETE: usize = 0x4;
+
+/// Maximum number of interrupt sources defined by the spec
+const MAX_SOURCES: usize = 1024;
+
+/// Local interrupt number of the supervisor external interrupt
+const IRQ_S_EXT: u32 = 9;
+
+/// Called with the source number when a claimed interrupt is dispatched
+pub type IrqHandler = fn(source: u32);
+
+// Also taken by the external interrupt handler
+static PLIC: IrqLock<Option<Plic>, LocalInterrupts> = IrqLock::new("plic", None);
+static HANDLERS: IrqLock<[Option<IrqHandler>; MAX_SOURCES], LocalInterrupts> =
+    IrqLock::new("plic_handlers", [None; MAX_SOURCES]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Plic {
+    reg: RegBlock,
+    /// S-mode context of the hart the PLIC was found for
+    context: u32,
+}
+
+impl Plic {
+    /// Find the PLIC in the devicetree, along with the S-mode context for
+    /// the hart.
+    pub fn from_dt(dt: &DeviceTree, hartid: usize) -> Option<Plic> {
+        let plic = dt
+            .find_compatible("riscv,plic0")
+            .next()
+            .or_else(|| dt.find_compatible("sifive,plic-1.0.0").next())?;
+        let reg = dt.property_translated_reg_iter(plic).next().and_then(|reg| reg.regblock())?;
+        let intc = hart_intc_phandle(dt, hartid)?;
+        let context = dt
+            .property(&plic, "interrupts-extended")
+            .and_then(|prop| s_mode_context(dt.property_value_as_u32_iter(&prop), intc))?;
+        Some(Plic { reg, context })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let ptr = (self.reg.addr as usize + offset) as *const u32;
+        unsafe { read_volatile(ptr) }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        let ptr = (self.reg.addr as usize + offset) as *mut u32;
+        unsafe { write_volatile(ptr, val) }
+    }
+
+    fn context_offset(context: u32) -> usize {
+        CONTEXT_BASE + context as usize * CONTEXT_STRIDE
+    }
+
+    fn enable_offset(source: u32, context: u32) -> usize {
+        ENABLE_BASE + context as usize * ENABLE_CONTEXT_STRIDE + (source as usize / 32) * 4
+    }
+
+    /// S-mode context of this hart
+    pub fn context(&self) -> u32 {
+        self.context
+    }
+
+    /// Set the priority of the interrupt source.  0 disables the source.
+    pub fn set_priority(&self, source: u32, priority: u32) {
+        self.write(PRIORITY_BASE + source as usize * 4, priority);
+    }
+
+    /// Only interrupts with a priority greater than the threshold are
+    /// delivered to the context.
+    pub fn set_threshold(&self, context: u32, threshold: u32) {
+        self.write(Self::context_offset(context) + CONTEXT_THRESHOLD, threshold);
+    }
+
+    /// Enable delivery of the interrupt source to the context
+    pub fn enable_interrupt(&self, source: u32, context: u32) {
+        let offset = Self::enable_offset(source, context);
+        self.write(offset, self.read(offset) | (1 << (source % 32)));
+    }
+
+    /// Disable delivery of the interrupt source to the context
+    pub fn disable_interrupt(&self, source: u32, context: u32) {
+        let offset = Self::enable_offset(source, context);
+        self.write(offset, self.read(offset) & !(1 << (source % 32)));
+    }
+
+    /// Return true if the interrupt source is pending
+    pub fn is_pending(&self, source: u32) -> bool {
+        let offset = PENDING_BASE + (source as usize / 32) * 4;
+        self.read(offset) & (1 << (source % 32)) != 0
+    }
+
+    /// Claim the highest priority pending interrupt for the context.  Returns
+    /// 0 if there's nothing pending.
+    pub fn claim_context(&self, context: u32) -> u32 {
+        self.read(Self::context_offset(context) + CONTEXT_CLAIM_COMPLETE)
+    }
+
+    /// Signal that the claimed interrupt source has been handled
+    pub fn complete_context(&self, context: u32, source: u32) {
+        self.write(Self::context_offset(context) + CONTEXT_CLAIM_COMPLETE, source);
+    }
+
+    /// Claim the highest priority pending interrupt for this hart's S-mode
+    /// context.  Returns 0 if there's nothing pending.
+    pub fn claim(&self) -> u32 {
+        self.claim_context(self.context)
+    }
+
+    /// Complete the claimed interrupt for this hart's S-mode context
+    pub fn complete(&self, source: u32) {
+        self.complete_context(self.context, source);
+    }
+
+    /// Give the source the priority and deliver it to this hart's S-mode
+    /// context.
+    pub fn enable(&self, source: u32, priority: u32) {
+        self.set_priority(source, priority);
+        self.enable_interrupt(source, self.context);
+    }
+}
+
+/// Return the phandle of the interrupt controller of the hart
+fn hart_intc_phandle(dt: &DeviceTree, hartid: usize) -> Option<u32> {
+    let cpus = dt.find_by_path("/cpus")?;
+    let cpu = dt.children(&cpus).find(|cpu| {
+        dt.property(cpu, "reg").and_then(|prop| dt.property_value_as_u32(&prop))
+            == Some(hartid as u32)
+    })?;
+    let intc = dt
+        .children(&cpu)
+        .find(|node| dt.node_name(node).is_some_and(|name| name == "interrupt-controller"))?;
+    dt.property(&intc, "phandle")
+        .or_else(|| dt.property(&intc, "linux,phandle"))
+        .and_then(|prop| dt.property_value_as_u32(&prop))
+}
+
+/// Given the cells of the PLIC's interrupts-extended property, return the
+/// context that raises the supervisor external interrupt on the hart
+/// interrupt controller with the given phandle.
+fn s_mode_context(mut cells: impl Iterator<Item = u32>, intc_phandle: u32) -> Option<u32> {
+    let mut context = 0;
+    while let (Some(phandle), Some(irq)) = (cells.next(), cells.next()) {
+        if phandle == intc_phandle && irq == IRQ_S_EXT {
+            return Some(context);
+        }
+        context += 1;
+    }
+    None
+}
+
+/// Find the PLIC in the devicetree and make it available via plic().  Interrupts
+/// are delivered to the S-mode context of the given hart.
+pub fn init(dt: &DeviceTree, hartid: usize) {
+    let plic = Plic::from_dt(dt, hartid);
+    if let Some(plic) = plic {
+        plic.set_threshold(plic.context, 0);
+    }
+    let node = LockNode::new();
+    *PLIC.lock(&node) = plic;
+}
+
+/// Return the PLIC found by init, if any
+pub fn plic() -> Option<Plic> {
+    let node = LockNode::new();
+    let plic = PLIC.lock(&node);
+    *plic
+}
+
+/// Register a handler for the interrupt source and enable it.  Returns false
+/// if there's no PLIC or the source is out of range.
+pub fn register_handler(source: u32, handler: IrqHandler) -> bool {
+    let Some(plic) = plic() else {
+        return false;
+    };
+    if source == 0 || source as usize >= MAX_SOURCES {
+        return false;
+    }
+    {
+        let node = LockNode::new();
+        HANDLERS.lock(&node)[source as usize] = Some(handler);
+    }
+    plic.enable(source, 1);
+    true
+}
+
+/// Enable supervisor external interrupts (sie.SEIE).  trap::init must have
+/// set the trap vector.
+pub fn enable_external_interrupts() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "li {tmp}, 1 << 9",
+            "csrs sie, {tmp}",
+            "csrsi sstatus, 1 << 1",
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// Called from trap on a supervisor external interrupt.  Claims and
+/// dispatches each pending source in turn.
+pub fn external_interrupt_handler() {
+    let Some(plic) = plic() else {
+        return;
+    };
+    loop {
+        let source = plic.claim();
+        if source == 0 {
+            break;
+        }
+        let handler = {
+            let node = LockNode::new();
+            let handlers = HANDLERS.lock(&node);
+            handlers.get(source as usize).copied().flatten()
+        };
+        if let Some(handler) = handler {
+            handler(source);
+        }
+        plic.complete(source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_s_mode_context() {
+        // qemu virt with two harts, whose interrupt controllers have
+        // phandles 3 and 1.  M-mode (11) and S-mode (9) contexts alternate.
+        let cells = [3, 11, 3, 9, 1, 11, 1, 9];
+        assert_eq!(s_mode_context(cells.into_iter(), 3), Some(1));
+        assert_eq!(s_mode_context(cells.into_iter(), 1), Some(3));
+        assert_eq!(s_mode_context(cells.into_iter(), 2), None);
+
+        // Harts without an M-mode context, e.g. with 0xffffffff entries
+        let cells = [0xffff_ffff, 0xffff_ffff, 5, 9];
+        assert_eq!(s_mode_context(cells.into_iter(), 5), Some(1));
+
+        // A trailing odd cell is ignored
+        assert_eq!(s_mode_context([5].into_iter(), 5), None);
+    }
+}
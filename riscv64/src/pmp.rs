@@ -0,0 +1,149 @@
+//! Physical Memory Protection
+//!
+//! PMP entries restrict which physical addresses S-mode and U-mode can
+//! access.  Once any entry is configured, accesses from S-mode and U-mode
+//! that don't match an entry fail, so configure_default adds a catch-all
+//! entry allowing everything, and lock_range adds higher priority entries to
+//! restrict specific ranges.
+//!
+//! The PMP CSRs are only accessible from M-mode.  r9 normally runs in S-mode
+//! under SBI firmware, which sets up PMP itself, so these must only be called
+//! when running in M-mode.
+
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of entries configured through pmpcfg0.  The spec allows for 64, but
+/// 8 is enough for now.
+const NUM_ENTRIES: usize = 8;
+
+/// The catch-all entry.  Lower numbered entries take priority, so use the
+/// last one.
+const DEFAULT_ENTRY: usize = NUM_ENTRIES - 1;
+
+// pmpcfg fields
+const CFG_R: u8 = 1 << 0;
+const CFG_W: u8 = 1 << 1;
+const CFG_X: u8 = 1 << 2;
+const CFG_A_NAPOT: u8 = 3 << 3;
+const CFG_L: u8 = 1 << 7;
+
+/// Next free entry for lock_range
+static NEXT_ENTRY: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmpPerms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl PmpPerms {
+    pub const NONE: PmpPerms = PmpPerms { read: false, write: false, execute: false };
+    pub const RO: PmpPerms = PmpPerms { read: true, write: false, execute: false };
+    pub const RW: PmpPerms = PmpPerms { read: true, write: true, execute: false };
+    pub const RX: PmpPerms = PmpPerms { read: true, write: false, execute: true };
+    pub const RWX: PmpPerms = PmpPerms { read: true, write: true, execute: true };
+
+    fn cfg_bits(&self) -> u8 {
+        (if self.read { CFG_R } else { 0 })
+            | (if self.write { CFG_W } else { 0 })
+            | (if self.execute { CFG_X } else { 0 })
+    }
+}
+
+#[derive(Debug)]
+pub enum PmpError {
+    /// NAPOT ranges must be a power of two of at least 8 bytes, aligned to
+    /// their size.
+    InvalidRange(u64, u64),
+    NoFreeEntries,
+}
+
+/// Encode a naturally aligned power of two range for pmpaddr
+fn napot_addr(addr: u64, size: u64) -> Result<u64, PmpError> {
+    if size < 8 || !size.is_power_of_two() || addr & (size - 1) != 0 {
+        return Err(PmpError::InvalidRange(addr, size));
+    }
+    Ok((addr >> 2) | ((size >> 3) - 1))
+}
+
+/// Allow S-mode and U-mode read, write and execute access to all of memory
+pub fn configure_default() {
+    set_entry(DEFAULT_ENTRY, u64::MAX >> 10, CFG_A_NAPOT | PmpPerms::RWX.cfg_bits());
+}
+
+/// Restrict the range addr..addr+size to perms.  The entry is locked, so it
+/// also applies to M-mode, and can't be changed until reset.
+pub fn lock_range(addr: u64, size: u64, perms: PmpPerms) -> Result<(), PmpError> {
+    let pmpaddr = napot_addr(addr, size)?;
+    let entry = NEXT_ENTRY
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |i| {
+            (i < DEFAULT_ENTRY).then_some(i + 1)
+        })
+        .map_err(|_| PmpError::NoFreeEntries)?;
+    set_entry(entry, pmpaddr, CFG_A_NAPOT | CFG_L | perms.cfg_bits());
+    Ok(())
+}
+
+/// Write pmpaddr for the entry, then its byte of pmpcfg0
+fn set_entry(entry: usize, pmpaddr: u64, cfg: u8) {
+    write_pmpaddr(entry, pmpaddr);
+    let shift = entry * 8;
+    let pmpcfg0 = (read_pmpcfg0() & !(0xff << shift)) | ((cfg as u64) << shift);
+    write_pmpcfg0(pmpcfg0);
+}
+
+fn read_pmpcfg0() -> u64 {
+    #[cfg(not(test))]
+    {
+        let cfg: u64;
+        unsafe { core::arch::asm!("csrr {cfg}, pmpcfg0", cfg = out(reg) cfg) };
+        cfg
+    }
+    #[cfg(test)]
+    0
+}
+
+#[allow(unused_variables)]
+fn write_pmpcfg0(cfg: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("csrw pmpcfg0, {cfg}", "sfence.vma", cfg = in(reg) cfg);
+    }
+}
+
+#[allow(unused_variables)]
+fn write_pmpaddr(entry: usize, addr: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        use core::arch::asm;
+        match entry {
+            0 => asm!("csrw pmpaddr0, {addr}", addr = in(reg) addr),
+            1 => asm!("csrw pmpaddr1, {addr}", addr = in(reg) addr),
+            2 => asm!("csrw pmpaddr2, {addr}", addr = in(reg) addr),
+            3 => asm!("csrw pmpaddr3, {addr}", addr = in(reg) addr),
+            4 => asm!("csrw pmpaddr4, {addr}", addr = in(reg) addr),
+            5 => asm!("csrw pmpaddr5, {addr}", addr = in(reg) addr),
+            6 => asm!("csrw pmpaddr6, {addr}", addr = in(reg) addr),
+            7 => asm!("csrw pmpaddr7, {addr}", addr = in(reg) addr),
+            _ => panic!("unsupported pmp entry {entry}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn napot_encoding() {
+        assert_eq!(napot_addr(0x8000_0000, 8).unwrap(), 0x2000_0000);
+        assert_eq!(napot_addr(0x8000_0000, 0x1000).unwrap(), 0x2000_01ff);
+        assert_eq!(napot_addr(0x8000_0000, 0x8000_0000).unwrap(), 0x2fff_ffff);
+        assert!(napot_addr(0x8000_0000, 4).is_err());
+        assert!(napot_addr(0x8000_0000, 0x3000).is_err());
+        assert!(napot_addr(0x8000_1000, 0x2000).is_err());
+    }
+}
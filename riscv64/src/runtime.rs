@@ -19,6 +19,10 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("no information available.");
     }
+    // A panic fails the kernel tests
+    #[cfg(feature = "qemu_test")]
+    crate::ktest::exit(port::ktest::ExitCode::Failure);
+    #[cfg(not(feature = "qemu_test"))]
     abort();
 }
 #[no_mangle]
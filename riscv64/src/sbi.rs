@@ -1,6 +1,7 @@
 //! SBI interface.
 //!
 //! Chapter 5: Legacy Extensions
+//! Chapter 9: Hart State Management Extension
 
 #![allow(dead_code)]
 
@@ -14,6 +15,12 @@ const _SBI_REMOTE_SFENCE_VMA: usize = 6;
 const _SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+const SBI_EXT_HSM: usize = 0x48534D;
+const SBI_HSM_HART_START: usize = 0;
+
+/// Result of an SBI call.  Ok holds the returned value, Err the SBI error code.
+pub type SbiResult = Result<usize, isize>;
+
 #[cfg(target_arch = "riscv64")]
 fn sbi_call_legacy(eid: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let ret;
@@ -34,6 +41,32 @@ fn sbi_call_legacy(_eid: usize, _arg0: usize, _arg1: usize, _arg2: usize) -> usi
     0
 }
 
+#[cfg(target_arch = "riscv64")]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiResult {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x16") fid,
+            in("x17") eid
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error)
+    }
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn sbi_call(_eid: usize, _fid: usize, _arg0: usize, _arg1: usize, _arg2: usize) -> SbiResult {
+    Ok(0)
+}
+
 pub fn _set_timer(timer: usize) {
     sbi_call_legacy(SBI_SET_TIMER, timer, 0, 0);
 }
@@ -48,6 +81,12 @@ pub fn _consgetb() -> u8 {
     sbi_call_legacy(SBI_CONSOLE_GETCHAR, 0, 0, 0).try_into().unwrap()
 }
 
+/// Start executing hart_id in supervisor mode at start_addr, with a0 set to
+/// hart_id and a1 set to opaque.
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiResult {
+    sbi_call(SBI_EXT_HSM, SBI_HSM_HART_START, hart_id, start_addr, opaque)
+}
+
 pub fn shutdown() -> ! {
     sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
     loop {
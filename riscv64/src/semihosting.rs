@@ -0,0 +1,35 @@
+//! RISC-V semihosting calls, for port::semihosting.  The call is an `ebreak`.
+
+use port::semihosting::Semihosting;
+
+pub struct Semihost;
+
+impl Semihosting for Semihost {
+    /// Make a semihosting call, returning the result from a0.  QEMU only
+    /// recognises the `ebreak` as a semihosting call when it's surrounded by
+    /// these uncompressed instructions, all in the same page.
+    #[cfg(target_arch = "riscv64")]
+    fn call(op: usize, param: *const u8) -> usize {
+        let result: usize;
+        unsafe {
+            core::arch::asm!(
+                ".option push",
+                ".option norvc",
+                ".balign 16",
+                "slli zero, zero, 0x1f",
+                "ebreak",
+                "srai zero, zero, 0x7",
+                ".option pop",
+                inout("a0") op => result,
+                in("a1") param,
+                options(nostack)
+            );
+        }
+        result
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    fn call(_op: usize, _param: *const u8) -> usize {
+        0
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{clint, plic};
+use bitstruct::bitstruct;
+use core::fmt;
+use port::mcslock::Interrupts;
+use port::println;
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("trap.S"));
+
+// Interrupt cause codes
+const IRQ_S_SOFTWARE: u64 = 1;
+const IRQ_S_TIMER: u64 = 5;
+const IRQ_S_EXT: u64 = 9;
+const IRQ_COUNTER_OVERFLOW: u64 = 13;
+
+// Exception cause codes
+const EXC_INSTRUCTION_MISALIGNED: u64 = 0;
+const EXC_INSTRUCTION_ACCESS_FAULT: u64 = 1;
+const EXC_ILLEGAL_INSTRUCTION: u64 = 2;
+const EXC_BREAKPOINT: u64 = 3;
+const EXC_LOAD_MISALIGNED: u64 = 4;
+const EXC_LOAD_ACCESS_FAULT: u64 = 5;
+const EXC_STORE_MISALIGNED: u64 = 6;
+const EXC_STORE_ACCESS_FAULT: u64 = 7;
+const EXC_ECALL_U: u64 = 8;
+const EXC_ECALL_S: u64 = 9;
+const EXC_INSTRUCTION_PAGE_FAULT: u64 = 12;
+const EXC_LOAD_PAGE_FAULT: u64 = 13;
+const EXC_STORE_PAGE_FAULT: u64 = 15;
+const EXC_SOFTWARE_CHECK: u64 = 18;
+const EXC_HARDWARE_ERROR: u64 = 19;
+
+bitstruct! {
+    /// Supervisor trap cause register
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct Scause(pub u64) {
+        pub code: u64 = 0..63;
+        pub interrupt: bool = 63;
+    }
+}
+
+impl Scause {
+    /// Name of the cause, or None if it's not a standard cause
+    pub fn name(&self) -> Option<&'static str> {
+        let name = if self.interrupt() {
+            match self.code() {
+                IRQ_S_SOFTWARE => "Supervisor software interrupt",
+                IRQ_S_TIMER => "Supervisor timer interrupt",
+                IRQ_S_EXT => "Supervisor external interrupt",
+                IRQ_COUNTER_OVERFLOW => "Counter overflow interrupt",
+                _ => return None,
+            }
+        } else {
+            match self.code() {
+                EXC_INSTRUCTION_MISALIGNED => "Instruction address misaligned",
+                EXC_INSTRUCTION_ACCESS_FAULT => "Instruction access fault",
+                EXC_ILLEGAL_INSTRUCTION => "Illegal instruction",
+                EXC_BREAKPOINT => "Breakpoint",
+                EXC_LOAD_MISALIGNED => "Load address misaligned",
+                EXC_LOAD_ACCESS_FAULT => "Load access fault",
+                EXC_STORE_MISALIGNED => "Store/AMO address misaligned",
+                EXC_STORE_ACCESS_FAULT => "Store/AMO access fault",
+                EXC_ECALL_U => "Environment call from U-mode",
+                EXC_ECALL_S => "Environment call from S-mode",
+                EXC_INSTRUCTION_PAGE_FAULT => "Instruction page fault",
+                EXC_LOAD_PAGE_FAULT => "Load page fault",
+                EXC_STORE_PAGE_FAULT => "Store/AMO page fault",
+                EXC_SOFTWARE_CHECK => "Software check",
+                EXC_HARDWARE_ERROR => "Hardware error",
+                _ => return None,
+            }
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Debug for Scause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None if self.interrupt() => write!(f, "Unknown interrupt {}", self.code()),
+            None => write!(f, "Unknown exception {}", self.code()),
+        }
+    }
+}
+
+/// Set the trap vector to supervisor_trap_vector in trap.S.  Interrupts are
+/// enabled separately, by the drivers that use them.
+pub fn init() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "la {tmp}, supervisor_trap_vector",
+            "csrw stvec, {tmp}",
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// Masks supervisor interrupts (sstatus.SIE) on this hart
+pub struct LocalInterrupts;
+
+impl Interrupts for LocalInterrupts {
+    /// Whether interrupts were enabled
+    type State = bool;
+
+    fn disable() -> bool {
+        #[cfg(not(test))]
+        {
+            let sstatus: usize;
+            unsafe { core::arch::asm!("csrrci {}, sstatus, 1 << 1", out(reg) sstatus) };
+            sstatus & (1 << 1) != 0
+        }
+        #[cfg(test)]
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn restore(enabled: bool) {
+        #[cfg(not(test))]
+        if enabled {
+            unsafe { core::arch::asm!("csrsi sstatus, 1 << 1") };
+        }
+    }
+}
+
+/// Register frame at time trap was taken.  The general purpose registers are
+/// in order, so xN is at offset 8*N.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    zero: u64, // Not saved
+    ra: u64,
+    sp: u64,
+    gp: u64,
+    tp: u64,
+    t0: u64,
+    t1: u64,
+    t2: u64,
+    s0: u64,
+    s1: u64,
+    a0: u64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    a4: u64,
+    a5: u64,
+    a6: u64,
+    a7: u64,
+    s2: u64,
+    s3: u64,
+    s4: u64,
+    s5: u64,
+    s6: u64,
+    s7: u64,
+    s8: u64,
+    s9: u64,
+    s10: u64,
+    s11: u64,
+    t3: u64,
+    t4: u64,
+    t5: u64,
+    t6: u64,
+    sepc: u64,
+    sstatus: u64,
+    scause: Scause,
+    stval: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn trap(frame: &mut TrapFrame) {
+    let scause = frame.scause;
+    if scause.interrupt() {
+        match scause.code() {
+            IRQ_S_TIMER => return clint::timer_handler(),
+            IRQ_S_EXT => return plic::external_interrupt_handler(),
+            _ => {}
+        }
+    }
+
+    // Just print out the frame and loop for now
+    println!("{:?} at sepc {:#018x} (stval: {:#018x})", scause, frame.sepc, frame.stval);
+    println!("{:#x?}", frame);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{offset_of, size_of};
+
+    #[test]
+    fn decode_scause() {
+        let scause = Scause(0x8000_0000_0000_0005);
+        assert!(scause.interrupt());
+        assert_eq!(scause.code(), IRQ_S_TIMER);
+        assert_eq!(scause.name(), Some("Supervisor timer interrupt"));
+
+        let scause = Scause(13);
+        assert!(!scause.interrupt());
+        assert_eq!(scause.name(), Some("Load page fault"));
+
+        // Exception and interrupt codes overlap
+        assert_eq!(Scause(9).name(), Some("Environment call from S-mode"));
+        assert_eq!(Scause(0x8000_0000_0000_0009).name(), Some("Supervisor external interrupt"));
+
+        assert_eq!(Scause(14).name(), None);
+        assert_eq!(format!("{:?}", Scause(14)), "Unknown exception 14");
+        assert_eq!(format!("{:?}", Scause(0x8000_0000_0000_0003)), "Unknown interrupt 3");
+    }
+
+    #[test]
+    fn trap_frame_layout() {
+        // Must match trap.S
+        assert_eq!(offset_of!(TrapFrame, a0), 8 * 10);
+        assert_eq!(offset_of!(TrapFrame, t6), 8 * 31);
+        assert_eq!(offset_of!(TrapFrame, sepc), 8 * 32);
+        assert_eq!(offset_of!(TrapFrame, stval), 8 * 35);
+        assert_eq!(size_of::<TrapFrame>(), 8 * 36);
+    }
+}
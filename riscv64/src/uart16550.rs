@@ -1,18 +1,79 @@
-use core::convert::TryInto;
 use core::fmt::Error;
 use core::fmt::Write;
 
 use port::devcons::Uart;
-use port::fdt::RegBlock;
+use port::fdt::{DeviceTree, Node};
+use port::ringbuf::AtomicRingBuf;
+use port::uart16550::{self, Uart16550Regs, FCR, FCR_ENABLE_CLEAR, IER_RX_AVAILABLE};
+
+/// DesignWare APB UART status register
+const USR: usize = 0x1f;
+/// Set while a character is being sent or received, or the FIFOs aren't empty
+const USR_BUSY: u8 = 0x01;
+
+/// Size of the buffer of bytes received under interrupt
+const RX_BUFFER_SIZE: usize = 64;
+
+/// Bytes drained from the UART by the interrupt handler, waiting for
+/// try_getb.  The interrupt handler is the only producer and try_getb the only
+/// consumer.
+static RX_BUFFER: AtomicRingBuf<u8, RX_BUFFER_SIZE> = AtomicRingBuf::new();
+
+/// A UART's memory mapped registers.  Register n is at base + (n <<
+/// reg_shift) and is reg_io_width bytes wide, as in the devicetree properties
+/// of the same names.
+pub struct MmioRegs {
+    base: usize,
+    reg_shift: u32,
+    reg_io_width: u32,
+    dw_apb: bool,
+}
+
+impl MmioRegs {
+    /// dw_apb is true for Synopsys DesignWare APB UARTs, which ignore writes
+    /// to LCR while they're busy.
+    pub const fn new(base: usize, reg_shift: u32, reg_io_width: u32, dw_apb: bool) -> Self {
+        MmioRegs { base, reg_shift, reg_io_width, dw_apb }
+    }
+}
+
+impl Uart16550Regs for MmioRegs {
+    fn read(&self, reg: usize) -> u8 {
+        let addr = self.base + (reg << self.reg_shift);
+        match self.reg_io_width {
+            4 => unsafe { (addr as *const u32).read_volatile() as u8 },
+            _ => unsafe { (addr as *const u8).read_volatile() },
+        }
+    }
+
+    fn write(&self, reg: usize, val: u8) {
+        let addr = self.base + (reg << self.reg_shift);
+        match self.reg_io_width {
+            4 => unsafe { (addr as *mut u32).write_volatile(val as u32) },
+            _ => unsafe { (addr as *mut u8).write_volatile(val) },
+        }
+    }
+
+    /// LCR, and so DLL and DLM, can't be written while a DesignWare UART is
+    /// busy, so wait for it to go idle.  Like Linux's dw8250 driver, keep
+    /// clearing the FIFOs so incoming data can't hold it busy.
+    fn wait_lcr_writable(&self) {
+        while self.dw_apb && self.read(USR) & USR_BUSY != 0 {
+            self.write(FCR, FCR_ENABLE_CLEAR);
+            core::hint::spin_loop();
+        }
+    }
+}
 
 pub struct Uart16550 {
-    pub ns16550a_reg: RegBlock,
+    uart: uart16550::Uart16550<MmioRegs>,
+    clock_hz: u32,
 }
 
 impl Write for Uart16550 {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
         for c in out.bytes() {
-            self.put(c);
+            self.putb(c);
         }
         Ok(())
     }
@@ -20,51 +81,54 @@ impl Write for Uart16550 {
 
 impl Uart for Uart16550 {
     fn putb(&self, b: u8) {
-        let ptr = self.ns16550a_reg.addr as *mut u8;
-        unsafe {
-            ptr.add(0).write_volatile(b);
-        }
+        self.uart.write_byte(b);
+    }
+
+    fn try_getb(&self) -> Option<u8> {
+        // Bytes buffered by the interrupt handler arrived first.  This is the
+        // buffer's only consumer.
+        unsafe { RX_BUFFER.pop() }.or_else(|| self.uart.try_getc())
     }
 }
 
 impl Uart16550 {
-    pub fn new(ns16550a_reg: RegBlock) -> Self {
-        Uart16550 { ns16550a_reg }
+    pub const fn new(regs: MmioRegs, clock_hz: u32) -> Self {
+        Uart16550 { uart: uart16550::Uart16550::new(regs), clock_hz }
     }
 
-    pub fn init(&mut self, baud: u32) {
-        let ptr = self.ns16550a_reg.addr as *mut u8;
-        unsafe {
-            let lcr = 3; // word length
-            ptr.add(3).write_volatile(lcr); // set word length
-            ptr.add(2).write_volatile(1); // enable FIFO
-            ptr.add(1).write_volatile(1); // enable receiver interrupts
-            let divisor: u16 = (2_227_900 / (baud * 16)) as u16; // set baud rate
-            let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
-            let divisor_most: u8 = (divisor >> 8).try_into().unwrap();
-            ptr.add(3).write_volatile(lcr | 1 << 7); // access DLAB
-            ptr.add(0).write_volatile(divisor_least); // DLL
-            ptr.add(1).write_volatile(divisor_most); // DLM
-            ptr.add(3).write_volatile(lcr); // close DLAB
-        }
+    /// The UART described by node, with its register layout from the reg,
+    /// reg-shift and reg-io-width properties, and its input clock from
+    /// clock-frequency, or default_clock_hz if there's no such property.
+    pub fn from_dt(
+        dt: &DeviceTree,
+        node: Node,
+        default_clock_hz: u32,
+        dw_apb: bool,
+    ) -> Option<Self> {
+        let reg = dt.property_translated_reg_iter(node).next().and_then(|reg| reg.regblock())?;
+        let prop_u32 =
+            |name| dt.property(&node, name).and_then(|prop| dt.property_value_as_u32(&prop));
+        let reg_shift = prop_u32("reg-shift").unwrap_or(0);
+        let reg_io_width = prop_u32("reg-io-width").unwrap_or(1);
+        let clock_hz = prop_u32("clock-frequency").unwrap_or(default_clock_hz);
+        let regs = MmioRegs::new(reg.addr as usize, reg_shift, reg_io_width, dw_apb);
+        Some(Uart16550::new(regs, clock_hz))
     }
 
-    pub fn put(&mut self, c: u8) {
-        let ptr = self.ns16550a_reg.addr as *mut u8;
-        unsafe {
-            ptr.add(0).write_volatile(c);
-        }
+    /// Set the baud rate, with 8N1 framing and the receive interrupt enabled
+    pub fn init(&mut self, baud: u32) {
+        self.uart.init(self.clock_hz, baud, IER_RX_AVAILABLE);
     }
 
-    #[allow(dead_code)]
-    pub fn get(&mut self) -> Option<u8> {
-        let ptr = self.ns16550a_reg.addr as *mut u8;
-        unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                None
-            } else {
-                Some(ptr.add(0).read_volatile())
-            }
+    /// Receive interrupt handler.  Drains the receive FIFO into the buffer
+    /// read by try_getb, which also clears the interrupt.  nezha doesn't
+    /// route UART interrupts yet.
+    #[cfg_attr(platform = "nezha", allow(dead_code))]
+    pub fn handle_interrupt(&self) {
+        while let Some(b) = self.uart.try_getc() {
+            // The handler is the buffer's only producer.  Bytes that don't
+            // fit are dropped.
+            unsafe { RX_BUFFER.push(b) };
         }
     }
 }
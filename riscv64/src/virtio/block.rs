@@ -0,0 +1,164 @@
+//! Virtio block device driver
+//!
+//! Requests are made one at a time, by polling, through a single queue.
+//! Each request is a chain of three buffers: a header saying what to do,
+//! the data, and a status byte for the device to fill in.  These live in a
+//! page of their own, so the caller's buffer needn't be reachable by the
+//! device.
+
+use super::mmio::{DeviceType, VirtioMmio};
+use super::queue::{Buffer, VirtQueue, QUEUE_SIZE};
+use crate::pagealloc;
+use core::fmt;
+use core::mem::{offset_of, size_of};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_bytes, write_volatile};
+use port::mcslock::{Lock, LockNode};
+use port::mem::{PhysAddr, PhysRange, PAGE_SIZE_4K};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Offset in the config space of the capacity, in sectors
+const CONFIG_CAPACITY: usize = 0;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The sector is past the end of the device
+    OutOfRange,
+    /// The device reported an error, with the given status
+    Io(u8),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "sector out of range"),
+            BlockError::Io(status) => write!(f, "I/O error (status {status})"),
+        }
+    }
+}
+
+#[repr(C)]
+struct RequestHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Layout of the request page
+#[repr(C)]
+struct Request {
+    header: RequestHeader,
+    status: u8,
+    data: [u8; SECTOR_SIZE],
+}
+
+/// Give pages from pagealloc::allocate_contiguous back
+fn free_pages(pages: PhysRange) {
+    for pa in pages.step_by_rounded(PAGE_SIZE_4K) {
+        pagealloc::deallocate(pa).expect("freeing virtio block pages");
+    }
+}
+
+pub struct VirtioBlk {
+    transport: VirtioMmio,
+    queue: VirtQueue,
+    request_pa: PhysAddr,
+    /// Capacity in sectors
+    capacity: u64,
+    /// Serialises requests, which share the queue and request page
+    lock: Lock<()>,
+}
+
+impl VirtioBlk {
+    /// Set up the block device, or return None if it isn't one or can't be
+    /// initialised.
+    pub fn new(transport: VirtioMmio) -> Option<VirtioBlk> {
+        if transport.device_type() != DeviceType::Block {
+            return None;
+        }
+        // This marks the device as failed itself if it goes wrong
+        transport.negotiate_features(0).ok()?;
+
+        // One page for the queue and one for the request
+        let Ok(pages) = pagealloc::allocate_contiguous(2) else {
+            transport.fail();
+            return None;
+        };
+        let queue = unsafe { VirtQueue::new(pages.start()) };
+        let request_pa = PhysAddr::new(pages.start().addr() + PAGE_SIZE_4K as u64);
+        unsafe { write_bytes(request_pa.addr() as *mut Request, 0, 1) };
+
+        let queue_set_up = transport.setup_queue(
+            0,
+            QUEUE_SIZE as u16,
+            queue.desc_addr(),
+            queue.avail_addr(),
+            queue.used_addr(),
+        );
+        if queue_set_up.is_err() {
+            transport.fail();
+            free_pages(pages);
+            return None;
+        }
+        transport.driver_ok();
+
+        let capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        Some(VirtioBlk {
+            transport,
+            queue,
+            request_pa,
+            capacity,
+            lock: Lock::new("virtio_blk", ()),
+        })
+    }
+
+    /// Capacity in sectors
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Read a sector into buf
+    pub fn read_block(&self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), BlockError> {
+        if sector >= self.capacity {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let node = LockNode::new();
+        let _lock = self.lock.lock(&node);
+
+        let request = self.request_pa.addr() as *mut Request;
+        unsafe {
+            write_volatile(
+                addr_of_mut!((*request).header),
+                RequestHeader { req_type: VIRTIO_BLK_T_IN, reserved: 0, sector },
+            );
+            write_volatile(addr_of_mut!((*request).status), 0xff);
+        }
+
+        let pa = |offset| self.request_pa.addr() + offset as u64;
+        let buffers = [
+            Buffer {
+                pa: pa(offset_of!(Request, header)),
+                len: size_of::<RequestHeader>() as u32,
+                device_writable: false,
+            },
+            Buffer {
+                pa: pa(offset_of!(Request, data)),
+                len: SECTOR_SIZE as u32,
+                device_writable: true,
+            },
+            Buffer { pa: pa(offset_of!(Request, status)), len: 1, device_writable: true },
+        ];
+        self.queue.submit_and_wait(&self.transport, 0, &buffers);
+
+        let status = unsafe { read_volatile(addr_of!((*request).status)) };
+        if status != VIRTIO_BLK_S_OK {
+            return Err(BlockError::Io(status));
+        }
+        *buf = unsafe { read_volatile(addr_of!((*request).data)) };
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+//! Virtio MMIO transport
+//!
+//! Each virtio MMIO device has a block of registers, found through a
+//! `virtio,mmio` devicetree node.  QEMU's virt machine has 8 of these slots,
+//! and unused slots report device ID 0.  Only the modern (version 2) register
+//! layout is supported, so QEMU must be run with
+//! `-global virtio-mmio.force-legacy=false`.
+
+#![allow(dead_code)]
+
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use port::fdt::{DeviceTree, Node, RegBlock};
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+/// Version of the modern register layout
+const VERSION_MODERN: u32 = 2;
+
+// Register offsets
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_AVAIL_LOW: usize = 0x090;
+const QUEUE_AVAIL_HIGH: usize = 0x094;
+const QUEUE_USED_LOW: usize = 0x0a0;
+const QUEUE_USED_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// Set by devices that follow the virtio 1.0 spec or later.  The driver
+/// must accept it to use the modern interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Type of a virtio device, from its device ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Other(u32),
+}
+
+impl DeviceType {
+    fn from_id(id: u32) -> DeviceType {
+        match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            id => DeviceType::Other(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device didn't accept the features we asked for
+    FeaturesNotAccepted,
+    /// The queue doesn't exist or is already in use
+    QueueUnavailable,
+    /// The queue can't hold as many entries as asked for
+    QueueTooSmall,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtioError::FeaturesNotAccepted => write!(f, "device didn't accept features"),
+            VirtioError::QueueUnavailable => write!(f, "queue unavailable"),
+            VirtioError::QueueTooSmall => write!(f, "queue too small"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VirtioMmio {
+    reg: RegBlock,
+    device_type: DeviceType,
+}
+
+impl VirtioMmio {
+    /// Probe the virtio device for a `virtio,mmio` node.  Returns None if the
+    /// registers don't look like virtio, use the legacy layout, or the slot
+    /// has no device.  The registers must already be mapped.
+    pub fn from_dt_node(dt: &DeviceTree, node: Node) -> Option<VirtioMmio> {
+        let reg = dt.property_translated_reg_iter(node).next().and_then(|reg| reg.regblock())?;
+        VirtioMmio::new(reg)
+    }
+
+    /// Probe the virtio device with registers at reg
+    pub fn new(reg: RegBlock) -> Option<VirtioMmio> {
+        let mut dev = VirtioMmio { reg, device_type: DeviceType::Other(0) };
+        if dev.read(MAGIC_VALUE) != MAGIC || dev.read(VERSION) != VERSION_MODERN {
+            return None;
+        }
+        let device_id = dev.read(DEVICE_ID);
+        if device_id == 0 {
+            return None;
+        }
+        dev.device_type = DeviceType::from_id(device_id);
+        Some(dev)
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.read(VENDOR_ID)
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.reg.addr
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let ptr = (self.reg.addr as usize + offset) as *const u32;
+        unsafe { read_volatile(ptr) }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        let ptr = (self.reg.addr as usize + offset) as *mut u32;
+        unsafe { write_volatile(ptr, val) }
+    }
+
+    fn set_status(&self, bits: u32) {
+        self.write(STATUS, self.read(STATUS) | bits);
+    }
+
+    /// The features the device offers
+    pub fn device_features(&self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    /// Reset the device and negotiate features, returning those both sides
+    /// support.  `VIRTIO_F_VERSION_1` is always asked for.  The driver then
+    /// sets up its queues and calls `driver_ok`.
+    pub fn negotiate_features(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.write(STATUS, 0);
+        while self.read(STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+
+        let features = self.device_features() & (wanted | VIRTIO_F_VERSION_1);
+        self.set_driver_features(features);
+        self.set_status(STATUS_FEATURES_OK);
+        if self.read(STATUS) & STATUS_FEATURES_OK == 0 || features & VIRTIO_F_VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesNotAccepted);
+        }
+        Ok(features)
+    }
+
+    /// Give the device the physical addresses of a queue's descriptor table
+    /// and available and used rings, and enable it.  Must be called between
+    /// `negotiate_features` and `driver_ok`.
+    pub fn setup_queue(
+        &self,
+        index: u32,
+        size: u16,
+        desc: u64,
+        avail: u64,
+        used: u64,
+    ) -> Result<(), VirtioError> {
+        self.write(QUEUE_SEL, index);
+        if self.read(QUEUE_READY) != 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        match self.read(QUEUE_NUM_MAX) {
+            0 => return Err(VirtioError::QueueUnavailable),
+            max if max < size as u32 => return Err(VirtioError::QueueTooSmall),
+            _ => {}
+        }
+        self.write(QUEUE_NUM, size as u32);
+        self.write(QUEUE_DESC_LOW, desc as u32);
+        self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.write(QUEUE_AVAIL_LOW, avail as u32);
+        self.write(QUEUE_AVAIL_HIGH, (avail >> 32) as u32);
+        self.write(QUEUE_USED_LOW, used as u32);
+        self.write(QUEUE_USED_HIGH, (used >> 32) as u32);
+        self.write(QUEUE_READY, 1);
+        Ok(())
+    }
+
+    /// Tell the device there are new buffers in the queue
+    pub fn notify(&self, index: u32) {
+        self.write(QUEUE_NOTIFY, index);
+    }
+
+    /// Read a 32 bit field of the device-specific configuration
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    /// Read a 64 bit field of the device-specific configuration.  The
+    /// halves are read separately, so retry if the device changed the
+    /// configuration in between.
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.read(CONFIG_GENERATION);
+            let low = self.read(CONFIG + offset) as u64;
+            let high = self.read(CONFIG + offset + 4) as u64;
+            if self.read(CONFIG_GENERATION) == generation {
+                return (high << 32) | low;
+            }
+        }
+    }
+
+    /// Tell the device the driver is ready, finishing initialisation
+    pub fn driver_ok(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device the driver has given up on it
+    pub fn fail(&self) {
+        self.set_status(STATUS_FAILED);
+    }
+
+    /// Initialise a device that needs no queues set up
+    pub fn init(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let features = self.negotiate_features(wanted)?;
+        self.driver_ok();
+        Ok(features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Registers of a modern block device offering VIRTIO_F_VERSION_1.
+    /// Plain memory keeps whatever's written, so the device appears to
+    /// accept any features.
+    fn block_regs() -> [u32; 64] {
+        let mut regs = [0u32; 64];
+        regs[MAGIC_VALUE / 4] = MAGIC;
+        regs[VERSION / 4] = VERSION_MODERN;
+        regs[DEVICE_ID / 4] = 2;
+        regs[VENDOR_ID / 4] = 0x554d_4551;
+        regs
+    }
+
+    fn regblock(regs: &mut [u32; 64]) -> RegBlock {
+        RegBlock { addr: regs.as_mut_ptr() as u64, len: Some(0x100) }
+    }
+
+    #[test]
+    fn probe() {
+        let mut regs = block_regs();
+        let dev = VirtioMmio::new(regblock(&mut regs)).unwrap();
+        assert_eq!(dev.device_type(), DeviceType::Block);
+        assert_eq!(dev.vendor_id(), 0x554d_4551);
+
+        let mut empty = block_regs();
+        empty[DEVICE_ID / 4] = 0;
+        assert!(VirtioMmio::new(regblock(&mut empty)).is_none());
+
+        let mut legacy = block_regs();
+        legacy[VERSION / 4] = 1;
+        assert!(VirtioMmio::new(regblock(&mut legacy)).is_none());
+
+        let mut bad_magic = block_regs();
+        bad_magic[MAGIC_VALUE / 4] = 0;
+        assert!(VirtioMmio::new(regblock(&mut bad_magic)).is_none());
+    }
+
+    #[test]
+    fn handshake() {
+        let mut regs = block_regs();
+        // The same register reads back for both halves, so this offers
+        // features 0 and 32 (VIRTIO_F_VERSION_1)
+        regs[DEVICE_FEATURES / 4] = 1;
+        let dev = VirtioMmio::new(regblock(&mut regs)).unwrap();
+        assert_eq!(dev.init(1 | 1 << 5), Ok(VIRTIO_F_VERSION_1 | 1));
+        let status = unsafe { read_volatile(&regs[STATUS / 4]) };
+        assert_eq!(
+            status,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK
+        );
+    }
+}
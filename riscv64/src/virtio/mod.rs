@@ -0,0 +1,7 @@
+//! Virtio devices
+//!
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+pub mod block;
+pub mod mmio;
+pub mod queue;
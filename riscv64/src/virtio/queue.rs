@@ -0,0 +1,146 @@
+//! Split virtqueues
+//!
+//! A split virtqueue has three parts, all in memory shared with the device:
+//! a table of buffer descriptors, the available ring, where the driver puts
+//! chains of descriptors for the device, and the used ring, where the device
+//! returns them.  Here all three share a single page, and requests are made
+//! one at a time, so each request can use the descriptors from 0.
+
+use super::mmio::VirtioMmio;
+use core::mem::offset_of;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_bytes, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use port::mem::PhysAddr;
+
+/// Number of entries in the queue
+pub const QUEUE_SIZE: usize = 8;
+
+/// The descriptor continues in the one given by `next`
+const DESC_F_NEXT: u16 = 1;
+/// The device writes to the buffer, rather than reading it
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// Layout of the queue's page.  repr(C) gives the 16 byte alignment the
+/// descriptor table needs (from the page), and 2 and 4 bytes for the rings.
+#[repr(C)]
+struct QueueLayout {
+    desc: [Descriptor; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+}
+
+const _: () = assert!(core::mem::size_of::<QueueLayout>() <= 4096);
+
+/// A buffer for the device to read or write, by physical address
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub pa: u64,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+pub struct VirtQueue {
+    pa: PhysAddr,
+    layout: *mut QueueLayout,
+}
+
+impl VirtQueue {
+    /// Lay out an empty queue in the page at pa.
+    ///
+    /// # Safety
+    ///
+    /// The page must be mapped at its physical address, and not used for
+    /// anything else while the device can access it.
+    pub unsafe fn new(pa: PhysAddr) -> VirtQueue {
+        let layout = pa.addr() as *mut QueueLayout;
+        unsafe { write_bytes(layout, 0, 1) };
+        VirtQueue { pa, layout }
+    }
+
+    pub fn desc_addr(&self) -> u64 {
+        self.pa.addr() + offset_of!(QueueLayout, desc) as u64
+    }
+
+    pub fn avail_addr(&self) -> u64 {
+        self.pa.addr() + offset_of!(QueueLayout, avail) as u64
+    }
+
+    pub fn used_addr(&self) -> u64 {
+        self.pa.addr() + offset_of!(QueueLayout, used) as u64
+    }
+
+    /// Give the device the buffers as a single chain, and wait for it to
+    /// use them.  Returns the number of bytes the device wrote.
+    pub fn submit_and_wait(&self, transport: &VirtioMmio, index: u32, buffers: &[Buffer]) -> u32 {
+        assert!(!buffers.is_empty() && buffers.len() <= QUEUE_SIZE);
+        let layout = self.layout;
+
+        for (i, buf) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let mut flags = if last { 0 } else { DESC_F_NEXT };
+            if buf.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            let desc = Descriptor {
+                addr: buf.pa,
+                len: buf.len,
+                flags,
+                next: if last { 0 } else { i as u16 + 1 },
+            };
+            unsafe { write_volatile(addr_of_mut!((*layout).desc[i]), desc) };
+        }
+
+        // Publish the head of the chain, then the new index once the ring
+        // entry is visible
+        unsafe {
+            let avail_idx = read_volatile(addr_of!((*layout).avail.idx));
+            let slot = avail_idx as usize % QUEUE_SIZE;
+            write_volatile(addr_of_mut!((*layout).avail.ring[slot]), 0);
+            fence(Ordering::SeqCst);
+            write_volatile(addr_of_mut!((*layout).avail.idx), avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            transport.notify(index);
+
+            // Only one request is ever outstanding, so the device is done
+            // with it once the used index catches up
+            let wanted = avail_idx.wrapping_add(1);
+            while read_volatile(addr_of!((*layout).used.idx)) != wanted {
+                core::hint::spin_loop();
+            }
+            fence(Ordering::SeqCst);
+            read_volatile(addr_of!((*layout).used.ring[slot])).len
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! ACPI tables, found from the RSDP that the BIOS leaves in low memory.  Only
+//! as much is parsed as is needed to find the CPUs and the PCI configuration
+//! space.
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html
+
+use crate::e820::{phys_bytes, read_u32, read_u64};
+
+// Root system description pointer
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT_ADDRESS: usize = 16;
+const RSDP_XSDT_ADDRESS: usize = 24;
+/// Length of the ACPI 1.0 RSDP, which the checksum covers
+const RSDP_V1_LEN: usize = 20;
+/// Length of the ACPI 2.0 RSDP, which adds the XSDT address
+const RSDP_V2_LEN: usize = 36;
+
+/// Physical address of the BIOS data area word holding the EBDA's segment
+const EBDA_SEGMENT_PA: u64 = 0x40e;
+/// The RSDP is in the first KiB of the EBDA, or in the BIOS ROM
+const EBDA_SEARCH_LEN: usize = 1024;
+const BIOS_ROM_PA: u64 = 0xe_0000;
+const BIOS_ROM_LEN: usize = 0x2_0000;
+
+/// Tables must be in the first 4GiB, which l.S maps
+const MAPPED_PHYS_LIMIT: u64 = 4 << 30;
+
+// System description table header, common to all the tables
+const SDT_LENGTH: usize = 4;
+const SDT_HEADER_LEN: usize = 36;
+
+// Multiple APIC description table.  The interrupt controller structures
+// follow the header, the local APIC address and the flags.
+const MADT_SIGNATURE: &[u8] = b"APIC";
+const MADT_ENTRIES: usize = SDT_HEADER_LEN + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_LOCAL_APIC_ID: usize = 3;
+const MADT_LOCAL_APIC_FLAGS: usize = 4;
+const MADT_LOCAL_X2APIC_ID: usize = 4;
+const MADT_LOCAL_X2APIC_FLAGS: usize = 8;
+const MADT_ENABLED: u32 = 1 << 0;
+
+// PCI Express memory mapped configuration table.  The configuration space
+// base address allocations follow the header and 8 reserved bytes.
+const MCFG_SIGNATURE: &[u8] = b"MCFG";
+const MCFG_ENTRIES: usize = SDT_HEADER_LEN + 8;
+const MCFG_ENTRY_LEN: usize = 16;
+const MCFG_BASE: usize = 0;
+const MCFG_SEGMENT: usize = 8;
+const MCFG_START_BUS: usize = 10;
+const MCFG_END_BUS: usize = 11;
+
+/// A PCI segment's ECAM region, as described in the MCFG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion {
+    /// Physical address of bus 0's configuration space, even if start_bus
+    /// isn't 0
+    pub base: u64,
+    pub start_bus: u8,
+    /// Last bus, inclusive
+    pub end_bus: u8,
+}
+
+/// The bytes of an ACPI structure sum to zero
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Find the RSDP in area, where it's 16 byte aligned, returning its offset
+fn find_rsdp(area: &[u8]) -> Option<usize> {
+    (0..area.len().saturating_sub(RSDP_V1_LEN - 1)).step_by(16).find(|&offset| {
+        let rsdp = &area[offset..offset + RSDP_V1_LEN];
+        rsdp.starts_with(RSDP_SIGNATURE) && checksum_ok(rsdp)
+    })
+}
+
+/// Physical addresses of the tables listed in the RSDT or XSDT
+fn table_addrs(sdt: &[u8], entry_size: usize) -> impl Iterator<Item = u64> + '_ {
+    sdt.get(SDT_HEADER_LEN..).unwrap_or(&[]).chunks_exact(entry_size).map(move |entry| {
+        if entry_size == 8 {
+            read_u64(entry, 0).unwrap()
+        } else {
+            read_u32(entry, 0).unwrap() as u64
+        }
+    })
+}
+
+/// APIC IDs of the enabled CPUs in the MADT, from both local APICs and local
+/// x2APICs, in the MADT's order.  CPUs that could be brought online later
+/// aren't included.
+fn madt_apic_ids(madt: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    let mut offset = MADT_ENTRIES;
+    core::iter::from_fn(move || {
+        let (&kind, &len) = (madt.get(offset)?, madt.get(offset + 1)?);
+        if len < 2 {
+            return None;
+        }
+        let entry = madt.get(offset..offset + len as usize).unwrap_or(&[]);
+        offset += len as usize;
+        let id_and_flags = match kind {
+            MADT_LOCAL_APIC => entry
+                .get(MADT_LOCAL_APIC_ID)
+                .map(|&id| id as u32)
+                .zip(read_u32(entry, MADT_LOCAL_APIC_FLAGS)),
+            MADT_LOCAL_X2APIC => {
+                read_u32(entry, MADT_LOCAL_X2APIC_ID).zip(read_u32(entry, MADT_LOCAL_X2APIC_FLAGS))
+            }
+            _ => None,
+        };
+        Some(id_and_flags.filter(|&(_, flags)| flags & MADT_ENABLED != 0).map(|(id, _)| id))
+    })
+    .flatten()
+}
+
+/// The ECAM region for PCI segment 0 in the MCFG.  We don't support any
+/// other segments.
+fn mcfg_segment0(mcfg: &[u8]) -> Option<EcamRegion> {
+    mcfg.get(MCFG_ENTRIES..)?.chunks_exact(MCFG_ENTRY_LEN).find_map(|entry| {
+        let segment = u16::from_le_bytes([entry[MCFG_SEGMENT], entry[MCFG_SEGMENT + 1]]);
+        (segment == 0).then(|| EcamRegion {
+            base: read_u64(entry, MCFG_BASE).unwrap(),
+            start_bus: entry[MCFG_START_BUS],
+            end_bus: entry[MCFG_END_BUS],
+        })
+    })
+}
+
+/// The RSDP, from the EBDA or the BIOS ROM
+unsafe fn rsdp() -> Option<&'static [u8]> {
+    let ebda_segment = unsafe { phys_bytes(EBDA_SEGMENT_PA, 2) };
+    let ebda_pa = (u16::from_le_bytes([ebda_segment[0], ebda_segment[1]]) as u64) << 4;
+    [(ebda_pa, EBDA_SEARCH_LEN), (BIOS_ROM_PA, BIOS_ROM_LEN)]
+        .into_iter()
+        .filter(|&(pa, _)| pa != 0)
+        .find_map(|(pa, len)| {
+            let area = unsafe { phys_bytes(pa, len) };
+            let offset = find_rsdp(area)?;
+            Some(unsafe { phys_bytes(pa + offset as u64, RSDP_V2_LEN) })
+        })
+}
+
+/// The system description table at pa, if it's mapped and its checksum is
+/// good
+unsafe fn sdt(pa: u64) -> Option<&'static [u8]> {
+    let mapped =
+        |len: usize| pa.checked_add(len as u64).is_some_and(|end| end <= MAPPED_PHYS_LIMIT);
+    if !mapped(SDT_HEADER_LEN) {
+        return None;
+    }
+    let header = unsafe { phys_bytes(pa, SDT_HEADER_LEN) };
+    let len = read_u32(header, SDT_LENGTH)? as usize;
+    if len < SDT_HEADER_LEN || !mapped(len) {
+        return None;
+    }
+    let table = unsafe { phys_bytes(pa, len) };
+    checksum_ok(table).then_some(table)
+}
+
+/// Find the table with the given signature, from the XSDT if there is one,
+/// otherwise the RSDT.
+unsafe fn find_table(signature: &[u8]) -> Option<&'static [u8]> {
+    let rsdp = unsafe { rsdp()? };
+    let xsdt_pa = if rsdp[RSDP_REVISION] >= 2 { read_u64(rsdp, RSDP_XSDT_ADDRESS)? } else { 0 };
+    let (root, entry_size) = if xsdt_pa != 0 {
+        (unsafe { sdt(xsdt_pa)? }, 8)
+    } else {
+        (unsafe { sdt(read_u32(rsdp, RSDP_RSDT_ADDRESS)? as u64)? }, 4)
+    };
+    table_addrs(root, entry_size)
+        .filter_map(|pa| unsafe { sdt(pa) })
+        .find(|table| table.starts_with(signature))
+}
+
+/// APIC IDs of the enabled CPUs, according to the MADT, or None if there
+/// isn't one
+pub fn cpu_apic_ids() -> Option<impl Iterator<Item = u32>> {
+    let madt = unsafe { find_table(MADT_SIGNATURE)? };
+    Some(madt_apic_ids(madt))
+}
+
+/// The ECAM region for PCI segment 0, or None if there's no MCFG or it
+/// doesn't describe segment 0
+pub fn pci_ecam_region() -> Option<EcamRegion> {
+    let mcfg = unsafe { find_table(MCFG_SIGNATURE)? };
+    mcfg_segment0(mcfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsdp_found_on_16_byte_boundary() {
+        let mut area = [0u8; 64];
+        area[32..40].copy_from_slice(RSDP_SIGNATURE);
+        let sum = area[..RSDP_V1_LEN + 32].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        area[40] = 0u8.wrapping_sub(sum);
+        assert_eq!(find_rsdp(&area), Some(32));
+
+        // A bad checksum, or a misaligned signature, isn't an RSDP
+        area[40] = area[40].wrapping_add(1);
+        assert_eq!(find_rsdp(&area), None);
+        let mut area = [0u8; 64];
+        area[8..16].copy_from_slice(RSDP_SIGNATURE);
+        assert_eq!(find_rsdp(&area), None);
+    }
+
+    #[test]
+    fn madt_lists_enabled_cpus() {
+        let mut madt = [0u8; MADT_ENTRIES].to_vec();
+        madt[..4].copy_from_slice(MADT_SIGNATURE);
+        // Local APICs: enabled, online capable but not enabled, enabled
+        for (uid, apic_id, flags) in [(0u8, 0u8, 1u32), (1, 1, 2), (2, 4, 1)] {
+            madt.extend_from_slice(&[MADT_LOCAL_APIC, 8, uid, apic_id]);
+            madt.extend_from_slice(&flags.to_le_bytes());
+        }
+        // An I/O APIC
+        madt.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // An enabled local x2APIC
+        madt.extend_from_slice(&[MADT_LOCAL_X2APIC, 16, 0, 0]);
+        madt.extend_from_slice(&300u32.to_le_bytes());
+        madt.extend_from_slice(&1u32.to_le_bytes());
+        madt.extend_from_slice(&3u32.to_le_bytes());
+        assert!(madt_apic_ids(&madt).eq([0, 4, 300]));
+
+        // A truncated entry at the end is ignored
+        madt.extend_from_slice(&[MADT_LOCAL_APIC, 8, 4]);
+        assert!(madt_apic_ids(&madt).eq([0, 4, 300]));
+    }
+
+    #[test]
+    fn mcfg_segment0_region() {
+        let mut mcfg = [0u8; MCFG_ENTRIES].to_vec();
+        mcfg[..4].copy_from_slice(MCFG_SIGNATURE);
+        assert_eq!(mcfg_segment0(&mcfg), None);
+
+        // Segment 1, then segment 0 as QEMU's Q35 machine describes it
+        for (base, segment, start_bus, end_bus) in
+            [(0xc000_0000u64, 1u16, 0u8, 0x3fu8), (0xb000_0000, 0, 0, 0xff)]
+        {
+            mcfg.extend_from_slice(&base.to_le_bytes());
+            mcfg.extend_from_slice(&segment.to_le_bytes());
+            mcfg.extend_from_slice(&[start_bus, end_bus, 0, 0, 0, 0]);
+        }
+        assert_eq!(
+            mcfg_segment0(&mcfg),
+            Some(EcamRegion { base: 0xb000_0000, start_bus: 0, end_bus: 0xff })
+        );
+
+        // A truncated entry is ignored
+        let mut mcfg = mcfg[..MCFG_ENTRIES + MCFG_ENTRY_LEN].to_vec();
+        mcfg.extend_from_slice(&[0; MCFG_ENTRY_LEN - 1]);
+        assert_eq!(mcfg_segment0(&mcfg), None);
+    }
+
+    #[test]
+    fn rsdt_and_xsdt_entries() {
+        let mut rsdt = [0u8; SDT_HEADER_LEN].to_vec();
+        rsdt.extend_from_slice(&0x7fe_1000u32.to_le_bytes());
+        rsdt.extend_from_slice(&0x7fe_2000u32.to_le_bytes());
+        assert!(table_addrs(&rsdt, 4).eq([0x7fe_1000, 0x7fe_2000]));
+
+        let mut xsdt = [0u8; SDT_HEADER_LEN].to_vec();
+        xsdt.extend_from_slice(&0x1_0000_1000u64.to_le_bytes());
+        assert!(table_addrs(&xsdt, 8).eq([0x1_0000_1000]));
+    }
+}
@@ -0,0 +1,60 @@
+//! Interrupts delivered by the local APIC
+//!
+//! Sets up the local APIC (see lapic) with a periodic timer, and counts the
+//! ticks.  Interrupt handlers must call eoi once they're done.
+
+use crate::lapic;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use crate::lapic::SPURIOUS_VECTOR;
+
+/// Vector for the APIC timer.  The first vector after the CPU exceptions.
+pub const TIMER_VECTOR: u8 = 0x20;
+
+/// Initial count for the timer, with the timer clock divided by 16.
+/// QEMU's APIC timer runs at 1GHz, so this gives a 100Hz tick.
+/// TODO Calibrate against the PIT or TSC rather than assuming QEMU.
+const TIMER_INITIAL_COUNT: u32 = 625_000;
+
+/// Timer counts per millisecond, on the same assumption
+const TIMER_COUNTS_PER_MS: u64 = 62_500;
+
+/// Number of timer ticks since init
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Enable the local APIC on this CPU and start the periodic timer.
+/// Interrupts still need enabling once the IDT is loaded.
+pub fn init() {
+    lapic::init_lapic();
+    lapic::start_periodic_timer(TIMER_VECTOR, lapic::TimerDivide::By16, TIMER_INITIAL_COUNT);
+}
+
+/// Signal the end of an interrupt
+pub fn eoi() {
+    lapic::lapic_eoi();
+}
+
+/// Called on each timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer ticks since init
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Busy wait for at least us microseconds, by watching the timer count down.
+/// Works with interrupts disabled, but needs init to have started the timer.
+pub fn delay_us(us: u64) {
+    let target = us * TIMER_COUNTS_PER_MS / 1000;
+    let mut elapsed = 0;
+    let mut last = lapic::timer_current_count();
+    while elapsed < target {
+        core::hint::spin_loop();
+        let now = lapic::timer_current_count();
+        // The count reloads from the initial count when it reaches zero
+        elapsed += if now <= last { last - now } else { last + TIMER_INITIAL_COUNT - now } as u64;
+        last = now;
+    }
+}
@@ -0,0 +1,110 @@
+//! CPU feature detection using CPUID
+
+#![allow(dead_code)]
+
+// CPUID leaves
+const LEAF_BASIC: u32 = 0x0;
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_EXTENDED_FEATURES: u32 = 0x7;
+const LEAF_EXTENDED_BASIC: u32 = 0x8000_0000;
+const LEAF_EXTENDED_PROCESSOR: u32 = 0x8000_0001;
+
+/// Features of the current CPU that the kernel might care about
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuFeatures {
+    // Leaf 1
+    pub has_sse3: bool,
+    pub has_x2apic: bool,
+    pub has_xsave: bool,
+    pub has_osxsave: bool,
+    pub has_avx: bool,
+    pub has_rdrand: bool,
+    pub has_tsc: bool,
+    pub has_msr: bool,
+    pub has_pae: bool,
+    pub has_apic: bool,
+    pub has_pge: bool,
+    pub has_sse2: bool,
+    // Leaf 7
+    pub has_fsgsbase: bool,
+    pub has_avx2: bool,
+    pub has_smep: bool,
+    pub has_smap: bool,
+    // Leaf 0x8000_0001
+    pub has_syscall: bool,
+    pub has_nx: bool,
+    pub has_1gb_pages: bool,
+}
+
+/// Execute CPUID, returning (eax, ebx, ecx, edx)
+///
+/// # Safety
+///
+/// Leaves beyond the maximum supported return undefined values, so check
+/// leaf 0 or 0x8000_0000 first.
+#[allow(unused_variables)]
+pub unsafe fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    #[cfg(not(test))]
+    {
+        let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+        unsafe {
+            // rbx is reserved by LLVM, so preserve it by hand
+            core::arch::asm!(
+                "movq %rbx, {tmp:r}",
+                "cpuid",
+                "xchgq %rbx, {tmp:r}",
+                tmp = out(reg) ebx,
+                inlateout("eax") leaf => eax,
+                inlateout("ecx") subleaf => ecx,
+                out("edx") edx,
+                options(att_syntax, nomem, nostack, preserves_flags),
+            );
+        }
+        (eax, ebx, ecx, edx)
+    }
+    #[cfg(test)]
+    (0, 0, 0, 0)
+}
+
+fn bit(reg: u32, bit: u32) -> bool {
+    reg & (1 << bit) != 0
+}
+
+/// Query CPUID for the features of the current CPU
+pub fn detect_features() -> CpuFeatures {
+    let mut features = CpuFeatures::default();
+
+    let (max_leaf, _, _, _) = unsafe { cpuid(LEAF_BASIC, 0) };
+    if max_leaf >= LEAF_FEATURES {
+        let (_, _, ecx, edx) = unsafe { cpuid(LEAF_FEATURES, 0) };
+        features.has_sse3 = bit(ecx, 0);
+        features.has_x2apic = bit(ecx, 21);
+        features.has_xsave = bit(ecx, 26);
+        features.has_osxsave = bit(ecx, 27);
+        features.has_avx = bit(ecx, 28);
+        features.has_rdrand = bit(ecx, 30);
+        features.has_tsc = bit(edx, 4);
+        features.has_msr = bit(edx, 5);
+        features.has_pae = bit(edx, 6);
+        features.has_apic = bit(edx, 9);
+        features.has_pge = bit(edx, 13);
+        features.has_sse2 = bit(edx, 26);
+    }
+    if max_leaf >= LEAF_EXTENDED_FEATURES {
+        let (_, ebx, _, _) = unsafe { cpuid(LEAF_EXTENDED_FEATURES, 0) };
+        features.has_fsgsbase = bit(ebx, 0);
+        features.has_avx2 = bit(ebx, 5);
+        features.has_smep = bit(ebx, 7);
+        features.has_smap = bit(ebx, 20);
+    }
+
+    let (max_extended_leaf, _, _, _) = unsafe { cpuid(LEAF_EXTENDED_BASIC, 0) };
+    if max_extended_leaf >= LEAF_EXTENDED_PROCESSOR {
+        let (_, _, _, edx) = unsafe { cpuid(LEAF_EXTENDED_PROCESSOR, 0) };
+        features.has_syscall = bit(edx, 11);
+        features.has_nx = bit(edx, 20);
+        features.has_1gb_pages = bit(edx, 26);
+    }
+
+    features
+}
@@ -1,21 +1,27 @@
 // Racy to start.
 
+use crate::uart16550::{PortRegs, Uart16550, UART_CLOCK_HZ};
+use crate::vga::VgaConsole;
 use core::cell::SyncUnsafeCell;
-use port::devcons::{Console, Uart};
-
-struct Uart16550 {
-    port: u16,
-}
-
-impl Uart for Uart16550 {
-    fn putb(&self, b: u8) {
-        crate::uart16550::putb(self.port, b);
-    }
-}
+use core::mem::MaybeUninit;
+use port::devcons::Console;
 
+/// Use the first serial port for the console, or the VGA text screen if
+/// there's no serial port.
 pub fn init() {
     Console::new(|| {
-        static CONS: SyncUnsafeCell<Uart16550> = SyncUnsafeCell::new(Uart16550 { port: 0x3f8 });
-        unsafe { &mut *CONS.get() }
+        static CONS: SyncUnsafeCell<Uart16550> =
+            SyncUnsafeCell::new(Uart16550::new(PortRegs::new(0x3f8)));
+        let uart = unsafe { &mut *CONS.get() };
+        if uart.is_present() {
+            uart.init(UART_CLOCK_HZ, 115200, 0);
+            return uart;
+        }
+
+        static VGA: SyncUnsafeCell<MaybeUninit<VgaConsole>> =
+            SyncUnsafeCell::new(MaybeUninit::uninit());
+        let vga = unsafe { (*VGA.get()).write(VgaConsole::new()) };
+        vga.clear();
+        vga
     });
 }
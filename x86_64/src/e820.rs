@@ -0,0 +1,220 @@
+//! Physical memory map, as passed by the bootloader.
+//!
+//! The map is the BIOS E820 map, passed either in the multiboot information
+//! structure, which is what l.S asks for, or the multiboot2 memory map tag
+//! (type 6).
+
+use crate::param::KZERO;
+
+/// Value of eax on entry from a multiboot compliant bootloader
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2bad_b002;
+/// Value of eax on entry from a multiboot2 compliant bootloader
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+// Multiboot information structure
+const MULTIBOOT_INFO_FLAG_MMAP: u32 = 1 << 6;
+const MULTIBOOT_INFO_FLAGS: usize = 0;
+const MULTIBOOT_INFO_MMAP_LENGTH: usize = 44;
+const MULTIBOOT_INFO_MMAP_ADDR: usize = 48;
+const MULTIBOOT_INFO_SIZE: usize = 52;
+
+// Multiboot2 tags
+const MULTIBOOT2_TAG_END: u32 = 0;
+const MULTIBOOT2_TAG_MMAP: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Bad,
+    Unknown(u32),
+}
+
+impl From<u32> for MemoryKind {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => MemoryKind::Available,
+            2 => MemoryKind::Reserved,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::Bad,
+            v => MemoryKind::Unknown(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    pub kind: MemoryKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// Each entry is prefixed with its size, not including the size field
+    Multiboot,
+    /// All entries have the same size
+    Multiboot2 { entry_size: usize },
+}
+
+/// The memory map entries, as laid out by the bootloader
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMap<'a> {
+    entries: &'a [u8],
+    format: Format,
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Bytes at the physical address pa, which must be in the first 4GiB mapped
+/// by l.S.
+pub unsafe fn phys_bytes(pa: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((pa as usize + KZERO) as *const u8, len) }
+}
+
+impl<'a> MemoryMap<'a> {
+    /// Memory map from the mmap_addr buffer of a multiboot information
+    /// structure.
+    pub fn multiboot(entries: &'a [u8]) -> Self {
+        Self { entries, format: Format::Multiboot }
+    }
+
+    /// Memory map from a multiboot2 information structure, which is a list
+    /// of tags.  Returns None if there's no memory map tag.
+    pub fn multiboot2(info: &'a [u8]) -> Option<Self> {
+        // Tags start after the total_size and reserved fields, and are 8 byte aligned
+        let mut offset = 8;
+        loop {
+            let tag_type = read_u32(info, offset)?;
+            let tag_size = read_u32(info, offset + 4)? as usize;
+            match tag_type {
+                MULTIBOOT2_TAG_END => return None,
+                MULTIBOOT2_TAG_MMAP => {
+                    let entry_size = read_u32(info, offset + 8)? as usize;
+                    let entries = info.get(offset + 16..offset + tag_size)?;
+                    return Some(Self { entries, format: Format::Multiboot2 { entry_size } });
+                }
+                _ => {}
+            }
+            offset += (tag_size + 7) & !7;
+        }
+    }
+
+    /// Find the memory map passed by the bootloader in ebx.
+    ///
+    /// # Safety
+    ///
+    /// info_pa must be the physical address of the multiboot information
+    /// passed by a bootloader matching magic.
+    pub unsafe fn from_bootloader(magic: u32, info_pa: u64) -> Option<MemoryMap<'static>> {
+        match magic {
+            MULTIBOOT_BOOTLOADER_MAGIC => {
+                let info = unsafe { phys_bytes(info_pa, MULTIBOOT_INFO_SIZE) };
+                if read_u32(info, MULTIBOOT_INFO_FLAGS)? & MULTIBOOT_INFO_FLAG_MMAP == 0 {
+                    return None;
+                }
+                let len = read_u32(info, MULTIBOOT_INFO_MMAP_LENGTH)? as usize;
+                let addr = read_u32(info, MULTIBOOT_INFO_MMAP_ADDR)? as u64;
+                Some(MemoryMap::multiboot(unsafe { phys_bytes(addr, len) }))
+            }
+            MULTIBOOT2_BOOTLOADER_MAGIC => {
+                let total_size = read_u32(unsafe { phys_bytes(info_pa, 4) }, 0)? as usize;
+                MemoryMap::multiboot2(unsafe { phys_bytes(info_pa, total_size) })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = MemoryRegion> + 'a {
+        let entries = self.entries;
+        let format = self.format;
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let (entry, next) = match format {
+                Format::Multiboot => {
+                    let size = read_u32(entries, offset)? as usize;
+                    (offset + 4, offset + 4 + size)
+                }
+                Format::Multiboot2 { entry_size } => (offset, offset + entry_size.max(24)),
+            };
+            let region = MemoryRegion {
+                base: read_u64(entries, entry)?,
+                length: read_u64(entries, entry + 8)?,
+                kind: read_u32(entries, entry + 16)?.into(),
+            };
+            offset = next;
+            Some(region)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(base: u64, length: u64, kind: u32) -> [u8; 20] {
+        let mut e = [0; 20];
+        e[0..8].copy_from_slice(&base.to_le_bytes());
+        e[8..16].copy_from_slice(&length.to_le_bytes());
+        e[16..20].copy_from_slice(&kind.to_le_bytes());
+        e
+    }
+
+    #[test]
+    fn parse_multiboot_mmap() {
+        let mut buf = [0u8; 48];
+        buf[0..4].copy_from_slice(&20u32.to_le_bytes());
+        buf[4..24].copy_from_slice(&entry(0, 0x9fc00, 1));
+        buf[24..28].copy_from_slice(&20u32.to_le_bytes());
+        buf[28..48].copy_from_slice(&entry(0x9fc00, 0x400, 2));
+
+        let mut regions = MemoryMap::multiboot(&buf).regions();
+        assert_eq!(
+            regions.next(),
+            Some(MemoryRegion { base: 0, length: 0x9fc00, kind: MemoryKind::Available })
+        );
+        assert_eq!(
+            regions.next(),
+            Some(MemoryRegion { base: 0x9fc00, length: 0x400, kind: MemoryKind::Reserved })
+        );
+        assert_eq!(regions.next(), None);
+    }
+
+    #[test]
+    fn parse_multiboot2_mmap() {
+        let mut buf = [0u8; 96];
+        buf[0..4].copy_from_slice(&96u32.to_le_bytes());
+        // Some other tag, with a size that needs rounding up
+        buf[8..12].copy_from_slice(&1u32.to_le_bytes());
+        buf[12..16].copy_from_slice(&12u32.to_le_bytes());
+        // Memory map tag, with 2 entries
+        buf[24..28].copy_from_slice(&6u32.to_le_bytes());
+        buf[28..32].copy_from_slice(&64u32.to_le_bytes());
+        buf[32..36].copy_from_slice(&24u32.to_le_bytes());
+        buf[40..60].copy_from_slice(&entry(0x100000, 0x7ee0000, 1));
+        buf[64..84].copy_from_slice(&entry(0xfffc0000, 0x40000, 2));
+        // End tag
+        buf[92..96].copy_from_slice(&8u32.to_le_bytes());
+
+        let map = MemoryMap::multiboot2(&buf).unwrap();
+        let mut regions = map.regions();
+        assert_eq!(
+            regions.next(),
+            Some(MemoryRegion { base: 0x100000, length: 0x7ee0000, kind: MemoryKind::Available })
+        );
+        assert_eq!(
+            regions.next(),
+            Some(MemoryRegion { base: 0xfffc0000, length: 0x40000, kind: MemoryKind::Reserved })
+        );
+        assert_eq!(regions.next(), None);
+    }
+}
@@ -0,0 +1,55 @@
+//! Kernel tests run under QEMU with `cargo xtask qemu-test`.  See
+//! `port::ktest` for the exit protocol.
+
+extern crate alloc;
+
+use crate::pagealloc;
+use crate::pio;
+use alloc::vec::Vec;
+use port::ktest::{run_tests, ExitCode, KernelTest};
+
+/// I/O port of QEMU's isa-debug-exit device
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+const TESTS: &[KernelTest] = &[
+    KernelTest { name: "heap_alloc", run: heap_alloc },
+    KernelTest { name: "page_alloc", run: page_alloc },
+];
+
+/// Run the kernel tests and report the result to QEMU.  Never returns, but
+/// isn't `-> !` so the rest of main9 doesn't warn as unreachable.
+pub fn run() {
+    exit(run_tests(TESTS))
+}
+
+/// Exit QEMU through the isa-debug-exit device.  QEMU exits with
+/// `(value << 1) | 1`, so 33 for success and 35 for failure.
+pub fn exit(code: ExitCode) -> ! {
+    let value = match code {
+        ExitCode::Success => 0x10,
+        ExitCode::Failure => 0x11,
+    };
+    unsafe { pio::outl(DEBUG_EXIT_PORT, value) };
+
+    // Only reached if QEMU has no isa-debug-exit device
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+fn heap_alloc() {
+    let v: Vec<u64> = (0..1000).collect();
+    assert_eq!(v.iter().sum::<u64>(), 499500);
+}
+
+fn page_alloc() {
+    let a = pagealloc::allocate().expect("page allocation failed");
+    let b = pagealloc::allocate().expect("page allocation failed");
+    assert_ne!(a, b);
+    pagealloc::deallocate(a).expect("page free failed");
+    pagealloc::deallocate(b).expect("page free failed");
+
+    // Freed pages are handed out again
+    let c = pagealloc::allocate().expect("page allocation failed");
+    assert!(c == a || c == b);
+    pagealloc::deallocate(c).expect("page free failed");
+}
@@ -0,0 +1,195 @@
+//! Local APIC
+//!
+//! Each CPU has a local APIC, which receives interrupts from devices (via the
+//! IO APIC) and other CPUs, and also has its own timer.  It replaces the
+//! legacy 8259 PIC, which we mask off.
+//!
+//! The registers are accessed through a 4KiB MMIO page, usually at
+//! 0xfee00000.  l.S maps the first 4GiB of physical memory at KZERO, which
+//! includes the LAPIC page, so no further mapping is needed.
+
+#![allow(dead_code)]
+
+use crate::param::KZERO;
+use crate::pio::outb;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86::msr::IA32_APIC_BASE;
+
+// IA32_APIC_BASE fields
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Register offsets
+const ID: usize = 0x020;
+const VERSION: usize = 0x030;
+const TPR: usize = 0x080;
+const EOI: usize = 0x0b0;
+const SVR: usize = 0x0f0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE_CONFIG: usize = 0x3e0;
+
+// Spurious interrupt vector register
+const SVR_ENABLE: u32 = 1 << 8;
+
+// Local vector table entries
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_DELIVERY_EXTINT: u32 = 0b111 << 8;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+// Interrupt command register
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SHIFT: u32 = 24;
+
+/// Vector delivered for spurious interrupts.  The low 4 bits must be set on
+/// older processors.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Vector for APIC errors
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+// Legacy 8259 PIC data ports
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xa1;
+
+/// Virtual address of the LAPIC registers, or 0 if not initialised
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(unused_variables)]
+fn rdmsr(msr: u32) -> u64 {
+    #[cfg(not(test))]
+    unsafe {
+        x86::msr::rdmsr(msr)
+    }
+    #[cfg(test)]
+    0
+}
+
+#[allow(unused_variables)]
+fn wrmsr(msr: u32, value: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        x86::msr::wrmsr(msr, value)
+    }
+}
+
+fn read(offset: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "lapic not initialised");
+    unsafe { read_volatile((base + offset) as *const u32) }
+}
+
+fn write(offset: usize, val: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "lapic not initialised");
+    unsafe { write_volatile((base + offset) as *mut u32, val) }
+}
+
+/// Mask all interrupts on the legacy PICs, so that only the APIC delivers
+/// interrupts.
+fn disable_pic() {
+    unsafe {
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+/// Enable the local APIC on this CPU.  Interrupts from the legacy PIC on
+/// LINT0 are masked, and LINT1 is used for NMIs.
+pub fn init_lapic() {
+    disable_pic();
+
+    let apic_base = rdmsr(IA32_APIC_BASE);
+    if apic_base & APIC_BASE_ENABLE == 0 {
+        wrmsr(IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+    }
+    let pa = (apic_base & APIC_BASE_ADDR_MASK) as usize;
+    LAPIC_BASE.store(pa + KZERO, Ordering::Relaxed);
+
+    write(SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    write(LVT_LINT0, LVT_MASKED | LVT_DELIVERY_EXTINT);
+    write(LVT_LINT1, LVT_DELIVERY_NMI);
+    write(LVT_ERROR, ERROR_VECTOR as u32);
+    write(LVT_TIMER, LVT_MASKED);
+
+    // Accept all interrupt priorities
+    write(TPR, 0);
+
+    // Clear any outstanding interrupts
+    lapic_eoi();
+}
+
+/// Divider applied to the bus clock to drive the timer
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+/// Start the timer counting down from initial_count repeatedly, delivering
+/// vector each time it reaches zero.
+pub fn start_periodic_timer(vector: u8, divide: TimerDivide, initial_count: u32) {
+    write(TIMER_DIVIDE_CONFIG, divide as u32);
+    write(LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL_COUNT, initial_count);
+}
+
+/// Current value of the timer count down
+pub fn timer_current_count() -> u32 {
+    read(TIMER_CURRENT_COUNT)
+}
+
+/// Signal the end of an interrupt
+pub fn lapic_eoi() {
+    write(EOI, 0);
+}
+
+/// APIC ID of the current CPU
+pub fn lapic_id() -> u32 {
+    read(ID) >> 24
+}
+
+/// Version register, containing the version and number of LVT entries
+pub fn lapic_version() -> u32 {
+    read(VERSION)
+}
+
+/// Send an inter-processor interrupt to the CPU with the APIC ID, and wait
+/// for the local APIC to accept it.
+fn send_ipi(apic_id: u32, command: u32) {
+    write(ICR_HIGH, apic_id << ICR_DESTINATION_SHIFT);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Send an INIT IPI, which resets the CPU and leaves it waiting for a
+/// startup IPI.
+pub fn send_init_ipi(apic_id: u32) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup IPI.  The CPU starts in real mode at physical address
+/// vector * 4KiB.
+pub fn send_startup_ipi(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector as u32);
+}
@@ -6,11 +6,25 @@
 #![allow(clippy::upper_case_acronyms)]
 #![forbid(unsafe_op_in_unsafe_fn)]
 
+mod acpi;
+mod apic;
+mod cpu;
 mod dat;
 mod devcons;
+mod e820;
+#[cfg(feature = "qemu_test")]
+mod ktest;
+mod lapic;
+mod pagealloc;
+mod param;
+mod pci;
 mod pio;
 mod proc;
+mod smp;
+mod syscall;
+mod trap;
 mod uart16550;
+mod vga;
 
 use proc::{swtch, Label};
 
@@ -32,11 +46,78 @@ fn jumpback() {
     }
 }
 
+/// l.S passes the address of the Mach, which we don't use yet, and the
+/// multiboot magic number and information pointer from the bootloader.
 #[no_mangle]
-pub extern "C" fn main9() {
+pub extern "C" fn main9(_mach: usize, multiboot_magic: u32, multiboot_info: u32) {
     devcons::init();
     println!();
     println!("r9 from the Internet");
+
+    // Set up the page allocator before anything needs memory
+    let Some(memory_map) =
+        (unsafe { e820::MemoryMap::from_bootloader(multiboot_magic, multiboot_info as u64) })
+    else {
+        panic!("No memory map from bootloader (magic {multiboot_magic:#x})");
+    };
+    println!("Physical memory map:");
+    for region in memory_map.regions() {
+        println!(
+            "  {:#018x}..{:#018x} {:?}",
+            region.base,
+            region.base + region.length,
+            region.kind
+        );
+    }
+    if let Err(err) = pagealloc::init(&memory_map) {
+        panic!("Couldn't initialise page allocator: {err:?}");
+    }
+    let (used, total) = pagealloc::usage_bytes();
+    println!("Memory usage: used {used:#x} total {total:#x}");
+
+    let features = cpu::detect_features();
+    println!("{features:?}");
+    if !features.has_apic || !features.has_msr {
+        panic!("CPU doesn't have an APIC and MSRs");
+    }
+
+    trap::init();
+    syscall::init(0);
+    apic::init();
+    println!("lapic id {} version {:#x}", lapic::lapic_id(), lapic::lapic_version());
+    smp::init_boot_cpu();
+    pagealloc::init_per_cpu_cache(0);
+
+    #[cfg(feature = "qemu_test")]
+    ktest::run();
+
+    // Wait for a few timer ticks
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("sti")
+    };
+    while apic::ticks() < 5 {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("hlt")
+        };
+    }
+    println!("timer ticks: {}", apic::ticks());
+
+    // Without a MADT, try as many CPUs as we support, assuming the APIC IDs
+    // are contiguous from 0, as QEMU's are
+    let num_cpus = match acpi::cpu_apic_ids() {
+        Some(apic_ids) => smp::start_aps(apic_ids),
+        None => smp::start_aps(0..smp::MAX_CPUS as u32),
+    };
+    println!("{num_cpus} cpus running");
+
+    match acpi::pci_ecam_region()
+        .and_then(|region| pci::Ecam::new(region.base, region.start_bus, region.end_bus))
+    {
+        Some(ecam) => ecam.enumerate(),
+        None => println!("No usable PCI ECAM region in the MCFG"),
+    }
     println!("looping now");
     let mut ctx = Label::new();
     let mut thr = Label::new();
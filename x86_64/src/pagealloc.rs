@@ -0,0 +1,143 @@
+/// This module acts as an interface between the portable allocator and the
+/// arch-specific use of it.
+///
+/// The allocator starts with all pages marked as allocated.  `init` then
+/// marks the available regions of the bootloader's memory map as free,
+/// except for the kernel itself and the low memory below it.
+///
+/// Once a CPU has called `init_per_cpu_cache`, its allocations and frees go
+/// through its own `PageCache`, which only takes the global allocator's lock
+/// to move pages in batches.
+use crate::e820::{MemoryKind, MemoryMap};
+use crate::smp::{self, MAX_CPUS};
+use crate::trap::LocalInterrupts;
+use core::cell::SyncUnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use port::bitmapalloc::{BitmapPageAlloc, BitmapPageAllocError};
+use port::mcslock::{Interrupts, Lock, LockNode};
+use port::mem::{PhysAddr, PhysRange, PAGE_SIZE_4K};
+use port::pagecache::PageCache;
+
+/// Covers the first 4GiB, which is all that l.S maps
+static PAGE_ALLOC: Lock<BitmapPageAlloc<32, PAGE_SIZE_4K>> = Lock::new(
+    "page_alloc",
+    const { BitmapPageAlloc::<32, PAGE_SIZE_4K>::new_all_allocated(PAGE_SIZE_4K) },
+);
+
+/// Number of pages each CPU can cache
+const PAGE_CACHE_SIZE: usize = 32;
+
+/// Per-CPU page caches, indexed by CPU number.  Each is only used by its own CPU
+/// with interrupts masked, so needs no lock.
+static PAGE_CACHES: SyncUnsafeCell<[Option<PageCache<PAGE_CACHE_SIZE>>; MAX_CPUS]> =
+    SyncUnsafeCell::new([const { None }; MAX_CPUS]);
+
+/// Set once any CPU has a cache.  Until then the local APIC may not be set
+/// up, so we can't find out which CPU we're on.
+static CACHES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Maximum number of available regions we track from the memory map
+const MAX_REGIONS: usize = 32;
+
+const MAX_PHYS_ADDR: u64 = 4 << 30;
+
+/// Physical address of the end of the kernel image, from kernel.ld
+fn kernel_end() -> PhysAddr {
+    #[cfg(not(test))]
+    {
+        use crate::param::KZERO;
+        extern "C" {
+            static end: [u64; 0];
+        }
+        PhysAddr::new((unsafe { end.as_ptr().addr() } - KZERO) as u64)
+    }
+    #[cfg(test)]
+    PhysAddr::new(0)
+}
+
+/// Mark the available regions of the memory map as free.  Everything from 0
+/// to the end of the kernel stays allocated, since it holds the BIOS data,
+/// the early page tables and stacks set up by l.S, and the kernel.
+pub fn init(memory_map: &MemoryMap) -> Result<(), BitmapPageAllocError> {
+    // Clamp available regions to what we can map, and round inwards to
+    // whole pages
+    let mut regions: [PhysRange; MAX_REGIONS] = core::array::from_fn(|_| PhysRange::with_end(0, 0));
+    let mut num_regions = 0;
+    for region in memory_map.regions().filter(|r| r.kind == MemoryKind::Available) {
+        let start = PhysAddr::new(region.base.min(MAX_PHYS_ADDR)).round_up(PAGE_SIZE_4K as u64);
+        let end = PhysAddr::new(region.base.saturating_add(region.length).min(MAX_PHYS_ADDR))
+            .round_down(PAGE_SIZE_4K as u64);
+        if start < end && num_regions < MAX_REGIONS {
+            regions[num_regions] = PhysRange::new(start, end);
+            num_regions += 1;
+        }
+    }
+    let regions = &mut regions[..num_regions];
+    regions.sort_unstable_by_key(|r| r.start());
+
+    let used = [PhysRange::new(PhysAddr::new(0), kernel_end())];
+
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    for region in regions.iter() {
+        page_alloc.free_unused_ranges(region, used.iter())?;
+    }
+    Ok(())
+}
+
+/// Give the CPU a page cache.  Must be called on the CPU itself, after it's
+/// been numbered by smp.
+pub fn init_per_cpu_cache(cpu: usize) {
+    let state = LocalInterrupts::disable();
+    unsafe { (*PAGE_CACHES.get())[cpu] = Some(PageCache::new()) };
+    CACHES_ENABLED.store(true, Ordering::Release);
+    LocalInterrupts::restore(state);
+}
+
+/// Call f with this CPU's page cache, if it has one, with interrupts masked
+fn with_cache<R>(f: impl FnOnce(Option<&mut PageCache<PAGE_CACHE_SIZE>>) -> R) -> R {
+    let state = LocalInterrupts::disable();
+    let cache = if CACHES_ENABLED.load(Ordering::Acquire) {
+        smp::cpu_id().and_then(|cpu| unsafe { (*PAGE_CACHES.get())[cpu].as_mut() })
+    } else {
+        None
+    };
+    let result = f(cache);
+    LocalInterrupts::restore(state);
+    result
+}
+
+/// Try to allocate a page, returning its physical address
+#[allow(dead_code)]
+pub fn allocate() -> Result<PhysAddr, BitmapPageAllocError> {
+    with_cache(|cache| match cache {
+        Some(cache) => cache.allocate(&PAGE_ALLOC),
+        None => {
+            let node = LockNode::new();
+            let mut lock = PAGE_ALLOC.lock(&node);
+            lock.allocate()
+        }
+    })
+}
+
+/// Free a page allocated with `allocate`
+#[allow(dead_code)]
+pub fn deallocate(pa: PhysAddr) -> Result<(), BitmapPageAllocError> {
+    with_cache(|cache| match cache {
+        Some(cache) => cache.deallocate(&PAGE_ALLOC, pa),
+        None => {
+            let node = LockNode::new();
+            let mut lock = PAGE_ALLOC.lock(&node);
+            lock.deallocate(pa)
+        }
+    })
+}
+
+/// Return a tuple of (bytes used, total bytes available) based on the page allocator.
+pub fn usage_bytes() -> (usize, usize) {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.usage_bytes()
+}
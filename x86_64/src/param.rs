@@ -0,0 +1,2 @@
+// This needs to match KZERO in l.S
+pub const KZERO: usize = 0xffff_8000_0000_0000;
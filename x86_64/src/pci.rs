@@ -0,0 +1,126 @@
+//! PCI configuration space access through ECAM (the PCIe enhanced
+//! configuration access mechanism), where each function's configuration
+//! space is a 4KiB MMIO page.  The ECAM region comes from the ACPI MCFG.
+
+use crate::param::KZERO;
+use core::ptr::read_volatile;
+use port::mem::VirtRange;
+use port::println;
+
+/// The ECAM region must be in the first 4GiB, which l.S maps
+const MAPPED_PHYS_LIMIT: u64 = 4 << 30;
+
+const NUM_DEVICES: u8 = 32;
+const NUM_FUNCTIONS: u8 = 8;
+
+// Configuration space header offsets
+const VENDOR_DEVICE_ID: u16 = 0x00;
+const CLASS_REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0c;
+
+const HEADER_TYPE_MULTIFUNCTION: u32 = 1 << 23;
+const NO_VENDOR: u16 = 0xffff;
+
+/// Offset of a register in the ECAM region
+fn config_offset(bus: u8, device: u8, func: u8, offset: u16) -> usize {
+    ((bus as usize) << 20)
+        | ((device as usize & 0x1f) << 15)
+        | ((func as usize & 0x7) << 12)
+        | (offset as usize & 0xffc)
+}
+
+/// The configuration space of a range of buses
+pub struct Ecam {
+    range: VirtRange,
+    start_bus: u8,
+}
+
+impl Ecam {
+    /// The ECAM region for start_bus to end_bus inclusive, where base is the
+    /// physical address of bus 0's configuration space.  Returns None if the
+    /// bus range is empty or the region isn't mapped.
+    pub fn new(base: u64, start_bus: u8, end_bus: u8) -> Option<Ecam> {
+        let num_buses = end_bus.checked_sub(start_bus)? as u64 + 1;
+        let start = base.checked_add((start_bus as u64) << 20)?;
+        let len = num_buses << 20;
+        if start.checked_add(len)? > MAPPED_PHYS_LIMIT {
+            return None;
+        }
+        Some(Ecam { range: VirtRange::with_len(KZERO + start as usize, len as usize), start_bus })
+    }
+
+    /// Virtual address of a register, if its bus is in the region
+    fn config_addr(&self, bus: u8, device: u8, func: u8, offset: u16) -> Option<usize> {
+        let bus = bus.checked_sub(self.start_bus)?;
+        self.range.offset_addr(config_offset(bus, device, func, offset))
+    }
+
+    /// Read a 32 bit register from a function's configuration space.  Reads
+    /// from buses outside the region return all ones, as if there were no
+    /// device.
+    pub fn read_config_u32(&self, bus: u8, device: u8, func: u8, offset: u16) -> u32 {
+        match self.config_addr(bus, device, func, offset) {
+            Some(addr) => unsafe { read_volatile(addr as *const u32) },
+            None => u32::MAX,
+        }
+    }
+
+    fn print_function(&self, bus: u8, device: u8, func: u8) -> bool {
+        let id = self.read_config_u32(bus, device, func, VENDOR_DEVICE_ID);
+        let vendor = id as u16;
+        if vendor == NO_VENDOR {
+            return false;
+        }
+        let device_id = (id >> 16) as u16;
+        let class = self.read_config_u32(bus, device, func, CLASS_REVISION) >> 8;
+        println!("  {bus:02x}:{device:02x}.{func} {vendor:04x}:{device_id:04x} class {class:06x}");
+        true
+    }
+
+    /// Scan the first bus and print each device found
+    pub fn enumerate(&self) {
+        println!("PCI devices:");
+        let bus = self.start_bus;
+        for device in 0..NUM_DEVICES {
+            if !self.print_function(bus, device, 0) {
+                continue;
+            }
+            if self.read_config_u32(bus, device, 0, HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION != 0 {
+                for func in 1..NUM_FUNCTIONS {
+                    self.print_function(bus, device, func);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecam_offsets() {
+        assert_eq!(config_offset(0, 0, 0, 0), 0);
+        assert_eq!(config_offset(0, 0x1f, 0, 0x08), 0xf8008);
+        assert_eq!(config_offset(1, 2, 3, 0x0c), 0x11300c);
+        assert_eq!(config_offset(0xff, 0x1f, 7, 0xfff), 0x0fff_fffc);
+    }
+
+    #[test]
+    fn ecam_bus_range() {
+        let ecam = Ecam::new(0xb000_0000, 0, 0xff).unwrap();
+        assert_eq!(ecam.config_addr(0, 0, 0, 0), Some(KZERO + 0xb000_0000));
+        assert_eq!(ecam.config_addr(0xff, 0x1f, 7, 0xffc), Some(KZERO + 0xbfff_fffc));
+
+        // The base is bus 0's, even when the region starts later
+        let ecam = Ecam::new(0xb000_0000, 0x10, 0x1f).unwrap();
+        assert_eq!(ecam.config_addr(0x10, 0, 0, 0), Some(KZERO + 0xb100_0000));
+        assert_eq!(ecam.config_addr(0x0f, 0, 0, 0), None);
+        assert_eq!(ecam.config_addr(0x20, 0, 0, 0), None);
+
+        // Empty or unmapped regions
+        assert!(Ecam::new(0xb000_0000, 1, 0).is_none());
+        assert!(Ecam::new(0xf010_0000, 0, 0xff).is_none());
+        assert!(Ecam::new(0xf000_0000, 0, 0xff).is_some());
+    }
+}
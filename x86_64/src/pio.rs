@@ -1,22 +1,127 @@
 #![allow(unused_variables, dead_code)]
 
+// The instructions, shared by the functions and the encoding test
+macro_rules! outb_asm {
+    () => {
+        "outb %al, %dx"
+    };
+}
+macro_rules! outw_asm {
+    () => {
+        "outw %ax, %dx"
+    };
+}
+macro_rules! outl_asm {
+    () => {
+        "outl %eax, %dx"
+    };
+}
+macro_rules! inb_asm {
+    () => {
+        "inb %dx, %al"
+    };
+}
+macro_rules! inw_asm {
+    () => {
+        "inw %dx, %ax"
+    };
+}
+macro_rules! inl_asm {
+    () => {
+        "inl %dx, %eax"
+    };
+}
+
 pub unsafe fn outb(port: u16, b: u8) {
     #[cfg(not(test))]
     unsafe {
-        core::arch::asm!("outb %al, %dx", in("dx") port, in("al") b, options(att_syntax));
+        core::arch::asm!(outb_asm!(), in("dx") port, in("al") b, options(att_syntax));
     }
 }
 
 pub unsafe fn outw(port: u16, w: u16) {
     #[cfg(not(test))]
     unsafe {
-        core::arch::asm!("outw %ax, %dx", in("dx") port, in("ax") w, options(att_syntax));
+        core::arch::asm!(outw_asm!(), in("dx") port, in("ax") w, options(att_syntax));
     }
 }
 
 pub unsafe fn outl(port: u16, l: u32) {
     #[cfg(not(test))]
     unsafe {
-        core::arch::asm!("outl %eax, %dx", in("dx") port, in("ax") l, options(att_syntax));
+        core::arch::asm!(outl_asm!(), in("dx") port, in("ax") l, options(att_syntax));
+    }
+}
+
+pub unsafe fn inb(port: u16) -> u8 {
+    #[cfg(not(test))]
+    {
+        let b: u8;
+        unsafe {
+            core::arch::asm!(inb_asm!(), in("dx") port, out("al") b, options(att_syntax));
+        }
+        b
+    }
+    #[cfg(test)]
+    0
+}
+
+pub unsafe fn inw(port: u16) -> u16 {
+    #[cfg(not(test))]
+    {
+        let w: u16;
+        unsafe {
+            core::arch::asm!(inw_asm!(), in("dx") port, out("ax") w, options(att_syntax));
+        }
+        w
+    }
+    #[cfg(test)]
+    0
+}
+
+pub unsafe fn inl(port: u16) -> u32 {
+    #[cfg(not(test))]
+    {
+        let l: u32;
+        unsafe {
+            core::arch::asm!(inl_asm!(), in("dx") port, out("eax") l, options(att_syntax));
+        }
+        l
+    }
+    #[cfg(test)]
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    // Host tests can't do port I/O, so assemble the functions' instructions
+    // out of line, where the test can read back their encodings
+    core::arch::global_asm!(
+        ".pushsection .text.pio_encodings, \"ax\"",
+        ".globl pio_encodings",
+        "pio_encodings:",
+        outb_asm!(),
+        outw_asm!(),
+        outl_asm!(),
+        inb_asm!(),
+        inw_asm!(),
+        inl_asm!(),
+        ".popsection",
+        options(att_syntax)
+    );
+
+    extern "C" {
+        static pio_encodings: [u8; 8];
+    }
+
+    #[test]
+    fn asm_encodings() {
+        // The port is always in dx.  The 16 bit forms have an operand size
+        // prefix.
+        let outs = [0xee, 0x66, 0xef, 0xef];
+        let ins = [0xec, 0x66, 0xed, 0xed];
+        let encodings = unsafe { pio_encodings };
+        assert_eq!(encodings[..4], outs);
+        assert_eq!(encodings[4..], ins);
     }
 }
@@ -7,6 +7,14 @@ use core::panic::PanicInfo;
 
 #[panic_handler]
 pub fn panic(_info: &PanicInfo) -> ! {
+    // A panic fails the kernel tests
+    #[cfg(feature = "qemu_test")]
+    {
+        port::println!("{_info}");
+        crate::ktest::exit(port::ktest::ExitCode::Failure)
+    }
+
+    #[cfg(not(feature = "qemu_test"))]
     #[allow(clippy::empty_loop)]
     loop {}
 }
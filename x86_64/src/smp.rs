@@ -0,0 +1,286 @@
+//! Multiprocessor startup
+//!
+//! CPU0 starts the application processors (APs) by copying the trampoline in
+//! l.S (b1978 to e1978) to APENTRY in low memory, then sending each AP an INIT
+//! IPI followed by two startup IPIs pointing at APENTRY.  The trampoline takes
+//! the AP from real mode to long mode, using the stack and entry point in the
+//! ApArea whose address CPU0 leaves at APPERCPU.
+//!
+//! Each CPU has its own TSS, whose descriptor is in the GDT in l.S.  It holds
+//! the stack used for interrupts taken from user mode.
+
+use crate::param::KZERO;
+use crate::{apic, lapic, pagealloc, syscall, trap};
+use core::cell::SyncUnsafeCell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use port::println;
+
+/// Maximum number of CPUs.  Must match MAXCPUS in l.S.
+pub const MAX_CPUS: usize = 8;
+
+// These need to match l.S
+const APENTRY: usize = 0x7000;
+const APPERCPU: usize = 0x8000 - 8;
+const PGSZ: usize = 4096;
+const PTSZ: usize = 4096;
+const MACHSTKSZ: usize = 8 * PGSZ;
+
+/// Selector of CPU0's TSS descriptor.  Each descriptor takes two GDT entries.
+const TSS_SELECTOR_BASE: u16 = 7 << 3;
+
+// TSS descriptor fields
+const TSS_TYPE_AVAILABLE: u64 = 0x9 << 40;
+const TSS_PRESENT: u64 = 1 << 47;
+
+const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+/// 64-bit task state segment.  Only used to find the stack on a change of
+/// privilege level.
+#[derive(Clone, Copy)]
+#[repr(C, packed(4))]
+struct Tss {
+    reserved0: u32,
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+impl Tss {
+    const fn new(rsp0: u64) -> Tss {
+        Tss {
+            reserved0: 0,
+            rsp: [rsp0, 0, 0],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            // No I/O permission bitmap
+            iomap_base: size_of::<Tss>() as u16,
+        }
+    }
+}
+
+/// Return the two GDT entries describing a TSS
+fn tss_descriptor(base: u64, limit: u32) -> [u64; 2] {
+    let limit = limit as u64;
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | TSS_TYPE_AVAILABLE
+        | TSS_PRESENT
+        | ((limit >> 16) & 0xf) << 48
+        | ((base >> 24) & 0xff) << 56;
+    [low, base >> 32]
+}
+
+/// GDT selector of the CPU's TSS
+fn tss_selector(cpu: usize) -> u16 {
+    TSS_SELECTOR_BASE + cpu as u16 * 16
+}
+
+#[repr(C, align(16))]
+struct InterruptStack([u8; INTERRUPT_STACK_SIZE]);
+
+static TSS: SyncUnsafeCell<[Tss; MAX_CPUS]> = SyncUnsafeCell::new([Tss::new(0); MAX_CPUS]);
+
+static INTERRUPT_STACKS: SyncUnsafeCell<[InterruptStack; MAX_CPUS]> =
+    SyncUnsafeCell::new([const { InterruptStack([0; INTERRUPT_STACK_SIZE]) }; MAX_CPUS]);
+
+/// The start of the Mach that the trampoline passes to the AP entry point
+#[repr(C)]
+struct ApMach {
+    cpu: u64,
+    /// Entry point, called with a pointer to the ApMach
+    splpc: u64,
+}
+
+/// Boot stack and Mach for an AP, laid out as the trampoline expects
+#[repr(C, align(4096))]
+struct ApArea {
+    stack: [u8; MACHSTKSZ],
+    _unused: [u8; PTSZ + PGSZ],
+    mach: ApMach,
+}
+
+impl ApArea {
+    const fn empty() -> ApArea {
+        ApArea {
+            stack: [0; MACHSTKSZ],
+            _unused: [0; PTSZ + PGSZ],
+            mach: ApMach { cpu: 0, splpc: 0 },
+        }
+    }
+}
+
+static AP_AREAS: SyncUnsafeCell<[ApArea; MAX_CPUS]> =
+    SyncUnsafeCell::new([const { ApArea::empty() }; MAX_CPUS]);
+
+/// Set by each CPU once it's initialised
+static ONLINE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// APIC ID of each CPU, indexed by CPU number.  Set by each CPU once its
+/// local APIC is enabled.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(u32::MAX) }; MAX_CPUS];
+
+/// Record this CPU's APIC ID against its CPU number
+fn set_cpu_id(cpu: usize) {
+    APIC_IDS[cpu].store(lapic::lapic_id(), Ordering::Release);
+}
+
+/// Number CPU0, the boot CPU.  Must be called after its local APIC is
+/// enabled.
+pub fn init_boot_cpu() {
+    set_cpu_id(0);
+}
+
+/// Return this CPU's number, if it's been given one.  CPU numbers run from 0
+/// in the order the CPUs were started, unlike APIC IDs.
+pub fn cpu_id() -> Option<usize> {
+    let apic_id = lapic::lapic_id();
+    APIC_IDS.iter().position(|id| id.load(Ordering::Acquire) == apic_id)
+}
+
+/// Point this CPU's TSS at its interrupt stack, and load it
+fn load_tss(cpu: usize) {
+    let tss = unsafe { &mut (*TSS.get())[cpu] };
+    let stack = unsafe { &(*INTERRUPT_STACKS.get())[cpu] };
+    *tss = Tss::new(stack.0.as_ptr() as u64 + INTERRUPT_STACK_SIZE as u64);
+
+    #[cfg(not(test))]
+    unsafe {
+        use x86::segmentation::SegmentSelector;
+        extern "C" {
+            static mut gdttss: [[u64; 2]; MAX_CPUS];
+        }
+        let descriptor = tss_descriptor(tss as *const Tss as u64, size_of::<Tss>() as u32 - 1);
+        core::ptr::addr_of_mut!(gdttss[cpu]).write_volatile(descriptor);
+        x86::task::load_tr(SegmentSelector::from_raw(tss_selector(cpu)));
+    }
+}
+
+/// Copy the trampoline to APENTRY, where the startup IPI points the APs
+fn copy_trampoline() {
+    #[cfg(not(test))]
+    unsafe {
+        extern "C" {
+            static b1978: [u8; 0];
+            static e1978: [u8; 0];
+        }
+        let start = b1978.as_ptr();
+        let len = e1978.as_ptr().offset_from(start) as usize;
+        core::ptr::copy_nonoverlapping(start, (KZERO + APENTRY) as *mut u8, len);
+    }
+}
+
+/// Called by the trampoline on the AP's boot stack.  Loads the IDT and TSS,
+/// sets up the syscall MSRs, enables the local APIC and sets up the CPU's
+/// page cache, then halts.  The
+/// trampoline has already loaded the GDT.
+extern "C" fn ap_entry(mach: &ApMach) -> ! {
+    let cpu = mach.cpu as usize;
+    trap::load();
+    load_tss(cpu);
+    syscall::init(cpu);
+    lapic::init_lapic();
+    set_cpu_id(cpu);
+    pagealloc::init_per_cpu_cache(cpu);
+    ONLINE[cpu].store(true, Ordering::Release);
+    #[allow(clippy::empty_loop)]
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("cli", "hlt")
+        };
+    }
+}
+
+/// Send the INIT-SIPI-SIPI sequence to the AP with the APIC ID, to run as
+/// cpu, and wait for it to come online
+fn start_ap(cpu: usize, apic_id: u32) -> bool {
+    let area = unsafe { &mut (*AP_AREAS.get())[cpu] };
+    area.mach = ApMach { cpu: cpu as u64, splpc: ap_entry as usize as u64 };
+    let area = area as *mut ApArea as u64;
+    unsafe { core::ptr::write_volatile((KZERO + APPERCPU) as *mut u64, area) };
+
+    lapic::send_init_ipi(apic_id);
+    apic::delay_us(10_000);
+    for _ in 0..2 {
+        lapic::send_startup_ipi(apic_id, (APENTRY / PGSZ) as u8);
+        apic::delay_us(200);
+    }
+
+    // Give it 100ms
+    for _ in 0..1000 {
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+        apic::delay_us(100);
+    }
+    false
+}
+
+/// Start the APs with the given APIC IDs one at a time, up to MAX_CPUS
+/// including CPU0, the boot CPU, whose own ID is skipped.  Returns the number
+/// running.  Needs the local APIC timer running, for delays.
+///
+/// CPUs are numbered in the order they're started, so the APIC IDs needn't
+/// be contiguous.
+pub fn start_aps(apic_ids: impl Iterator<Item = u32>) -> usize {
+    load_tss(0);
+    ONLINE[0].store(true, Ordering::Release);
+
+    copy_trampoline();
+    let boot_apic_id = lapic::lapic_id();
+    let mut num_cpus = 1;
+    for apic_id in apic_ids.filter(|&apic_id| apic_id != boot_apic_id) {
+        if num_cpus == MAX_CPUS {
+            println!("Not starting APIC ID {apic_id} or later: only {MAX_CPUS} CPUs supported");
+            break;
+        }
+        // xAPIC mode only has 8 bits for the destination
+        if apic_id > 0xff {
+            println!("Not starting APIC ID {apic_id}: needs x2APIC mode");
+            continue;
+        }
+        if start_ap(num_cpus, apic_id) {
+            num_cpus += 1;
+        } else {
+            println!("APIC ID {apic_id} didn't start");
+        }
+    }
+    num_cpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn tss_layout() {
+        assert_eq!(size_of::<Tss>(), 104);
+        assert_eq!(offset_of!(Tss, rsp), 4);
+        assert_eq!(offset_of!(Tss, ist), 36);
+        assert_eq!(offset_of!(Tss, iomap_base), 102);
+
+        let [low, high] = tss_descriptor(0xffff_8000_1234_5678, 103);
+        assert_eq!(low, 0x1200_8934_5678_0067);
+        assert_eq!(high, 0xffff_8000);
+
+        // After the 7 entries for the code and data segments
+        assert_eq!(tss_selector(0), 0x38);
+        assert_eq!(tss_selector(7), 0xa8);
+    }
+
+    #[test]
+    fn ap_area_layout() {
+        // The trampoline finds the Mach after the stack and two pages, and
+        // the entry point in the second word.
+        let mach = offset_of!(ApArea, mach);
+        assert_eq!(mach, MACHSTKSZ + PTSZ + PGSZ);
+        assert_eq!(offset_of!(ApMach, splpc), 8);
+    }
+}
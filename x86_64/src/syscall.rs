@@ -0,0 +1,118 @@
+//! SYSCALL/SYSRET system call entry.
+//!
+//! System calls are made with the `syscall` instruction.  The syscall number
+//! is passed in rax and up to 6 arguments in rdi, rsi, rdx, r10, r8 and r9.
+//! The result is returned in rax, with negative values indicating an error.
+
+use crate::smp::MAX_CPUS;
+use core::cell::SyncUnsafeCell;
+use port::syscall;
+use x86::msr::{IA32_FMASK, IA32_KERNEL_GSBASE, IA32_LSTAR, IA32_STAR};
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("syscall.S"), options(att_syntax));
+
+// These need to match the GDT in l.S.  SYSCALL loads CS from STAR[47:32] and
+// SS from the next descriptor, so the kernel data segment must come right
+// after the kernel code segment.  SYSRET loads SS from STAR[63:48]+8 and CS
+// from STAR[63:48]+16, so the user data segment must come right before the
+// user code segment.
+const KERNEL_CODE_SELECTOR: u64 = 1 << 3;
+const USER_SYSRET_BASE: u64 = 4 << 3;
+
+// RFLAGS bits cleared on entry
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+static SYSCALL_STACKS: SyncUnsafeCell<[KernelStack; MAX_CPUS]> =
+    SyncUnsafeCell::new([const { KernelStack([0; KERNEL_STACK_SIZE]) }; MAX_CPUS]);
+
+/// Per-CPU state used by syscall_entry, found through KERNEL_GS_BASE.  The
+/// layout must match the offsets used in syscall.S.
+#[repr(C)]
+struct SyscallMach {
+    kernel_stack_top: u64,
+    user_stack: u64,
+}
+
+static MACHS: SyncUnsafeCell<[SyscallMach; MAX_CPUS]> =
+    SyncUnsafeCell::new([const { SyscallMach { kernel_stack_top: 0, user_stack: 0 } }; MAX_CPUS]);
+
+/// Registers saved by syscall_entry
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    num: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    r10: u64,
+    r8: u64,
+    r9: u64,
+    rflags: u64,
+    rip: u64,
+}
+
+#[allow(unused_variables)]
+fn wrmsr(msr: u32, value: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        x86::msr::wrmsr(msr, value)
+    }
+}
+
+/// Set up this CPU's MSRs so that the syscall instruction enters
+/// syscall_entry on its own kernel stack.  SYSCALL itself is enabled in EFER
+/// by l.S.
+pub fn init(cpu: usize) {
+    #[cfg(not(test))]
+    extern "C" {
+        fn syscall_entry();
+    }
+    #[cfg(not(test))]
+    let entry = syscall_entry as usize as u64;
+    #[cfg(test)]
+    let entry = 0;
+
+    let mach = unsafe { &mut (*MACHS.get())[cpu] };
+    let stack = unsafe { &(*SYSCALL_STACKS.get())[cpu] };
+    mach.kernel_stack_top = stack.0.as_ptr() as u64 + KERNEL_STACK_SIZE as u64;
+
+    wrmsr(IA32_STAR, (USER_SYSRET_BASE << 48) | (KERNEL_CODE_SELECTOR << 32));
+    wrmsr(IA32_LSTAR, entry);
+    wrmsr(IA32_FMASK, RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+    wrmsr(IA32_KERNEL_GSBASE, mach as *mut SyscallMach as u64);
+}
+
+#[no_mangle]
+pub extern "C" fn dispatch_syscall(frame: &mut SyscallFrame) -> isize {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9].map(|x| x as usize);
+    syscall::dispatch(frame.num as usize, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use port::syscall::{Syscall, EBADF, ENOSYS};
+
+    fn frame(num: u64, args: [u64; 6]) -> SyscallFrame {
+        let [rdi, rsi, rdx, r10, r8, r9] = args;
+        SyscallFrame { num, rdi, rsi, rdx, r10, r8, r9, rflags: 0, rip: 0 }
+    }
+
+    #[test]
+    fn dispatch_from_frame() {
+        assert_eq!(dispatch_syscall(&mut frame(Syscall::GetPid as u64, [0; 6])), 0);
+        // fd comes from rdi and len from rdx
+        assert_eq!(dispatch_syscall(&mut frame(Syscall::Write as u64, [3, 0, 0, 0, 0, 0])), EBADF);
+        assert_eq!(dispatch_syscall(&mut frame(Syscall::Write as u64, [1, 0, 0, 0, 0, 0])), 0);
+        assert_eq!(dispatch_syscall(&mut frame(1000, [0; 6])), ENOSYS);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::apic;
+use bitstruct::bitstruct;
+use core::cell::SyncUnsafeCell;
+use core::fmt;
+use port::mcslock::Interrupts;
+use port::println;
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("trap.S"), options(att_syntax));
+
+// This needs to match GdtCODE64 in l.S
+const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+
+// Present, DPL 0, 64-bit interrupt gate
+const INTERRUPT_GATE: u8 = 0x8e;
+
+/// Number of CPU exception vectors.  Everything above is an interrupt.
+const NUM_EXCEPTIONS: u64 = 32;
+
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// Entry in the interrupt descriptor table
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    const fn empty() -> Gate {
+        Gate {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn interrupt(handler: usize) -> Gate {
+        Gate {
+            offset_low: handler as u16,
+            selector: KERNEL_CODE_SELECTOR,
+            ist: 0,
+            type_attr: INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+static IDT: SyncUnsafeCell<[Gate; 256]> = SyncUnsafeCell::new([Gate::empty(); 256]);
+
+/// Vector number and entry point, as laid out in trap_vectors in trap.S
+#[repr(C)]
+struct TrapVector {
+    num: u64,
+    entry: usize,
+}
+
+#[cfg(not(test))]
+fn trap_vectors() -> &'static [TrapVector] {
+    extern "C" {
+        static trap_vectors: [TrapVector; 0];
+        static etrap_vectors: [TrapVector; 0];
+    }
+    unsafe {
+        let start = trap_vectors.as_ptr();
+        let len = etrap_vectors.as_ptr().offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+#[cfg(test)]
+fn trap_vectors() -> &'static [TrapVector] {
+    &[]
+}
+
+/// Fill in the IDT from the entry points in trap.S, and load it
+pub fn init() {
+    let idt = unsafe { &mut *IDT.get() };
+    for vector in trap_vectors() {
+        idt[vector.num as usize] = Gate::interrupt(vector.entry);
+    }
+    load();
+}
+
+/// Load the IDT on this CPU.  The IDT is shared by all CPUs, so other CPUs
+/// only need to load it once init has run.
+pub fn load() {
+    #[cfg(not(test))]
+    unsafe {
+        use x86::dtables::{lidt, DescriptorTablePointer};
+        lidt(&DescriptorTablePointer::new_from_slice(&*IDT.get()));
+    }
+}
+
+/// Masks interrupts on this CPU (RFLAGS.IF), for IrqLock
+pub struct LocalInterrupts;
+
+impl Interrupts for LocalInterrupts {
+    /// Whether interrupts were enabled
+    type State = bool;
+
+    fn disable() -> bool {
+        #[cfg(not(test))]
+        {
+            use x86::bits64::rflags::{self, RFlags};
+            let enabled = rflags::read().contains(RFlags::FLAGS_IF);
+            unsafe { x86::irq::disable() };
+            enabled
+        }
+        #[cfg(test)]
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn restore(enabled: bool) {
+        #[cfg(not(test))]
+        if enabled {
+            unsafe { x86::irq::enable() };
+        }
+    }
+}
+
+/// Register frame at time interrupt was taken
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    error_code: u64,
+    // Pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+bitstruct! {
+    /// Error code pushed by the CPU for a page fault
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct PageFaultError(pub u64) {
+        pub present: bool = 0;           // Protection violation if set, else page not present
+        pub write: bool = 1;             // Caused by a write
+        pub user: bool = 2;              // Occurred in user mode
+        pub reserved: bool = 3;          // Reserved bit set in a page table entry
+        pub instruction_fetch: bool = 4; // Caused by an instruction fetch
+        pub protection_key: bool = 5;
+        pub shadow_stack: bool = 6;
+    }
+}
+
+impl fmt::Debug for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageFaultError")
+            .field("present", &self.present())
+            .field("write", &self.write())
+            .field("user", &self.user())
+            .field("reserved", &self.reserved())
+            .field("instruction_fetch", &self.instruction_fetch())
+            .field("protection_key", &self.protection_key())
+            .field("shadow_stack", &self.shadow_stack())
+            .finish()
+    }
+}
+
+/// Faulting address for the last page fault
+fn cr2() -> u64 {
+    #[cfg(not(test))]
+    unsafe {
+        x86::controlregs::cr2() as u64
+    }
+    #[cfg(test)]
+    0
+}
+
+fn page_fault(frame: &TrapFrame) {
+    let err = PageFaultError(frame.error_code);
+    println!(
+        "Page fault {} {:#018x} at rip {:#018x}: {}{}",
+        if err.instruction_fetch() {
+            "executing"
+        } else if err.write() {
+            "writing"
+        } else {
+            "reading"
+        },
+        cr2(),
+        frame.rip,
+        if err.present() { "protection violation" } else { "page not present" },
+        if err.user() { " (user)" } else { " (kernel)" },
+    );
+    println!("{:?}", err);
+}
+
+#[no_mangle]
+pub extern "C" fn trap(frame: &mut TrapFrame) {
+    match frame.vector {
+        v if v == apic::TIMER_VECTOR as u64 => {
+            apic::tick();
+            apic::eoi();
+        }
+        // Spurious interrupts mustn't be acknowledged
+        v if v == apic::SPURIOUS_VECTOR as u64 => {}
+        v if v < NUM_EXCEPTIONS => {
+            if v == PAGE_FAULT_VECTOR {
+                page_fault(frame);
+            }
+            // Just print out the frame and loop for now
+            println!(
+                "Exception {} (error code {:#x}) at rip {:#018x}",
+                v, frame.error_code, frame.rip
+            );
+            println!("{:#x?}", frame);
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+        v => {
+            println!("Unexpected interrupt {v}");
+            apic::eoi();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_page_fault_error() {
+        // User mode write to a read-only page
+        let err = PageFaultError(0x7);
+        assert!(err.present());
+        assert!(err.write());
+        assert!(err.user());
+        assert!(!err.reserved());
+        assert!(!err.instruction_fetch());
+
+        // Kernel instruction fetch from a non-present page
+        let err = PageFaultError(0x10);
+        assert!(!err.present());
+        assert!(!err.write());
+        assert!(!err.user());
+        assert!(err.instruction_fetch());
+    }
+}
@@ -1,7 +1,32 @@
-//! Simple UART driver to get started.
+//! Access to the 8250/16550 compatible UARTs of the PC serial ports, such
+//! as COM1 at port 0x3f8.  The registers are in I/O port space; the driver
+//! itself is in port::uart16550.
 
-pub fn putb(port: u16, b: u8) {
-    unsafe {
-        crate::pio::outb(port, b);
+use crate::pio::{inb, outb};
+use port::uart16550::{self, Uart16550Regs};
+
+/// Frequency of the PC UART clock.  The baud rate is this / (16 * divisor).
+pub const UART_CLOCK_HZ: u32 = 1_843_200;
+
+/// A UART's registers, at consecutive ports from base
+pub struct PortRegs {
+    base: u16,
+}
+
+impl PortRegs {
+    pub const fn new(base: u16) -> Self {
+        PortRegs { base }
+    }
+}
+
+impl Uart16550Regs for PortRegs {
+    fn read(&self, reg: usize) -> u8 {
+        unsafe { inb(self.base + reg as u16) }
+    }
+
+    fn write(&self, reg: usize, val: u8) {
+        unsafe { outb(self.base + reg as u16, val) }
     }
 }
+
+pub type Uart16550 = uart16550::Uart16550<PortRegs>;
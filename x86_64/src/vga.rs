@@ -0,0 +1,182 @@
+//! VGA text mode console
+//!
+//! The text buffer is 80x25 cells at physical address 0xb8000, each a byte
+//! of character and a byte of colour attribute.  l.S maps the first 4GiB of
+//! physical memory at KZERO, which includes the buffer.  Output scrolls up a
+//! line once the cursor passes the bottom row.
+
+use crate::param::KZERO;
+use crate::pio::outb;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use port::devcons::Uart;
+
+const BUFFER_PA: usize = 0xb8000;
+const COLS: usize = 80;
+const ROWS: usize = 25;
+
+/// Light grey on black
+pub const DEFAULT_ATTR: u8 = 0x07;
+
+// CRT controller index and data ports, and the cursor location registers
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_HIGH: u8 = 0x0e;
+const CURSOR_LOW: u8 = 0x0f;
+
+/// The cursor is only changed with the console lock held, so relaxed atomics
+/// are enough.
+pub struct VgaConsole {
+    buffer: usize,
+    row: AtomicUsize,
+    col: AtomicUsize,
+}
+
+impl VgaConsole {
+    /// The console for the VGA text buffer
+    pub fn new() -> VgaConsole {
+        // Safety: the buffer is always mapped and holds ROWS * COLS cells
+        unsafe { VgaConsole::with_buffer(BUFFER_PA + KZERO) }
+    }
+
+    /// A console writing to the buffer of ROWS * COLS cells at buffer
+    ///
+    /// # Safety
+    ///
+    /// buffer must be valid for reads and writes of ROWS * COLS u16s for
+    /// as long as the console is used.
+    unsafe fn with_buffer(buffer: usize) -> VgaConsole {
+        VgaConsole { buffer, row: AtomicUsize::new(0), col: AtomicUsize::new(0) }
+    }
+
+    fn row(&self) -> usize {
+        self.row.load(Ordering::Relaxed)
+    }
+
+    fn col(&self) -> usize {
+        self.col.load(Ordering::Relaxed)
+    }
+
+    fn set_cursor(&self, row: usize, col: usize) {
+        self.row.store(row, Ordering::Relaxed);
+        self.col.store(col, Ordering::Relaxed);
+    }
+
+    fn cell(&self, i: usize) -> *mut u16 {
+        (self.buffer as *mut u16).wrapping_add(i)
+    }
+
+    /// Clear the screen and move the cursor to the top left
+    pub fn clear(&self) {
+        for row in 0..ROWS {
+            self.clear_row(row);
+        }
+        self.set_cursor(0, 0);
+        self.update_cursor();
+    }
+
+    /// Write b with the colour attribute attr at the cursor, and advance it.
+    /// Newlines, carriage returns and backspaces move the cursor.
+    pub fn write_byte(&self, b: u8, attr: u8) {
+        match b {
+            b'\n' => self.newline(),
+            b'\r' => self.set_cursor(self.row(), 0),
+            0x08 => self.set_cursor(self.row(), self.col().saturating_sub(1)),
+            _ => {
+                if self.col() == COLS {
+                    self.newline();
+                }
+                self.set_cell(self.row(), self.col(), b, attr);
+                self.set_cursor(self.row(), self.col() + 1);
+            }
+        }
+        self.update_cursor();
+    }
+
+    fn newline(&self) {
+        if self.row() + 1 < ROWS {
+            self.set_cursor(self.row() + 1, 0);
+        } else {
+            self.scroll_up();
+            self.set_cursor(ROWS - 1, 0);
+        }
+    }
+
+    /// Move every line up one, and clear the bottom line
+    fn scroll_up(&self) {
+        for i in COLS..ROWS * COLS {
+            unsafe { write_volatile(self.cell(i - COLS), read_volatile(self.cell(i))) };
+        }
+        self.clear_row(ROWS - 1);
+    }
+
+    fn clear_row(&self, row: usize) {
+        for col in 0..COLS {
+            self.set_cell(row, col, b' ', DEFAULT_ATTR);
+        }
+    }
+
+    fn set_cell(&self, row: usize, col: usize, b: u8, attr: u8) {
+        let cell = (attr as u16) << 8 | b as u16;
+        unsafe { write_volatile(self.cell(row * COLS + col), cell) };
+    }
+
+    /// Move the blinking hardware cursor to the cursor position
+    fn update_cursor(&self) {
+        let pos = (self.row() * COLS + self.col().min(COLS - 1)) as u16;
+        unsafe {
+            outb(CRTC_INDEX, CURSOR_HIGH);
+            outb(CRTC_DATA, (pos >> 8) as u8);
+            outb(CRTC_INDEX, CURSOR_LOW);
+            outb(CRTC_DATA, pos as u8);
+        }
+    }
+}
+
+impl Uart for VgaConsole {
+    fn putb(&self, b: u8) {
+        self.write_byte(b, DEFAULT_ATTR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_text(buffer: &[u16], row: usize) -> String {
+        let row = &buffer[row * COLS..(row + 1) * COLS];
+        row.iter().map(|&cell| cell as u8 as char).collect::<String>().trim_end().to_string()
+    }
+
+    #[test]
+    fn write_wrap_and_scroll() {
+        let mut buffer = vec![0u16; ROWS * COLS];
+        let vga = unsafe { VgaConsole::with_buffer(buffer.as_mut_ptr().addr()) };
+        vga.clear();
+
+        for &b in b"hi\n" {
+            vga.putb(b);
+        }
+        vga.write_byte(b'!', 0x4f);
+        assert_eq!(buffer[0], 0x0768);
+        assert_eq!(buffer[COLS], 0x4f21);
+        assert_eq!(row_text(&buffer, 0), "hi");
+
+        // A full line wraps onto the next
+        for _ in 0..COLS {
+            vga.putb(b'x');
+        }
+        assert_eq!(row_text(&buffer, 1), format!("!{}", "x".repeat(COLS - 1)));
+        assert_eq!(row_text(&buffer, 2), "x");
+
+        // Writing past the bottom scrolls everything up a line
+        for _ in 2..ROWS {
+            vga.putb(b'\n');
+        }
+        vga.putb(b'z');
+        assert!(row_text(&buffer, 0).starts_with('!'));
+        assert_eq!(row_text(&buffer, 1), "x");
+        assert_eq!(row_text(&buffer, ROWS - 1), "z");
+        assert_eq!(buffer[(ROWS - 1) * COLS + 1], (DEFAULT_ATTR as u16) << 8 | b' ' as u16);
+    }
+}
@@ -1,6 +1,6 @@
 /// Test
 ///
-use crate::{Command, Profile};
+use crate::{workspace, Command, Profile};
 
 use serde::{Deserialize, Serialize};
 use std::{
@@ -79,6 +79,39 @@ pub struct Qemu {
     pub dtb: Option<String>,
 }
 
+/// Size section
+/// Limits checked by the size step.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Size {
+    /// Fail if the kernel's .text, .rodata, .data and .bss add up to more
+    /// than this many bytes
+    pub max_total: Option<u64>,
+}
+
+/// Netboot section
+/// Where the netboot step puts the kernel for the board to fetch over TFTP.
+/// ```toml
+/// [netboot]
+/// tftp_root = "/srv/tftp"
+/// reset_port = "/dev/ttyUSB0"
+/// reset_command = "reset\r"
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Netboot {
+    /// TFTP server directory.  The TFTP_ROOT environment variable overrides
+    /// this.
+    pub tftp_root: Option<String>,
+
+    /// Serial device connected to the board's console
+    pub reset_port: Option<String>,
+
+    /// Written to reset_port to reset the board, e.g. at a U-Boot prompt
+    pub reset_command: Option<String>,
+}
+
+/// Values accepted for config.platform
+const KNOWN_PLATFORMS: &[&str] = &["nezha", "raspi3b", "raspi4b", "vfive2", "virt"];
+
 /// the TOML document
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Configuration {
@@ -86,10 +119,13 @@ pub struct Configuration {
     pub config: Option<Config>,
     pub link: Option<HashMap<String, String>>,
     pub qemu: Option<Qemu>,
+    pub size: Option<Size>,
+    pub netboot: Option<Netboot>,
 }
 
 impl Configuration {
-    pub fn load(filename: String) -> Self {
+    /// Load and validate the configuration for the arch, exiting on error
+    pub fn load(filename: String, arch: &str) -> Self {
         let contents = match fs::read_to_string(filename.clone()) {
             Ok(c) => c,
             Err(_) => {
@@ -105,8 +141,57 @@ impl Configuration {
                 exit(1);
             }
         };
+        if let Err(errors) = config.validate(arch) {
+            eprintln!("Invalid configuration `{filename}`:");
+            for e in errors {
+                eprintln!("  {e}");
+            }
+            exit(1);
+        }
         config
     }
+
+    /// Check the configuration makes sense for the arch, returning all the
+    /// problems found.
+    pub fn validate(&self, arch: &str) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        match &self.build {
+            Some(build) => {
+                // Either a target triple or a target spec file named after one
+                let expected = format!("{arch}-unknown-none-elf");
+                let name = build.target.rsplit('/').next().unwrap_or_default();
+                if name != expected && name != format!("{expected}.json") {
+                    errors.push(format!(
+                        "build.target `{}` should be {expected} or a path to {expected}.json",
+                        build.target
+                    ));
+                }
+            }
+            None => errors.push("build.target is missing".to_string()),
+        }
+
+        if let Some(platform) = self.config.as_ref().and_then(|c| c.platform.as_ref()) {
+            if !KNOWN_PLATFORMS.contains(&platform.as_str()) {
+                errors.push(format!(
+                    "config.platform `{platform}` should be one of: {}",
+                    KNOWN_PLATFORMS.join(", ")
+                ));
+            }
+        }
+
+        if let Some(script) = self.link.as_ref().and_then(|link| link.get("script")) {
+            if !workspace().join(script).is_file() {
+                errors.push(format!("link.script `{script}` doesn't exist"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
 }
 
 fn apply_build(cmd: &mut Command, rustflags: &mut Vec<String>, config: &Configuration) {
@@ -1,9 +1,10 @@
 use crate::config::Configuration;
 use config::{apply_to_build_step, apply_to_clippy_step, apply_to_qemu_step};
 use std::{
-    env, fmt,
+    env, fmt, fs,
+    io::IsTerminal,
     path::{Path, PathBuf},
-    process::{self, Command},
+    process::{self, Command, Stdio},
     str::FromStr,
 };
 use target_lexicon::Triple;
@@ -76,6 +77,103 @@ impl fmt::Display for Arch {
     }
 }
 
+/// QEMU -d log items accepted by --trace
+const QEMU_TRACE_FLAGS: &[&str] = &[
+    "out_asm",
+    "in_asm",
+    "op",
+    "op_opt",
+    "op_ind",
+    "op_plugin",
+    "int",
+    "exec",
+    "cpu",
+    "fpu",
+    "mmu",
+    "pcall",
+    "cpu_reset",
+    "unimp",
+    "guest_errors",
+    "page",
+    "nochain",
+    "plugin",
+    "strace",
+    "tid",
+    "invalid_mem",
+];
+
+/// Trace items used if --trace is given without a filter
+const DEFAULT_TRACE_FILTER: &str = "cpu,exec,int,in_asm";
+
+/// Check that each comma separated item is a QEMU -d log item
+fn parse_trace_filter(filter: &str) -> std::result::Result<String, String> {
+    for item in filter.split(',') {
+        if !QEMU_TRACE_FLAGS.contains(&item) {
+            return Err(format!(
+                "unknown trace item '{item}', expected one of: {}",
+                QEMU_TRACE_FLAGS.join(",")
+            ));
+        }
+    }
+    Ok(filter.to_string())
+}
+
+/// Parse a hex address, with or without a leading 0x
+fn parse_hex_addr(addr: &str) -> std::result::Result<u64, String> {
+    let digits = addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")).unwrap_or(addr);
+    u64::from_str_radix(digits, 16).map_err(|e| format!("bad address '{addr}': {e}"))
+}
+
+/// The aarch64 UART to connect to the terminal under QEMU.  This should match
+/// the UART the kernel uses.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Uart {
+    /// PL011 (UART0), QEMU's first serial port
+    Pl011,
+    /// Mini UART (UART1), QEMU's second serial port
+    #[value(name = "miniuart")]
+    MiniUart,
+}
+
+/// Where QEMU writes its trace log
+#[derive(Clone, Debug)]
+enum TraceMode {
+    File(PathBuf),
+    Stderr,
+}
+
+/// QEMU trace items, and where to write them
+#[derive(Clone, Debug)]
+struct Trace {
+    filter: String,
+    mode: TraceMode,
+}
+
+impl Trace {
+    fn from(matches: &clap::ArgMatches) -> Option<Self> {
+        let filter = matches.get_one::<String>("trace")?.clone();
+        let mode = if matches.get_flag("trace_stderr") {
+            TraceMode::Stderr
+        } else {
+            TraceMode::File(matches.get_one::<PathBuf>("trace_file").unwrap().clone())
+        };
+        Some(Self { filter, mode })
+    }
+
+    /// Add -d with the default items for the arch plus the trace items, and
+    /// -D if tracing to a file.
+    fn apply(trace: Option<&Self>, default_items: Option<&str>, cmd: &mut Command) {
+        let items: Vec<&str> =
+            default_items.into_iter().chain(trace.map(|t| t.filter.as_str())).collect();
+        if !items.is_empty() {
+            cmd.arg("-d").arg(items.join(","));
+        }
+        if let Some(Trace { mode: TraceMode::File(path), .. }) = trace {
+            cmd.arg("-D").arg(path);
+        }
+    }
+}
+
 struct RustupState {
     installed_targets: Vec<Triple>,
     curr_toolchain: String,
@@ -199,14 +297,126 @@ fn main() {
                 clap::arg!(--debug "Build a debug version").conflicts_with("release"),
                 clap::arg!(--arch <arch> "Target architecture")
                     .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
-                clap::arg!(--gdb "Wait for gdb connection on start"),
+                clap::arg!(--gdb "Wait for gdb connection on start; connect with cargo xtask gdb"),
                 clap::arg!(--kvm "Run with KVM"),
+                clap::arg!(--cpu <model> "CPU model, passed verbatim to QEMU's -cpu")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new()),
                 clap::arg!(--config <name> "Configuration")
                     .value_parser(clap::builder::NonEmptyStringValueParser::new())
                     .default_value("default"),
                 clap::arg!(--verbose "Print commands"),
-                clap::arg!(--dump_dtb <file> "Dump the DTB from QEMU to a file")
+                clap::arg!(--dump_dtb <file> "Dump the DTB from QEMU to a file (not x86-64)")
                     .value_parser(clap::value_parser!(String)),
+                clap::arg!(--smp <N> "Number of CPUs")
+                    .value_parser(clap::value_parser!(u8).range(1..)),
+                clap::arg!(--memory <M> "RAM size, e.g. 1024M or 8G")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new()),
+                clap::arg!(--trace [filter] "Trace with QEMU -d, e.g. int,in_asm")
+                    .value_parser(parse_trace_filter)
+                    .default_missing_value(DEFAULT_TRACE_FILTER),
+                clap::arg!(--trace_file <file> "Write the trace to a file")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .default_value("trace.log")
+                    .conflicts_with("trace_stderr"),
+                clap::arg!(--trace_stderr "Write the trace to stderr"),
+                clap::arg!(--uart <uart> "UART to connect to the terminal (aarch64 only)")
+                    .value_parser(clap::builder::EnumValueParser::<Uart>::new()),
+                clap::arg!(--disk "Attach target/disk.bin as a virtio block device (riscv64 only)"),
+                // Escape hatch for QEMU options xtask doesn't support.  The
+                // arguments are added to the end of the command unvalidated.
+                clap::arg!(--extra_qemu_args [ARGS] "Extra QEMU arguments, space separated")
+                    .allow_hyphen_values(true),
+            ]),
+        )
+        .subcommand(
+            clap::Command::new("qemu-test")
+                .about("Run the kernel tests under QEMU, exiting with their result")
+                .args(&[
+                    clap::arg!(--release "Build a release version").conflicts_with("debug"),
+                    clap::arg!(--debug "Build a debug version").conflicts_with("release"),
+                    clap::arg!(--arch <arch> "Target architecture")
+                        .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                    clap::arg!(--config <name> "Configuration")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .default_value("default"),
+                    clap::arg!(--verbose "Print commands"),
+                ]),
+        )
+        .subcommand(
+            clap::Command::new("size").about("Report the kernel section sizes").args(&[
+                clap::arg!(--release "Build a release version").conflicts_with("debug"),
+                clap::arg!(--debug "Build a debug version").conflicts_with("release"),
+                clap::arg!(--arch <arch> "Target architecture")
+                    .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                clap::arg!(--config <name> "Configuration")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                    .default_value("default"),
+                clap::arg!(--verbose "Print commands"),
+            ]),
+        )
+        .subcommand(
+            clap::Command::new("objdump").about("Disassemble the kernel").args(&[
+                clap::arg!(--release "Build a release version").conflicts_with("debug"),
+                clap::arg!(--debug "Build a debug version").conflicts_with("release"),
+                clap::arg!(--arch <arch> "Target architecture")
+                    .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                clap::arg!(--config <name> "Configuration")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                    .default_value("default"),
+                clap::arg!(--section <name> "Only disassemble this section, e.g. .text")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new()),
+                clap::arg!(--verbose "Print commands"),
+            ]),
+        )
+        .subcommand(
+            clap::Command::new("netboot")
+                .about("Copy the aarch64 kernel to a TFTP directory for netbooting a board")
+                .args(&[
+                    clap::arg!(--release "Build a release version").conflicts_with("debug"),
+                    clap::arg!(--debug "Build a debug version").conflicts_with("release"),
+                    clap::arg!(--arch <arch> "Target architecture")
+                        .value_parser(clap::builder::EnumValueParser::<Arch>::new())
+                        .default_value("aarch64"),
+                    clap::arg!(--config <name> "Configuration")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .default_value("default"),
+                    clap::arg!(--reset "Reset the board via its serial console"),
+                    clap::arg!(--verbose "Print commands"),
+                ]),
+        )
+        .subcommand(
+            clap::Command::new("symbols")
+                .about("Write the kernel symbol table to kernel.syms")
+                .args(&[
+                    clap::arg!(--release "Build a release version").conflicts_with("debug"),
+                    clap::arg!(--debug "Build a debug version").conflicts_with("release"),
+                    clap::arg!(--arch <arch> "Target architecture")
+                        .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                    clap::arg!(--config <name> "Configuration")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .default_value("default"),
+                    clap::arg!(--verbose "Print commands"),
+                ]),
+        )
+        .subcommand(
+            clap::Command::new("addr2line")
+                .about("Find the kernel symbol for an address, using kernel.syms")
+                .args(&[
+                    clap::arg!(<addr> "Address in hex, e.g. 0xffff800000081234")
+                        .value_parser(parse_hex_addr),
+                    clap::arg!(--release "Look up in the release version").conflicts_with("debug"),
+                    clap::arg!(--debug "Look up in the debug version").conflicts_with("release"),
+                    clap::arg!(--arch <arch> "Target architecture")
+                        .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                ]),
+        )
+        .subcommand(
+            clap::Command::new("gdb").about("Run gdb attached to r9 under qemu --gdb").args(&[
+                clap::arg!(--release "Debug a release version").conflicts_with("debug"),
+                clap::arg!(--debug "Debug a debug version").conflicts_with("release"),
+                clap::arg!(--arch <arch> "Target architecture")
+                    .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+                clap::arg!(--verbose "Print commands"),
             ]),
         )
         .subcommand(clap::Command::new("clean").about("Cargo clean"))
@@ -230,6 +440,35 @@ fn main() {
             let s3 = QemuStep::new(m);
             s1.run().and_then(|_| s2.run()).and_then(|_| s3.run())
         }
+        Some(("qemu-test", m)) => {
+            let s1 = BuildStep::new(m).with_kernel_tests();
+            let s2 = DistStep::new(m);
+            let s3 = QemuStep::for_kernel_tests(m);
+            s1.run().and_then(|_| s2.run()).and_then(|_| s3.run())
+        }
+        Some(("size", m)) => {
+            let s1 = BuildStep::new(m);
+            let s2 = SizeStep::new(m);
+            s1.run().and_then(|_| s2.run())
+        }
+        Some(("objdump", m)) => {
+            let s1 = BuildStep::new(m);
+            let s2 = ObjdumpStep::new(m);
+            s1.run().and_then(|_| s2.run())
+        }
+        Some(("netboot", m)) => {
+            let s1 = BuildStep::new(m);
+            let s2 = DistStep::new(m);
+            let s3 = NetbootStep::new(m);
+            s1.run().and_then(|_| s2.run()).and_then(|_| s3.run())
+        }
+        Some(("symbols", m)) => {
+            let s1 = BuildStep::new(m);
+            let s2 = SymbolsStep::new(m);
+            s1.run().and_then(|_| s2.run())
+        }
+        Some(("addr2line", m)) => Addr2lineStep::new(m).run(),
+        Some(("gdb", m)) => GdbStep::new(m).run(),
         Some(("clean", _)) => CleanStep::new().run(),
         _ => Err("bad subcommand".into()),
     } {
@@ -247,42 +486,65 @@ fn cargo() -> String {
     env_or("CARGO", "cargo")
 }
 
+fn gdb() -> String {
+    env_or("GDB", "gdb")
+}
+
+/// Return the path to the LLVM tool in the current toolchain, if installed
+/// (via the llvm-tools component), or else just the name of the tool.
+fn llvm_tool(name: &str) -> String {
+    let toolchain = env_or("RUSTUP_TOOLCHAIN", "nightly-x86_64-unknown-none");
+
+    // find host architecture by taking last 3 segments from toolchain
+    let mut arch_segments: Box<[_]> = toolchain.split('-').rev().take(3).collect();
+    arch_segments.reverse();
+    let host = arch_segments.join("-");
+
+    let home = env_or("RUSTUP_HOME", "");
+    let mut path = PathBuf::from(home);
+    path.push("toolchains");
+    path.push(toolchain);
+    path.push("lib");
+    path.push("rustlib");
+    path.push(host);
+    path.push("bin");
+    path.push(name);
+    if path.exists() {
+        path.into_os_string().into_string().unwrap()
+    } else {
+        name.into()
+    }
+}
+
 fn objcopy() -> String {
-    let llvm_objcopy = {
-        let toolchain = env_or("RUSTUP_TOOLCHAIN", "nightly-x86_64-unknown-none");
-
-        // find host architecture by taking last 3 segments from toolchain
-        let mut arch_segments: Box<[_]> = toolchain.split('-').rev().take(3).collect();
-        arch_segments.reverse();
-        let host = arch_segments.join("-");
-
-        let home = env_or("RUSTUP_HOME", "");
-        let mut path = PathBuf::from(home);
-        path.push("toolchains");
-        path.push(toolchain);
-        path.push("lib");
-        path.push("rustlib");
-        path.push(host);
-        path.push("bin");
-        path.push("llvm-objcopy");
-        if path.exists() {
-            path.into_os_string().into_string().unwrap()
-        } else {
-            "llvm-objcopy".into()
-        }
-    };
-    env_or("OBJCOPY", &llvm_objcopy)
+    env_or("OBJCOPY", &llvm_tool("llvm-objcopy"))
+}
+
+fn llvm_size() -> String {
+    env_or("LLVM_SIZE", &llvm_tool("llvm-size"))
+}
+
+fn llvm_objdump() -> String {
+    env_or("OBJDUMP", &llvm_tool("llvm-objdump"))
+}
+
+fn llvm_nm() -> String {
+    env_or("NM", &llvm_tool("llvm-nm"))
+}
+
+/// The pager command, which may include arguments, e.g. `less -R`
+fn pager() -> String {
+    env_or("PAGER", "less")
 }
 
 fn load_config(arch: Arch, matches: &clap::ArgMatches) -> Configuration {
     let default = "default".to_string();
     let config_file = matches.try_get_one("config").ok().flatten().unwrap_or(&default);
-    Configuration::load(format!(
-        "{}/{}/lib/config_{}.toml",
-        workspace().display(),
-        arch.to_string().to_lowercase(),
-        config_file
-    ))
+    let arch = arch.to_string().to_lowercase();
+    Configuration::load(
+        format!("{}/{}/lib/config_{}.toml", workspace().display(), arch, config_file),
+        &arch,
+    )
 }
 
 fn verbose(matches: &clap::ArgMatches) -> bool {
@@ -293,6 +555,8 @@ struct BuildStep {
     arch: Arch,
     config: Configuration,
     profile: Profile,
+    /// Build with the arch's `qemu_test` feature, which runs the kernel tests
+    kernel_tests: bool,
     verbose: bool,
 }
 
@@ -303,7 +567,11 @@ impl BuildStep {
         let profile = Profile::from(matches);
         let verbose = verbose(matches);
 
-        Self { arch, config, profile, verbose }
+        Self { arch, config, profile, kernel_tests: false, verbose }
+    }
+
+    fn with_kernel_tests(self) -> Self {
+        Self { kernel_tests: true, ..self }
     }
 
     fn run(self) -> Result<()> {
@@ -322,6 +590,10 @@ impl BuildStep {
         cmd.arg("--workspace");
         cmd.arg("--exclude").arg("xtask");
         exclude_other_arches(self.arch, &mut cmd);
+        if self.kernel_tests {
+            cmd.arg("--features")
+                .arg(format!("{}/qemu_test", self.arch.to_string().to_lowercase()));
+        }
         if self.profile == Profile::Release {
             cmd.arg("--release");
         }
@@ -439,7 +711,20 @@ struct QemuStep {
     profile: Profile,
     wait_for_gdb: bool,
     kvm: bool,
+    /// QEMU -cpu value, replacing the arch's default
+    cpu: Option<String>,
     dump_dtb: String,
+    smp: Option<u8>,
+    memory: Option<String>,
+    trace: Option<Trace>,
+    uart: Option<Uart>,
+    /// Attach a virtio block device backed by target/disk.bin
+    disk: bool,
+    /// Escape hatch for QEMU options xtask doesn't know about
+    extra_qemu_args: Vec<String>,
+    /// Give the kernel a way to exit QEMU, and treat the exit status as the
+    /// result of its tests
+    kernel_tests: bool,
     verbose: bool,
 }
 
@@ -450,15 +735,90 @@ impl QemuStep {
         let profile = Profile::from(matches);
         let wait_for_gdb = matches.get_flag("gdb");
         let kvm = matches.get_flag("kvm");
+        let cpu = matches.get_one::<String>("cpu").cloned();
         let dump_dtb: String = matches
             .try_get_one::<String>("dump_dtb")
             .ok()
             .flatten()
             .unwrap_or(&"".to_string())
             .clone();
+        let smp = matches.get_one::<u8>("smp").copied();
+        let memory = matches.get_one::<String>("memory").cloned();
+        let trace = Trace::from(matches);
+        let uart = matches.get_one::<Uart>("uart").copied();
+        let disk = matches.get_flag("disk");
+        let extra_qemu_args = matches
+            .get_one::<String>("extra_qemu_args")
+            .map(|args| args.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
         let verbose = verbose(matches);
 
-        Self { arch, config, profile, wait_for_gdb, kvm, dump_dtb, verbose }
+        Self {
+            arch,
+            config,
+            profile,
+            wait_for_gdb,
+            kvm,
+            cpu,
+            dump_dtb,
+            smp,
+            memory,
+            trace,
+            uart,
+            disk,
+            extra_qemu_args,
+            kernel_tests: false,
+            verbose,
+        }
+    }
+
+    /// Run a kernel built with its `qemu_test` feature, with the defaults for
+    /// everything else.
+    fn for_kernel_tests(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let config = load_config(arch, matches);
+        let profile = Profile::from(matches);
+        let verbose = verbose(matches);
+
+        Self {
+            arch,
+            config,
+            profile,
+            wait_for_gdb: false,
+            kvm: false,
+            cpu: None,
+            dump_dtb: "".to_string(),
+            smp: None,
+            memory: None,
+            trace: None,
+            uart: None,
+            disk: false,
+            extra_qemu_args: Vec::new(),
+            kernel_tests: true,
+            verbose,
+        }
+    }
+
+    /// Check QEMU's exit status.  The kernel tests report their result
+    /// through it, as described in `port::ktest`: x86-64 exits via the
+    /// isa-debug-exit device, so (0x10 << 1) | 1 means success, and the
+    /// others via semihosting with 0 for success.
+    fn check_status(&self, status: process::ExitStatus) -> Result<()> {
+        if !self.kernel_tests {
+            if !status.success() {
+                return Err("qemu failed".into());
+            }
+            return Ok(());
+        }
+        let success = match self.arch {
+            Arch::X86_64 => 0x21,
+            Arch::Aarch64 | Arch::Riscv64 => 0,
+        };
+        match status.code() {
+            Some(code) if code == success => Ok(()),
+            Some(code) => Err(format!("kernel tests failed (qemu exit status {code})").into()),
+            None => Err("qemu killed by a signal".into()),
+        }
     }
 
     fn run(self) -> Result<()> {
@@ -469,6 +829,22 @@ impl QemuStep {
         if self.kvm && self.arch != Arch::X86_64 {
             return Err("KVM only supported under x86-64".into());
         }
+        // KVM runs guests on the host CPU, so other models can't be used.
+        // Options can still be added, e.g. host,-avx.
+        if let Some(cpu) = self.cpu.as_deref().filter(|_| self.kvm) {
+            if cpu.split(',').next() != Some("host") {
+                return Err(format!("--kvm needs the host CPU model, not {cpu}").into());
+            }
+        }
+        if !self.dump_dtb.is_empty() && self.arch == Arch::X86_64 {
+            return Err("x86-64 doesn't use a DTB, so there's none to dump".into());
+        }
+        if self.uart.is_some() && self.arch != Arch::Aarch64 {
+            return Err("UART selection only supported under aarch64".into());
+        }
+        if self.disk && self.arch != Arch::Riscv64 {
+            return Err("--disk only supported under riscv64".into());
+        }
 
         match self.arch {
             Arch::Aarch64 => {
@@ -476,32 +852,67 @@ impl QemuStep {
 
                 apply_to_qemu_step(&mut cmd, &self.config);
 
-                // TODO Choose UART at cmdline
-                // If using UART0 (PL011), this enables serial
+                // The machine and DTB come from the config, e.g. raspi4b.  If
+                // the config doesn't say, fall back to the Pi 3.
+                let qemu_config = self.config.qemu.as_ref();
+                if qemu_config.and_then(|qemu| qemu.machine.as_ref()).is_none() {
+                    cmd.arg("-M").arg("raspi3b");
+                    if qemu_config.and_then(|qemu| qemu.dtb.as_ref()).is_none() {
+                        cmd.arg("-dtb").arg("aarch64/lib/bcm2710-rpi-3-b.dtb");
+                    }
+                }
+
+                // QEMU merges -machine options, so this applies to the
+                // machine from the config.  QEMU writes the DTB it would
+                // pass to the kernel, then exits.
+                if !self.dump_dtb.is_empty() {
+                    cmd.arg("-machine").arg(format!("dumpdtb={}", self.dump_dtb));
+                }
+
                 cmd.arg("-nographic");
+                if let Some(cpu) = &self.cpu {
+                    cmd.arg("-cpu").arg(cpu);
+                }
+
+                // The raspi machines have the PL011 on the first serial port
+                // and the mini UART on the second.  Connect the one the
+                // kernel uses to the terminal, defaulting to the mini UART.
+                match self.uart.unwrap_or(Uart::MiniUart) {
+                    Uart::Pl011 => {
+                        cmd.arg("-serial").arg("mon:stdio");
+                        cmd.arg("-serial").arg("null");
+                    }
+                    Uart::MiniUart => {
+                        cmd.arg("-serial").arg("null");
+                        cmd.arg("-serial").arg("mon:stdio");
+                    }
+                }
 
-                // If using UART1 (MiniUART), this enables serial
-                cmd.arg("-serial");
-                cmd.arg("null");
-                cmd.arg("-serial");
-                cmd.arg("mon:stdio");
+                // The raspi machines have a fixed number of CPUs and amount of
+                // RAM, so only pass these if asked.
+                if let Some(smp) = self.smp {
+                    cmd.arg("-smp").arg(smp.to_string());
+                }
+                if let Some(memory) = &self.memory {
+                    cmd.arg("-m").arg(memory);
+                }
 
                 if self.wait_for_gdb {
                     cmd.arg("-s").arg("-S");
                 }
-                // Show exception level change events in stdout
-                cmd.arg("-d");
-                cmd.arg("int");
+                if self.kernel_tests {
+                    cmd.arg("-semihosting");
+                }
+                Trace::apply(self.trace.as_ref(), None, &mut cmd);
                 cmd.arg("-kernel");
                 cmd.arg(format!("target/{}/{}/aarch64-qemu.gz", target, dir));
+                cmd.args(&self.extra_qemu_args);
                 cmd.current_dir(workspace());
                 if self.verbose {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
             Arch::Riscv64 => {
                 let mut cmd = Command::new(qemu_system);
@@ -514,49 +925,53 @@ impl QemuStep {
                 } else {
                     cmd.arg("-machine").arg("virt");
                 }
-                cmd.arg("-cpu").arg("rv64");
-                // FIXME: This is not needed as of now, and will only work once the
-                // FIXME: disk.bin is also taken care of. Doesn't exist by default.
-                if false {
-                    cmd.arg("-drive").arg("file=disk.bin,format=raw,id=hd0");
+                cmd.arg("-cpu").arg(self.cpu.as_deref().unwrap_or("rv64"));
+                if self.disk {
+                    let disk = ensure_disk_image()?;
+                    cmd.arg("-drive").arg(format!("file={},format=raw,id=hd0", disk.display()));
                     cmd.arg("-device").arg("virtio-blk-device,drive=hd0");
                 }
+                // The kernel only drives the modern virtio MMIO interface
+                cmd.arg("-global").arg("virtio-mmio.force-legacy=false");
                 cmd.arg("-netdev").arg("type=user,id=net0");
                 cmd.arg("-device").arg("virtio-net-device,netdev=net0");
-                cmd.arg("-smp").arg("4");
-                cmd.arg("-m").arg("1024M");
+                cmd.arg("-smp").arg(self.smp.unwrap_or(4).to_string());
+                cmd.arg("-m").arg(self.memory.as_deref().unwrap_or("1024M"));
                 cmd.arg("-serial").arg("mon:stdio");
                 if self.wait_for_gdb {
                     cmd.arg("-s").arg("-S");
                 }
-                cmd.arg("-d").arg("guest_errors,unimp");
+                if self.kernel_tests {
+                    cmd.arg("-semihosting");
+                }
+                Trace::apply(self.trace.as_ref(), Some("guest_errors,unimp"), &mut cmd);
                 cmd.arg("-kernel");
                 cmd.arg(format!("target/{}/{}/riscv64", target, dir));
+                cmd.args(&self.extra_qemu_args);
                 cmd.current_dir(workspace());
                 if self.verbose {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
             Arch::X86_64 => {
                 let mut cmd = Command::new(qemu_system);
                 cmd.arg("-nographic");
                 //cmd.arg("-curses");
-                if self.kvm {
+                let default_cpu = if self.kvm {
                     cmd.arg("-accel").arg("kvm");
-                    cmd.arg("-cpu").arg("host,pdpe1gb,xsaveopt,fsgsbase,apic,msr");
+                    "host,pdpe1gb,xsaveopt,fsgsbase,apic,msr"
                 } else {
                     cmd.arg("-M").arg("q35");
-                    cmd.arg("-cpu").arg("qemu64,pdpe1gb,xsaveopt,fsgsbase,apic,msr");
-                }
+                    "qemu64,pdpe1gb,xsaveopt,fsgsbase,apic,msr"
+                };
+                cmd.arg("-cpu").arg(self.cpu.as_deref().unwrap_or(default_cpu));
                 cmd.arg("-smp");
-                cmd.arg("8");
+                cmd.arg(self.smp.unwrap_or(8).to_string());
                 cmd.arg("-s");
                 cmd.arg("-m");
-                cmd.arg("8192");
+                cmd.arg(self.memory.as_deref().unwrap_or("8192"));
                 if self.wait_for_gdb {
                     cmd.arg("-s").arg("-S");
                 }
@@ -566,16 +981,19 @@ impl QemuStep {
                 //cmd.arg("id=sdahci0,file=sdahci0.img,if=none");
                 //cmd.arg("-device");
                 //cmd.arg("ide-hd,drive=sdahci0,bus=ahci0.0");
+                if self.kernel_tests {
+                    cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+                }
+                Trace::apply(self.trace.as_ref(), None, &mut cmd);
                 cmd.arg("-kernel");
                 cmd.arg(format!("target/{}/{}/r9.elf32", target, dir));
+                cmd.args(&self.extra_qemu_args);
                 cmd.current_dir(workspace());
                 if self.verbose {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
         };
 
@@ -850,6 +1268,298 @@ impl CheckStep {
     }
 }
 
+struct SizeStep {
+    arch: Arch,
+    config: Configuration,
+    profile: Profile,
+    verbose: bool,
+}
+
+impl SizeStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let config = load_config(arch, matches);
+        let profile = Profile::from(matches);
+        let verbose = verbose(matches);
+        Self { arch, config, profile, verbose }
+    }
+
+    fn run(self) -> Result<()> {
+        let mut cmd = Command::new(llvm_size());
+        // SysV format lists each section with its size
+        cmd.arg("-A");
+        cmd.arg(format!(
+            "target/{}/{}/{}",
+            self.arch.target(),
+            self.profile.dir(),
+            self.arch.to_string().to_lowercase()
+        ));
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(
+                format!("llvm-size failed: {}", String::from_utf8_lossy(&output.stderr)).into()
+            );
+        }
+
+        // Add up the sizes of .text, .text.foo, etc.
+        let mut sizes = [(".text", 0u64), (".rodata", 0), (".data", 0), (".bss", 0)];
+        for line in String::from_utf8(output.stdout)?.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(section), Some(size)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let Ok(size) = size.parse::<u64>() else {
+                continue;
+            };
+            for (name, total) in sizes.iter_mut() {
+                if section == *name || section.starts_with(&format!("{name}.")) {
+                    *total += size;
+                }
+            }
+        }
+
+        let total: u64 = sizes.iter().map(|(_, size)| size).sum();
+        for (name, size) in sizes {
+            println!("{name:<8} {size:>10}");
+        }
+        println!("{:<8} {total:>10}", "total");
+
+        if let Some(max_total) = self.config.size.as_ref().and_then(|size| size.max_total) {
+            if total > max_total {
+                return Err(format!("kernel size {total} exceeds max_total {max_total}").into());
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ObjdumpStep {
+    arch: Arch,
+    profile: Profile,
+    section: Option<String>,
+    verbose: bool,
+}
+
+impl ObjdumpStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let profile = Profile::from(matches);
+        let section = matches.get_one::<String>("section").cloned();
+        let verbose = verbose(matches);
+        Self { arch, profile, section, verbose }
+    }
+
+    fn run(self) -> Result<()> {
+        let mut cmd = Command::new(llvm_objdump());
+        cmd.arg("--disassemble-all");
+        if let Some(section) = &self.section {
+            cmd.arg(format!("--section={section}"));
+        }
+        cmd.arg(format!(
+            "target/{}/{}/{}",
+            self.arch.target(),
+            self.profile.dir(),
+            self.arch.to_string().to_lowercase()
+        ));
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+
+        // The disassembly is long, so page it if a person is reading it
+        if !std::io::stdout().is_terminal() {
+            let status = annotated_status(&mut cmd)?;
+            if !status.success() {
+                return Err("llvm-objdump failed".into());
+            }
+            return Ok(());
+        }
+
+        let pager = pager();
+        let mut pager_words = pager.split_whitespace();
+        let mut pager_cmd = Command::new(pager_words.next().unwrap_or("less"));
+        pager_cmd.args(pager_words);
+
+        let mut objdump = cmd
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("{}: {}", cmd.get_program().to_string_lossy(), e))?;
+        let objdump_stdout = objdump.stdout.take().ok_or("llvm-objdump has no stdout")?;
+        pager_cmd.stdin(objdump_stdout);
+        if self.verbose {
+            println!("Executing {pager_cmd:?}");
+        }
+        let pager_status = annotated_status(&mut pager_cmd);
+        // Close our end of the pipe, so llvm-objdump can't block on it
+        drop(pager_cmd);
+        let status = objdump.wait()?;
+        // Quitting the pager early kills llvm-objdump with SIGPIPE, which
+        // isn't a failure
+        pager_status?;
+        if !status.success() && status.code().is_some() {
+            return Err("llvm-objdump failed".into());
+        }
+        Ok(())
+    }
+}
+
+/// Path of the kernel symbol table written by SymbolsStep, relative to the
+/// workspace
+fn symbols_path(arch: Arch, profile: Profile) -> PathBuf {
+    PathBuf::from(format!("target/{}/{}/kernel.syms", arch.target(), profile.dir()))
+}
+
+/// Find the symbol at or below addr in the output of `llvm-nm
+/// --numeric-sort`, returning its name and addr's offset from it.
+/// Undefined symbols, which have no address, are skipped.
+fn nearest_symbol(syms: &str, addr: u64) -> Option<(&str, u64)> {
+    let mut nearest = None;
+    for line in syms.lines() {
+        // Names can contain spaces, so they take the rest of the line
+        let mut fields = line.splitn(3, ' ');
+        let (Some(sym_addr), Some(_kind), Some(name)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(sym_addr) = u64::from_str_radix(sym_addr, 16) else {
+            continue;
+        };
+        // The symbols are sorted, so the last one at or below addr wins
+        if sym_addr > addr {
+            break;
+        }
+        nearest = Some((name, addr - sym_addr));
+    }
+    nearest
+}
+
+struct SymbolsStep {
+    arch: Arch,
+    profile: Profile,
+    verbose: bool,
+}
+
+impl SymbolsStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let profile = Profile::from(matches);
+        let verbose = verbose(matches);
+        Self { arch, profile, verbose }
+    }
+
+    fn run(self) -> Result<()> {
+        let mut cmd = Command::new(llvm_nm());
+        cmd.arg("--numeric-sort");
+        cmd.arg(format!(
+            "target/{}/{}/{}",
+            self.arch.target(),
+            self.profile.dir(),
+            self.arch.to_string().to_lowercase()
+        ));
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(
+                format!("llvm-nm failed: {}", String::from_utf8_lossy(&output.stderr)).into()
+            );
+        }
+
+        let path = workspace().join(symbols_path(self.arch, self.profile));
+        fs::write(&path, output.stdout)
+            .map_err(|e| format!("couldn't write {}: {e}", path.display()))?;
+        println!("{}", path.display());
+        Ok(())
+    }
+}
+
+struct Addr2lineStep {
+    arch: Arch,
+    profile: Profile,
+    addr: u64,
+}
+
+impl Addr2lineStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let profile = Profile::from(matches);
+        let addr = *matches.get_one::<u64>("addr").unwrap();
+        Self { arch, profile, addr }
+    }
+
+    fn run(self) -> Result<()> {
+        let path = workspace().join(symbols_path(self.arch, self.profile));
+        let syms = fs::read_to_string(&path).map_err(|e| {
+            format!("couldn't read {} ({e}), run `cargo xtask symbols` first", path.display())
+        })?;
+        let Some((name, offset)) = nearest_symbol(&syms, self.addr) else {
+            return Err(format!("no symbol at or below {:#x}", self.addr).into());
+        };
+        println!("{:#x} <{name}> + {offset:#x}", self.addr);
+        Ok(())
+    }
+}
+
+/// Port QEMU's gdb stub listens on, with -s
+const GDB_PORT: u16 = 1234;
+
+struct GdbStep {
+    arch: Arch,
+    profile: Profile,
+    verbose: bool,
+}
+
+impl GdbStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let profile = Profile::from(matches);
+        let verbose = verbose(matches);
+        Self { arch, profile, verbose }
+    }
+
+    /// Write a gdb script next to the kernel, then run gdb with it.  Symbols
+    /// come from the kernel ELF, not the binary QEMU boots.
+    fn run(self) -> Result<()> {
+        let dir = workspace().join("target").join(self.arch.target()).join(self.profile.dir());
+        let elf = dir.join(self.arch.to_string().to_lowercase());
+        if !elf.exists() {
+            return Err(format!("{} not found, build it first", elf.display()).into());
+        }
+        let gdb_arch = match self.arch {
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv:rv64",
+            Arch::X86_64 => "i386:x86-64",
+        };
+        let script = dir.join("gdbinit");
+        fs::write(
+            &script,
+            format!(
+                "set architecture {gdb_arch}\nfile {}\ntarget remote :{GDB_PORT}\n",
+                elf.display()
+            ),
+        )?;
+
+        let mut cmd = Command::new(gdb());
+        cmd.arg("-x").arg(&script);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let status = annotated_status(&mut cmd)?;
+        if !status.success() {
+            return Err("gdb failed".into());
+        }
+        Ok(())
+    }
+}
+
 struct CleanStep {}
 
 impl CleanStep {
@@ -869,6 +1579,84 @@ impl CleanStep {
     }
 }
 
+struct NetbootStep {
+    arch: Arch,
+    config: Configuration,
+    profile: Profile,
+    reset: bool,
+    verbose: bool,
+}
+
+impl NetbootStep {
+    fn new(matches: &clap::ArgMatches) -> Self {
+        let arch = Arch::from(matches);
+        let config = load_config(arch, matches);
+        let profile = Profile::from(matches);
+        let reset = matches.get_flag("reset");
+        let verbose = verbose(matches);
+        Self { arch, config, profile, reset, verbose }
+    }
+
+    fn run(self) -> Result<()> {
+        if self.arch != Arch::Aarch64 {
+            return Err("netboot only supported for aarch64".into());
+        }
+        let netboot = self.config.netboot.as_ref();
+
+        let tftp_root = env::var("TFTP_ROOT")
+            .ok()
+            .or_else(|| netboot.and_then(|n| n.tftp_root.clone()))
+            .ok_or("no TFTP directory: set TFTP_ROOT or tftp_root in the netboot config")?;
+        let tftp_root = PathBuf::from(tftp_root);
+        if !tftp_root.is_dir() {
+            return Err(format!("TFTP directory {} doesn't exist", tftp_root.display()).into());
+        }
+
+        let kernel =
+            format!("target/{}/{}/aarch64-qemu.gz", self.arch.target(), self.profile.dir());
+        let src = workspace().join(&kernel);
+        let dst = tftp_root.join("aarch64-qemu.gz");
+        if self.verbose {
+            println!("Copying {} to {}", src.display(), dst.display());
+        }
+        let size = std::fs::copy(&src, &dst)
+            .map_err(|e| format!("copying {} to {}: {e}", src.display(), dst.display()))?;
+        println!("{} ({size} bytes)", dst.display());
+
+        if self.reset {
+            let port = netboot
+                .and_then(|n| n.reset_port.as_ref())
+                .ok_or("no reset_port in the netboot config")?;
+            let command = netboot
+                .and_then(|n| n.reset_command.as_ref())
+                .ok_or("no reset_command in the netboot config")?;
+            if self.verbose {
+                println!("Writing {command:?} to {port}");
+            }
+            std::fs::write(port, command).map_err(|e| format!("resetting via {port}: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of the disk image created for the riscv64 virtio block device
+const DISK_IMAGE_SIZE: usize = 1024 * 1024;
+
+/// Return the path of the disk image for the riscv64 virtio block device,
+/// creating it if it doesn't exist.  A new image has a marker at the start
+/// of block 0, so reading it can be checked.
+fn ensure_disk_image() -> Result<PathBuf> {
+    let path = workspace().join("target").join("disk.bin");
+    if !path.exists() {
+        let mut image = vec![0u8; DISK_IMAGE_SIZE];
+        let marker = b"r9 test disk\n";
+        image[..marker.len()].copy_from_slice(marker);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, image)?;
+    }
+    Ok(path)
+}
+
 fn workspace() -> PathBuf {
     Path::new(&env!("CARGO_MANIFEST_DIR")).ancestors().nth(1).unwrap().to_path_buf()
 }
@@ -895,3 +1683,22 @@ fn exclude_other_arches(arch: Arch, cmd: &mut Command) {
 fn annotated_status(cmd: &mut Command) -> Result<process::ExitStatus> {
     Ok(cmd.status().map_err(|e| format!("{}: {}", cmd.get_program().to_string_lossy(), e))?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_symbol_finds_enclosing_symbol() {
+        let syms = "                 U undefined_sym\n\
+                    ffff800000080000 T _start\n\
+                    ffff800000080100 t closure {{closure}}\n\
+                    ffff800000080200 T main9\n";
+        assert_eq!(nearest_symbol(syms, 0xffff800000080000), Some(("_start", 0)));
+        assert_eq!(nearest_symbol(syms, 0xffff800000080010), Some(("_start", 0x10)));
+        assert_eq!(nearest_symbol(syms, 0xffff8000000801ff), Some(("closure {{closure}}", 0xff)));
+        assert_eq!(nearest_symbol(syms, 0xffff800000090000), Some(("main9", 0xfe00)));
+        assert_eq!(nearest_symbol(syms, 0xffff80000007ffff), None);
+        assert_eq!(nearest_symbol("", 0x1000), None);
+    }
+}